@@ -0,0 +1,209 @@
+//! Builds the files the kernel and its tests need that are not kept in the repository.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+fn main() {
+    build_initramfs();
+    build_fat_image();
+}
+
+/// The initial ramfs holds `initramfs/root` with `tests/programs/hello.elf` as `bin/hello`. The
+/// kernel embeds the ustar archive; the newc cpio archive of the same tree is used by the parser
+/// tests. Both are written with every owner and time set to 0, so that they only change with
+/// their contents.
+fn build_initramfs() {
+    let root = Path::new("initramfs/root");
+    let hello = Path::new("tests/programs/hello.elf");
+    println!("cargo::rerun-if-changed={}", root.display());
+    println!("cargo::rerun-if-changed={}", hello.display());
+
+    let mut entries = vec![InitramfsEntry::directory(String::from("."))];
+    add_directory(&mut entries, root, ".");
+    if !entries.iter().any(|entry| entry.name == "./bin") {
+        entries.push(InitramfsEntry::directory(String::from("./bin")));
+    }
+    entries.push(InitramfsEntry::file(String::from("./bin/hello"), hello));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    fs::write(out_dir.join("initramfs.tar"), tar_archive(&entries))
+        .expect("cannot write the initramfs tar archive");
+    fs::write(out_dir.join("initramfs.cpio"), cpio_archive(&entries))
+        .expect("cannot write the initramfs cpio archive");
+}
+
+struct InitramfsEntry {
+    /// Relative to the archive root, starting with `.` and without a trailing slash.
+    name: String,
+    mode: u32,
+    /// `None` for a directory.
+    data: Option<Vec<u8>>,
+}
+
+impl InitramfsEntry {
+    fn directory(name: String) -> Self {
+        InitramfsEntry {
+            name,
+            mode: 0o755,
+            data: None,
+        }
+    }
+
+    fn file(name: String, path: &Path) -> Self {
+        let metadata = fs::metadata(path)
+            .unwrap_or_else(|error| panic!("cannot read {}: {error}", path.display()));
+        InitramfsEntry {
+            name,
+            mode: metadata.permissions().mode() & 0o7777,
+            data: Some(
+                fs::read(path)
+                    .unwrap_or_else(|error| panic!("cannot read {}: {error}", path.display())),
+            ),
+        }
+    }
+}
+
+fn add_directory(entries: &mut Vec<InitramfsEntry>, directory: &Path, name: &str) {
+    let children = fs::read_dir(directory)
+        .unwrap_or_else(|error| panic!("cannot read {}: {error}", directory.display()));
+    for child in children {
+        let child = child.expect("cannot read a directory entry");
+        let child_name = format!("{name}/{}", child.file_name().to_string_lossy());
+        if child.path().is_dir() {
+            entries.push(InitramfsEntry::directory(child_name.clone()));
+            add_directory(entries, &child.path(), &child_name);
+        } else {
+            entries.push(InitramfsEntry::file(child_name, &child.path()));
+        }
+    }
+}
+
+/// A ustar archive laid out like GNU tar's: directory names end in a slash and the archive is
+/// padded to a whole 10 KiB record.
+fn tar_archive(entries: &[InitramfsEntry]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 512;
+    const RECORD_SIZE: usize = 20 * BLOCK_SIZE;
+
+    let mut archive = Vec::new();
+    for entry in entries {
+        let mut header = [0u8; BLOCK_SIZE];
+        let (name, type_flag, data) = match &entry.data {
+            Some(data) => (entry.name.clone(), b'0', data.as_slice()),
+            None => (format!("{}/", entry.name), b'5', [].as_slice()),
+        };
+        assert!(name.len() < 100, "{name} is too long for a ustar header");
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], entry.mode.into());
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], data.len() as u64);
+        write_octal(&mut header[136..148], 0);
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        write_octal(&mut header[329..337], 0);
+        write_octal(&mut header[337..345], 0);
+        // The checksum is taken with its own field filled with spaces.
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+        write_octal(&mut header[148..155], checksum);
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+    }
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    archive.resize(archive.len().next_multiple_of(RECORD_SIZE), 0);
+    archive
+}
+
+/// Fills `field` with `value` in zero-padded octal, followed by a NUL.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}\0", width = field.len() - 1);
+    assert_eq!(
+        digits.len(),
+        field.len(),
+        "{value} does not fit in a tar header field"
+    );
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// A newc cpio archive, with inode numbers counted from 1 and ending in the usual trailer entry.
+fn cpio_archive(entries: &[InitramfsEntry]) -> Vec<u8> {
+    const MODE_DIRECTORY: u32 = 0o040000;
+    const MODE_REGULAR: u32 = 0o100000;
+
+    let mut archive = Vec::new();
+    let mut add = |inode: u32, mode: u32, links: u32, name: &str, data: &[u8]| {
+        let fields = [
+            inode,
+            mode,
+            0,
+            0,
+            links,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    };
+    for (inode, entry) in (1..).zip(entries) {
+        match &entry.data {
+            Some(data) => add(inode, MODE_REGULAR | entry.mode, 1, &entry.name, data),
+            None => add(inode, MODE_DIRECTORY | entry.mode, 2, &entry.name, &[]),
+        }
+    }
+    add(0, 0, 1, "TRAILER!!!", &[]);
+    archive
+}
+
+/// `tests/disks/fat.img`, which QEMU attaches to the test kernels, comes from mkfs.fat and
+/// mtools through `tests/disks/fat.sh`. Without them, an empty disk takes its place so that the
+/// other tests still run and only the FAT tests fail.
+fn build_fat_image() {
+    let script = Path::new("tests/disks/fat.sh");
+    let image = Path::new("tests/disks/fat.img");
+    println!("cargo::rerun-if-changed={}", script.display());
+    println!("cargo::rerun-if-changed={}", image.display());
+    if is_up_to_date(image, script) {
+        return;
+    }
+    let built = Command::new("sh")
+        .arg(script)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !built {
+        println!(
+            "cargo::warning=could not build {} with mkfs.fat and mtools; the FAT tests will fail",
+            image.display()
+        );
+        fs::write(image, []).expect("cannot write the placeholder FAT image");
+    }
+}
+
+/// Whether `output` has been built, and not before `input` last changed.
+fn is_up_to_date(output: &Path, input: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (fs::metadata(output), modified(output), modified(input)) {
+        (Ok(metadata), Ok(output), Ok(input)) => metadata.len() > 0 && output >= input,
+        _ => false,
+    }
+}
@@ -0,0 +1,89 @@
+//! Just enough ACPI to find the firmware's description tables, such as the MCFG table locating
+//! PCI Express configuration space.
+
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The real-mode segment of the extended BIOS data area is stored here.
+const EBDA_SEGMENT_ADDRESS: u64 = 0x40E;
+const BIOS_AREA: core::ops::Range<u64> = 0xE0000..0x100000;
+const HEADER_SIZE: usize = 36;
+
+/// Returns the contents of the first table with the given signature, header included, if the
+/// firmware provides one with a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root, entry_size) = root_table()?;
+    root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            4 => u64::from(read_u32(entry, 0)),
+            _ => read_u64(entry, 0),
+        })
+        .filter_map(|address| table_at(PhysAddr::new(address)))
+        .find(|table| &table[..4] == signature)
+}
+
+/// The RSDT or XSDT, and the size of its entries.
+fn root_table() -> Option<(&'static [u8], usize)> {
+    let address = find_rsdp()?;
+    let rsdp = unsafe { physical_bytes(address, 20) };
+    // Revision 2 adds the 64-bit XSDT address, covered by a checksum over 36 bytes.
+    if rsdp[15] >= 2 {
+        let extended = unsafe { physical_bytes(address, 36) };
+        if checksum(extended)
+            && let Some(xsdt) = table_at(PhysAddr::new(read_u64(extended, 24)))
+        {
+            return Some((xsdt, 8));
+        }
+    }
+    let rsdt = table_at(PhysAddr::new(u64::from(read_u32(rsdp, 16))))?;
+    Some((rsdt, 4))
+}
+
+/// Scans the first KiB of the extended BIOS data area and the BIOS read-only area for the root
+/// system description pointer.
+fn find_rsdp() -> Option<PhysAddr> {
+    let segment = unsafe { physical_bytes(PhysAddr::new(EBDA_SEGMENT_ADDRESS), 2) };
+    let ebda = u64::from(u16::from_le_bytes([segment[0], segment[1]])) << 4;
+    let candidates = (ebda..ebda + 1024).step_by(16).filter(|_| ebda != 0);
+    candidates
+        .chain(BIOS_AREA.step_by(16))
+        .map(PhysAddr::new)
+        .find(|&address| {
+            let bytes = unsafe { physical_bytes(address, 20) };
+            &bytes[..8] == RSDP_SIGNATURE && checksum(bytes)
+        })
+}
+
+/// The table at `address` if its checksum is valid.
+pub(crate) fn table_at(address: PhysAddr) -> Option<&'static [u8]> {
+    let header = unsafe { physical_bytes(address, HEADER_SIZE) };
+    let length = read_u32(header, 4) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = unsafe { physical_bytes(address, length) };
+    checksum(table).then_some(table)
+}
+
+/// Checks that the bytes of a table add up to zero.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// # Safety
+/// The range must lie in physical memory mapped by the bootloader.
+unsafe fn physical_bytes(address: PhysAddr, length: usize) -> &'static [u8] {
+    let start = memory::physical_to_virtual(address);
+    unsafe { core::slice::from_raw_parts(start.as_ptr(), length) }
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,72 @@
+use linked_list_allocator::LockedHeap;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+};
+
+use crate::memory::{self, MemoryError};
+
+/// The kernel heap lives in the higher half so it never collides with user mappings.
+pub const HEAP_START: u64 = 0xffff_a000_0000_0000;
+pub const HEAP_SIZE: u64 = 8 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// Maps the heap pages into the boot page table and hands them to the global allocator.
+///
+/// Must run before any address space is created so every address space shares the heap.
+pub fn init_heap() -> Result<(), MemoryError> {
+    let first_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(HEAP_START));
+    let last_page: Page<Size4KiB> =
+        Page::containing_address(VirtAddr::new(HEAP_START + HEAP_SIZE - 1));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let mut page_table = memory::PAGE_TABLE
+        .get()
+        .expect("memory::init has not been called")
+        .lock();
+    let mut frame_allocator = memory::FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock();
+    for page in Page::range_inclusive(first_page, last_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MemoryError::OutOfFrames)?;
+        unsafe {
+            page_table
+                .map_to(page, frame, flags, &mut *frame_allocator)
+                .map_err(|_| MemoryError::AlreadyMapped)?
+                .flush();
+        }
+    }
+
+    unsafe {
+        ALLOCATOR
+            .lock()
+            .init(HEAP_START as *mut u8, HEAP_SIZE as usize);
+    }
+    Ok(())
+}
+
+/// Returns the number of heap bytes currently handed out and the number still free.
+pub fn usage() -> (usize, usize) {
+    let heap = ALLOCATOR.lock();
+    (heap.used(), heap.free())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+
+    #[test_case]
+    fn test_heap_allocations_are_reused() {
+        for value in 0..super::HEAP_SIZE / 64 {
+            let boxed = Box::new(value);
+            assert_eq!(*boxed, value);
+        }
+        let values: Vec<u64> = (0..1000).collect();
+        assert_eq!(values.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+}
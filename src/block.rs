@@ -0,0 +1,102 @@
+//! Block devices: disks addressed in fixed-size blocks, and the registry drivers add them to.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+use spin::Mutex;
+
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod virtio;
+
+pub use cache::BlockCache;
+pub use partition::Partition;
+
+/// The block size of every disk the kernel currently drives.
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last block of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    BadBufferSize,
+    /// The device did not answer in time.
+    Timeout,
+    /// The device reported an error; the value is its error register.
+    Device(u8),
+    ReadOnly,
+    /// The device cannot carry out this kind of request.
+    Unsupported,
+}
+
+/// A device that reads and writes whole blocks.
+pub trait BlockDevice: Send + Sync {
+    /// The name the device is registered under, such as `hda`.
+    fn name(&self) -> &str;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64;
+
+    /// Reads `buffer.len() / block_size()` blocks starting at `block`.
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer.len() / block_size()` blocks starting at `block`.
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes every completed write durable.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Checks that `length` bytes starting at `block` are whole blocks on the device.
+    fn check_request(&self, block: u64, length: usize) -> Result<u64, BlockError> {
+        let block_size = self.block_size();
+        if !length.is_multiple_of(block_size) {
+            return Err(BlockError::BadBufferSize);
+        }
+        let count = (length / block_size) as u64;
+        match block.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl fmt::Debug for dyn BlockDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockDevice")
+            .field("name", &self.name())
+            .field("block_count", &self.block_count())
+            .finish()
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Probes the disk controllers and registers the devices found. Must run after PCI enumeration.
+pub fn init() {
+    ata::init();
+    crate::pci::register_driver(&virtio::DRIVER);
+    partition::register_all();
+}
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push(device);
+}
+
+/// Every registered device, in registration order.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
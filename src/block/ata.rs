@@ -0,0 +1,453 @@
+//! A polling PIO driver for ATA disks on the legacy IDE ports, as emulated by QEMU's PIIX
+//! controller. ATAPI and SATA devices answer IDENTIFY with a different signature and are skipped.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// The I/O base and control ports of the primary and secondary channels.
+const CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
+const REGISTER_DATA: u16 = 0;
+const REGISTER_ERROR: u16 = 1;
+const REGISTER_SECTOR_COUNT: u16 = 2;
+const REGISTER_LBA_LOW: u16 = 3;
+const REGISTER_LBA_MID: u16 = 4;
+const REGISTER_LBA_HIGH: u16 = 5;
+const REGISTER_DRIVE: u16 = 6;
+const REGISTER_STATUS: u16 = 7;
+const REGISTER_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+/// What a read of the status register returns when no controller drives the bus.
+const STATUS_FLOATING: u8 = 0xFF;
+
+/// Device control bit that masks the channel's interrupt; the driver polls instead.
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// The first sector that cannot be addressed with 28-bit LBA.
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA48_LIMIT: u64 = 1 << 48;
+/// Larger transfers are split into several commands of at most this many sectors.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+/// How often the status register is read before a command is given up on. The timer cannot be
+/// used as drives are probed before interrupts are enabled.
+const POLL_LIMIT: u32 = 1_000_000;
+
+const IDENTIFY_SERIAL: core::ops::Range<usize> = 10..20;
+const IDENTIFY_MODEL: core::ops::Range<usize> = 27..47;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_COMMAND_SETS: usize = 83;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Master,
+    Slave,
+}
+
+/// How the sector number of a command is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    Lba28,
+    Lba48,
+}
+
+impl Addressing {
+    fn limit(self) -> u64 {
+        match self {
+            Addressing::Lba28 => LBA28_LIMIT,
+            Addressing::Lba48 => LBA48_LIMIT,
+        }
+    }
+}
+
+/// The registers of one IDE channel, shared by its master and slave drive.
+#[derive(Debug)]
+struct Channel {
+    base: u16,
+    control: u16,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn set_control(&mut self, value: u8) {
+        unsafe { Port::new(self.control).write(value) }
+    }
+
+    /// Reads the alternate status register, which unlike the status register does not
+    /// acknowledge an interrupt.
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Selects a drive and gives it the 400ns it needs to put its status on the bus.
+    fn select(&mut self, position: Position, bits: u8) {
+        let slave = match position {
+            Position::Master => 0,
+            Position::Slave => DRIVE_SLAVE,
+        };
+        self.write(REGISTER_DRIVE, DRIVE_LBA | slave | bits);
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits until the drive is ready to transfer a sector.
+    fn wait_data(&self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+                return Err(BlockError::Device(self.read(REGISTER_ERROR)));
+            }
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits for a command without a data phase to finish.
+    fn wait_done(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(BlockError::Device(self.read(REGISTER_ERROR)));
+        }
+        // Reading the status register acknowledges the command.
+        self.read(REGISTER_STATUS);
+        Ok(())
+    }
+
+    /// Selects a drive and loads the sector number and count of a command.
+    fn set_address(&mut self, position: Position, addressing: Addressing, lba: u64, count: u16) {
+        match addressing {
+            Addressing::Lba28 => {
+                self.select(position, (lba >> 24) as u8 & 0x0F);
+                self.write(REGISTER_SECTOR_COUNT, count as u8);
+                self.write(REGISTER_LBA_LOW, lba as u8);
+                self.write(REGISTER_LBA_MID, (lba >> 8) as u8);
+                self.write(REGISTER_LBA_HIGH, (lba >> 16) as u8);
+            }
+            Addressing::Lba48 => {
+                self.select(position, 0);
+                // The high bytes go first; each register holds two bytes.
+                self.write(REGISTER_SECTOR_COUNT, (count >> 8) as u8);
+                self.write(REGISTER_LBA_LOW, (lba >> 24) as u8);
+                self.write(REGISTER_LBA_MID, (lba >> 32) as u8);
+                self.write(REGISTER_LBA_HIGH, (lba >> 40) as u8);
+                self.write(REGISTER_SECTOR_COUNT, count as u8);
+                self.write(REGISTER_LBA_LOW, lba as u8);
+                self.write(REGISTER_LBA_MID, (lba >> 8) as u8);
+                self.write(REGISTER_LBA_HIGH, (lba >> 16) as u8);
+            }
+        }
+    }
+
+    fn read_sector(&mut self, sector: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.base + REGISTER_DATA);
+        for word in sector.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&mut self, sector: &[u8]) {
+        let mut data = Port::<u16>::new(self.base + REGISTER_DATA);
+        for word in sector.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    /// Runs IDENTIFY on a drive, returning its 256 identification words if it is an ATA disk.
+    fn identify(&mut self, position: Position) -> Option<[u16; 256]> {
+        self.select(position, 0);
+        self.write(REGISTER_SECTOR_COUNT, 0);
+        self.write(REGISTER_LBA_LOW, 0);
+        self.write(REGISTER_LBA_MID, 0);
+        self.write(REGISTER_LBA_HIGH, 0);
+        self.write(REGISTER_COMMAND, COMMAND_IDENTIFY);
+        if self.read(REGISTER_STATUS) == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA devices abort IDENTIFY and leave their signature in the LBA registers.
+        if self.read(REGISTER_LBA_MID) != 0 || self.read(REGISTER_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+        let mut bytes = [0; SECTOR_SIZE];
+        self.read_sector(&mut bytes);
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(words)
+    }
+}
+
+/// An ATA hard disk.
+#[derive(Debug)]
+pub struct AtaDrive {
+    name: String,
+    channel: Arc<Mutex<Channel>>,
+    position: Position,
+    model: String,
+    serial: String,
+    sectors: u64,
+    lba48: bool,
+}
+
+impl AtaDrive {
+    fn new(
+        name: String,
+        channel: Arc<Mutex<Channel>>,
+        position: Position,
+        identity: &[u16; 256],
+    ) -> Self {
+        let lba48 = identity[IDENTIFY_COMMAND_SETS] & COMMAND_SET_LBA48 != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, index| {
+                sectors | u64::from(identity[IDENTIFY_LBA48_SECTORS + index]) << (16 * index)
+            })
+        } else {
+            u64::from(identity[IDENTIFY_LBA28_SECTORS])
+                | u64::from(identity[IDENTIFY_LBA28_SECTORS + 1]) << 16
+        };
+        Self {
+            name,
+            channel,
+            position,
+            model: identify_string(&identity[IDENTIFY_MODEL]),
+            serial: identify_string(&identity[IDENTIFY_SERIAL]),
+            sectors,
+            lba48,
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.lba48
+    }
+
+    /// Reads sectors using the given addressing mode rather than the one picked for the request.
+    pub fn read_sectors(
+        &self,
+        lba: u64,
+        buffer: &mut [u8],
+        addressing: Addressing,
+    ) -> Result<(), BlockError> {
+        let count = self.check_addressing(lba, buffer.len(), addressing)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let command = match addressing {
+            Addressing::Lba28 => COMMAND_READ_SECTORS,
+            Addressing::Lba48 => COMMAND_READ_SECTORS_EXT,
+        };
+        let mut channel = self.channel.lock();
+        let mut lba = lba;
+        for chunk in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE) {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            channel.set_address(self.position, addressing, lba, sectors as u16);
+            channel.write(REGISTER_COMMAND, command);
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.read_sector(sector);
+            }
+            lba += sectors as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes sectors using the given addressing mode and flushes the drive's write cache.
+    pub fn write_sectors(
+        &self,
+        lba: u64,
+        buffer: &[u8],
+        addressing: Addressing,
+    ) -> Result<(), BlockError> {
+        let count = self.check_addressing(lba, buffer.len(), addressing)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let command = match addressing {
+            Addressing::Lba28 => COMMAND_WRITE_SECTORS,
+            Addressing::Lba48 => COMMAND_WRITE_SECTORS_EXT,
+        };
+        let mut channel = self.channel.lock();
+        let mut lba = lba;
+        for chunk in buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE) {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            channel.set_address(self.position, addressing, lba, sectors as u16);
+            channel.write(REGISTER_COMMAND, command);
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.write_sector(sector);
+            }
+            channel.wait_done()?;
+            lba += sectors as u64;
+        }
+        Self::flush_cache(&mut channel, self.position, addressing)
+    }
+
+    /// The addressing mode for a request: 48-bit only where 28 bits are not enough.
+    fn addressing_for(&self, lba: u64, length: usize) -> Addressing {
+        let end = lba.saturating_add((length / SECTOR_SIZE) as u64);
+        if self.lba48 && end > LBA28_LIMIT {
+            Addressing::Lba48
+        } else {
+            Addressing::Lba28
+        }
+    }
+
+    fn check_addressing(
+        &self,
+        lba: u64,
+        length: usize,
+        addressing: Addressing,
+    ) -> Result<u64, BlockError> {
+        if addressing == Addressing::Lba48 && !self.lba48 {
+            return Err(BlockError::Unsupported);
+        }
+        let count = self.check_request(lba, length)?;
+        if lba + count > addressing.limit() {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(count)
+    }
+
+    fn flush_cache(
+        channel: &mut Channel,
+        position: Position,
+        addressing: Addressing,
+    ) -> Result<(), BlockError> {
+        channel.select(position, 0);
+        channel.write(
+            REGISTER_COMMAND,
+            match addressing {
+                Addressing::Lba28 => COMMAND_CACHE_FLUSH,
+                Addressing::Lba48 => COMMAND_CACHE_FLUSH_EXT,
+            },
+        );
+        channel.wait_done()
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let addressing = self.addressing_for(block, buffer.len());
+        self.read_sectors(block, buffer, addressing)
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let addressing = self.addressing_for(block, buffer.len());
+        self.write_sectors(block, buffer, addressing)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let addressing = match self.lba48 {
+            true => Addressing::Lba48,
+            false => Addressing::Lba28,
+        };
+        Self::flush_cache(&mut self.channel.lock(), self.position, addressing)
+    }
+}
+
+/// Decodes an IDENTIFY string, which stores two characters per word with the first in the high
+/// byte.
+fn identify_string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+static DRIVES: Mutex<Vec<Arc<AtaDrive>>> = Mutex::new(Vec::new());
+
+/// The ATA disks found at boot, for callers that need more than [`BlockDevice`].
+pub fn drives() -> Vec<Arc<AtaDrive>> {
+    DRIVES.lock().clone()
+}
+
+/// Probes both channels and registers every ATA disk found as `hda` to `hdd`.
+pub fn init() {
+    for (index, &(base, control)) in CHANNELS.iter().enumerate() {
+        let mut channel = Channel { base, control };
+        if channel.read(REGISTER_STATUS) == STATUS_FLOATING {
+            continue;
+        }
+        channel.set_control(CONTROL_NO_INTERRUPTS);
+        let channel = Arc::new(Mutex::new(channel));
+        for (offset, position) in [Position::Master, Position::Slave].into_iter().enumerate() {
+            let Some(identity) = channel.lock().identify(position) else {
+                continue;
+            };
+            let name = format!("hd{}", char::from(b'a' + (index * 2 + offset) as u8));
+            let drive = Arc::new(AtaDrive::new(name, channel.clone(), position, &identity));
+            DRIVES.lock().push(drive.clone());
+            super::register(drive);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! A write-back cache of the most recently used blocks of a device.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec};
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks written to the device, on eviction or by a sync.
+    pub write_backs: u64,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// When the block was last used; the smallest stamp is evicted first.
+    stamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// The cached blocks by stamp, least recently used first.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    statistics: CacheStatistics,
+}
+
+/// Keeps up to `capacity` blocks of a device in memory. Writes only reach the device when a dirty
+/// block is evicted, on [`BlockCache::sync`] or when the cache is dropped.
+#[derive(Debug)]
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        assert!(capacity > 0, "a block cache needs room for one block");
+        Self {
+            device,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        self.state.lock().statistics
+    }
+
+    /// The number of modified blocks not yet written to the device.
+    pub fn dirty_blocks(&self) -> usize {
+        let state = self.state.lock();
+        state.blocks.values().filter(|block| block.dirty).count()
+    }
+
+    /// Writes every modified block to the device, in block order, and flushes the device.
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        for (&number, block) in state.blocks.iter_mut().filter(|(_, block)| block.dirty) {
+            self.device.write_blocks(number, &block.data)?;
+            block.dirty = false;
+            state.statistics.write_backs += 1;
+        }
+        self.device.flush()
+    }
+
+    /// Returns the cached copy of `number`, reading it from the device unless `fill` is false,
+    /// in which case a miss yields a zeroed block the caller is about to overwrite.
+    fn block<'a>(
+        &self,
+        state: &'a mut CacheState,
+        number: u64,
+        fill: bool,
+    ) -> Result<&'a mut CachedBlock, BlockError> {
+        state.clock += 1;
+        let stamp = state.clock;
+        if let Some(block) = state.blocks.get_mut(&number) {
+            state.recency.remove(&block.stamp);
+            state.recency.insert(stamp, number);
+            block.stamp = stamp;
+            state.statistics.hits += 1;
+            return Ok(state.blocks.get_mut(&number).unwrap());
+        }
+
+        state.statistics.misses += 1;
+        if state.blocks.len() >= self.capacity {
+            self.evict(state)?;
+        }
+        let mut data = vec![0; self.device.block_size()].into_boxed_slice();
+        if fill {
+            self.device.read_blocks(number, &mut data)?;
+        }
+        state.recency.insert(stamp, number);
+        Ok(state.blocks.entry(number).or_insert(CachedBlock {
+            data,
+            dirty: false,
+            stamp,
+        }))
+    }
+
+    /// Drops the least recently used block, writing it back first if it was modified.
+    fn evict(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let Some((&stamp, &number)) = state.recency.iter().next() else {
+            return Ok(());
+        };
+        let block = &state.blocks[&number];
+        if block.dirty {
+            self.device.write_blocks(number, &block.data)?;
+            state.statistics.write_backs += 1;
+        }
+        state.recency.remove(&stamp);
+        state.blocks.remove(&number);
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(block, buffer.len())?;
+        let mut state = self.state.lock();
+        for (number, chunk) in (block..).zip(buffer.chunks_exact_mut(self.block_size())) {
+            chunk.copy_from_slice(&self.block(&mut state, number, true)?.data);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.check_request(block, buffer.len())?;
+        let mut state = self.state.lock();
+        for (number, chunk) in (block..).zip(buffer.chunks_exact(self.block_size())) {
+            let cached = self.block(&mut state, number, false)?;
+            cached.data.copy_from_slice(chunk);
+            cached.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        // Nobody is left to report the error to.
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::SECTOR_SIZE;
+    use alloc::vec::Vec;
+
+    /// A disk in memory that counts the blocks read from it.
+    #[derive(Debug)]
+    struct MemoryDisk {
+        data: Mutex<Vec<u8>>,
+        reads: Mutex<u64>,
+    }
+
+    impl BlockDevice for MemoryDisk {
+        fn name(&self) -> &str {
+            "memory"
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.data.lock().len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+            let start = block as usize * SECTOR_SIZE;
+            buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+            *self.reads.lock() += (buffer.len() / SECTOR_SIZE) as u64;
+            Ok(())
+        }
+
+        fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+            let start = block as usize * SECTOR_SIZE;
+            self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn memory_disk(blocks: usize) -> Arc<MemoryDisk> {
+        Arc::new(MemoryDisk {
+            data: Mutex::new(vec![0; blocks * SECTOR_SIZE]),
+            reads: Mutex::new(0),
+        })
+    }
+
+    #[test_case]
+    fn test_least_recently_used_block_is_evicted() {
+        let disk = memory_disk(8);
+        let cache = BlockCache::new(disk.clone(), 2);
+        let mut buffer = [0; SECTOR_SIZE];
+        cache.read_blocks(0, &mut buffer).unwrap();
+        cache.read_blocks(1, &mut buffer).unwrap();
+        cache.read_blocks(0, &mut buffer).unwrap();
+        // Evicts block 1, which was used less recently than block 0.
+        cache.read_blocks(2, &mut buffer).unwrap();
+        cache.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(*disk.reads.lock(), 3);
+        cache.read_blocks(1, &mut buffer).unwrap();
+        assert_eq!(*disk.reads.lock(), 4);
+        assert_eq!(
+            cache.statistics(),
+            CacheStatistics {
+                hits: 2,
+                misses: 4,
+                write_backs: 0
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_writes_reach_the_device_on_eviction_and_sync() {
+        let disk = memory_disk(8);
+        let cache = BlockCache::new(disk.clone(), 2);
+        cache.write_blocks(3, &[3; 2 * SECTOR_SIZE]).unwrap();
+        assert_eq!(cache.dirty_blocks(), 2);
+        assert!(disk.data.lock().iter().all(|&byte| byte == 0));
+
+        let mut buffer = [0; SECTOR_SIZE];
+        cache.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(disk.data.lock()[3 * SECTOR_SIZE], 3);
+        assert_eq!(disk.data.lock()[4 * SECTOR_SIZE], 0);
+
+        cache.sync().unwrap();
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(disk.data.lock()[4 * SECTOR_SIZE], 3);
+        assert_eq!(cache.statistics().write_backs, 2);
+        // Written blocks were never read from the device.
+        assert_eq!(*disk.reads.lock(), 1);
+    }
+}
@@ -0,0 +1,359 @@
+//! MBR and GPT partition tables, and partitions as block devices of their own.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Logical partitions are numbered after the four primary slots.
+const FIRST_LOGICAL_NUMBER: u32 = 5;
+/// Bounds the walk of a corrupt, cyclic chain of extended boot records.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// More entries than any real table has; the standard table holds 128.
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+    /// Sector 0 holds no MBR signature.
+    NoPartitionTable,
+    /// Neither the primary nor the backup GPT header is valid.
+    BadGptHeader,
+    /// The partition entries do not match the CRC in the GPT header.
+    BadGptEntries,
+    /// A partition reaches outside the disk.
+    OutOfRange,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(error: BlockError) -> Self {
+        PartitionError::Block(error)
+    }
+}
+
+/// What a partition table says a partition holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID byte of an MBR entry.
+    Mbr(u8),
+    Gpt {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+        label: String,
+    },
+}
+
+/// A contiguous range of blocks of another device.
+#[derive(Debug)]
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    number: u32,
+    first_block: u64,
+    block_count: u64,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    /// The partition's number as Linux counts it: 1 to 4 for MBR primary partitions, 5 on for
+    /// logical partitions, and the entry index plus one for GPT.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    pub fn first_block(&self) -> u64 {
+        self.first_block
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(block, buffer.len())?;
+        self.device.read_blocks(self.first_block + block, buffer)
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.check_request(block, buffer.len())?;
+        self.device.write_blocks(self.first_block + block, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Where a partition table places a partition, before it is checked against the disk.
+struct Entry {
+    number: u32,
+    first_block: u64,
+    block_count: u64,
+    kind: PartitionKind,
+}
+
+/// Reads the partition table of `device`. A protective MBR is followed to the GPT behind it.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    if device.block_size() != SECTOR_SIZE {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let mut mbr = [0; SECTOR_SIZE];
+    device.read_blocks(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let entries = if mbr_entries(&mbr).any(|(system_id, _, _)| system_id == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        read_gpt(device.as_ref())?
+    } else {
+        read_mbr(device.as_ref(), &mbr)?
+    };
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let end = entry.first_block.checked_add(entry.block_count);
+            if end.is_none_or(|end| end > device.block_count()) {
+                return Err(PartitionError::OutOfRange);
+            }
+            Ok(Partition {
+                name: partition_name(device.name(), entry.number),
+                device: device.clone(),
+                number: entry.number,
+                first_block: entry.first_block,
+                block_count: entry.block_count,
+                kind: entry.kind,
+            })
+        })
+        .collect()
+}
+
+/// Scans every registered disk and registers the partitions found on it.
+pub fn register_all() {
+    for device in super::devices() {
+        if let Ok(partitions) = scan(&device) {
+            for partition in partitions {
+                super::register(Arc::new(partition));
+            }
+        }
+    }
+}
+
+/// Names partitions like Linux does: `hda1`, but `nvme0n1p1` where the disk name ends in a digit.
+fn partition_name(disk: &str, number: u32) -> String {
+    if disk.ends_with(|character: char| character.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+/// The system ID, first block and block count of the four entries of an MBR or EBR.
+fn mbr_entries(sector: &[u8]) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+    sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * MBR_ENTRY_SIZE]
+        .chunks_exact(MBR_ENTRY_SIZE)
+        .map(|entry| {
+            (
+                entry[4],
+                u64::from(read_u32(entry, 8)),
+                u64::from(read_u32(entry, 12)),
+            )
+        })
+}
+
+fn read_mbr(device: &dyn BlockDevice, mbr: &[u8]) -> Result<Vec<Entry>, PartitionError> {
+    let mut entries = Vec::new();
+    for (number, (system_id, first_block, block_count)) in (1..).zip(mbr_entries(mbr)) {
+        if system_id == MBR_TYPE_EMPTY || block_count == 0 {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&system_id) {
+            read_logical_partitions(device, first_block, &mut entries)?;
+        } else {
+            entries.push(Entry {
+                number,
+                first_block,
+                block_count,
+                kind: PartitionKind::Mbr(system_id),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Walks the chain of extended boot records of the extended partition starting at `extended`.
+/// Each record describes one logical partition relative to itself, and the next record relative
+/// to the start of the extended partition.
+fn read_logical_partitions(
+    device: &dyn BlockDevice,
+    extended: u64,
+    entries: &mut Vec<Entry>,
+) -> Result<(), PartitionError> {
+    let mut record = extended;
+    let mut sector = [0; SECTOR_SIZE];
+    for number in FIRST_LOGICAL_NUMBER..FIRST_LOGICAL_NUMBER + MAX_LOGICAL_PARTITIONS {
+        if record >= device.block_count() {
+            return Err(PartitionError::OutOfRange);
+        }
+        device.read_blocks(record, &mut sector)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            return Err(PartitionError::NoPartitionTable);
+        }
+        let mut slots = mbr_entries(&sector);
+        let (system_id, first_block, block_count) = slots.next().unwrap();
+        let (next_id, next_offset, _) = slots.next().unwrap();
+        if system_id != MBR_TYPE_EMPTY && block_count != 0 {
+            entries.push(Entry {
+                number,
+                first_block: record + first_block,
+                block_count,
+                kind: PartitionKind::Mbr(system_id),
+            });
+        }
+        if next_id == MBR_TYPE_EMPTY || next_offset == 0 {
+            return Ok(());
+        }
+        record = extended + next_offset;
+    }
+    Ok(())
+}
+
+/// Reads the GPT from the primary header, falling back to the backup header in the last block.
+fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<Entry>, PartitionError> {
+    match read_gpt_at(device, 1) {
+        Ok(entries) => Ok(entries),
+        Err(PartitionError::Block(error)) => Err(error.into()),
+        Err(_) => read_gpt_at(device, device.block_count() - 1),
+    }
+}
+
+fn read_gpt_at(device: &dyn BlockDevice, header_block: u64) -> Result<Vec<Entry>, PartitionError> {
+    let mut header = [0; SECTOR_SIZE];
+    device.read_blocks(header_block, &mut header)?;
+    let header_size = read_u32(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE
+        || !(GPT_HEADER_MIN_SIZE..=SECTOR_SIZE).contains(&header_size)
+        || read_u64(&header, 24) != header_block
+    {
+        return Err(PartitionError::BadGptHeader);
+    }
+    let expected_crc = read_u32(&header, 16);
+    let mut checked = header;
+    checked[16..20].fill(0);
+    if crc32(&checked[..header_size]) != expected_crc {
+        return Err(PartitionError::BadGptHeader);
+    }
+
+    let entries_block = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80);
+    let entry_size = read_u32(&header, 84) as usize;
+    if entry_count > GPT_MAX_ENTRIES
+        || entry_size < GPT_MIN_ENTRY_SIZE
+        || !entry_size.is_multiple_of(8)
+    {
+        return Err(PartitionError::BadGptHeader);
+    }
+    let table_size = entry_count as usize * entry_size;
+    let blocks = table_size.div_ceil(SECTOR_SIZE) as u64;
+    if entries_block
+        .checked_add(blocks)
+        .is_none_or(|end| end > device.block_count())
+    {
+        return Err(PartitionError::BadGptHeader);
+    }
+    let mut table = vec![0; blocks as usize * SECTOR_SIZE];
+    device.read_blocks(entries_block, &mut table)?;
+    if crc32(&table[..table_size]) != read_u32(&header, 88) {
+        return Err(PartitionError::BadGptEntries);
+    }
+
+    let mut entries = Vec::new();
+    for (number, entry) in (1..).zip(table[..table_size].chunks_exact(entry_size)) {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first_block = read_u64(entry, 32);
+        let last_block = read_u64(entry, 40);
+        if last_block < first_block {
+            return Err(PartitionError::OutOfRange);
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        entries.push(Entry {
+            number,
+            first_block,
+            block_count: last_block - first_block + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: entry[16..32].try_into().unwrap(),
+                label: String::from_utf16_lossy(&name),
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// The CRC-32 used by GPT, zlib and Ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(u32::MAX, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test_case]
+    fn test_partition_names() {
+        assert_eq!(partition_name("hda", 1), "hda1");
+        assert_eq!(partition_name("nvme0n1", 5), "nvme0n1p5");
+    }
+}
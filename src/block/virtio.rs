@@ -0,0 +1,319 @@
+//! virtio-blk disks. One request is in flight per disk at a time; its data goes through a bounce
+//! buffer of physically contiguous frames, and completion is signalled by the device interrupt.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PhysFrame, frame::PhysFrameRange},
+};
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{
+    interupt::pic,
+    memory,
+    pci::{DeviceMatch, PciDevice, PciDriver},
+    virtio::{self, Buffer, Transport, VirtQueue, VirtioError},
+};
+
+const DEVICE_TYPE: u16 = 2;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const HEADER_SIZE: u32 = 16;
+const STATUS_OK: u8 = 0;
+const STATUS_IO_ERROR: u8 = 1;
+
+/// Larger requests are split into several of at most the bounce buffer's size.
+const BOUNCE_FRAMES: u64 = 16;
+const BOUNCE_SIZE: usize = BOUNCE_FRAMES as usize * 4096;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: virtio::LEGACY_DEVICE_ID_BASE + DEVICE_TYPE - 1,
+        },
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: virtio::MODERN_DEVICE_ID_BASE + DEVICE_TYPE,
+        },
+    ],
+    probe,
+};
+
+/// Every disk set up so far, so the interrupt handler can find them.
+static DISKS: Mutex<Vec<Arc<VirtioBlock>>> = Mutex::new(Vec::new());
+
+/// The queue and the DMA memory a request is built in.
+#[derive(Debug)]
+struct Request {
+    queue: VirtQueue,
+    /// Holds the request header, followed by the status byte the device writes.
+    header: PhysFrame,
+    bounce: PhysFrameRange,
+}
+
+#[derive(Debug)]
+pub struct VirtioBlock {
+    name: String,
+    transport: Transport,
+    interrupt_line: u8,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    interrupts: AtomicU64,
+    request: Mutex<Request>,
+}
+
+impl VirtioBlock {
+    fn new(device: &PciDevice, name: String) -> Result<Self, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.initialize(FEATURE_READ_ONLY | FEATURE_FLUSH);
+        let queue = features.and_then(|_| transport.setup_queue(0));
+        let header = memory::allocate_frame().ok_or(VirtioError::OutOfMemory);
+        let bounce =
+            memory::allocate_contiguous_frames(BOUNCE_FRAMES).ok_or(VirtioError::OutOfMemory);
+        let (features, queue, header, bounce) = match (features, queue, header, bounce) {
+            (Ok(features), Ok(queue), Ok(header), Ok(bounce)) => (features, queue, header, bounce),
+            (features, queue, header, bounce) => {
+                let error = [
+                    features.err(),
+                    queue.as_ref().err().copied(),
+                    header.err(),
+                    bounce.err(),
+                ];
+                // The device may already know where the queue is, so it is reset first.
+                transport.fail();
+                if let Ok(queue) = queue {
+                    unsafe { queue.deallocate() };
+                }
+                for frame in header.into_iter().chain(bounce.into_iter().flatten()) {
+                    unsafe { memory::deallocate_frame(frame) };
+                }
+                return Err(error.into_iter().flatten().next().unwrap());
+            }
+        };
+        transport.finish_initialization();
+        Ok(Self {
+            name,
+            sectors: transport.read_config_u64(CONFIG_CAPACITY),
+            transport,
+            interrupt_line: device.interrupt_line,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            can_flush: features & FEATURE_FLUSH != 0,
+            interrupts: AtomicU64::new(0),
+            request: Mutex::new(Request {
+                queue,
+                header,
+                bounce,
+            }),
+        })
+    }
+
+    /// Whether the disk is driven through the modern virtio interface.
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The number of queue interrupts the disk has raised.
+    pub fn interrupt_count(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Sends one request whose data, if any, is the first `length` bytes of the bounce buffer,
+    /// and waits for the device to complete it.
+    fn submit(
+        &self,
+        request: &mut Request,
+        kind: u32,
+        sector: u64,
+        length: usize,
+    ) -> Result<(), BlockError> {
+        let header_address = request.header.start_address();
+        let header = memory::physical_to_virtual(header_address).as_mut_ptr::<u8>();
+        unsafe {
+            header.cast::<u32>().write_volatile(kind);
+            header.add(4).cast::<u32>().write_volatile(0);
+            header.add(8).cast::<u64>().write_volatile(sector);
+            header.add(HEADER_SIZE as usize).write_volatile(0xFF);
+        }
+        let header_buffer = Buffer {
+            address: header_address,
+            length: HEADER_SIZE,
+            writable: false,
+        };
+        let data_buffer = Buffer {
+            address: request.bounce.start.start_address(),
+            length: length as u32,
+            writable: kind == REQUEST_IN,
+        };
+        let status_buffer = Buffer {
+            address: header_address + u64::from(HEADER_SIZE),
+            length: 1,
+            writable: true,
+        };
+        let chain = [header_buffer, data_buffer, status_buffer];
+        let chain: &[Buffer] = match length {
+            0 => &[header_buffer, status_buffer],
+            _ => &chain,
+        };
+        request
+            .queue
+            .add(chain)
+            .map_err(|_| BlockError::Device(STATUS_IO_ERROR))?;
+        self.transport.notify(request.queue.index());
+        wait_for_completion(&request.queue);
+        request.queue.pop_used();
+
+        match unsafe { header.add(HEADER_SIZE as usize).read_volatile() } {
+            STATUS_OK => Ok(()),
+            status => Err(BlockError::Device(status)),
+        }
+    }
+
+    fn bounce_buffer(request: &Request) -> *mut u8 {
+        memory::physical_to_virtual(request.bounce.start.start_address()).as_mut_ptr()
+    }
+}
+
+/// Waits for the device to use the request just made available. The interrupt only wakes the
+/// CPU; whether the request is done is read from the used ring. Without interrupts the ring is
+/// polled.
+fn wait_for_completion(queue: &VirtQueue) {
+    if !interrupts::are_enabled() {
+        while !queue.has_used() {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    loop {
+        interrupts::disable();
+        if queue.has_used() {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(block, buffer.len())?;
+        let mut request = self.request.lock();
+        let mut sector = block;
+        for chunk in buffer.chunks_mut(BOUNCE_SIZE) {
+            self.submit(&mut request, REQUEST_IN, sector, chunk.len())?;
+            let bounce = Self::bounce_buffer(&request);
+            unsafe { core::ptr::copy_nonoverlapping(bounce, chunk.as_mut_ptr(), chunk.len()) };
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_request(block, buffer.len())?;
+        let mut request = self.request.lock();
+        let mut sector = block;
+        for chunk in buffer.chunks(BOUNCE_SIZE) {
+            let bounce = Self::bounce_buffer(&request);
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), bounce, chunk.len()) };
+            self.submit(&mut request, REQUEST_OUT, sector, chunk.len())?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        let mut request = self.request.lock();
+        self.submit(&mut request, REQUEST_FLUSH, 0, 0)
+    }
+}
+
+/// The virtio-blk disks found on the bus.
+pub fn disks() -> Vec<Arc<VirtioBlock>> {
+    interrupts::without_interrupts(|| DISKS.lock().clone())
+}
+
+fn probe(device: &Arc<PciDevice>) -> bool {
+    let count = interrupts::without_interrupts(|| DISKS.lock().len());
+    let Ok(disk) = VirtioBlock::new(device, disk_name(count)) else {
+        return false;
+    };
+    let disk = Arc::new(disk);
+    let line = disk.interrupt_line;
+    let line_claimed = interrupts::without_interrupts(|| {
+        let mut disks = DISKS.lock();
+        let claimed = disks.iter().any(|other| other.interrupt_line == line);
+        disks.push(disk.clone());
+        claimed
+    });
+    // Without a usable line, requests complete when the timer interrupt wakes the CPU.
+    if !line_claimed && pic::can_claim(line) {
+        pic::register_irq_handler(line, handle_interrupt);
+    }
+    super::register(disk);
+    true
+}
+
+/// Names disks as Linux does: `vda` to `vdz`, then `vdaa` to `vdzz`, `vdaaa` and so on.
+fn disk_name(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut remaining = index + 1;
+    while remaining > 0 {
+        remaining -= 1;
+        letters.push(char::from(b'a' + (remaining % 26) as u8));
+        remaining /= 26;
+    }
+    format!("vd{}", letters.iter().rev().collect::<String>())
+}
+
+fn handle_interrupt() {
+    for disk in DISKS.lock().iter() {
+        if disk.transport.acknowledge_interrupt() {
+            disk.interrupts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_disk_names() {
+        assert_eq!(disk_name(0), "vda");
+        assert_eq!(disk_name(25), "vdz");
+        assert_eq!(disk_name(26), "vdaa");
+        assert_eq!(disk_name(27), "vdab");
+        assert_eq!(disk_name(26 + 26 * 26 - 1), "vdzz");
+        assert_eq!(disk_name(26 + 26 * 26), "vdaaa");
+    }
+}
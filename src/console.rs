@@ -0,0 +1,173 @@
+//! Virtual consoles taking turns on the VGA screen and the keyboard, switched with Alt+F1 to
+//! Alt+F6. Each has its own screen contents, cursor, colors and typed input.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    ring_buffer::RingBuffer,
+    scheduler,
+    vga_buffer::{DEFAULT_SCROLLBACK_ROWS, TextMode, VGA_WRITER, VgaWriter},
+};
+
+pub const CONSOLE_COUNT: usize = 6;
+/// The console shown at boot, where `print!` and kernel messages go.
+pub const LOG_CONSOLE: usize = 0;
+/// The console the shell runs on.
+pub const SHELL_CONSOLE: usize = 1;
+
+pub struct Console {
+    pub writer: &'static Mutex<VgaWriter>,
+    /// Bytes typed while the console was shown that have not yet been consumed by a reader. Keys
+    /// without a character, such as the arrows, arrive as the escape sequences a VT100 terminal
+    /// sends.
+    pub input: Mutex<RingBuffer<u8, 256>>,
+    /// Whether typed characters are printed as they arrive. Readers drawing their own input, such
+    /// as the shell's line editor, turn this off.
+    pub echo: AtomicBool,
+}
+
+impl Console {
+    /// Blocks until at least one byte has been typed, then drains what is available into
+    /// `buffer`, returning how many bytes were read.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        loop {
+            let count = interrupts::without_interrupts(|| {
+                let mut input = self.input.lock();
+                let mut count = 0;
+                while count < buffer.len()
+                    && let Some(byte) = input.pop()
+                {
+                    buffer[count] = byte;
+                    count += 1;
+                }
+                count
+            });
+            if count > 0 {
+                return count;
+            }
+            scheduler::sleep(1);
+        }
+    }
+}
+
+/// The log console draws with [`VGA_WRITER`]; the others start off the screen.
+static CONSOLES: Lazy<[Console; CONSOLE_COUNT]> = Lazy::new(|| {
+    core::array::from_fn(|index| {
+        let writer = if index == LOG_CONSOLE {
+            &*VGA_WRITER
+        } else {
+            Box::leak(Box::new(Mutex::new(VgaWriter::new_off_screen())))
+        };
+        Console {
+            writer,
+            input: Mutex::new(RingBuffer::new(0)),
+            echo: AtomicBool::new(true),
+        }
+    })
+});
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(LOG_CONSOLE);
+
+/// Creates the consoles, allocates the buffers they draw into while hidden and starts keeping
+/// their scrollback. Needs the heap, and has to run before the keyboard interrupt can look up or
+/// switch the console being shown, as the interrupt does not allocate.
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        for console in CONSOLES.iter() {
+            let mut writer = console.writer.lock();
+            writer.reserve_off_screen();
+            writer.set_scrollback_limit(DEFAULT_SCROLLBACK_ROWS);
+        }
+    });
+}
+
+/// The console numbered `index`, counting from zero.
+///
+/// # Panics
+///
+/// If `index` is not below [`CONSOLE_COUNT`].
+pub fn get(index: usize) -> &'static Console {
+    &CONSOLES[index]
+}
+
+/// The number of the console on the screen.
+pub fn active_index() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// The console on the screen, which receives the keyboard input.
+pub fn active() -> &'static Console {
+    get(active_index())
+}
+
+/// Puts the console numbered `index` on the screen and sends the keyboard to it. Out of range
+/// numbers are ignored.
+pub fn switch_to(index: usize) {
+    if index >= CONSOLE_COUNT {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let previous = ACTIVE.swap(index, Ordering::Relaxed);
+        if previous != index {
+            get(previous).writer.lock().hide();
+            get(index).writer.lock().show();
+        }
+    });
+}
+
+/// The text mode of the screen.
+pub fn text_mode() -> TextMode {
+    interrupts::without_interrupts(|| active().writer.lock().text_mode())
+}
+
+/// Switches the screen to `mode`, changing the size of every console along with it.
+pub fn set_text_mode(mode: TextMode) {
+    interrupts::without_interrupts(|| {
+        for console in CONSOLES.iter() {
+            console.writer.lock().set_text_mode(mode);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LOG_CONSOLE, active_index, get, switch_to};
+    use x86_64::instructions::interrupts;
+
+    /// The first characters of the top row of the screen.
+    fn top_left() -> [u8; 4] {
+        let cells: [u16; 4] = unsafe { core::ptr::read_volatile(0xb8000 as *const [u16; 4]) };
+        cells.map(|cell| cell as u8)
+    }
+
+    #[test_case]
+    fn test_switching_consoles() {
+        interrupts::without_interrupts(|| {
+            get(LOG_CONSOLE).writer.lock().clear();
+            get(LOG_CONSOLE).writer.lock().write_string_at(0, 0, "log ");
+            get(3).writer.lock().clear();
+            get(3).writer.lock().write_string_at(0, 0, "tty3");
+            assert_eq!(&top_left(), b"log ");
+
+            switch_to(3);
+            assert_eq!(active_index(), 3);
+            assert_eq!(&top_left(), b"tty3");
+            assert!(!get(LOG_CONSOLE).writer.lock().is_visible());
+            get(LOG_CONSOLE).writer.lock().write_string_at(0, 3, "!");
+            assert_eq!(&top_left(), b"tty3");
+
+            switch_to(LOG_CONSOLE);
+            assert_eq!(&top_left(), b"log!");
+            assert!(!get(3).writer.lock().is_visible());
+            switch_to(3);
+            assert_eq!(&top_left(), b"tty3");
+            switch_to(LOG_CONSOLE);
+        });
+    }
+}
@@ -0,0 +1,245 @@
+//! A parser for statically linked ELF64 executables.
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const CURRENT_VERSION: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3e;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    UnsupportedClass,
+    UnsupportedEndianness,
+    UnsupportedVersion,
+    UnsupportedType,
+    UnsupportedMachine,
+    BadProgramHeaders,
+    BadSegment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ElfType {
+    Executable = 2,
+    SharedObject = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentType {
+    Null,
+    Load,
+    Dynamic,
+    Interpreter,
+    Note,
+    ProgramHeader,
+    ThreadLocalStorage,
+    Other(u32),
+}
+
+impl From<u32> for SegmentType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SegmentType::Null,
+            1 => SegmentType::Load,
+            2 => SegmentType::Dynamic,
+            3 => SegmentType::Interpreter,
+            4 => SegmentType::Note,
+            6 => SegmentType::ProgramHeader,
+            7 => SegmentType::ThreadLocalStorage,
+            other => SegmentType::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub segment_type: SegmentType,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub alignment: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_readable(&self) -> bool {
+        self.flags & PF_R != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A validated view over the bytes of an ELF64 file.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub elf_type: ElfType,
+    pub entry_point: u64,
+    program_header_offset: u64,
+    program_header_entry_size: u16,
+    pub program_header_count: u16,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianness);
+        }
+        if data[6] != CURRENT_VERSION || read_u32(data, 20) != CURRENT_VERSION as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        let elf_type = match read_u16(data, 16) {
+            2 => ElfType::Executable,
+            3 => ElfType::SharedObject,
+            _ => return Err(ElfError::UnsupportedType),
+        };
+        if read_u16(data, 18) != MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        let elf_file = Self {
+            data,
+            elf_type,
+            entry_point: read_u64(data, 24),
+            program_header_offset: read_u64(data, 32),
+            program_header_entry_size: read_u16(data, 54),
+            program_header_count: read_u16(data, 56),
+        };
+        if (elf_file.program_header_entry_size as usize) < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let program_headers_end = (elf_file.program_header_count as u64)
+            .checked_mul(elf_file.program_header_entry_size as u64)
+            .and_then(|size| size.checked_add(elf_file.program_header_offset));
+        match program_headers_end {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::BadProgramHeaders),
+        }
+        for program_header in elf_file.program_headers() {
+            elf_file.validate_segment(&program_header)?;
+        }
+        Ok(elf_file)
+    }
+
+    fn validate_segment(&self, program_header: &ProgramHeader) -> Result<(), ElfError> {
+        if program_header.segment_type != SegmentType::Load {
+            return Ok(());
+        }
+        let file_end = program_header.offset.checked_add(program_header.file_size);
+        let memory_end = program_header
+            .virtual_address
+            .checked_add(program_header.memory_size);
+        match (file_end, memory_end) {
+            (Some(file_end), Some(_))
+                if file_end <= self.data.len() as u64
+                    && program_header.file_size <= program_header.memory_size =>
+            {
+                Ok(())
+            }
+            _ => Err(ElfError::BadSegment),
+        }
+    }
+
+    pub fn program_header(&self, index: u16) -> Option<ProgramHeader> {
+        if index >= self.program_header_count {
+            return None;
+        }
+        let offset = self.program_header_offset as usize
+            + index as usize * self.program_header_entry_size as usize;
+        let data = self.data;
+        Some(ProgramHeader {
+            segment_type: SegmentType::from(read_u32(data, offset)),
+            flags: read_u32(data, offset + 4),
+            offset: read_u64(data, offset + 8),
+            virtual_address: read_u64(data, offset + 16),
+            file_size: read_u64(data, offset + 32),
+            memory_size: read_u64(data, offset + 40),
+            alignment: read_u64(data, offset + 48),
+        })
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.program_header_count).filter_map(|index| self.program_header(index))
+    }
+
+    /// Returns the bytes stored in the file for the given segment.
+    pub fn segment_data(&self, program_header: &ProgramHeader) -> &'a [u8] {
+        let start = program_header.offset as usize;
+        &self.data[start..start + program_header.file_size as usize]
+    }
+
+    pub fn program_header_entry_size(&self) -> u16 {
+        self.program_header_entry_size
+    }
+
+    pub fn program_header_offset(&self) -> u64 {
+        self.program_header_offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ElfError, ElfFile, ElfType, SegmentType};
+
+    static HELLO: &[u8] = include_bytes!("../tests/programs/hello.elf");
+
+    #[test_case]
+    fn test_parse_executable() {
+        let elf_file = ElfFile::parse(HELLO).unwrap();
+        assert_eq!(elf_file.elf_type, ElfType::Executable);
+        assert_eq!(elf_file.entry_point, 0x4000_0000_1000);
+        assert!(
+            elf_file
+                .program_headers()
+                .any(|header| header.segment_type == SegmentType::Load && header.is_executable())
+        );
+    }
+
+    #[test_case]
+    fn test_parse_rejects_bad_magic() {
+        let mut data = [0u8; 64];
+        data[0..4].copy_from_slice(b"\x7fELG");
+        assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::BadMagic);
+        assert_eq!(ElfFile::parse(&data[..10]).unwrap_err(), ElfError::TooShort);
+    }
+}
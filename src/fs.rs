@@ -0,0 +1,645 @@
+//! The virtual file system: file systems mounted into a single tree, path resolution and open
+//! files.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{fmt, ops::BitOr};
+use spin::Mutex;
+
+use crate::block::BlockError;
+
+pub mod console;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initramfs;
+pub mod procfs;
+pub mod ramfs;
+
+pub use console::Console;
+pub use devfs::DevFs;
+pub use ext2::Ext2Fs;
+pub use fat::FatFs;
+pub use procfs::ProcFs;
+pub use ramfs::RamFs;
+
+pub type InodeNumber = u64;
+
+/// The longest file name, as on Linux.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// How many symbolic links one path lookup follows before giving up, as on Linux.
+pub const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidPath,
+    NameTooLong,
+    /// The file was not opened for this kind of access.
+    AccessMode,
+    ReadOnly,
+    NotSeekable,
+    InvalidArgument,
+    NoSpace,
+    /// A mount point is in use.
+    Busy,
+    /// A rename between two file systems.
+    CrossDevice,
+    /// A path lookup met more than [`MAX_SYMLINKS`] symbolic links.
+    TooManyLinks,
+    Io,
+    Unsupported,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::NameTooLong => "file name too long",
+            FsError::AccessMode => "bad file access mode",
+            FsError::ReadOnly => "read-only file system",
+            FsError::NotSeekable => "illegal seek",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::NoSpace => "no space left on device",
+            FsError::Busy => "device or resource busy",
+            FsError::CrossDevice => "invalid cross-device link",
+            FsError::TooManyLinks => "too many levels of symbolic links",
+            FsError::Io => "input/output error",
+            FsError::Unsupported => "operation not supported",
+        })
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharacterDevice,
+    BlockDevice,
+    Symlink,
+}
+
+/// What `stat` reports about an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Unix permission bits, such as `0o644`.
+    pub permissions: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Flags for [`open`], using the Linux `O_*` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ_ONLY: Self = Self(0);
+    pub const WRITE_ONLY: Self = Self(0o1);
+    pub const READ_WRITE: Self = Self(0o2);
+    pub const CREATE: Self = Self(0o100);
+    pub const EXCLUSIVE: Self = Self(0o200);
+    pub const TRUNCATE: Self = Self(0o1000);
+    pub const APPEND: Self = Self(0o2000);
+    pub const DIRECTORY: Self = Self(0o200000);
+
+    const ACCESS_MODE_MASK: u32 = 0o3;
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.0 & Self::ACCESS_MODE_MASK != Self::WRITE_ONLY.0
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.0 & Self::ACCESS_MODE_MASK != Self::READ_ONLY.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A mounted file system.
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes any cached changes back to the underlying device.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file, directory or device inside a file system.
+///
+/// Directory operations default to [`FsError::NotADirectory`] and data operations to
+/// [`FsError::Unsupported`], so each inode only implements what applies to it.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Finds the entry called `name` in this directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory called `name` in this directory.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Removes the entry called `name` from this directory.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Moves the entry `old_name` to `new_name` in `new_directory`, which belongs to the same
+    /// file system, replacing an existing file or empty directory there.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_directory: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// The target of a symbolic link.
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Lets device inodes hand out their own [`File`] instead of one reading through the inode.
+    fn open_file(&self, _flags: OpenFlags) -> Option<Result<Arc<dyn File>, FsError>> {
+        None
+    }
+}
+
+/// An open file. Every method takes `&self` so a file can be shared between file tables and
+/// read while blocking without holding a lock.
+pub trait File: Send + Sync {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError>;
+
+    fn seek(&self, _position: SeekFrom) -> Result<u64, FsError> {
+        Err(FsError::NotSeekable)
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError>;
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// The inode behind this file, if it lives in a file system and can be mapped into memory.
+    fn inode(&self) -> Option<Arc<dyn Inode>> {
+        None
+    }
+}
+
+/// A file opened through an inode, reading and writing at its own offset.
+struct InodeFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl File for InodeFile {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.is_readable() {
+            return Err(FsError::AccessMode);
+        }
+        let offset = *self.offset.lock();
+        let count = self.inode.read_at(offset, buffer)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.is_writable() {
+            return Err(FsError::AccessMode);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.inode.metadata().size
+        } else {
+            *self.offset.lock()
+        };
+        let count = self.inode.write_at(offset, buffer)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn seek(&self, position: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new_offset = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.metadata().size.checked_add_signed(delta),
+        };
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError> {
+        Ok(self.inode.metadata())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        self.inode.read_dir()
+    }
+
+    fn inode(&self) -> Option<Arc<dyn Inode>> {
+        Some(self.inode.clone())
+    }
+}
+
+/// Mounted file systems by the canonical path of their mount point.
+static MOUNTS: Mutex<BTreeMap<String, Arc<dyn FileSystem>>> = Mutex::new(BTreeMap::new());
+
+/// Mounts a ramfs as the root file system, fills it from the initramfs and mounts the device and
+/// process file systems at `/dev` and `/proc`.
+pub fn init() {
+    mount("/", Arc::new(RamFs::new())).expect("root file system already mounted");
+    initramfs::init();
+    let pseudo_file_systems: [(&str, Arc<dyn FileSystem>); 2] = [
+        ("/dev", Arc::new(DevFs::new())),
+        ("/proc", Arc::new(ProcFs::new())),
+    ];
+    for (path, file_system) in pseudo_file_systems {
+        create_directory(path).expect("cannot create a mount point in the root file system");
+        mount(path, file_system).expect("cannot mount a pseudo file system");
+    }
+}
+
+/// Mounts `file_system` at `path`, which must be `/` or an existing directory.
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let (path, inode) = match resolve(path) {
+        Ok(resolved) => resolved,
+        // The first file system is mounted before anything else can be resolved.
+        Err(FsError::NotFound) if canonicalize(path)? == "/" => (String::from("/"), None),
+        Err(error) => return Err(error),
+    };
+    if let Some(inode) = inode
+        && inode.metadata().file_type != FileType::Directory
+    {
+        return Err(FsError::NotADirectory);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.contains_key(&path) {
+        return Err(FsError::Busy);
+    }
+    mounts.insert(path, file_system);
+    Ok(())
+}
+
+/// Unmounts the file system at `path`, failing if other file systems are mounted inside it.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let path = canonicalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if !mounts.contains_key(&path) {
+        return Err(FsError::InvalidArgument);
+    }
+    if mounts
+        .keys()
+        .any(|mount_point| *mount_point != path && is_inside(mount_point, &path))
+    {
+        return Err(FsError::Busy);
+    }
+    Ok(mounts.remove(&path).unwrap())
+}
+
+/// The mount points and the names of the file systems mounted there.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|(path, file_system)| (path.clone(), file_system.name()))
+        .collect()
+}
+
+/// Resolves an absolute path to an inode.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    resolve(path)?.1.ok_or(FsError::NotFound)
+}
+
+/// Opens the file at `path`, creating it first if `flags` contains [`OpenFlags::CREATE`].
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<dyn File>, FsError> {
+    let inode = match lookup(path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = split_parent(path)?;
+            lookup(&parent)?.create(name, FileType::Regular)?
+        }
+        Err(error) => return Err(error),
+    };
+    let file_type = inode.metadata().file_type;
+    if file_type == FileType::Directory && flags.is_writable() {
+        return Err(FsError::IsADirectory);
+    }
+    if file_type != FileType::Directory && flags.contains(OpenFlags::DIRECTORY) {
+        return Err(FsError::NotADirectory);
+    }
+    if let Some(file) = inode.open_file(flags) {
+        return file;
+    }
+    if flags.contains(OpenFlags::TRUNCATE) && flags.is_writable() {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(InodeFile {
+        inode,
+        flags,
+        offset: Mutex::new(0),
+    }))
+}
+
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// The target of the symbolic link at `path`, which itself is not followed.
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    resolve_with(path, false)?
+        .1
+        .ok_or(FsError::NotFound)?
+        .read_link()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+    lookup(path)?.read_dir()
+}
+
+pub fn create_directory(path: &str) -> Result<(), FsError> {
+    let (parent, name) = split_parent(path)?;
+    lookup(&parent)?.create(name, FileType::Directory)?;
+    Ok(())
+}
+
+/// Removes a file or an empty directory. Mount points cannot be removed.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = split_parent(path)?;
+    if MOUNTS.lock().contains_key(&canonicalize(path)?) {
+        return Err(FsError::Busy);
+    }
+    lookup(&parent)?.unlink(name)
+}
+
+/// Moves a file or directory within one file system. Mount points cannot be moved.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent_path, old_name) = split_parent(old_path)?;
+    let (new_parent_path, new_name) = split_parent(new_path)?;
+    let (old_parent_path, old_parent) = resolve(&old_parent_path)?;
+    let (new_parent_path, new_parent) = resolve(&new_parent_path)?;
+    let (old_parent, new_parent) = old_parent.zip(new_parent).ok_or(FsError::NotFound)?;
+    {
+        let mounts = MOUNTS.lock();
+        let old_path = join(old_parent_path.split('/').chain([old_name]));
+        let new_path = join(new_parent_path.split('/').chain([new_name]));
+        if mounts.contains_key(&old_path) || mounts.contains_key(&new_path) {
+            return Err(FsError::Busy);
+        }
+        if mount_point(&mounts, &old_parent_path) != mount_point(&mounts, &new_parent_path) {
+            return Err(FsError::CrossDevice);
+        }
+    }
+    old_parent.rename(old_name, new_parent.as_ref(), new_name)
+}
+
+/// Walks `path` from the root, switching to the root of a mounted file system whenever a mount
+/// point is reached and following symbolic links. Returns the canonical path and its inode, which
+/// is `None` only when nothing is mounted at `/`.
+fn resolve(path: &str) -> Result<(String, Option<Arc<dyn Inode>>), FsError> {
+    resolve_with(path, true)
+}
+
+/// Like [`resolve`], but leaves a symbolic link as the last component unfollowed unless
+/// `follow_last` is set.
+fn resolve_with(
+    path: &str,
+    follow_last: bool,
+) -> Result<(String, Option<Arc<dyn Inode>>), FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let Some(root) = mounted_root("/") else {
+        return Err(FsError::NotFound);
+    };
+    // The components still to walk, last first, so a link's target can go in front of them.
+    let mut pending = components_reversed(path);
+    // The inodes from the root down to the current directory, so `..` can step back up.
+    let mut stack: Vec<(String, Arc<dyn Inode>)> = Vec::new();
+    let mut current = root.clone();
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "." | ".." if current.metadata().file_type != FileType::Directory => {
+                return Err(FsError::NotADirectory);
+            }
+            "." => {}
+            ".." => {
+                if let Some((_, parent)) = stack.pop() {
+                    current = parent;
+                }
+            }
+            name => {
+                check_name(name)?;
+                let child = current.lookup(name)?;
+                if child.metadata().file_type == FileType::Symlink
+                    && (follow_last || !pending.is_empty())
+                {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(FsError::TooManyLinks);
+                    }
+                    let target = child.read_link()?;
+                    if target.starts_with('/') {
+                        stack.clear();
+                        current = root.clone();
+                    }
+                    pending.extend(components_reversed(&target));
+                    continue;
+                }
+                stack.push((component, core::mem::replace(&mut current, child)));
+                let canonical = join(stack.iter().map(|(name, _)| name.as_str()));
+                if let Some(mounted) = mounted_root(&canonical) {
+                    current = mounted;
+                }
+            }
+        }
+    }
+    let canonical = join(stack.iter().map(|(name, _)| name.as_str()));
+    Ok((canonical, Some(current)))
+}
+
+fn components_reversed(path: &str) -> Vec<String> {
+    path.rsplit('/')
+        .filter(|component| !component.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The mount point of the file system containing the canonical `path`.
+fn mount_point<'a>(mounts: &'a BTreeMap<String, Arc<dyn FileSystem>>, path: &str) -> &'a str {
+    mounts
+        .keys()
+        .filter(|mount_point| *mount_point == path || is_inside(path, mount_point))
+        .max_by_key(|mount_point| mount_point.len())
+        .map_or("/", String::as_str)
+}
+
+fn mounted_root(path: &str) -> Option<Arc<dyn Inode>> {
+    let file_system = MOUNTS.lock().get(path).cloned()?;
+    Some(file_system.root())
+}
+
+/// Removes `.`, `..` and repeated slashes from an absolute path without touching any file
+/// system.
+pub fn canonicalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => {
+                check_name(name)?;
+                components.push(name);
+            }
+        }
+    }
+    Ok(join(components.into_iter()))
+}
+
+fn join<'a>(components: impl Iterator<Item = &'a str>) -> String {
+    let mut path = String::new();
+    for component in components.filter(|component| !component.is_empty()) {
+        path.push('/');
+        path.push_str(component);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Splits an absolute path into its parent directory and final component.
+fn split_parent(path: &str) -> Result<(String, &str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = trimmed.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    check_name(name)?;
+    let parent = if parent.is_empty() {
+        "/".to_string()
+    } else {
+        parent.to_string()
+    };
+    Ok((parent, name))
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+fn is_inside(path: &str, directory: &str) -> bool {
+    directory == "/"
+        || path
+            .strip_prefix(directory)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_canonicalize_and_split_paths() {
+        assert_eq!(canonicalize("/a/./b//c/../d/").unwrap(), "/a/b/d");
+        assert_eq!(canonicalize("/..").unwrap(), "/");
+        assert_eq!(canonicalize("relative"), Err(FsError::InvalidPath));
+        assert_eq!(split_parent("/a/b/").unwrap(), ("/a".to_string(), "b"));
+        assert_eq!(split_parent("/a").unwrap(), ("/".to_string(), "a"));
+        assert_eq!(split_parent("/"), Err(FsError::InvalidPath));
+        assert!(is_inside("/mnt/disk", "/mnt"));
+        assert!(!is_inside("/mntx", "/mnt"));
+    }
+}
@@ -0,0 +1,51 @@
+//! The log console, with its keyboard input and screen, as a character device.
+
+use alloc::sync::Arc;
+use x86_64::instructions::interrupts;
+
+use super::{File, FileType, FsError, Inode, Metadata, OpenFlags};
+use crate::console;
+
+/// Reads block until a key has been typed; writes go to the screen.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Console;
+
+impl Console {
+    const METADATA: Metadata = Metadata {
+        inode: 0,
+        file_type: FileType::CharacterDevice,
+        size: 0,
+        permissions: 0o620,
+    };
+}
+
+impl File for Console {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(console::get(console::LOG_CONSOLE).read(buffer))
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        // The writer decodes UTF-8 itself, so a character may be split across writes.
+        interrupts::without_interrupts(|| {
+            let mut writer = console::get(console::LOG_CONSOLE).writer.lock();
+            for &byte in buffer {
+                writer.write_byte(byte);
+            }
+        });
+        Ok(buffer.len())
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError> {
+        Ok(Self::METADATA)
+    }
+}
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Self::METADATA
+    }
+
+    fn open_file(&self, _flags: OpenFlags) -> Option<Result<Arc<dyn File>, FsError>> {
+        Some(Ok(Arc::new(Console)))
+    }
+}
@@ -0,0 +1,305 @@
+//! Device files: the console, the serial port, the memory devices and every registered block
+//! device. The directory is built on each lookup, so disks registered after mounting appear too.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, random::RdRand};
+
+use super::{
+    Console, DirectoryEntry, File, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata,
+    OpenFlags,
+};
+use crate::{
+    block::{self, BlockDevice},
+    serial::{self, SERIAL_PORT},
+};
+
+const ROOT_INODE: InodeNumber = 1;
+/// Block devices are numbered from here in registration order.
+const FIRST_BLOCK_DEVICE_INODE: InodeNumber = 16;
+
+/// The devices that always exist, by name.
+const FIXED_DEVICES: [(&str, Device); 5] = [
+    ("console", Device::Console),
+    ("serial0", Device::Serial),
+    ("null", Device::Null),
+    ("zero", Device::Zero),
+    ("random", Device::Random),
+];
+
+#[derive(Debug, Default)]
+pub struct DevFs;
+
+impl DevFs {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevDirectory)
+    }
+}
+
+/// The root directory, the only directory of a devfs.
+#[derive(Debug)]
+struct DevDirectory;
+
+impl DevDirectory {
+    fn devices() -> Vec<(String, InodeNumber, Device)> {
+        let fixed = (ROOT_INODE + 1..)
+            .zip(FIXED_DEVICES)
+            .map(|(inode, (name, device))| (name.to_string(), inode, device));
+        let disks = (FIRST_BLOCK_DEVICE_INODE..)
+            .zip(block::devices())
+            .map(|(inode, device)| (device.name().to_string(), inode, Device::Block(device)));
+        fixed.chain(disks).collect()
+    }
+}
+
+impl Inode for DevDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            size: 0,
+            permissions: 0o755,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let (_, number, device) = Self::devices()
+            .into_iter()
+            .find(|(device_name, _, _)| device_name == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(DeviceInode { number, device }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        Ok(Self::devices()
+            .into_iter()
+            .map(|(name, inode, device)| DirectoryEntry {
+                name,
+                inode,
+                file_type: device.file_type(),
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_directory: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Device {
+    Console,
+    Serial,
+    /// Reads end at once; writes are discarded.
+    Null,
+    /// Reads return zeroes; writes are discarded.
+    Zero,
+    Random,
+    Block(Arc<dyn BlockDevice>),
+}
+
+impl Device {
+    fn file_type(&self) -> FileType {
+        match self {
+            Device::Block(_) => FileType::BlockDevice,
+            _ => FileType::CharacterDevice,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeviceInode {
+    number: InodeNumber,
+    device: Device,
+}
+
+impl Inode for DeviceInode {
+    fn metadata(&self) -> Metadata {
+        let (size, permissions) = match &self.device {
+            Device::Console | Device::Serial => (0, 0o620),
+            Device::Null | Device::Zero | Device::Random => (0, 0o666),
+            Device::Block(device) => (device.block_count() * device.block_size() as u64, 0o660),
+        };
+        Metadata {
+            inode: self.number,
+            file_type: self.device.file_type(),
+            size,
+            permissions,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match &self.device {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buffer.fill(0);
+                Ok(buffer.len())
+            }
+            Device::Random => {
+                fill_random(buffer);
+                Ok(buffer.len())
+            }
+            Device::Block(device) => read_block_device(device.as_ref(), offset, buffer),
+            // Opened through `open_file`.
+            Device::Console | Device::Serial => Err(FsError::Unsupported),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        match &self.device {
+            Device::Null | Device::Zero | Device::Random => Ok(buffer.len()),
+            Device::Block(device) => write_block_device(device.as_ref(), offset, buffer),
+            Device::Console | Device::Serial => Err(FsError::Unsupported),
+        }
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        // Opening with `O_TRUNC` is allowed but changes nothing, as on Linux.
+        Ok(())
+    }
+
+    fn open_file(&self, _flags: OpenFlags) -> Option<Result<Arc<dyn File>, FsError>> {
+        match self.device {
+            Device::Console => Some(Ok(Arc::new(Console))),
+            Device::Serial => Some(Ok(Arc::new(Serial {
+                number: self.number,
+            }))),
+            _ => None,
+        }
+    }
+}
+
+/// COM1. Reads block until a byte has been received; writes go out unchanged.
+#[derive(Debug)]
+struct Serial {
+    number: InodeNumber,
+}
+
+impl File for Serial {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(serial::read(buffer))
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL_PORT.lock();
+            for &byte in buffer {
+                port.send_raw(byte);
+            }
+        });
+        Ok(buffer.len())
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            inode: self.number,
+            file_type: FileType::CharacterDevice,
+            size: 0,
+            permissions: 0o620,
+        })
+    }
+}
+
+/// Reads bytes from anywhere on `device`, stopping at its end.
+fn read_block_device(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<usize, FsError> {
+    let block_size = device.block_size() as u64;
+    let size = device.block_count() * block_size;
+    if offset >= size {
+        return Ok(0);
+    }
+    let length = buffer.len().min((size - offset) as usize);
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < length {
+        let position = offset + done as u64;
+        let within = (position % block_size) as usize;
+        let count = (block.len() - within).min(length - done);
+        device.read_blocks(position / block_size, &mut block)?;
+        buffer[done..done + count].copy_from_slice(&block[within..within + count]);
+        done += count;
+    }
+    Ok(length)
+}
+
+/// Writes bytes anywhere on `device`, reading back the blocks only partly overwritten.
+fn write_block_device(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buffer: &[u8],
+) -> Result<usize, FsError> {
+    let block_size = device.block_size() as u64;
+    let size = device.block_count() * block_size;
+    if !buffer.is_empty() && offset >= size {
+        return Err(FsError::NoSpace);
+    }
+    let length = buffer.len().min((size - offset) as usize);
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < length {
+        let position = offset + done as u64;
+        let within = (position % block_size) as usize;
+        let count = (block.len() - within).min(length - done);
+        if count < block.len() {
+            device.read_blocks(position / block_size, &mut block)?;
+        }
+        block[within..within + count].copy_from_slice(&buffer[done..done + count]);
+        device.write_blocks(position / block_size, &block)?;
+        done += count;
+    }
+    Ok(length)
+}
+
+/// The state of the generator used when the CPU has no `RDRAND`.
+static XORSHIFT_STATE: Mutex<u64> = Mutex::new(0);
+
+/// Fills `buffer` from `RDRAND` where the CPU has it. Otherwise a xorshift generator seeded from
+/// the time stamp counter is used, which is not fit for anything secret.
+fn fill_random(buffer: &mut [u8]) {
+    let rdrand = RdRand::new();
+    let mut state = XORSHIFT_STATE.lock();
+    for chunk in buffer.chunks_mut(8) {
+        let value = rdrand.and_then(RdRand::get_u64).unwrap_or_else(|| {
+            if *state == 0 {
+                // The state must never be zero, or the generator only yields zeroes.
+                *state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+            }
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            *state
+        });
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+}
@@ -0,0 +1,502 @@
+//! Read-only ext2 file systems.
+//!
+//! Inodes are read from disk on every lookup and never change, so they need no locking and no
+//! table of inodes in use.
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use super::{DirectoryEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Revision 0 file systems have fixed-size inodes and no feature flags.
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const MAX_BLOCK_SIZE: u64 = 65536;
+
+/// Directory entries carry the file type.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Group tables may be anywhere; the group descriptors still say where.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+/// Regular files may be 4 GiB or larger, with the top half of the size in another field.
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const ROOT_INODE: InodeNumber = 2;
+
+const MODE_TYPE_MASK: u16 = 0o170000;
+const MODE_REGULAR: u16 = 0o100000;
+const MODE_DIRECTORY: u16 = 0o040000;
+const MODE_SYMLINK: u16 = 0o120000;
+const MODE_CHARACTER_DEVICE: u16 = 0o020000;
+const MODE_BLOCK_DEVICE: u16 = 0o060000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+/// Symbolic links shorter than this keep their target in the block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+const CACHE_BLOCKS: usize = 256;
+
+/// The fields of an on-disk inode the driver uses.
+#[derive(Debug, Clone, Copy, Default)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    /// 512-byte sectors allocated to the inode, including its extended attribute block.
+    sectors: u32,
+    extended_attributes: u32,
+    blocks: [u32; 15],
+}
+
+impl RawInode {
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHARACTER_DEVICE => FileType::CharacterDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            // FIFOs and sockets have nothing to read either way.
+            _ => FileType::Regular,
+        }
+    }
+}
+
+/// A mounted ext2 file system. Writes fail with [`FsError::ReadOnly`].
+#[derive(Debug)]
+pub struct Ext2Fs {
+    this: Weak<Ext2Fs>,
+    device: BlockCache,
+    label: String,
+    block_size: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// The first block of each group's inode table.
+    inode_tables: Vec<u32>,
+    has_file_type: bool,
+    large_files: bool,
+    /// The root directory, read when mounting so that a damaged one fails the mount.
+    root: RawInode,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and group descriptors of `device` and mounts the ext2 file system on
+    /// it.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let sector_size = device.block_size() as u64;
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        read_device_bytes(device.as_ref(), SUPERBLOCK_OFFSET, &mut superblock)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let inode_count = read_u32(&superblock, 0);
+        let block_count = u64::from(read_u32(&superblock, 4));
+        let first_data_block = u64::from(read_u32(&superblock, 20));
+        let block_size = 1024u64
+            .checked_shl(read_u32(&superblock, 24))
+            .filter(|&size| size <= MAX_BLOCK_SIZE)
+            .ok_or(FsError::InvalidArgument)?;
+        let blocks_per_group = u64::from(read_u32(&superblock, 32));
+        let inodes_per_group = read_u32(&superblock, 40);
+        let revision = read_u32(&superblock, 76);
+        let (inode_size, incompat, ro_compat) = if revision == GOOD_OLD_REVISION {
+            (GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (
+                u64::from(read_u16(&superblock, 88)),
+                read_u32(&superblock, 96),
+                read_u32(&superblock, 100),
+            )
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if !block_size.is_multiple_of(sector_size)
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+            || !inode_size.is_power_of_two()
+            || block_count * block_size > device.block_count() * sector_size
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let group_count = block_count
+            .checked_sub(first_data_block)
+            .ok_or(FsError::InvalidArgument)?
+            .div_ceil(blocks_per_group);
+        if group_count * u64::from(inodes_per_group) < u64::from(inode_count) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut descriptors = vec![0; (group_count * GROUP_DESCRIPTOR_SIZE) as usize];
+        read_device_bytes(
+            device.as_ref(),
+            (first_data_block + 1) * block_size,
+            &mut descriptors,
+        )?;
+        let inode_tables = descriptors
+            .chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+            .map(|descriptor| read_u32(descriptor, 8))
+            .collect();
+
+        let label = &superblock[120..136];
+        let length = label.iter().position(|&byte| byte == 0).unwrap_or(16);
+        let label = String::from_utf8_lossy(&label[..length]).into_owned();
+
+        let mut file_system = Ext2Fs {
+            this: Weak::new(),
+            device: BlockCache::new(device, CACHE_BLOCKS),
+            label,
+            block_size,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            has_file_type: incompat & INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            root: RawInode::default(),
+        };
+        file_system.root = file_system.read_inode(ROOT_INODE)?;
+        if file_system.root.file_type() != FileType::Directory {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Arc::new_cyclic(|this| Ext2Fs {
+            this: this.clone(),
+            ..file_system
+        }))
+    }
+
+    /// The volume name from the superblock.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        read_device_bytes(&self.device, position, buffer)
+    }
+
+    fn read_inode(&self, number: InodeNumber) -> Result<RawInode, FsError> {
+        if number == 0 || number > u64::from(self.inode_count) {
+            return Err(FsError::Io);
+        }
+        let index = number - 1;
+        let group = (index / u64::from(self.inodes_per_group)) as usize;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Io)?;
+        let position = u64::from(table) * self.block_size
+            + index % u64::from(self.inodes_per_group) * self.inode_size;
+        let mut inode = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.read_bytes(position, &mut inode)?;
+
+        let mode = read_u16(&inode, 0);
+        let mut size = u64::from(read_u32(&inode, 4));
+        if self.large_files && mode & MODE_TYPE_MASK == MODE_REGULAR {
+            size |= u64::from(read_u32(&inode, 108)) << 32;
+        }
+        let mut blocks = [0; 15];
+        for (index, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(&inode, 40 + 4 * index);
+        }
+        Ok(RawInode {
+            mode,
+            size,
+            sectors: read_u32(&inode, 28),
+            extended_attributes: read_u32(&inode, 104),
+            blocks,
+        })
+    }
+
+    /// The block holding the `logical`th block of `inode`, or zero for a hole.
+    fn block_of(&self, inode: &RawInode, logical: u64) -> Result<u32, FsError> {
+        let per_block = self.block_size / 4;
+        if logical < DIRECT_BLOCKS as u64 {
+            return Ok(inode.blocks[logical as usize]);
+        }
+        let mut index = logical - DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for pointer in [INDIRECT_BLOCK, DOUBLE_INDIRECT_BLOCK, TRIPLE_INDIRECT_BLOCK] {
+            if index < span {
+                let mut block = inode.blocks[pointer];
+                // Walk down one level of indirection at a time.
+                while span > 1 {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    span /= per_block;
+                    let entry = index / span;
+                    index %= span;
+                    let mut pointer = [0; 4];
+                    self.read_bytes(u64::from(block) * self.block_size + entry * 4, &mut pointer)?;
+                    block = u32::from_le_bytes(pointer);
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(FsError::Io)
+    }
+
+    /// Reads from `offset` in the data of `inode`, which must not reach past its size. Holes read
+    /// as zeroes.
+    fn read_data(&self, inode: &RawInode, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = offset + done as u64;
+            let within = at % self.block_size;
+            let count = ((self.block_size - within) as usize).min(buffer.len() - done);
+            let chunk = &mut buffer[done..done + count];
+            match self.block_of(inode, at / self.block_size)? {
+                0 => chunk.fill(0),
+                block => self.read_bytes(u64::from(block) * self.block_size + within, chunk)?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn inode(&self, number: InodeNumber) -> Result<Arc<Ext2Inode>, FsError> {
+        Ok(Arc::new(Ext2Inode {
+            file_system: self
+                .this
+                .upgrade()
+                .expect("ext2 file system dropped while in use"),
+            number,
+            raw: self.read_inode(number)?,
+        }))
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode {
+            file_system: self
+                .this
+                .upgrade()
+                .expect("ext2 file system dropped while in use"),
+            number: ROOT_INODE,
+            raw: self.root,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Ext2Inode {
+    file_system: Arc<Ext2Fs>,
+    number: InodeNumber,
+    raw: RawInode,
+}
+
+impl Ext2Inode {
+    /// Every entry of this directory, including `.` and `..`.
+    fn entries(&self) -> Result<Vec<(String, InodeNumber, Option<FileType>)>, FsError> {
+        if self.raw.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let file_system = &self.file_system;
+        let block_size = file_system.block_size as usize;
+        let mut block = vec![0; block_size];
+        let mut entries = Vec::new();
+        for offset in (0..self.raw.size).step_by(block_size) {
+            file_system.read_data(&self.raw, offset, &mut block)?;
+            let mut position = 0;
+            while position + 8 <= block_size {
+                let inode = read_u32(&block, position);
+                let record_length = usize::from(read_u16(&block, position + 4));
+                let (name_length, file_type) = if file_system.has_file_type {
+                    (
+                        usize::from(block[position + 6]),
+                        entry_file_type(block[position + 7]),
+                    )
+                } else {
+                    (usize::from(read_u16(&block, position + 6)), None)
+                };
+                if record_length < 8
+                    || position + record_length > block_size
+                    || 8 + name_length > record_length
+                {
+                    return Err(FsError::Io);
+                }
+                // Entries with inode zero are unused space.
+                if inode != 0 {
+                    let name = &block[position + 8..position + 8 + name_length];
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    entries.push((name, InodeNumber::from(inode), file_type));
+                }
+                position += record_length;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The error for trying to change the entries of this inode.
+    fn modify_error(&self) -> FsError {
+        match self.raw.file_type() {
+            FileType::Directory => FsError::ReadOnly,
+            _ => FsError::NotADirectory,
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.number,
+            file_type: self.raw.file_type(),
+            size: self.raw.size,
+            permissions: self.raw.mode & 0o7777,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match self.raw.file_type() {
+            FileType::Regular => {}
+            FileType::Directory => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        if offset >= self.raw.size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((self.raw.size - offset) as usize);
+        self.file_system
+            .read_data(&self.raw, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let (_, number, _) = self
+            .entries()?
+            .into_iter()
+            .find(|(entry_name, _, _)| entry_name == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(self.file_system.inode(number)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        self.entries()?
+            .into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, inode, file_type)| {
+                let file_type = match file_type {
+                    Some(file_type) => file_type,
+                    None => self.file_system.read_inode(inode)?.file_type(),
+                };
+                Ok(DirectoryEntry {
+                    name,
+                    inode,
+                    file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(self.modify_error())
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(self.modify_error())
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_directory: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(self.modify_error())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        if self.raw.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let size = self.raw.size;
+        // A fast link's target is in the block pointers; only an extended attribute block may
+        // be allocated to it.
+        let attribute_sectors = match self.raw.extended_attributes {
+            0 => 0,
+            _ => (self.file_system.block_size / 512) as u32,
+        };
+        let mut target = vec![0; size as usize];
+        if size < FAST_SYMLINK_MAX && self.raw.sectors == attribute_sectors {
+            let pointers: Vec<u8> = self
+                .raw
+                .blocks
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .collect();
+            target.copy_from_slice(&pointers[..size as usize]);
+        } else {
+            self.file_system.read_data(&self.raw, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+}
+
+/// The file type recorded in a directory entry, if it records a known one.
+fn entry_file_type(code: u8) -> Option<FileType> {
+    match code {
+        1 => Some(FileType::Regular),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharacterDevice),
+        4 => Some(FileType::BlockDevice),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// Reads bytes from anywhere on `device`, which need not be aligned to its blocks.
+fn read_device_bytes(
+    device: &dyn BlockDevice,
+    position: u64,
+    buffer: &mut [u8],
+) -> Result<(), FsError> {
+    let block_size = device.block_size() as u64;
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < buffer.len() {
+        let at = position + done as u64;
+        let offset = (at % block_size) as usize;
+        let count = (block.len() - offset).min(buffer.len() - done);
+        device.read_blocks(at / block_size, &mut block)?;
+        buffer[done..done + count].copy_from_slice(&block[offset..offset + count]);
+        done += count;
+    }
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
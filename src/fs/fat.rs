@@ -0,0 +1,1346 @@
+//! FAT12, FAT16 and FAT32 file systems with long file names, read and written through a block
+//! cache.
+//!
+//! FAT has no inodes: an inode is numbered after the disk position of its directory entry, and
+//! the inodes in use are kept in a table so every lookup of an open file finds the same one.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use spin::{Mutex, MutexGuard};
+
+use super::{DirectoryEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+
+mod name;
+
+use name::{LongName, ShortName};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Read-only, hidden, system and volume ID together mark a long name slot.
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ATTRIBUTE_LONG_NAME_MASK: u8 = 0x3F;
+
+/// Fewer clusters than these make a FAT12 or FAT16 file system, as the specification decides.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCTURE_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+/// FSInfo's value for a free count or hint nobody has worked out.
+const FSINFO_UNKNOWN: u32 = u32::MAX;
+
+/// Bit 7 of the FAT32 extended flags: only the FAT numbered in bits 0 to 3 is in use.
+const FAT32_NO_MIRRORING: u16 = 1 << 7;
+const FAT32_CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+
+/// There is no real-time clock yet, so entries are stamped 1980-01-01 12:00:00.
+const DOS_TIME: u16 = 12 << 11;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+const MAX_SECTOR_SIZE: usize = 4096;
+const CACHE_BLOCKS: usize = 256;
+
+const ROOT_INODE: InodeNumber = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where a directory's entries are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Storage {
+    /// The fixed root directory area of FAT12 and FAT16.
+    FixedRoot,
+    Chain(u32),
+}
+
+/// Where an inode's directory entry is.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    directory: Storage,
+    /// The offset of the short entry in the directory.
+    offset: u64,
+    /// The offset of the first long name slot, or of the short entry if there are none.
+    first_slot: u64,
+    /// The short entry's byte position on the device.
+    position: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    number: InodeNumber,
+    attributes: u8,
+    /// Zero for an empty file.
+    first_cluster: u32,
+    size: u32,
+    /// `None` for the root directory and for unlinked files.
+    location: Option<Location>,
+    /// The inode was unlinked while in use; its clusters are freed when it is dropped.
+    unlinked: bool,
+}
+
+impl Node {
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+}
+
+/// A directory entry as read from the disk.
+#[derive(Debug)]
+struct RawEntry {
+    name: String,
+    short_name: ShortName,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    offset: u64,
+    first_slot: u64,
+}
+
+impl RawEntry {
+    fn matches(&self, name: &str) -> bool {
+        name::eq_ignore_case(&self.name, name)
+            || name::eq_ignore_case(&name::display(&self.short_name, 0), name)
+    }
+}
+
+/// The entries of a directory, read whole, and the clusters they came from.
+#[derive(Debug)]
+struct DirectoryData {
+    storage: Storage,
+    clusters: Vec<u32>,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct State {
+    free_clusters: u32,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// The free count or hint changed since FSInfo was last written.
+    fsinfo_dirty: bool,
+    inodes: BTreeMap<InodeNumber, Weak<FatInode>>,
+}
+
+/// A mounted FAT file system.
+///
+/// Every operation holds the file system's state lock, so they never see each other's half-made
+/// changes.
+#[derive(Debug)]
+pub struct FatFs {
+    this: Weak<FatFs>,
+    device: BlockCache,
+    fat_type: FatType,
+    label: String,
+    sector_size: u64,
+    sectors_per_cluster: u64,
+    /// The first sector of each FAT.
+    fat_sector: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    /// The only FAT in use, when the copies are not kept in step.
+    active_fat: Option<u64>,
+    root_sector: u64,
+    root_sectors: u64,
+    data_sector: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    fsinfo_sector: Option<u64>,
+    state: Mutex<State>,
+    /// Clusters of inodes dropped after being unlinked, freed by the next operation.
+    orphans: Mutex<Vec<u32>>,
+}
+
+impl FatFs {
+    /// Reads the boot sector of `device` and mounts the FAT file system on it.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let block_size = device.block_size();
+        if !(512..=MAX_SECTOR_SIZE).contains(&block_size) {
+            return Err(FsError::Unsupported);
+        }
+        let mut boot = vec![0; block_size];
+        device.read_blocks(0, &mut boot)?;
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let sector_size = u64::from(read_u16(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(read_u16(&boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(read_u16(&boot, 17));
+        let total_sectors = match read_u16(&boot, 19) {
+            0 => u64::from(read_u32(&boot, 32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_sectors = match read_u16(&boot, 22) {
+            0 => u64::from(read_u32(&boot, 36)),
+            sectors => u64::from(sectors),
+        };
+        if sector_size != block_size as u64 {
+            return Err(FsError::Unsupported);
+        }
+        if !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total_sectors > device.block_count()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let fat_sector = reserved_sectors;
+        let root_sector = fat_sector + fat_count * fat_sectors;
+        let data_sector = root_sector + root_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(FsError::InvalidArgument)?
+            / sectors_per_cluster;
+        let cluster_count = u32::try_from(cluster_count).map_err(|_| FsError::InvalidArgument)?;
+        let fat_type = match cluster_count {
+            count if count < FAT12_MAX_CLUSTERS => FatType::Fat12,
+            count if count < FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if fat_sectors * sector_size * 8 / entry_bits < u64::from(cluster_count) + 2 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let (mut root_cluster, mut fsinfo_sector, mut active_fat) = (0, None, None);
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 || read_u16(&boot, 22) != 0 {
+                return Err(FsError::InvalidArgument);
+            }
+            let flags = read_u16(&boot, 40);
+            if flags & FAT32_NO_MIRRORING != 0 {
+                active_fat = Some(u64::from(flags & 0xF)).filter(|&fat| fat < fat_count);
+                active_fat.ok_or(FsError::InvalidArgument)?;
+            }
+            root_cluster = read_u32(&boot, 44);
+            if !(2..cluster_count + 2).contains(&root_cluster) {
+                return Err(FsError::InvalidArgument);
+            }
+            fsinfo_sector = match u64::from(read_u16(&boot, 48)) {
+                0 | 0xFFFF => None,
+                sector => Some(sector).filter(|&sector| sector < reserved_sectors),
+            };
+        } else if root_entries == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let label_offset = if fat_type == FatType::Fat32 { 71 } else { 43 };
+        // The label is only there if the extended boot signature is.
+        let label = if boot[label_offset - 5] == 0x29 {
+            let label = &boot[label_offset..label_offset + 11];
+            let length = label
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |end| end + 1);
+            label[..length]
+                .iter()
+                .map(|&byte| char::from(byte))
+                .collect()
+        } else {
+            String::new()
+        };
+
+        let file_system = Arc::new_cyclic(|this| FatFs {
+            this: this.clone(),
+            device: BlockCache::new(device, CACHE_BLOCKS),
+            fat_type,
+            label,
+            sector_size,
+            sectors_per_cluster,
+            fat_sector,
+            fat_sectors,
+            fat_count,
+            active_fat,
+            root_sector,
+            root_sectors,
+            data_sector,
+            cluster_count,
+            root_cluster,
+            fsinfo_sector,
+            state: Mutex::new(State {
+                free_clusters: 0,
+                next_free: 2,
+                fsinfo_dirty: false,
+                inodes: BTreeMap::new(),
+            }),
+            orphans: Mutex::new(Vec::new()),
+        });
+        file_system.load_free_count()?;
+        Ok(file_system)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// The volume label from the boot sector, without its padding.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * self.sector_size
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        self.lock().free_clusters
+    }
+
+    /// Takes the state lock, first freeing the clusters of unlinked inodes dropped since the last
+    /// operation.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for first_cluster in orphans {
+            // A damaged chain only leaks clusters.
+            let _ = self.free_chain(&mut state, first_cluster);
+        }
+        state
+    }
+
+    /// Takes the free cluster count and hint from FSInfo, or counts the free clusters if FSInfo
+    /// does not know them.
+    fn load_free_count(&self) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if let Some(sector) = self.fsinfo_sector {
+            let mut fsinfo = [0; MAX_SECTOR_SIZE];
+            let fsinfo = &mut fsinfo[..self.sector_size as usize];
+            self.device.read_blocks(sector, fsinfo)?;
+            if read_u32(fsinfo, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(fsinfo, 484) == FSINFO_STRUCTURE_SIGNATURE
+            {
+                let next = read_u32(fsinfo, FSINFO_NEXT_FREE);
+                if next != FSINFO_UNKNOWN {
+                    state.next_free = next;
+                }
+                let free = read_u32(fsinfo, FSINFO_FREE_COUNT);
+                if free <= self.cluster_count {
+                    state.free_clusters = free;
+                    return Ok(());
+                }
+            }
+            // FSInfo gets the right count on the next sync.
+            state.fsinfo_dirty = true;
+        }
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                state.free_clusters += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let mut sector = [0; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..self.sector_size as usize];
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = position + done as u64;
+            let offset = (at % self.sector_size) as usize;
+            let count = (sector.len() - offset).min(buffer.len() - done);
+            self.device.read_blocks(at / self.sector_size, sector)?;
+            buffer[done..done + count].copy_from_slice(&sector[offset..offset + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&self, position: u64, buffer: &[u8]) -> Result<(), FsError> {
+        let mut sector = [0; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..self.sector_size as usize];
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = position + done as u64;
+            let offset = (at % self.sector_size) as usize;
+            let count = (sector.len() - offset).min(buffer.len() - done);
+            if count < sector.len() {
+                self.device.read_blocks(at / self.sector_size, sector)?;
+            }
+            sector[offset..offset + count].copy_from_slice(&buffer[done..done + count]);
+            self.device.write_blocks(at / self.sector_size, sector)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        (self.data_sector + u64::from(cluster - 2) * self.sectors_per_cluster) * self.sector_size
+    }
+
+    /// The byte offset of `cluster`'s entry in a FAT.
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn fat_position(&self, fat: u64, cluster: u32) -> u64 {
+        (self.fat_sector + fat * self.fat_sectors) * self.sector_size + self.fat_offset(cluster)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let position = self.fat_position(self.active_fat.unwrap_or(0), cluster);
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let mut pair = [0; 2];
+                self.read_bytes(position, &mut pair)?;
+                let pair = u32::from(u16::from_le_bytes(pair));
+                if cluster % 2 == 1 {
+                    pair >> 4
+                } else {
+                    pair & 0xFFF
+                }
+            }
+            FatType::Fat16 => {
+                let mut entry = [0; 2];
+                self.read_bytes(position, &mut entry)?;
+                u32::from(u16::from_le_bytes(entry))
+            }
+            FatType::Fat32 => {
+                let mut entry = [0; 4];
+                self.read_bytes(position, &mut entry)?;
+                u32::from_le_bytes(entry) & FAT32_CLUSTER_MASK
+            }
+        })
+    }
+
+    /// Sets `cluster`'s entry in every FAT in use.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let fats = match self.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.fat_count,
+        };
+        for fat in fats {
+            let position = self.fat_position(fat, cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let mut pair = [0; 2];
+                    self.read_bytes(position, &mut pair)?;
+                    let old = u16::from_le_bytes(pair);
+                    let value = value as u16 & 0xFFF;
+                    let new = if cluster % 2 == 1 {
+                        (old & 0x000F) | (value << 4)
+                    } else {
+                        (old & 0xF000) | value
+                    };
+                    self.write_bytes(position, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(position, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    let mut entry = [0; 4];
+                    self.read_bytes(position, &mut entry)?;
+                    // The top four bits are reserved and keep their value.
+                    let old = u32::from_le_bytes(entry);
+                    let new = (old & !FAT32_CLUSTER_MASK) | (value & FAT32_CLUSTER_MASK);
+                    self.write_bytes(position, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value that ends a cluster chain.
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => FAT32_CLUSTER_MASK,
+        }
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end of the chain. Free, reserved
+    /// and bad clusters in a chain mean the file system is damaged.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let value = self.fat_entry(cluster)?;
+        if value >= self.end_of_chain() & !7 {
+            Ok(None)
+        } else if value < 2 || value >= self.cluster_count + 2 {
+            Err(FsError::Io)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    /// Every cluster of the chain starting at `first_cluster`, which is empty for cluster zero.
+    fn chain(&self, first_cluster: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        if first_cluster == 0 {
+            return Ok(chain);
+        }
+        if !(2..self.cluster_count + 2).contains(&first_cluster) {
+            return Err(FsError::Io);
+        }
+        let mut cluster = Some(first_cluster);
+        while let Some(current) = cluster {
+            // A chain longer than the disk has a loop in it.
+            if chain.len() > self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    /// Takes a free cluster, linking it after `previous` if given. Its contents are left as they
+    /// were.
+    fn allocate_cluster(&self, state: &mut State, previous: Option<u32>) -> Result<u32, FsError> {
+        if state.free_clusters == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let count = self.cluster_count;
+        let start = match state.next_free {
+            next if (2..count + 2).contains(&next) => next,
+            _ => 2,
+        };
+        for index in 0..count {
+            let cluster = 2 + (start - 2 + index) % count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            state.free_clusters -= 1;
+            state.next_free = cluster + 1;
+            state.fsinfo_dirty = true;
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, state: &mut State, first_cluster: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first_cluster)? {
+            self.set_fat_entry(cluster, 0)?;
+            state.free_clusters += 1;
+        }
+        state.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        let zeroes = [0; MAX_SECTOR_SIZE];
+        let first = self.cluster_position(cluster) / self.sector_size;
+        for sector in first..first + self.sectors_per_cluster {
+            self.device
+                .write_blocks(sector, &zeroes[..self.sector_size as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Where the entries of the directory `node` are stored.
+    fn storage(&self, node: &Node) -> Storage {
+        if node.number == ROOT_INODE && self.fat_type != FatType::Fat32 {
+            Storage::FixedRoot
+        } else {
+            Storage::Chain(node.first_cluster)
+        }
+    }
+
+    /// The cluster a `..` entry names for the directory `node`, which is zero for the root.
+    fn parent_cluster(node: &Node) -> u32 {
+        if node.number == ROOT_INODE {
+            0
+        } else {
+            node.first_cluster
+        }
+    }
+
+    fn read_directory(&self, storage: Storage) -> Result<DirectoryData, FsError> {
+        let (clusters, bytes) = match storage {
+            Storage::FixedRoot => {
+                let mut bytes = vec![0; (self.root_sectors * self.sector_size) as usize];
+                self.device.read_blocks(self.root_sector, &mut bytes)?;
+                (Vec::new(), bytes)
+            }
+            Storage::Chain(0) => return Err(FsError::Io),
+            Storage::Chain(first_cluster) => {
+                let clusters = self.chain(first_cluster)?;
+                let cluster_size = self.cluster_size() as usize;
+                let mut bytes = vec![0; clusters.len() * cluster_size];
+                for (&cluster, chunk) in clusters.iter().zip(bytes.chunks_exact_mut(cluster_size)) {
+                    self.read_bytes(self.cluster_position(cluster), chunk)?;
+                }
+                (clusters, bytes)
+            }
+        };
+        Ok(DirectoryData {
+            storage,
+            clusters,
+            bytes,
+        })
+    }
+
+    /// The byte position on the device of the entry at `offset` in `directory`.
+    fn entry_position(&self, directory: &DirectoryData, offset: u64) -> u64 {
+        match directory.storage {
+            Storage::FixedRoot => self.root_sector * self.sector_size + offset,
+            Storage::Chain(_) => {
+                let cluster_size = self.cluster_size();
+                let cluster = directory.clusters[(offset / cluster_size) as usize];
+                self.cluster_position(cluster) + offset % cluster_size
+            }
+        }
+    }
+
+    /// Writes the entries from `start` to `end` of `directory` back to the device.
+    fn write_entries(
+        &self,
+        directory: &DirectoryData,
+        start: u64,
+        end: u64,
+    ) -> Result<(), FsError> {
+        for offset in (start..end).step_by(ENTRY_SIZE) {
+            let entry = &directory.bytes[offset as usize..offset as usize + ENTRY_SIZE];
+            self.write_bytes(self.entry_position(directory, offset), entry)?;
+        }
+        Ok(())
+    }
+
+    /// Adds entries for `name` to `directory`, growing it if it has no room, and returns where
+    /// the short entry went.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        directory: &mut DirectoryData,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+        size: u32,
+    ) -> Result<Location, FsError> {
+        let entries = parse_entries(&directory.bytes);
+        let (short_name, case_flags, slots) = match name::to_short_name(name) {
+            Some((short_name, case_flags)) => (short_name, case_flags, Vec::new()),
+            None => {
+                let short_name = name::generate_short_name(name, |candidate| {
+                    entries.iter().any(|entry| entry.short_name == *candidate)
+                });
+                let slots = name::long_name_slots(name, name::checksum(&short_name));
+                (short_name, 0, slots)
+            }
+        };
+        let needed = slots.len() + 1;
+
+        let first_slot = loop {
+            if let Some(first_slot) = find_free_run(&directory.bytes, needed) {
+                break first_slot;
+            }
+            let Storage::Chain(_) = directory.storage else {
+                return Err(FsError::NoSpace);
+            };
+            let cluster = self.allocate_cluster(state, directory.clusters.last().copied())?;
+            self.zero_cluster(cluster)?;
+            directory.clusters.push(cluster);
+            let size = directory.bytes.len() + self.cluster_size() as usize;
+            directory.bytes.resize(size, 0);
+        };
+
+        let mut offset = first_slot;
+        for slot in &slots {
+            directory.bytes[offset..offset + ENTRY_SIZE].copy_from_slice(slot);
+            offset += ENTRY_SIZE;
+        }
+        directory.bytes[offset..offset + ENTRY_SIZE].copy_from_slice(&short_entry(
+            &short_name,
+            case_flags,
+            attributes,
+            first_cluster,
+            size,
+        ));
+        self.write_entries(directory, first_slot as u64, (offset + ENTRY_SIZE) as u64)?;
+        Ok(Location {
+            directory: directory.storage,
+            offset: offset as u64,
+            first_slot: first_slot as u64,
+            position: self.entry_position(directory, offset as u64),
+        })
+    }
+
+    /// Marks the entries at `location` deleted.
+    fn remove_entry(&self, location: &Location) -> Result<(), FsError> {
+        let mut directory = self.read_directory(location.directory)?;
+        for offset in (location.first_slot..=location.offset).step_by(ENTRY_SIZE) {
+            directory.bytes[offset as usize] = ENTRY_DELETED;
+        }
+        self.write_entries(&directory, location.first_slot, location.offset + 1)
+    }
+
+    /// Writes `node`'s first cluster and size to its directory entry.
+    fn update_entry(&self, node: &Node) -> Result<(), FsError> {
+        let Some(location) = node.location else {
+            return Ok(());
+        };
+        let mut entry = [0; ENTRY_SIZE];
+        self.read_bytes(location.position, &mut entry)?;
+        set_entry_cluster(&mut entry, node.first_cluster);
+        entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+        entry[22..24].copy_from_slice(&DOS_TIME.to_le_bytes());
+        entry[24..26].copy_from_slice(&DOS_DATE.to_le_bytes());
+        self.write_bytes(location.position, &entry)
+    }
+
+    /// The inode for `entry` of `directory`, which is the one already in use if there is one.
+    fn inode(
+        &self,
+        state: &mut State,
+        directory: &DirectoryData,
+        entry: &RawEntry,
+    ) -> Arc<FatInode> {
+        let position = self.entry_position(directory, entry.offset);
+        let number = position / ENTRY_SIZE as u64;
+        if let Some(inode) = state.inodes.get(&number).and_then(Weak::upgrade) {
+            return inode;
+        }
+        self.insert_inode(
+            state,
+            Node {
+                number,
+                attributes: entry.attributes,
+                first_cluster: entry.first_cluster,
+                size: entry.size,
+                location: Some(Location {
+                    directory: directory.storage,
+                    offset: entry.offset,
+                    first_slot: entry.first_slot,
+                    position,
+                }),
+                unlinked: false,
+            },
+        )
+    }
+
+    fn insert_inode(&self, state: &mut State, node: Node) -> Arc<FatInode> {
+        let inode = Arc::new(FatInode {
+            file_system: self
+                .this
+                .upgrade()
+                .expect("FAT file system dropped while in use"),
+            node: Mutex::new(node),
+        });
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        state.inodes.insert(node.number, Arc::downgrade(&inode));
+        inode
+    }
+
+    fn write_fsinfo(&self, state: &mut State) -> Result<(), FsError> {
+        let Some(sector) = self.fsinfo_sector.filter(|_| state.fsinfo_dirty) else {
+            return Ok(());
+        };
+        let mut fsinfo = [0; MAX_SECTOR_SIZE];
+        let fsinfo = &mut fsinfo[..self.sector_size as usize];
+        self.device.read_blocks(sector, fsinfo)?;
+        if read_u32(fsinfo, 0) == FSINFO_LEAD_SIGNATURE {
+            fsinfo[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4]
+                .copy_from_slice(&state.free_clusters.to_le_bytes());
+            fsinfo[FSINFO_NEXT_FREE..FSINFO_NEXT_FREE + 4]
+                .copy_from_slice(&state.next_free.to_le_bytes());
+            self.device.write_blocks(sector, fsinfo)?;
+        }
+        state.fsinfo_dirty = false;
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut state = self.lock();
+        if let Some(root) = state.inodes.get(&ROOT_INODE).and_then(Weak::upgrade) {
+            return root;
+        }
+        self.insert_inode(
+            &mut state,
+            Node {
+                number: ROOT_INODE,
+                attributes: ATTRIBUTE_DIRECTORY,
+                first_cluster: self.root_cluster,
+                size: 0,
+                location: None,
+                unlinked: false,
+            },
+        )
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let mut state = self.lock();
+        self.write_fsinfo(&mut state)?;
+        Ok(self.device.sync()?)
+    }
+}
+
+impl Drop for FatFs {
+    fn drop(&mut self) {
+        // Nobody is left to report the error to.
+        let _ = self.sync();
+    }
+}
+
+#[derive(Debug)]
+pub struct FatInode {
+    file_system: Arc<FatFs>,
+    node: Mutex<Node>,
+}
+
+impl FatInode {
+    fn node(&self) -> Node {
+        *self.node.lock()
+    }
+
+    /// Writes `data` at `offset`, growing the file as needed, and returns the updated node.
+    fn write_data(
+        &self,
+        state: &mut State,
+        mut node: Node,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Node, FsError> {
+        let end = offset + data.len() as u64;
+        let size = u32::try_from(end).map_err(|_| FsError::NoSpace)?;
+        let chain = self.grow_chain(state, &mut node, end)?;
+        self.write_chain(&chain, offset, data)?;
+        node.size = node.size.max(size);
+        Ok(node)
+    }
+
+    /// Writes zeroes from `start` to `end`, so a file grown past its old end reads back zeroes.
+    fn zero_fill(
+        &self,
+        state: &mut State,
+        mut node: Node,
+        start: u64,
+        end: u64,
+    ) -> Result<Node, FsError> {
+        let size = u32::try_from(end).map_err(|_| FsError::NoSpace)?;
+        let chain = self.grow_chain(state, &mut node, end)?;
+        let zeroes = [0; MAX_SECTOR_SIZE];
+        let mut at = start;
+        while at < end {
+            let count = (end - at).min(MAX_SECTOR_SIZE as u64) as usize;
+            self.write_chain(&chain, at, &zeroes[..count])?;
+            at += count as u64;
+        }
+        node.size = node.size.max(size);
+        Ok(node)
+    }
+
+    /// Returns the file's cluster chain, allocating clusters until it holds `end` bytes.
+    fn grow_chain(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        end: u64,
+    ) -> Result<Vec<u32>, FsError> {
+        let file_system = &self.file_system;
+        let mut chain = file_system.chain(node.first_cluster)?;
+        let needed = end.div_ceil(file_system.cluster_size()) as usize;
+        while chain.len() < needed {
+            let cluster = match file_system.allocate_cluster(state, chain.last().copied()) {
+                Ok(cluster) => cluster,
+                Err(error) => {
+                    // Keep the clusters already added, so the entry does not lose them.
+                    file_system.update_entry(node)?;
+                    *self.node.lock() = *node;
+                    return Err(error);
+                }
+            };
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    /// Writes `data` at `offset` into the clusters of `chain`, which must already reach its end.
+    fn write_chain(&self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let file_system = &self.file_system;
+        let cluster_size = file_system.cluster_size();
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done as u64;
+            let cluster = chain[(at / cluster_size) as usize];
+            let within = at % cluster_size;
+            let count = ((cluster_size - within) as usize).min(data.len() - done);
+            file_system.write_bytes(
+                file_system.cluster_position(cluster) + within,
+                &data[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Runs `operation` on this directory's entries under the state lock.
+    fn with_directory<T>(
+        &self,
+        operation: impl FnOnce(&mut State, Node, DirectoryData) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let mut state = self.file_system.lock();
+        let node = self.node();
+        if !node.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let directory = self
+            .file_system
+            .read_directory(self.file_system.storage(&node))?;
+        operation(&mut state, node, directory)
+    }
+
+    /// Removes `entry` from `directory`, freeing its clusters unless an inode still uses them.
+    fn remove(
+        &self,
+        state: &mut State,
+        directory: &DirectoryData,
+        entry: &RawEntry,
+    ) -> Result<(), FsError> {
+        let file_system = &self.file_system;
+        let inode = file_system.inode(state, directory, entry);
+        let mut node = inode.node();
+        let location = node.location.ok_or(FsError::Io)?;
+        if node.is_directory() {
+            let children = file_system.read_directory(Storage::Chain(node.first_cluster))?;
+            if !parse_entries(&children.bytes).is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        file_system.remove_entry(&location)?;
+        state.inodes.remove(&node.number);
+        node.location = None;
+        node.unlinked = true;
+        *inode.node.lock() = node;
+        // Dropping the last reference, here or wherever it is held, frees the clusters.
+        Ok(())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let node = self.node.get_mut();
+        if node.unlinked && node.first_cluster != 0 {
+            // The state lock may be held by whoever dropped the last reference.
+            self.file_system.orphans.lock().push(node.first_cluster);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node();
+        let read_only = node.attributes & ATTRIBUTE_READ_ONLY != 0;
+        let (file_type, permissions) = match (node.is_directory(), read_only) {
+            (true, false) => (FileType::Directory, 0o755),
+            (true, true) => (FileType::Directory, 0o555),
+            (false, false) => (FileType::Regular, 0o644),
+            (false, true) => (FileType::Regular, 0o444),
+        };
+        Metadata {
+            inode: node.number,
+            file_type,
+            size: u64::from(node.size),
+            permissions,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let file_system = &self.file_system;
+        let _state = file_system.lock();
+        let node = self.node();
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u64::from(node.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        let cluster_size = file_system.cluster_size();
+        let chain = file_system.chain(node.first_cluster)?;
+        let mut done = 0;
+        while done < count {
+            let at = offset + done as u64;
+            let cluster = *chain.get((at / cluster_size) as usize).ok_or(FsError::Io)?;
+            let within = at % cluster_size;
+            let length = ((cluster_size - within) as usize).min(count - done);
+            file_system.read_bytes(
+                file_system.cluster_position(cluster) + within,
+                &mut buffer[done..done + length],
+            )?;
+            done += length;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.file_system.lock();
+        let mut node = self.node();
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        if offset > u64::from(node.size) {
+            node = self.zero_fill(&mut state, node, u64::from(node.size), offset)?;
+        }
+        node = self.write_data(&mut state, node, offset, buffer)?;
+        self.file_system.update_entry(&node)?;
+        *self.node.lock() = node;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let file_system = &self.file_system;
+        let mut state = file_system.lock();
+        let mut node = self.node();
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        if size > node.size {
+            node = self.zero_fill(&mut state, node, u64::from(node.size), u64::from(size))?;
+        } else {
+            let chain = file_system.chain(node.first_cluster)?;
+            let kept = u64::from(size).div_ceil(file_system.cluster_size()) as usize;
+            if let Some(&first_freed) = chain.get(kept) {
+                match kept {
+                    0 => node.first_cluster = 0,
+                    _ => file_system.set_fat_entry(chain[kept - 1], file_system.end_of_chain())?,
+                }
+                file_system.free_chain(&mut state, first_freed)?;
+            }
+            node.size = size;
+        }
+        file_system.update_entry(&node)?;
+        *self.node.lock() = node;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.with_directory(|state, _, directory| {
+            let entry = find_entry(&directory, name).ok_or(FsError::NotFound)?;
+            Ok(self.file_system.inode(state, &directory, &entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        self.with_directory(|_, _, directory| {
+            Ok(parse_entries(&directory.bytes)
+                .into_iter()
+                .map(|entry| DirectoryEntry {
+                    inode: self.file_system.entry_position(&directory, entry.offset)
+                        / ENTRY_SIZE as u64,
+                    file_type: if entry.attributes & ATTRIBUTE_DIRECTORY != 0 {
+                        FileType::Directory
+                    } else {
+                        FileType::Regular
+                    },
+                    name: entry.name,
+                })
+                .collect())
+        })
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        name::validate(name)?;
+        let file_system = &self.file_system;
+        self.with_directory(|state, node, mut directory| {
+            if find_entry(&directory, name).is_some() {
+                return Err(FsError::AlreadyExists);
+            }
+            let (attributes, first_cluster) = match file_type {
+                FileType::Regular => (ATTRIBUTE_ARCHIVE, 0),
+                FileType::Directory => {
+                    let cluster = file_system.allocate_cluster(state, None)?;
+                    let mut dots = vec![0; file_system.cluster_size() as usize];
+                    dots[..ENTRY_SIZE].copy_from_slice(&short_entry(
+                        b".          ",
+                        0,
+                        ATTRIBUTE_DIRECTORY,
+                        cluster,
+                        0,
+                    ));
+                    dots[ENTRY_SIZE..2 * ENTRY_SIZE].copy_from_slice(&short_entry(
+                        b"..         ",
+                        0,
+                        ATTRIBUTE_DIRECTORY,
+                        FatFs::parent_cluster(&node),
+                        0,
+                    ));
+                    file_system.write_bytes(file_system.cluster_position(cluster), &dots)?;
+                    (ATTRIBUTE_DIRECTORY, cluster)
+                }
+                _ => return Err(FsError::Unsupported),
+            };
+            let location = match file_system.add_entry(
+                state,
+                &mut directory,
+                name,
+                attributes,
+                first_cluster,
+                0,
+            ) {
+                Ok(location) => location,
+                Err(error) => {
+                    if first_cluster != 0 {
+                        file_system.free_chain(state, first_cluster)?;
+                    }
+                    return Err(error);
+                }
+            };
+            Ok(file_system.insert_inode(
+                state,
+                Node {
+                    number: location.position / ENTRY_SIZE as u64,
+                    attributes,
+                    first_cluster,
+                    size: 0,
+                    location: Some(location),
+                    unlinked: false,
+                },
+            ) as Arc<dyn Inode>)
+        })
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.with_directory(|state, _, directory| {
+            let entry = find_entry(&directory, name).ok_or(FsError::NotFound)?;
+            self.remove(state, &directory, &entry)
+        })
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_directory: &dyn Inode,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        name::validate(new_name)?;
+        let file_system = &self.file_system;
+        let target_number = new_directory.metadata().inode;
+        self.with_directory(|state, source_node, source_directory| {
+            let target = state
+                .inodes
+                .get(&target_number)
+                .and_then(Weak::upgrade)
+                .filter(|target| {
+                    core::ptr::addr_eq(Arc::as_ptr(target), new_directory as *const dyn Inode)
+                })
+                .ok_or(FsError::CrossDevice)?;
+            let target_node = target.node();
+            if !target_node.is_directory() {
+                return Err(FsError::NotADirectory);
+            }
+            let entry = find_entry(&source_directory, old_name).ok_or(FsError::NotFound)?;
+            let inode = file_system.inode(state, &source_directory, &entry);
+            let mut node = inode.node();
+            let old_location = node.location.ok_or(FsError::Io)?;
+            let same_directory = target_node.number == source_node.number;
+
+            if node.is_directory() && !same_directory {
+                // Walk up from the target through `..` entries; meeting the moved directory on
+                // the way means it would end up inside itself.
+                let mut cluster = target_node.first_cluster;
+                for _ in 0..file_system.cluster_count {
+                    if target_node.number == ROOT_INODE || cluster == 0 {
+                        break;
+                    }
+                    if cluster == node.first_cluster {
+                        return Err(FsError::InvalidArgument);
+                    }
+                    let mut dot_dot = [0; ENTRY_SIZE];
+                    file_system
+                        .read_bytes(file_system.cluster_position(cluster) + 32, &mut dot_dot)?;
+                    cluster = entry_cluster(&dot_dot);
+                }
+            }
+
+            let mut target_directory = if same_directory {
+                source_directory
+            } else {
+                file_system.read_directory(file_system.storage(&target_node))?
+            };
+            if let Some(existing) = find_entry(&target_directory, new_name) {
+                let existing_position =
+                    file_system.entry_position(&target_directory, existing.offset);
+                if existing_position == old_location.position {
+                    // Only the case of the name changes, if anything.
+                    if existing.name == new_name {
+                        return Ok(());
+                    }
+                } else {
+                    match (
+                        existing.attributes & ATTRIBUTE_DIRECTORY != 0,
+                        node.is_directory(),
+                    ) {
+                        (true, false) => return Err(FsError::IsADirectory),
+                        (false, true) => return Err(FsError::NotADirectory),
+                        _ => {}
+                    }
+                    self.remove(state, &target_directory, &existing)?;
+                    target_directory =
+                        file_system.read_directory(file_system.storage(&target_node))?;
+                }
+            }
+
+            let location = file_system.add_entry(
+                state,
+                &mut target_directory,
+                new_name,
+                node.attributes,
+                node.first_cluster,
+                node.size,
+            )?;
+            file_system.remove_entry(&old_location)?;
+            if node.is_directory() && !same_directory {
+                let mut dot_dot = [0; ENTRY_SIZE];
+                let position = file_system.cluster_position(node.first_cluster) + 32;
+                file_system.read_bytes(position, &mut dot_dot)?;
+                set_entry_cluster(&mut dot_dot, FatFs::parent_cluster(&target_node));
+                file_system.write_bytes(position, &dot_dot)?;
+            }
+
+            state.inodes.remove(&node.number);
+            node.number = location.position / ENTRY_SIZE as u64;
+            node.location = Some(location);
+            state.inodes.insert(node.number, Arc::downgrade(&inode));
+            *inode.node.lock() = node;
+            Ok(())
+        })
+    }
+}
+
+/// Reads the entries of a directory, skipping deleted entries, volume labels and `.` and `..`.
+fn parse_entries(bytes: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long_name = None;
+    for (index, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = (index * ENTRY_SIZE) as u64;
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = entry[11];
+        if attributes & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+            long_name = LongName::push(long_name.take(), entry, offset);
+            continue;
+        }
+        let long_name = long_name.take();
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+        let short_name: ShortName = entry[..name::SHORT_NAME_LENGTH].try_into().unwrap();
+        let case_flags = entry[12];
+        let (name, first_slot) = long_name
+            .and_then(|long_name| long_name.finish(&short_name))
+            .unwrap_or_else(|| (name::display(&short_name, case_flags), offset));
+        entries.push(RawEntry {
+            name,
+            short_name,
+            attributes,
+            first_cluster: entry_cluster(entry),
+            size: read_u32(entry, 28),
+            offset,
+            first_slot,
+        });
+    }
+    entries
+}
+
+fn find_entry(directory: &DirectoryData, name: &str) -> Option<RawEntry> {
+    parse_entries(&directory.bytes)
+        .into_iter()
+        .find(|entry| entry.matches(name))
+}
+
+/// Finds `count` consecutive unused entries, returning the offset of the first.
+fn find_free_run(bytes: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        if entry[0] == ENTRY_END || entry[0] == ENTRY_DELETED {
+            run += 1;
+            if run == count {
+                return Some((index + 1 - count) * ENTRY_SIZE);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+fn short_entry(
+    short_name: &ShortName,
+    case_flags: u8,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..name::SHORT_NAME_LENGTH].copy_from_slice(short_name);
+    entry[11] = attributes;
+    entry[12] = case_flags;
+    for offset in [14, 22] {
+        entry[offset..offset + 2].copy_from_slice(&DOS_TIME.to_le_bytes());
+    }
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DOS_DATE.to_le_bytes());
+    }
+    set_entry_cluster(&mut entry, first_cluster);
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn entry_cluster(entry: &[u8]) -> u32 {
+    u32::from(read_u16(entry, 20)) << 16 | u32::from(read_u16(entry, 26))
+}
+
+fn set_entry_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,318 @@
+//! 8.3 short names and the long names stored in slots before them.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::fs::FsError;
+
+pub const SHORT_NAME_LENGTH: usize = 11;
+
+/// Bits of the reserved byte of a short entry marking an all-lowercase base and extension.
+pub const LOWERCASE_BASE: u8 = 0x08;
+pub const LOWERCASE_EXTENSION: u8 = 0x10;
+
+const LAST_SLOT: u8 = 0x40;
+const SEQUENCE_MASK: u8 = 0x1F;
+const UNITS_PER_SLOT: usize = 13;
+/// Long names are at most 255 UTF-16 units, which takes 20 slots.
+const MAX_SLOTS: u8 = 20;
+const MAX_LONG_NAME: usize = 255;
+/// Where the UTF-16 units of a long name slot are.
+const UNIT_OFFSETS: [usize; UNITS_PER_SLOT] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Punctuation allowed in short names besides letters and digits.
+const SHORT_NAME_SYMBOLS: &[u8] = b"!#$%&'()-@^_`{}~";
+/// Characters no name may contain.
+const FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+pub type ShortName = [u8; SHORT_NAME_LENGTH];
+
+/// Checks that `name` can be stored in a directory.
+pub fn validate(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_LONG_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    // Trailing dots and spaces are dropped by other systems, so names ending in them would not
+    // survive a round trip.
+    if name.chars().any(|c| c < ' ' || FORBIDDEN.contains(&c)) || name.ends_with(['.', ' ']) {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// The checksum of a short name that its long name slots repeat.
+pub fn checksum(short_name: &ShortName) -> u8 {
+    short_name
+        .iter()
+        .fold(0, |sum: u8, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Shows a short name as `NAME.EXT`, lowercasing the parts the case flags say to.
+pub fn display(short_name: &ShortName, case_flags: u8) -> String {
+    let mut name = String::new();
+    let mut push = |bytes: &[u8], lowercase: bool| {
+        for (index, &byte) in bytes.iter().enumerate() {
+            // 0x05 stands in for a leading 0xE5, which marks deleted entries.
+            let byte = if index == 0 && byte == 0x05 {
+                0xE5
+            } else {
+                byte
+            };
+            let byte = if lowercase {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            };
+            name.push(char::from(byte));
+        }
+    };
+    let base = trim_padding(&short_name[..8]);
+    let extension = trim_padding(&short_name[8..]);
+    push(base, case_flags & LOWERCASE_BASE != 0);
+    if !extension.is_empty() {
+        push(b".", false);
+        push(extension, case_flags & LOWERCASE_EXTENSION != 0);
+    }
+    name
+}
+
+fn trim_padding(part: &[u8]) -> &[u8] {
+    let length = part
+        .iter()
+        .rposition(|&byte| byte != b' ')
+        .map_or(0, |end| end + 1);
+    &part[..length]
+}
+
+/// Converts a name that fits in 8.3 form, with each part in a single case, to a short name and
+/// its case flags. Other names need a long name.
+pub fn to_short_name(name: &str) -> Option<(ShortName, u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || (name.contains('.') && extension.is_empty())
+    {
+        return None;
+    }
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    let mut case_flags = 0;
+    for (part, range, flag) in [
+        (base, 0..8, LOWERCASE_BASE),
+        (extension, 8..11, LOWERCASE_EXTENSION),
+    ] {
+        if !part.bytes().all(is_short_name_byte) {
+            return None;
+        }
+        let has_lowercase = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let has_uppercase = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        match (has_lowercase, has_uppercase) {
+            (true, true) => return None,
+            (true, false) => case_flags |= flag,
+            _ => {}
+        }
+        short_name[range][..part.len()].copy_from_slice(part.to_ascii_uppercase().as_bytes());
+    }
+    Some((short_name, case_flags))
+}
+
+fn is_short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&byte)
+}
+
+/// Derives a short name such as `LONGFI~1.TXT` for a name that needs a long name, choosing the
+/// first numeric tail that `taken` says is free.
+pub fn generate_short_name(name: &str, taken: impl Fn(&ShortName) -> bool) -> ShortName {
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let convert = |part: &str, length: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match u8::try_from(c) {
+                Ok(byte) if is_short_name_byte(byte) => byte.to_ascii_uppercase(),
+                _ => b'_',
+            })
+            .take(length)
+            .collect()
+    };
+    let base = convert(base, 8);
+    let extension = convert(extension, 3);
+
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+    for number in 1u32.. {
+        let mut buffer = [b'~'; 11];
+        let digits = write_decimal(number, &mut buffer[1..]);
+        let tail = &buffer[..=digits];
+        let kept = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..kept].copy_from_slice(&base[..kept]);
+        short_name[kept..kept + tail.len()].copy_from_slice(tail);
+        if !taken(&short_name) {
+            break;
+        }
+    }
+    short_name
+}
+
+/// Writes `number` in decimal to the start of `buffer`, returning the number of digits.
+fn write_decimal(number: u32, buffer: &mut [u8]) -> usize {
+    let mut digits = 0;
+    let mut rest = number;
+    loop {
+        buffer[digits] = b'0' + (rest % 10) as u8;
+        digits += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    buffer[..digits].reverse();
+    digits
+}
+
+/// The long name slots for `name`, in the order they are stored: last part first.
+pub fn long_name_slots(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(UNITS_PER_SLOT) {
+        units.push(0);
+        units.resize(units.len().next_multiple_of(UNITS_PER_SLOT), 0xFFFF);
+    }
+    let count = units.len() / UNITS_PER_SLOT;
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut slot = [0; 32];
+            slot[0] = (index + 1) as u8;
+            if index + 1 == count {
+                slot[0] |= LAST_SLOT;
+            }
+            slot[11] = super::ATTRIBUTE_LONG_NAME;
+            slot[13] = checksum;
+            let part = &units[index * UNITS_PER_SLOT..(index + 1) * UNITS_PER_SLOT];
+            for (&offset, unit) in UNIT_OFFSETS.iter().zip(part) {
+                slot[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+/// A long name being collected from the slots before a short entry.
+#[derive(Debug)]
+pub struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// The sequence number the next slot must have.
+    next: u8,
+    /// The offset of the first slot in the directory.
+    first_slot: u64,
+}
+
+impl LongName {
+    /// Adds `slot`, found at `offset`, to the name being collected. The slot marked last starts a
+    /// new name; a slot out of sequence or with another checksum discards the name.
+    pub fn push(current: Option<Self>, slot: &[u8], offset: u64) -> Option<Self> {
+        let sequence = slot[0] & SEQUENCE_MASK;
+        let mut long_name = if slot[0] & LAST_SLOT != 0 {
+            if sequence == 0 || sequence > MAX_SLOTS {
+                return None;
+            }
+            LongName {
+                units: vec![0; usize::from(sequence) * UNITS_PER_SLOT],
+                checksum: slot[13],
+                next: sequence,
+                first_slot: offset,
+            }
+        } else {
+            current.filter(|long_name| long_name.checksum == slot[13])?
+        };
+        if sequence == 0 || long_name.next != sequence {
+            return None;
+        }
+        let start = usize::from(sequence - 1) * UNITS_PER_SLOT;
+        for (index, &offset) in UNIT_OFFSETS.iter().enumerate() {
+            long_name.units[start + index] = u16::from_le_bytes([slot[offset], slot[offset + 1]]);
+        }
+        long_name.next -= 1;
+        Some(long_name)
+    }
+
+    /// Completes the name with the short entry following it, returning the name and the offset
+    /// of its first slot if every slot was seen and the checksum matches.
+    pub fn finish(self, short_name: &ShortName) -> Option<(String, u64)> {
+        if self.next != 0 || self.checksum != checksum(short_name) {
+            return None;
+        }
+        let length = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.units.len());
+        let name = String::from_utf16(&self.units[..length]).ok()?;
+        Some((name, self.first_slot))
+    }
+}
+
+/// Compares names the way FAT does: ignoring the case of ASCII letters.
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.chars()
+            .zip(b.chars())
+            .all(|(a, b)| a.eq_ignore_ascii_case(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_short_names() {
+        assert_eq!(to_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(
+            to_short_name("lower.txt"),
+            Some((*b"LOWER   TXT", LOWERCASE_BASE | LOWERCASE_EXTENSION))
+        );
+        assert_eq!(to_short_name("Mixed.txt"), None);
+        assert_eq!(to_short_name("toolongname.txt"), None);
+        assert_eq!(to_short_name("a.b.c"), None);
+        assert_eq!(display(b"LOWER   TXT", LOWERCASE_BASE), "lower.TXT");
+        assert_eq!(display(b"DOCS       ", 0), "DOCS");
+
+        let taken = |short_name: &ShortName| short_name == b"LONGFI~1TXT";
+        assert_eq!(
+            generate_short_name("Long File Name.txt", taken),
+            *b"LONGFI~2TXT"
+        );
+        assert_eq!(generate_short_name(".hidden", |_| false), *b"HIDDEN~1   ");
+        assert_eq!(
+            generate_short_name("ü+x.tar.gz", |_| false),
+            *b"__XTAR~1GZ "
+        );
+    }
+
+    #[test_case]
+    fn test_long_name_slots_round_trip() {
+        let short_name = *b"LONGFI~1TXT";
+        let name = "Long File Name.txt";
+        let slots = long_name_slots(name, checksum(&short_name));
+        assert_eq!(slots.len(), 2);
+        let mut long_name = None;
+        for (index, slot) in slots.iter().enumerate() {
+            long_name = LongName::push(long_name, slot, index as u64 * 32);
+        }
+        assert_eq!(
+            long_name.unwrap().finish(&short_name),
+            Some((String::from(name), 0))
+        );
+        // The checksum ties the slots to the short entry after them.
+        let long_name = LongName::push(None, &slots[0], 0);
+        let long_name = LongName::push(long_name, &slots[1], 32).unwrap();
+        assert_eq!(long_name.finish(b"OTHER   TXT"), None);
+    }
+}
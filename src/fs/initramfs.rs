@@ -0,0 +1,353 @@
+//! The initial ramfs: an archive embedded into the kernel image and unpacked at boot, since the
+//! bootloader cannot load a ramdisk. Both ustar and newc cpio archives are understood.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+
+use super::{FsError, OpenFlags};
+
+/// Built from `initramfs/root` by `build.rs`.
+static INITRAMFS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.tar"));
+
+const TAR_BLOCK_SIZE: usize = 512;
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    UnknownFormat,
+    Truncated,
+    BadChecksum,
+    BadNumber,
+    BadName,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpackError {
+    Archive(ArchiveError),
+    Fs(FsError),
+}
+
+impl From<ArchiveError> for UnpackError {
+    fn from(error: ArchiveError) -> Self {
+        UnpackError::Archive(error)
+    }
+}
+
+impl From<FsError> for UnpackError {
+    fn from(error: FsError) -> Self {
+        UnpackError::Fs(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Hard links, devices and other entries the ramfs cannot represent.
+    Other,
+}
+
+/// A member of an archive. `path` is relative, without a leading `./` or `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the entries of a ustar or newc cpio archive.
+#[derive(Debug, Clone)]
+pub enum Archive<'a> {
+    Tar(&'a [u8]),
+    Cpio(&'a [u8]),
+    /// The end of the archive or an error has been reached.
+    Finished,
+}
+
+impl<'a> Archive<'a> {
+    /// Recognises the archive format from its first header.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ArchiveError> {
+        if bytes.starts_with(b"070701") || bytes.starts_with(b"070702") {
+            Ok(Archive::Cpio(bytes))
+        } else if bytes.get(257..262) == Some(b"ustar") {
+            Ok(Archive::Tar(bytes))
+        } else {
+            Err(ArchiveError::UnknownFormat)
+        }
+    }
+
+    fn next_tar_entry(bytes: &mut &'a [u8]) -> Result<Option<Entry<'a>>, ArchiveError> {
+        loop {
+            let header = bytes.get(..TAR_BLOCK_SIZE).ok_or(ArchiveError::Truncated)?;
+            // The archive ends with two zero blocks; the first one is enough to stop.
+            if header.iter().all(|&byte| byte == 0) {
+                return Ok(None);
+            }
+            let checksum = parse_octal(&header[148..156])?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(index, &byte)| match index {
+                    148..156 => u64::from(b' '),
+                    _ => u64::from(byte),
+                })
+                .sum();
+            if sum != checksum {
+                return Err(ArchiveError::BadChecksum);
+            }
+            let size = parse_octal(&header[124..136])? as usize;
+            let data_start = TAR_BLOCK_SIZE;
+            let data_end = data_start
+                .checked_add(size)
+                .filter(|&end| end <= bytes.len())
+                .ok_or(ArchiveError::Truncated)?;
+            let data = &bytes[data_start..data_end];
+            let mode = parse_octal(&header[100..108])? as u32;
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                b'2' => EntryKind::Symlink,
+                _ => EntryKind::Other,
+            };
+            let name = c_string(&header[0..100])?;
+            let prefix = c_string(&header[345..500])?;
+            *bytes =
+                &bytes[(data_end.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE).min(bytes.len())..];
+
+            let path = if prefix.is_empty() {
+                relative_path(name).to_string()
+            } else {
+                relative_path(&format!("{}/{}", prefix, name)).to_string()
+            };
+            if path.is_empty() {
+                continue;
+            }
+            let data = if kind == EntryKind::Symlink {
+                c_string(&header[157..257])?.as_bytes()
+            } else {
+                data
+            };
+            return Ok(Some(Entry {
+                path,
+                kind,
+                mode,
+                data,
+            }));
+        }
+    }
+
+    fn next_cpio_entry(bytes: &mut &'a [u8]) -> Result<Option<Entry<'a>>, ArchiveError> {
+        loop {
+            let header = bytes
+                .get(..CPIO_HEADER_SIZE)
+                .ok_or(ArchiveError::Truncated)?;
+            if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+                return Err(ArchiveError::UnknownFormat);
+            }
+            let field = |index: usize| parse_hex(&header[6 + index * 8..14 + index * 8]);
+            let mode = field(1)?;
+            let file_size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_end = CPIO_HEADER_SIZE
+                .checked_add(name_size)
+                .filter(|&end| end <= bytes.len() && name_size > 0)
+                .ok_or(ArchiveError::Truncated)?;
+            let name = c_string(&bytes[CPIO_HEADER_SIZE..name_end])?;
+            let data_start = name_end.next_multiple_of(4);
+            let data_end = data_start
+                .checked_add(file_size)
+                .filter(|&end| end <= bytes.len())
+                .ok_or(ArchiveError::Truncated)?;
+            let data = &bytes[data_start..data_end];
+            *bytes = &bytes[data_end.next_multiple_of(4).min(bytes.len())..];
+
+            if name == CPIO_TRAILER {
+                return Ok(None);
+            }
+            let path = relative_path(name).to_string();
+            if path.is_empty() {
+                continue;
+            }
+            let kind = match mode & MODE_TYPE_MASK {
+                MODE_REGULAR => EntryKind::File,
+                MODE_DIRECTORY => EntryKind::Directory,
+                MODE_SYMLINK => EntryKind::Symlink,
+                _ => EntryKind::Other,
+            };
+            return Ok(Some(Entry {
+                path,
+                kind,
+                mode: mode & !MODE_TYPE_MASK,
+                data,
+            }));
+        }
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self {
+            Archive::Tar(bytes) => Self::next_tar_entry(bytes),
+            Archive::Cpio(bytes) => Self::next_cpio_entry(bytes),
+            Archive::Finished => return None,
+        };
+        if !matches!(result, Ok(Some(_))) {
+            *self = Archive::Finished;
+        }
+        result.transpose()
+    }
+}
+
+/// Unpacks the embedded archive into the root file system.
+pub fn init() {
+    if let Err(error) = unpack(INITRAMFS, "/") {
+        panic!("could not unpack the initramfs: {:?}", error);
+    }
+}
+
+/// Creates every file and directory of `archive` below `directory`, creating missing parent
+/// directories on the way. Entries the ramfs cannot represent are skipped. Returns the number of
+/// entries unpacked.
+pub fn unpack(archive: &[u8], directory: &str) -> Result<usize, UnpackError> {
+    let mut count = 0;
+    for entry in Archive::new(archive)? {
+        let entry = entry?;
+        if entry.path.split('/').any(|component| component == "..") {
+            return Err(ArchiveError::BadName.into());
+        }
+        let path = format!("{}/{}", directory.trim_end_matches('/'), entry.path);
+        match entry.kind {
+            EntryKind::Directory => create_directories(&path)?,
+            EntryKind::File => {
+                if let Some((parent, _)) = path.rsplit_once('/') {
+                    create_directories(parent)?;
+                }
+                let file = super::open(
+                    &path,
+                    OpenFlags::WRITE_ONLY | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                )?;
+                let mut written = 0;
+                while written < entry.data.len() {
+                    written += file.write(&entry.data[written..])?;
+                }
+            }
+            EntryKind::Symlink | EntryKind::Other => continue,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Creates `path` and any missing directories above it.
+fn create_directories(path: &str) -> Result<(), FsError> {
+    let mut end = 0;
+    while end < path.len() {
+        end = path[end + 1..]
+            .find('/')
+            .map_or(path.len(), |index| end + 1 + index);
+        match super::create_directory(&path[..end]) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+fn relative_path(path: &str) -> &str {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if path == "." {
+            return "";
+        } else {
+            return path;
+        }
+    }
+}
+
+/// The text before the first NUL byte.
+fn c_string(bytes: &[u8]) -> Result<&str, ArchiveError> {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).map_err(|_| ArchiveError::BadName)
+}
+
+/// Parses a tar number: octal digits padded with spaces or NUL bytes.
+fn parse_octal(bytes: &[u8]) -> Result<u64, ArchiveError> {
+    let text = c_string(bytes).map_err(|_| ArchiveError::BadNumber)?.trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| ArchiveError::BadNumber)
+}
+
+fn parse_hex(bytes: &[u8]) -> Result<u32, ArchiveError> {
+    let text = core::str::from_utf8(bytes).map_err(|_| ArchiveError::BadNumber)?;
+    u32::from_str_radix(text, 16).map_err(|_| ArchiveError::BadNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    static CPIO: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+    static HELLO: &[u8] = include_bytes!("../../tests/programs/hello.elf");
+
+    fn entries(archive: &[u8]) -> Vec<(String, EntryKind, usize)> {
+        Archive::new(archive)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.path, entry.kind, entry.data.len()))
+            .collect()
+    }
+
+    #[test_case]
+    fn test_tar_and_cpio_list_the_same_entries() {
+        let expected = [
+            ("bin".to_string(), EntryKind::Directory, 0),
+            ("bin/hello".to_string(), EntryKind::File, HELLO.len()),
+            ("etc".to_string(), EntryKind::Directory, 0),
+            ("etc/motd".to_string(), EntryKind::File, 20),
+        ];
+        assert_eq!(entries(INITRAMFS), expected);
+        assert_eq!(entries(CPIO), expected);
+    }
+
+    #[test_case]
+    fn test_rejects_corrupt_archives() {
+        let mut corrupt = Vec::from(&INITRAMFS[..TAR_BLOCK_SIZE]);
+        corrupt[0] ^= 1;
+        assert_eq!(
+            Archive::new(&corrupt).unwrap().next(),
+            Some(Err(ArchiveError::BadChecksum))
+        );
+        assert_eq!(
+            Archive::new(&CPIO[..CPIO_HEADER_SIZE + 1]).unwrap().next(),
+            Some(Err(ArchiveError::Truncated))
+        );
+        assert_eq!(
+            Archive::new(b"plain text").err(),
+            Some(ArchiveError::UnknownFormat)
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Kernel state as text files: memory, interrupts, uptime, the CPU and one directory per
+//! process. Every read generates the file anew.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{arch::x86_64::__cpuid, fmt::Write};
+
+use super::{DirectoryEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::{
+    allocator,
+    interupt::{
+        pic::{self, IRQ_COUNT},
+        pit,
+    },
+    memory::{self, Backing},
+    process::{self, Pid, ProcessState},
+    scheduler::{self, ThreadState},
+};
+
+const ROOT_INODE: InodeNumber = 1;
+const SELF_INODE: InodeNumber = 2;
+/// Process directories and their files are numbered from here, a block of inodes per process.
+const FIRST_PROCESS_INODE: InodeNumber = 0x1000;
+const INODES_PER_PROCESS: InodeNumber = 4;
+
+/// The files at the top level, by name.
+const KERNEL_FILES: [(&str, Content); 4] = [
+    ("cpuinfo", Content::CpuInfo),
+    ("interrupts", Content::Interrupts),
+    ("meminfo", Content::MemInfo),
+    ("uptime", Content::Uptime),
+];
+
+/// Makes the content of a file in a process directory for a given process.
+type ProcessContent = fn(Pid) -> Content;
+
+/// The files in every process directory, by name.
+const PROCESS_FILES: [(&str, ProcessContent); 2] =
+    [("maps", Content::Maps), ("status", Content::Status)];
+
+#[derive(Debug, Default)]
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcInode::Root)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Content {
+    CpuInfo,
+    Interrupts,
+    MemInfo,
+    Uptime,
+    Maps(Pid),
+    Status(Pid),
+}
+
+impl Content {
+    fn inode(self) -> InodeNumber {
+        let kernel_file = |index| SELF_INODE + 1 + index;
+        match self {
+            Content::CpuInfo => kernel_file(0),
+            Content::Interrupts => kernel_file(1),
+            Content::MemInfo => kernel_file(2),
+            Content::Uptime => kernel_file(3),
+            Content::Maps(pid) => process_inode(pid) + 1,
+            Content::Status(pid) => process_inode(pid) + 2,
+        }
+    }
+
+    fn generate(self) -> Result<String, FsError> {
+        let mut text = String::new();
+        let written = match self {
+            Content::CpuInfo => cpu_info(&mut text),
+            Content::Interrupts => interrupts(&mut text),
+            Content::MemInfo => memory_info(&mut text),
+            Content::Uptime => {
+                let uptime = pit::uptime_milliseconds();
+                let idle = pit::ticks_to_milliseconds(scheduler::idle_ticks());
+                writeln!(
+                    text,
+                    "{}.{:02} {}.{:02}",
+                    uptime / 1000,
+                    uptime % 1000 / 10,
+                    idle / 1000,
+                    idle % 1000 / 10
+                )
+            }
+            Content::Maps(pid) => return maps(pid),
+            Content::Status(pid) => return status(pid),
+        };
+        written.expect("writing to a String cannot fail");
+        Ok(text)
+    }
+}
+
+fn process_inode(pid: Pid) -> InodeNumber {
+    FIRST_PROCESS_INODE + pid * INODES_PER_PROCESS
+}
+
+#[derive(Debug)]
+enum ProcInode {
+    Root,
+    /// `self`, a link to the directory of the process looking it up.
+    SelfLink(Pid),
+    Process(Pid),
+    File(Content),
+}
+
+impl ProcInode {
+    /// The error for trying to change the entries of this inode.
+    fn modify_error(&self) -> FsError {
+        match self {
+            ProcInode::Root | ProcInode::Process(_) => FsError::ReadOnly,
+            _ => FsError::NotADirectory,
+        }
+    }
+}
+
+impl Inode for ProcInode {
+    fn metadata(&self) -> Metadata {
+        let (inode, file_type, size, permissions) = match self {
+            ProcInode::Root => (ROOT_INODE, FileType::Directory, 0, 0o555),
+            ProcInode::SelfLink(pid) => (
+                SELF_INODE,
+                FileType::Symlink,
+                pid.to_string().len() as u64,
+                0o777,
+            ),
+            ProcInode::Process(pid) => (process_inode(*pid), FileType::Directory, 0, 0o555),
+            ProcInode::File(content) => {
+                // A process that has gone away leaves an empty file behind.
+                let size = content.generate().map_or(0, |text| text.len() as u64);
+                (content.inode(), FileType::Regular, size, 0o444)
+            }
+        };
+        Metadata {
+            inode,
+            file_type,
+            size,
+            permissions,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let ProcInode::File(content) = self else {
+            return Err(FsError::IsADirectory);
+        };
+        let text = content.generate()?;
+        let Some(rest) = text.as_bytes().get(offset as usize..) else {
+            return Ok(0);
+        };
+        let count = rest.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&rest[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let inode = match self {
+            ProcInode::Root if name == "self" => {
+                ProcInode::SelfLink(process::current_pid().ok_or(FsError::NotFound)?)
+            }
+            ProcInode::Root => match KERNEL_FILES.iter().find(|(file, _)| *file == name) {
+                Some(&(_, content)) => ProcInode::File(content),
+                None => {
+                    let pid = name.parse().map_err(|_| FsError::NotFound)?;
+                    if !process::pids().contains(&pid) {
+                        return Err(FsError::NotFound);
+                    }
+                    ProcInode::Process(pid)
+                }
+            },
+            ProcInode::Process(pid) => {
+                let (_, content) = PROCESS_FILES
+                    .iter()
+                    .find(|(file, _)| *file == name)
+                    .ok_or(FsError::NotFound)?;
+                ProcInode::File(content(*pid))
+            }
+            _ => return Err(FsError::NotADirectory),
+        };
+        Ok(Arc::new(inode))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        let file = |name: &str, content: Content| DirectoryEntry {
+            name: name.to_string(),
+            inode: content.inode(),
+            file_type: FileType::Regular,
+        };
+        match self {
+            ProcInode::Root => {
+                let mut entries: Vec<DirectoryEntry> = KERNEL_FILES
+                    .iter()
+                    .map(|&(name, content)| file(name, content))
+                    .collect();
+                if process::current_pid().is_some() {
+                    entries.push(DirectoryEntry {
+                        name: "self".to_string(),
+                        inode: SELF_INODE,
+                        file_type: FileType::Symlink,
+                    });
+                }
+                entries.extend(process::pids().into_iter().map(|pid| DirectoryEntry {
+                    name: pid.to_string(),
+                    inode: process_inode(pid),
+                    file_type: FileType::Directory,
+                }));
+                Ok(entries)
+            }
+            ProcInode::Process(pid) => Ok(PROCESS_FILES
+                .iter()
+                .map(|(name, content)| file(name, content(*pid)))
+                .collect()),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(self.modify_error())
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(self.modify_error())
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_directory: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(self.modify_error())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match self {
+            ProcInode::SelfLink(pid) => Ok(pid.to_string()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+fn cpu_info(text: &mut String) -> core::fmt::Result {
+    let leaf = |leaf| __cpuid(leaf);
+    let vendor = leaf(0);
+    let vendor: Vec<u8> = [vendor.ebx, vendor.edx, vendor.ecx]
+        .iter()
+        .flat_map(|register| register.to_le_bytes())
+        .collect();
+    let signature = leaf(1);
+    let base_family = (signature.eax >> 8) & 0xF;
+    let family = match base_family {
+        0xF => base_family + ((signature.eax >> 20) & 0xFF),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xF => ((signature.eax >> 4) & 0xF) | ((signature.eax >> 12) & 0xF0),
+        _ => (signature.eax >> 4) & 0xF,
+    };
+    writeln!(text, "vendor_id\t: {}", String::from_utf8_lossy(&vendor))?;
+    writeln!(text, "cpu family\t: {}", family)?;
+    writeln!(text, "model\t\t: {}", model)?;
+    if leaf(0x8000_0000).eax >= 0x8000_0004 {
+        let name: Vec<u8> = (0x8000_0002..=0x8000_0004)
+            .map(leaf)
+            .flat_map(|result| [result.eax, result.ebx, result.ecx, result.edx])
+            .flat_map(|register| register.to_le_bytes())
+            .take_while(|&byte| byte != 0)
+            .collect();
+        writeln!(
+            text,
+            "model name\t: {}",
+            String::from_utf8_lossy(&name).trim()
+        )?;
+    }
+    writeln!(text, "stepping\t: {}", signature.eax & 0xF)?;
+    let flags: Vec<&str> = CPU_FLAGS
+        .iter()
+        .filter(|&&(register, bit, _)| {
+            let value = match register {
+                Register::Ecx => signature.ecx,
+                Register::Edx => signature.edx,
+            };
+            value & (1 << bit) != 0
+        })
+        .map(|&(_, _, name)| name)
+        .collect();
+    writeln!(text, "flags\t\t: {}", flags.join(" "))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Ecx,
+    Edx,
+}
+
+/// Feature bits of CPUID leaf 1 and their names in Linux's `/proc/cpuinfo`.
+const CPU_FLAGS: [(Register, u32, &str); 16] = [
+    (Register::Edx, 0, "fpu"),
+    (Register::Edx, 4, "tsc"),
+    (Register::Edx, 5, "msr"),
+    (Register::Edx, 6, "pae"),
+    (Register::Edx, 9, "apic"),
+    (Register::Edx, 13, "pge"),
+    (Register::Edx, 15, "cmov"),
+    (Register::Edx, 23, "mmx"),
+    (Register::Edx, 24, "fxsr"),
+    (Register::Edx, 25, "sse"),
+    (Register::Edx, 26, "sse2"),
+    (Register::Ecx, 0, "pni"),
+    (Register::Ecx, 19, "sse4_1"),
+    (Register::Ecx, 20, "sse4_2"),
+    (Register::Ecx, 21, "x2apic"),
+    (Register::Ecx, 30, "rdrand"),
+];
+
+fn interrupts(text: &mut String) -> core::fmt::Result {
+    let counts = pic::interrupt_counts();
+    let handlers = pic::handler_counts();
+    writeln!(text, "{:>4} {:>12}", "", "CPU0")?;
+    for irq in 0..IRQ_COUNT {
+        let name = match irq {
+            0 => "timer".to_string(),
+            1 => "keyboard".to_string(),
+            2 => "cascade".to_string(),
+            _ if handlers[irq] > 0 => format!("{} handler(s)", handlers[irq]),
+            _ if counts[irq] > 0 => "unclaimed".to_string(),
+            _ => continue,
+        };
+        writeln!(text, "{:>3}: {:>12}  {}", irq, counts[irq], name)?;
+    }
+    Ok(())
+}
+
+fn memory_info(text: &mut String) -> core::fmt::Result {
+    let (allocated_frames, total_frames) = memory::frame_usage();
+    let (heap_used, heap_free) = allocator::usage();
+    let kilobytes = |frames: u64| frames * 4;
+    writeln!(text, "MemTotal:  {:>10} kB", kilobytes(total_frames))?;
+    writeln!(
+        text,
+        "MemFree:   {:>10} kB",
+        kilobytes(total_frames - allocated_frames)
+    )?;
+    writeln!(text, "HeapTotal: {:>10} kB", allocator::HEAP_SIZE / 1024)?;
+    writeln!(text, "HeapUsed:  {:>10} kB", heap_used / 1024)?;
+    writeln!(text, "HeapFree:  {:>10} kB", heap_free / 1024)
+}
+
+fn status(pid: Pid) -> Result<String, FsError> {
+    let (parent, state, threads, files) = process::with_process(pid, |process| {
+        (
+            process.parent,
+            process.state,
+            process.threads.clone(),
+            process.files.descriptors().len(),
+        )
+    })
+    .ok_or(FsError::NotFound)?;
+    let running = scheduler::thread_states()
+        .into_iter()
+        .filter(|(id, _, state)| threads.contains(id) && *state != ThreadState::Exited)
+        .count();
+    let state = match state {
+        ProcessState::Running => "R (running)",
+        ProcessState::Exited(_) => "Z (zombie)",
+    };
+    // Like Linux, a process without a parent reports pid 0.
+    Ok(format!(
+        "Pid:\t{}\nPPid:\t{}\nState:\t{}\nThreads:\t{}\nFDSize:\t{}\n",
+        pid,
+        parent.unwrap_or(0),
+        state,
+        running,
+        files
+    ))
+}
+
+/// The memory areas of a process, in the format of Linux's `/proc/<pid>/maps`.
+fn maps(pid: Pid) -> Result<String, FsError> {
+    if !process::pids().contains(&pid) {
+        return Err(FsError::NotFound);
+    }
+    // An exited process has no address space left, and no areas.
+    let areas = process::with_address_space(pid, |address_space| {
+        address_space.with_areas(|areas| areas.iter().cloned().collect::<Vec<_>>())
+    })
+    .unwrap_or_default();
+    let flag = |set: bool, letter: char| if set { letter } else { '-' };
+    Ok(areas
+        .iter()
+        .map(|area| {
+            let (offset, inode) = match &area.backing {
+                Backing::Anonymous => (0, 0),
+                Backing::File { inode, offset } => (*offset, inode.metadata().inode),
+            };
+            format!(
+                "{:08x}-{:08x} {}{}{}p {:08x} {}\n",
+                area.start.as_u64(),
+                area.end.as_u64(),
+                flag(area.protection.read, 'r'),
+                flag(area.protection.write, 'w'),
+                flag(area.protection.execute, 'x'),
+                offset,
+                inode
+            )
+        })
+        .collect())
+}
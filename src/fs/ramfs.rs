@@ -0,0 +1,357 @@
+//! A file system that keeps every file on the kernel heap.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{DirectoryEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+
+/// State shared by every inode of one ramfs.
+#[derive(Debug)]
+struct Shared {
+    next_inode: AtomicU64,
+    used_bytes: AtomicU64,
+    capacity: u64,
+    /// Every live inode, so a directory passed to [`Inode::rename`] can be found again.
+    inodes: Mutex<BTreeMap<InodeNumber, Weak<RamInode>>>,
+}
+
+impl Shared {
+    /// Accounts for a file growing from `old_size` to `new_size` bytes.
+    fn resize(&self, old_size: usize, new_size: usize) -> Result<(), FsError> {
+        if new_size <= old_size {
+            self.used_bytes
+                .fetch_sub((old_size - new_size) as u64, Ordering::Relaxed);
+            return Ok(());
+        }
+        let growth = (new_size - old_size) as u64;
+        self.used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(growth)
+                    .filter(|&used| used <= self.capacity)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoSpace)
+    }
+}
+
+#[derive(Debug)]
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+}
+
+#[derive(Debug)]
+pub struct RamInode {
+    number: InodeNumber,
+    shared: Arc<Shared>,
+    /// The directory containing this inode; the root is its own parent.
+    parent: Mutex<Weak<RamInode>>,
+    content: Mutex<Content>,
+}
+
+/// A heap-backed file system with directories and regular files.
+#[derive(Debug)]
+pub struct RamFs {
+    shared: Arc<Shared>,
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    /// Creates an empty ramfs limited only by the size of the heap.
+    pub fn new() -> Self {
+        Self::with_capacity(u64::MAX)
+    }
+
+    /// Creates an empty ramfs holding at most `capacity` bytes of file data.
+    pub fn with_capacity(capacity: u64) -> Self {
+        let shared = Arc::new(Shared {
+            next_inode: AtomicU64::new(1),
+            used_bytes: AtomicU64::new(0),
+            capacity,
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        let root = RamInode::new(&shared, Content::Directory(BTreeMap::new()));
+        *root.parent.lock() = Arc::downgrade(&root);
+        Self { shared, root }
+    }
+
+    /// The number of bytes of file data stored.
+    pub fn used_bytes(&self) -> u64 {
+        self.shared.used_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.shared.capacity
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl RamInode {
+    fn new(shared: &Arc<Shared>, content: Content) -> Arc<Self> {
+        let number = shared.next_inode.fetch_add(1, Ordering::Relaxed);
+        let inode = Arc::new(Self {
+            number,
+            shared: shared.clone(),
+            parent: Mutex::new(Weak::new()),
+            content: Mutex::new(content),
+        });
+        shared.inodes.lock().insert(number, Arc::downgrade(&inode));
+        inode
+    }
+
+    fn file_type(&self) -> FileType {
+        match *self.content.lock() {
+            Content::File(_) => FileType::Regular,
+            Content::Directory(_) => FileType::Directory,
+        }
+    }
+
+    /// Checks whether `self` is `directory` or one of its ancestors.
+    fn is_ancestor_of(&self, directory: &Arc<RamInode>) -> bool {
+        let mut current = directory.clone();
+        loop {
+            if current.number == self.number {
+                return true;
+            }
+            let parent = current.parent.lock().upgrade();
+            match parent {
+                Some(parent) if parent.number != current.number => current = parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        if let Content::File(data) = self.content.get_mut() {
+            self.shared
+                .used_bytes
+                .fetch_sub(data.len() as u64, Ordering::Relaxed);
+        }
+        self.shared.inodes.lock().remove(&self.number);
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, permissions) = match &*self.content.lock() {
+            Content::File(data) => (FileType::Regular, data.len() as u64, 0o644),
+            Content::Directory(entries) => (FileType::Directory, entries.len() as u64, 0o755),
+        };
+        Metadata {
+            inode: self.number,
+            file_type,
+            size,
+            permissions,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content.lock();
+        let Content::File(data) = &*content else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = (offset as usize).min(data.len());
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut content = self.content.lock();
+        let Content::File(data) = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        let offset = usize::try_from(offset).map_err(|_| FsError::InvalidArgument)?;
+        let end = offset
+            .checked_add(buffer.len())
+            .ok_or(FsError::InvalidArgument)?;
+        if end > data.len() {
+            self.shared.resize(data.len(), end)?;
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::File(data) = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        let size = usize::try_from(size).map_err(|_| FsError::InvalidArgument)?;
+        self.shared.resize(data.len(), size)?;
+        data.resize(size, 0);
+        if size < data.capacity() / 2 {
+            data.shrink_to_fit();
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => entries
+                .get(name)
+                .map(|inode| inode.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirectoryEntry>, FsError> {
+        let entries: Vec<(String, Arc<RamInode>)> = match &*self.content.lock() {
+            Content::Directory(entries) => entries
+                .iter()
+                .map(|(name, inode)| (name.clone(), inode.clone()))
+                .collect(),
+            Content::File(_) => return Err(FsError::NotADirectory),
+        };
+        Ok(entries
+            .into_iter()
+            .map(|(name, inode)| DirectoryEntry {
+                name,
+                inode: inode.number,
+                file_type: inode.file_type(),
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let content = match file_type {
+            FileType::Regular => Content::File(Vec::new()),
+            FileType::Directory => Content::Directory(BTreeMap::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+        let mut directory = self.content.lock();
+        let Content::Directory(entries) = &mut *directory else {
+            return Err(FsError::NotADirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = RamInode::new(&self.shared, content);
+        let parent = self.shared.inodes.lock().get(&self.number).cloned();
+        *inode.parent.lock() = parent.unwrap_or_default();
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut directory = self.content.lock();
+        let Content::Directory(entries) = &mut *directory else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        if let Content::Directory(children) = &*inode.content.lock()
+            && !children.is_empty()
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        // Open files keep the inode, and with it the data, alive until they are closed.
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_directory: &dyn Inode,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let new_number = new_directory.metadata().inode;
+        // Upgrade outside the lock: dropping the last reference removes the inode from the map.
+        let target = self.shared.inodes.lock().get(&new_number).cloned();
+        let target = target
+            .and_then(|target| target.upgrade())
+            .filter(|target| {
+                core::ptr::addr_eq(Arc::as_ptr(target), new_directory as *const dyn Inode)
+            })
+            .ok_or(FsError::CrossDevice)?;
+
+        let inode = match &*self.content.lock() {
+            Content::Directory(entries) => {
+                entries.get(old_name).cloned().ok_or(FsError::NotFound)?
+            }
+            Content::File(_) => return Err(FsError::NotADirectory),
+        };
+        let is_directory = inode.file_type() == FileType::Directory;
+        if is_directory && inode.is_ancestor_of(&target) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if target.number == self.number {
+            let mut directory = self.content.lock();
+            let Content::Directory(entries) = &mut *directory else {
+                return Err(FsError::NotADirectory);
+            };
+            check_replaceable(entries.get(new_name), &inode)?;
+            entries.remove(old_name);
+            entries.insert(new_name.to_string(), inode);
+            return Ok(());
+        }
+
+        // Lock both directories in inode order so concurrent renames cannot deadlock.
+        let (mut source, mut destination) = if self.number < target.number {
+            let source = self.content.lock();
+            (source, target.content.lock())
+        } else {
+            let destination = target.content.lock();
+            (self.content.lock(), destination)
+        };
+        let Content::Directory(destination_entries) = &mut *destination else {
+            return Err(FsError::NotADirectory);
+        };
+        let Content::Directory(source_entries) = &mut *source else {
+            return Err(FsError::NotADirectory);
+        };
+        check_replaceable(destination_entries.get(new_name), &inode)?;
+        source_entries.remove(old_name);
+        *inode.parent.lock() = Arc::downgrade(&target);
+        destination_entries.insert(new_name.to_string(), inode);
+        Ok(())
+    }
+}
+
+/// Checks that `existing` may be replaced by `inode` in a rename.
+fn check_replaceable(
+    existing: Option<&Arc<RamInode>>,
+    inode: &Arc<RamInode>,
+) -> Result<(), FsError> {
+    let Some(existing) = existing else {
+        return Ok(());
+    };
+    if Arc::ptr_eq(existing, inode) {
+        return Ok(());
+    }
+    match (&*existing.content.lock(), inode.file_type()) {
+        (Content::Directory(children), FileType::Directory) => match children.is_empty() {
+            true => Ok(()),
+            false => Err(FsError::DirectoryNotEmpty),
+        },
+        (Content::Directory(_), _) => Err(FsError::IsADirectory),
+        (Content::File(_), FileType::Directory) => Err(FsError::NotADirectory),
+        (Content::File(_), _) => Ok(()),
+    }
+}
@@ -10,12 +10,25 @@ use x86_64::{
 pub struct GlobalDescriptorTableAccessor {
     pub global_descriptor_table: GlobalDescriptorTable,
     pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The privilege stack the CPU switches to when an interrupt arrives while in ring 3.
+pub const KERNEL_PRIVILEGE_STACK_INDEX: usize = 0;
 
-pub static TASK_STATE_SEGMENT: Lazy<TaskStateSegment> = Lazy::new(|| {
+/// The byte offset of the kernel privilege stack pointer within the task state segment.
+pub const KERNEL_PRIVILEGE_STACK_OFFSET: usize =
+    core::mem::offset_of!(TaskStateSegment, privilege_stack_table)
+        + KERNEL_PRIVILEGE_STACK_INDEX * core::mem::size_of::<VirtAddr>();
+
+/// Mutable because the privilege stack is switched whenever a different thread enters ring 3.
+pub(crate) static mut TASK_STATE_SEGMENT: TaskStateSegment = TaskStateSegment::new();
+
+static TASK_STATE_SEGMENT_INIT: Lazy<&'static TaskStateSegment> = Lazy::new(|| {
     let mut task_state_segment = TaskStateSegment::new();
     task_state_segment.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
         // TODO replace with a stack
@@ -23,33 +36,64 @@ pub static TASK_STATE_SEGMENT: Lazy<TaskStateSegment> = Lazy::new(|| {
         static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
         let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        let stack_end = stack_start + (STACK_SIZE as u64);
-        stack_end
+        stack_start + (STACK_SIZE as u64)
+    };
+    task_state_segment.privilege_stack_table[KERNEL_PRIVILEGE_STACK_INDEX] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        (stack_start + (STACK_SIZE as u64)).align_down(16u64)
     };
-    task_state_segment
+    unsafe {
+        (&raw mut TASK_STATE_SEGMENT).write(task_state_segment);
+        &*core::ptr::addr_of!(TASK_STATE_SEGMENT)
+    }
 });
 
+/// Sets the stack the CPU switches to when an interrupt arrives while in ring 3.
+pub fn set_kernel_privilege_stack(stack_top: VirtAddr) {
+    unsafe {
+        (&raw mut TASK_STATE_SEGMENT)
+            .cast::<u8>()
+            .add(KERNEL_PRIVILEGE_STACK_OFFSET)
+            .cast::<VirtAddr>()
+            .write_unaligned(stack_top);
+    }
+}
+
+// The order of the segments is fixed by `syscall`/`sysret`, which derive the selectors from
+// STAR: kernel code and data must be adjacent, followed by user data and then user code.
 pub static GLOBAL_DESCRIPTOR_TABLE: Lazy<GlobalDescriptorTableAccessor> = Lazy::new(|| {
     let mut global_descriptor_table = GlobalDescriptorTable::new();
 
     let code_selector = global_descriptor_table.append(Descriptor::kernel_code_segment());
+    let data_selector = global_descriptor_table.append(Descriptor::kernel_data_segment());
+    let user_data_selector = global_descriptor_table.append(Descriptor::user_data_segment());
+    let user_code_selector = global_descriptor_table.append(Descriptor::user_code_segment());
     let tss_selector =
-        global_descriptor_table.append(Descriptor::tss_segment(&*TASK_STATE_SEGMENT));
+        global_descriptor_table.append(Descriptor::tss_segment(&TASK_STATE_SEGMENT_INIT));
 
     GlobalDescriptorTableAccessor {
         global_descriptor_table,
-        tss_selector,
         code_selector,
+        data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
     }
 });
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
     GLOBAL_DESCRIPTOR_TABLE.global_descriptor_table.load();
     unsafe {
         CS::set_reg(GLOBAL_DESCRIPTOR_TABLE.code_selector);
+        SS::set_reg(GLOBAL_DESCRIPTOR_TABLE.data_selector);
+        DS::set_reg(GLOBAL_DESCRIPTOR_TABLE.data_selector);
+        ES::set_reg(GLOBAL_DESCRIPTOR_TABLE.data_selector);
         load_tss(GLOBAL_DESCRIPTOR_TABLE.tss_selector);
     }
 }
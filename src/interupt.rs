@@ -1,9 +1,14 @@
 use spin::Lazy;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::{gdt, println};
+use crate::{
+    eprintln, gdt,
+    memory::{self, Access},
+    println, process,
+};
 
 pub mod pic;
+pub mod pit;
 
 pub static INTERUPT_DESCRIPTOR_TABLE: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut interrupt_descriptor_table = InterruptDescriptorTable::new();
@@ -46,12 +51,40 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    todo!("Handle loading new page");
-    // crate::hlt_loop();
+    // Copy-on-write and lazily populated pages, touched from ring 3 or through a user pointer.
+    if let Ok(address) = Cr2::read()
+        && memory::is_user_range(address, 1)
+    {
+        let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else {
+            Access::Read
+        };
+        match memory::handle_user_fault(address, access) {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(error) => eprintln!("Could not map page {:?}: {:?}", address, error),
+        }
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        eprintln!(
+            "Killed user program: page fault at {:?} ({:?}) from {:?}",
+            Cr2::read(),
+            error_code,
+            stack_frame.instruction_pointer
+        );
+        unsafe { crate::syscall::exit_user_mode(process::FAULT_EXIT_CODE) };
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:?} ({:?})\n{:#?}",
+        Cr2::read(),
+        error_code,
+        stack_frame
+    );
 }
 
 #[cfg(test)]
@@ -1,12 +1,18 @@
-use pc_keyboard::Keyboard;
+use alloc::vec::Vec;
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use pc_keyboard::{DecodedKey, KeyCode, Keyboard};
 use pic8259::ChainedPics;
 use spin::{Lazy, Mutex};
 use x86_64::{
-    instructions::port::Port,
+    PrivilegeLevel,
+    instructions::{interrupts, port::Port},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
-use crate::print;
+use crate::console::{self, CONSOLE_COUNT};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -20,10 +26,94 @@ pub enum InterruptIndex {
     Keyboard,
 }
 
+/// The first line that drivers can claim with [`register_irq_handler`]; line 2 cascades to the
+/// secondary controller.
+const FIRST_SHARED_IRQ: u8 = 3;
+pub const IRQ_COUNT: usize = 16;
+
+/// Handlers of the lines claimed by drivers. PCI devices may share a line, so every handler of a
+/// line is called and has to check whether its device raised the interrupt.
+static IRQ_HANDLERS: Mutex<[IrqHandlers; IRQ_COUNT]> =
+    Mutex::new([const { Vec::new() }; IRQ_COUNT]);
+
+type IrqHandlers = Vec<fn()>;
+
+/// How many interrupts each line has raised since boot.
+static INTERRUPT_COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+
 pub fn set_pic_handlers(interrupt_descriptor_table: &mut InterruptDescriptorTable) {
     interrupt_descriptor_table[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
     interrupt_descriptor_table[InterruptIndex::Keyboard as u8]
         .set_handler_fn(keyboard_interrupt_handler);
+    let handlers: [extern "x86-interrupt" fn(InterruptStackFrame);
+        IRQ_COUNT - FIRST_SHARED_IRQ as usize] = [
+        irq_handler::<3>,
+        irq_handler::<4>,
+        irq_handler::<5>,
+        irq_handler::<6>,
+        irq_handler::<7>,
+        irq_handler::<8>,
+        irq_handler::<9>,
+        irq_handler::<10>,
+        irq_handler::<11>,
+        irq_handler::<12>,
+        irq_handler::<13>,
+        irq_handler::<14>,
+        irq_handler::<15>,
+    ];
+    for (irq, handler) in (FIRST_SHARED_IRQ..).zip(handlers) {
+        interrupt_descriptor_table[PIC_1_OFFSET + irq].set_handler_fn(handler);
+    }
+}
+
+/// Whether drivers may register a handler for line `irq`.
+pub fn can_claim(irq: u8) -> bool {
+    (FIRST_SHARED_IRQ..IRQ_COUNT as u8).contains(&irq)
+}
+
+/// The number of interrupts each line has raised since boot, indexed by IRQ.
+pub fn interrupt_counts() -> [u64; IRQ_COUNT] {
+    core::array::from_fn(|irq| INTERRUPT_COUNTS[irq].load(Ordering::Relaxed))
+}
+
+/// The number of handlers drivers have registered for each line.
+pub fn handler_counts() -> [usize; IRQ_COUNT] {
+    interrupts::without_interrupts(|| {
+        let handlers = IRQ_HANDLERS.lock();
+        core::array::from_fn(|irq| handlers[irq].len())
+    })
+}
+
+/// Calls `handler` on every interrupt of line `irq` and unmasks the line.
+pub fn register_irq_handler(irq: u8, handler: fn()) {
+    assert!(can_claim(irq), "IRQ {} cannot be claimed", irq);
+    interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize].push(handler);
+        let mut controller = PROGRAMMABLE_INTERRUPT_CONTROLLER.lock();
+        unsafe {
+            let [mut primary, mut secondary] = controller.read_masks();
+            if irq < 8 {
+                primary &= !(1 << irq);
+            } else {
+                // The secondary controller only reaches the CPU through the cascade line.
+                primary &= !(1 << 2);
+                secondary &= !(1 << (irq - 8));
+            }
+            controller.write_masks(primary, secondary);
+        }
+    });
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    INTERRUPT_COUNTS[IRQ as usize].fetch_add(1, Ordering::Relaxed);
+    for handler in IRQ_HANDLERS.lock()[IRQ as usize].iter() {
+        handler();
+    }
+    unsafe {
+        PROGRAMMABLE_INTERRUPT_CONTROLLER
+            .lock()
+            .notify_end_of_interrupt(PIC_1_OFFSET + IRQ);
+    }
 }
 
 pub static PROGRAMMABLE_INTERRUPT_CONTROLLER: Lazy<Mutex<ChainedPics>> =
@@ -37,27 +127,76 @@ pub static KEYBOARD: Lazy<
     Mutex::new(Keyboard::new(
         pc_keyboard::ScancodeSet1::new(),
         pc_keyboard::layouts::Us104Key,
-        pc_keyboard::HandleControl::Ignore,
+        pc_keyboard::HandleControl::MapLettersToUnicode,
     ))
 });
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Alt and these keys switch to the console of the same number.
+const CONSOLE_KEYS: [KeyCode; CONSOLE_COUNT] = [
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+];
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    INTERRUPT_COUNTS[0].fetch_add(1, Ordering::Relaxed);
+    super::pit::tick();
     unsafe {
         PROGRAMMABLE_INTERRUPT_CONTROLLER
             .lock()
             .notify_end_of_interrupt(InterruptIndex::Timer as u8);
     }
+    // Only ring 3 is preempted; kernel threads give up the CPU themselves.
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        crate::scheduler::yield_now();
+    }
 }
 
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    INTERRUPT_COUNTS[1].fetch_add(1, Ordering::Relaxed);
     let mut keyboard = KEYBOARD.lock();
     let scan_code: u8 = unsafe { DATA_PORT.lock().read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scan_code) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                pc_keyboard::DecodedKey::Unicode(character) => print!("{}", character),
-                pc_keyboard::DecodedKey::RawKey(key) => print!("{:?}", key),
+    if let Ok(Some(key_event)) = keyboard.add_byte(scan_code)
+        && let Some(key) = keyboard.process_keyevent(key_event)
+    {
+        let console = console::active();
+        let mut encoded = [0; 4];
+        let bytes = match key {
+            // The layout reports Delete as DEL, which serial terminals send for Backspace.
+            DecodedKey::Unicode('\x7f') => Some(b"\x1b[3~".as_slice()),
+            DecodedKey::Unicode(character) => {
+                if console.echo.load(Ordering::Relaxed) {
+                    let _ = write!(console.writer.lock(), "{}", character);
+                }
+                Some(character.encode_utf8(&mut encoded).as_bytes())
             }
+            // Shift+PageUp and Shift+PageDown scroll the screen by half its height.
+            DecodedKey::RawKey(key @ (KeyCode::PageUp | KeyCode::PageDown))
+                if keyboard.get_modifiers().is_shifted() =>
+            {
+                let mut writer = console.writer.lock();
+                let rows = writer.height() / 2;
+                if key == KeyCode::PageUp {
+                    writer.scroll_view_up(rows);
+                } else {
+                    writer.scroll_view_down(rows);
+                }
+                None
+            }
+            DecodedKey::RawKey(key) => match CONSOLE_KEYS.iter().position(|&other| other == key) {
+                Some(index) if keyboard.get_modifiers().is_alt() => {
+                    console::switch_to(index);
+                    None
+                }
+                _ => escape_sequence(key),
+            },
+        };
+        let mut input = console.input.lock();
+        for &byte in bytes.unwrap_or_default() {
+            input.push(byte);
         }
     }
     unsafe {
@@ -66,3 +205,20 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: Interrupt
             .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
     }
 }
+
+/// The sequence a VT100 terminal sends for a key without a character, if it has one.
+fn escape_sequence(key: KeyCode) -> Option<&'static [u8]> {
+    let sequence: &[u8] = match key {
+        KeyCode::ArrowUp => b"\x1b[A",
+        KeyCode::ArrowDown => b"\x1b[B",
+        KeyCode::ArrowRight => b"\x1b[C",
+        KeyCode::ArrowLeft => b"\x1b[D",
+        KeyCode::Home => b"\x1b[H",
+        KeyCode::End => b"\x1b[F",
+        KeyCode::Insert => b"\x1b[2~",
+        KeyCode::PageUp => b"\x1b[5~",
+        KeyCode::PageDown => b"\x1b[6~",
+        _ => return None,
+    };
+    Some(sequence)
+}
@@ -0,0 +1,54 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Lazy, Mutex};
+use x86_64::instructions::port::Port;
+
+/// The frequency the programmable interval timer is driven at.
+const BASE_FREQUENCY_HZ: u64 = 1_193_182;
+/// The frequency the timer interrupt is programmed to fire at.
+pub const TIMER_FREQUENCY_HZ: u64 = 100;
+
+const CHANNEL_0_PORT_ADDRESS: u16 = 0x40;
+const COMMAND_PORT_ADDRESS: u16 = 0x43;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const RATE_GENERATOR_COMMAND: u8 = 0b0011_0100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static PORTS: Lazy<Mutex<(Port<u8>, Port<u8>)>> = Lazy::new(|| {
+    Mutex::new((
+        Port::new(COMMAND_PORT_ADDRESS),
+        Port::new(CHANNEL_0_PORT_ADDRESS),
+    ))
+});
+
+pub fn init() {
+    let divisor = (BASE_FREQUENCY_HZ / TIMER_FREQUENCY_HZ) as u16;
+    let mut ports = PORTS.lock();
+    unsafe {
+        ports.0.write(RATE_GENERATOR_COMMAND);
+        ports.1.write(divisor as u8);
+        ports.1.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt handler on every tick.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_milliseconds() -> u64 {
+    ticks_to_milliseconds(ticks())
+}
+
+pub fn ticks_to_milliseconds(ticks: u64) -> u64 {
+    ticks * 1000 / TIMER_FREQUENCY_HZ
+}
+
+pub fn milliseconds_to_ticks(milliseconds: u64) -> u64 {
+    milliseconds.div_ceil(1000 / TIMER_FREQUENCY_HZ)
+}
@@ -5,13 +5,33 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+use bootloader::BootInfo;
+
+pub mod acpi;
+pub mod allocator;
+pub mod block;
+pub mod console;
+pub mod elf;
+pub mod fs;
 pub mod gdt;
 pub mod interupt;
+pub mod loader;
+pub mod memory;
+pub mod pci;
+pub mod power;
+pub mod process;
 pub mod qemu_exit;
+pub mod ring_buffer;
+pub mod scheduler;
 pub mod serial;
+pub mod shell;
+pub mod syscall;
 pub mod vga_buffer;
+pub mod virtio;
 
-pub fn init() {
+pub fn init(boot_info: &'static BootInfo) {
     gdt::init();
     interupt::init();
     unsafe {
@@ -19,6 +39,16 @@ pub fn init() {
             .lock()
             .initialize()
     };
+    interupt::pit::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    serial::init();
+    console::init();
+    fs::init();
+    pci::init();
+    block::init();
+    scheduler::init();
+    syscall::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -62,9 +92,11 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 }
 
 #[cfg(test)]
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    init();
+bootloader::entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
 
     hlt_loop();
@@ -0,0 +1,263 @@
+//! Loads ELF64 executables into a fresh address space and starts them in ring 3.
+
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+};
+
+use crate::{
+    elf::{ElfError, ElfFile, SegmentType},
+    memory::{self, AddressSpace, Backing, MemoryError, Protection, VirtualMemoryArea},
+    scheduler, syscall,
+};
+
+/// One past the highest address of the user stack.
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_PAGES: u64 = 16;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AUXILIARY_VECTOR_LENGTH: u64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    Memory(MemoryError),
+    SegmentOutsideUserSpace,
+    EntryPointOutsideUserSpace,
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MemoryError> for LoadError {
+    fn from(error: MemoryError) -> Self {
+        LoadError::Memory(error)
+    }
+}
+
+/// A program that has been mapped into its own address space and is ready to run.
+#[derive(Debug)]
+pub struct LoadedProgram {
+    pub address_space: AddressSpace,
+    pub entry_point: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Maps the `PT_LOAD` segments of `elf_bytes` into a new address space and builds a System V
+/// initial stack holding `arguments`, `environment` and the auxiliary vector.
+pub fn load(
+    elf_bytes: &[u8],
+    arguments: &[&str],
+    environment: &[&str],
+) -> Result<LoadedProgram, LoadError> {
+    let elf_file = ElfFile::parse(elf_bytes)?;
+    let entry_point = VirtAddr::try_new(elf_file.entry_point)
+        .map_err(|_| LoadError::EntryPointOutsideUserSpace)?;
+    if !memory::is_user_range(entry_point, 1) {
+        return Err(LoadError::EntryPointOutsideUserSpace);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+    let mut program_header_address = None;
+    for program_header in elf_file.program_headers() {
+        match program_header.segment_type {
+            SegmentType::ProgramHeader => {
+                program_header_address = Some(program_header.virtual_address);
+            }
+            SegmentType::Load if program_header.memory_size > 0 => {
+                let start = VirtAddr::try_new(program_header.virtual_address)
+                    .map_err(|_| LoadError::SegmentOutsideUserSpace)?;
+                if !memory::is_user_range(start, program_header.memory_size) {
+                    return Err(LoadError::SegmentOutsideUserSpace);
+                }
+                let mut flags = PageTableFlags::empty();
+                if program_header.is_writable() {
+                    flags |= PageTableFlags::WRITABLE;
+                }
+                if !program_header.is_executable() {
+                    flags |= PageTableFlags::NO_EXECUTE;
+                }
+                map_segment(&mut address_space, start, program_header.memory_size, flags)?;
+                let protection = Protection {
+                    read: program_header.is_readable(),
+                    write: program_header.is_writable(),
+                    execute: program_header.is_executable(),
+                };
+                add_segment_area(
+                    &mut address_space,
+                    start,
+                    program_header.memory_size,
+                    protection,
+                )?;
+                address_space.write(start, elf_file.segment_data(&program_header))?;
+                address_space.zero(
+                    start + program_header.file_size,
+                    program_header.memory_size - program_header.file_size,
+                )?;
+                if program_header.offset == 0 && program_header_address.is_none() {
+                    program_header_address =
+                        Some(program_header.virtual_address + elf_file.program_header_offset());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let stack_bottom = Page::containing_address(VirtAddr::new(
+        USER_STACK_TOP - USER_STACK_PAGES * Page::<Size4KiB>::SIZE,
+    ));
+    address_space.map_user_pages(
+        stack_bottom,
+        USER_STACK_PAGES,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    address_space.map_area(VirtualMemoryArea::new(
+        stack_bottom.start_address(),
+        VirtAddr::new(USER_STACK_TOP),
+        Protection::READ_WRITE,
+        Backing::Anonymous,
+    ))?;
+    let auxiliary_vector = [
+        (AT_PHDR, program_header_address.unwrap_or(0)),
+        (AT_PHENT, elf_file.program_header_entry_size() as u64),
+        (AT_PHNUM, elf_file.program_header_count as u64),
+        (AT_PAGESZ, Page::<Size4KiB>::SIZE),
+        (AT_ENTRY, entry_point.as_u64()),
+        (AT_NULL, 0),
+    ];
+    let stack_pointer = build_stack(
+        &mut address_space,
+        stack_bottom.start_address(),
+        arguments,
+        environment,
+        &auxiliary_vector,
+    )?;
+
+    Ok(LoadedProgram {
+        address_space,
+        entry_point,
+        stack_pointer,
+    })
+}
+
+/// Switches to the program's address space and runs it until it exits, returning its exit code.
+pub fn run(program: &LoadedProgram) -> i64 {
+    let previous_frame = AddressSpace::active().level_4_frame();
+    unsafe { scheduler::switch_address_space(program.address_space.level_4_frame()) };
+    let exit_code = unsafe { syscall::enter_user_mode(program.entry_point, program.stack_pointer) };
+    unsafe { scheduler::switch_address_space(previous_frame) };
+    exit_code
+}
+
+/// Maps every page touched by a segment, merging permissions with pages already mapped by a
+/// neighbouring segment that shares a page boundary.
+fn map_segment(
+    address_space: &mut AddressSpace,
+    start: VirtAddr,
+    length: u64,
+    flags: PageTableFlags,
+) -> Result<(), MemoryError> {
+    let first_page: Page = Page::containing_address(start);
+    let last_page: Page = Page::containing_address(start + (length - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        match address_space.translate(page.start_address()) {
+            Some((_, existing_flags)) => {
+                let mut merged_flags = existing_flags | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged_flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+                address_space.update_page_flags(page, merged_flags)?;
+            }
+            None => address_space.map_user_pages(page, 1, flags)?,
+        }
+    }
+    Ok(())
+}
+
+/// Records the pages of a segment as an area, widening the protection of the first page instead
+/// if the previous segment already covers it.
+fn add_segment_area(
+    address_space: &mut AddressSpace,
+    start: VirtAddr,
+    length: u64,
+    protection: Protection,
+) -> Result<(), MemoryError> {
+    let mut first_page: Page = Page::containing_address(start);
+    let end_page: Page = Page::containing_address(start + (length - 1)) + 1;
+    let shared_page = first_page.start_address();
+    let existing = address_space.with_areas(|areas| areas.find(shared_page).map(|a| a.protection));
+    if let Some(existing) = existing {
+        address_space.with_areas(|areas| {
+            areas.protect(
+                shared_page,
+                shared_page + Page::<Size4KiB>::SIZE,
+                existing.union(protection),
+            )
+        })?;
+        first_page += 1;
+    }
+    if first_page < end_page {
+        address_space.map_area(VirtualMemoryArea::new(
+            first_page.start_address(),
+            end_page.start_address(),
+            protection,
+            Backing::Anonymous,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Lays out `argc`, `argv`, `envp` and `auxv` at the top of the stack, with the strings they
+/// point to above them, and returns the initial stack pointer.
+fn build_stack(
+    address_space: &mut AddressSpace,
+    stack_limit: VirtAddr,
+    arguments: &[&str],
+    environment: &[&str],
+    auxiliary_vector: &[(u64, u64)],
+) -> Result<VirtAddr, LoadError> {
+    let string_bytes: u64 = arguments
+        .iter()
+        .chain(environment.iter())
+        .map(|string| string.len() as u64 + 1)
+        .sum();
+    let word_count =
+        1 + arguments.len() as u64 + 1 + environment.len() as u64 + 1 + AUXILIARY_VECTOR_LENGTH * 2;
+    let stack_size = string_bytes + word_count * 8 + 16;
+    if stack_size > USER_STACK_TOP - stack_limit.as_u64() {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+    let mut string_address = VirtAddr::new(USER_STACK_TOP) - string_bytes;
+    let stack_pointer = (string_address - word_count * 8).align_down(16u64);
+
+    let mut word_address = stack_pointer;
+    let mut push_word = |address_space: &mut AddressSpace, word: u64| {
+        let result = address_space.write(word_address, &word.to_le_bytes());
+        word_address += 8u64;
+        result
+    };
+    push_word(address_space, arguments.len() as u64)?;
+    for strings in [arguments, environment] {
+        for string in strings {
+            push_word(address_space, string_address.as_u64())?;
+            address_space.write(string_address, string.as_bytes())?;
+            address_space.write(string_address + string.len() as u64, &[0])?;
+            string_address += string.len() as u64 + 1;
+        }
+        push_word(address_space, 0)?;
+    }
+    for &(key, value) in auxiliary_vector {
+        push_word(address_space, key)?;
+        push_word(address_space, value)?;
+    }
+    Ok(stack_pointer)
+}
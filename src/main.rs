@@ -7,15 +7,21 @@ use core::panic::PanicInfo;
 use rust_os::*;
 
 #[cfg(not(test))]
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    rust_os::init();
-    hlt_loop();
+bootloader::entry_point!(kernel_main);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    scheduler::spawn_kernel_thread(shell::run_on_console, console::SHELL_CONSOLE as u64);
+    scheduler::spawn_kernel_thread(shell::run_on_serial, 0);
+    console::switch_to(console::SHELL_CONSOLE);
+    scheduler::exit_current();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    print!("\n{}", info);
+    console::switch_to(console::LOG_CONSOLE);
+    print_colored!(vga_buffer::PANIC_COLOR_CODE, "\n{}", info);
     hlt_loop();
 }
@@ -0,0 +1,938 @@
+use alloc::collections::BTreeMap;
+use bootloader::BootInfo;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::{Mutex, Once};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, tlb},
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate, frame::PhysFrameRange,
+        mapper::TranslateResult, page_table::PageTableEntry,
+    },
+};
+
+pub mod vma;
+
+pub use vma::{Access, Backing, Protection, VirtualMemoryArea, VmaTree};
+
+/// The first address handed out to user space (level 4 entry 128).
+pub const USER_SPACE_START: u64 = 0x0000_4000_0000_0000;
+/// One past the last address handed out to user space (end of the lower half).
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+pub static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+pub static PAGE_TABLE: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+pub static FRAME_ALLOCATOR: Once<Mutex<BootInfoFrameAllocator>> = Once::new();
+static KERNEL_LEVEL_4_FRAME: Once<PhysFrame> = Once::new();
+/// Reference counts of the frames mapped by more than one address space. Frames missing from the
+/// map have a single owner.
+static FRAME_REFERENCES: Mutex<BTreeMap<PhysFrame, u64>> = Mutex::new(BTreeMap::new());
+/// The areas of every address space, indexed by level 4 frame so that every handle to an address
+/// space sees the same areas.
+static AREAS: Mutex<BTreeMap<PhysFrame, VmaTree>> = Mutex::new(BTreeMap::new());
+
+/// Device registers are mapped into the higher half above the kernel heap.
+const DEVICE_MEMORY_START: u64 = 0xffff_b000_0000_0000;
+static NEXT_DEVICE_ADDRESS: Mutex<u64> = Mutex::new(DEVICE_MEMORY_START);
+
+/// Marks a user page that was writable before being shared by [`AddressSpace::fork`].
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    OutOfFrames,
+    AlreadyMapped,
+    NotMapped,
+    NotUserAddress,
+    /// The file behind a mapping could not be read.
+    Io,
+}
+
+pub fn init(boot_info: &'static BootInfo) {
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    KERNEL_LEVEL_4_FRAME.call_once(|| Cr3::read().0);
+    PAGE_TABLE.call_once(|| {
+        let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
+        Mutex::new(unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) })
+    });
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(BootInfoFrameAllocator::new(&boot_info.memory_map)));
+    // Make the kernel fault on read-only pages too, so writes through user pointers cannot
+    // modify frames shared copy-on-write.
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+/// The level 4 table set up by the bootloader, used by kernel threads.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME
+        .get()
+        .expect("memory::init has not been called")
+}
+
+/// Allocates a physical frame from the global frame allocator.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock()
+        .allocate_frame()
+}
+
+/// Allocates `count` physically contiguous frames, as needed for device DMA buffers.
+pub fn allocate_contiguous_frames(count: u64) -> Option<PhysFrameRange> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock()
+        .allocate_contiguous(count)
+}
+
+/// Returns a frame to the global frame allocator.
+///
+/// # Safety
+/// The frame must no longer be mapped or otherwise in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    unsafe {
+        FRAME_ALLOCATOR
+            .get()
+            .expect("memory::init has not been called")
+            .lock()
+            .deallocate_frame(frame)
+    }
+}
+
+/// Returns the number of frames currently handed out and the number of usable frames.
+pub fn frame_usage() -> (u64, u64) {
+    let frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock();
+    (
+        frame_allocator.allocated_frames(),
+        frame_allocator.total_frames(),
+    )
+}
+
+/// Records another mapping of `frame`, so [`release_frame`] only frees it after the last one.
+pub fn share_frame(frame: PhysFrame) {
+    *FRAME_REFERENCES.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops one mapping of `frame`, freeing it when no mapping is left.
+///
+/// # Safety
+/// The caller must have removed one mapping of the frame.
+pub unsafe fn release_frame(frame: PhysFrame) {
+    let mut references = FRAME_REFERENCES.lock();
+    match references.get_mut(&frame) {
+        Some(count) if *count > 2 => *count -= 1,
+        Some(_) => {
+            references.remove(&frame);
+        }
+        None => {
+            drop(references);
+            unsafe { deallocate_frame(frame) };
+        }
+    }
+}
+
+/// The number of address spaces mapping `frame`.
+pub fn frame_reference_count(frame: PhysFrame) -> u64 {
+    FRAME_REFERENCES.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// Returns the virtual address at which the given physical address is mapped.
+pub fn physical_to_virtual(physical_address: PhysAddr) -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory::init has not been called")
+        + physical_address.as_u64()
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset` and this must only be
+/// called once to avoid aliasing `&mut` references.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+    let virtual_address = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    unsafe { &mut *virtual_address.as_mut_ptr() }
+}
+
+pub fn is_user_range(start: VirtAddr, length: u64) -> bool {
+    let start = start.as_u64();
+    match start.checked_add(length) {
+        Some(end) => start >= USER_SPACE_START && end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+/// Checks that every page in the given range is mapped and accessible from ring 3 in the active
+/// address space.
+pub fn is_user_accessible(start: VirtAddr, length: u64, writable: bool) -> bool {
+    AddressSpace::active().is_user_accessible(start, length, writable)
+}
+
+/// Resolves a fault on a user address in the active address space; see
+/// [`AddressSpace::handle_fault`].
+pub fn handle_user_fault(address: VirtAddr, access: Access) -> Result<bool, MemoryError> {
+    AddressSpace::active().handle_fault(address, access)
+}
+
+/// Maps `page_count` zeroed pages starting at `start` into the active address space.
+pub fn map_user_pages(
+    start: Page,
+    page_count: u64,
+    flags: PageTableFlags,
+) -> Result<(), MemoryError> {
+    AddressSpace::active().map_user_pages(start, page_count, flags)
+}
+
+/// Maps `length` bytes of device registers at `physical_address` uncached into the kernel's half
+/// and returns the address they can be accessed at.
+///
+/// Like the heap, device memory must be mapped before any address space is created for every
+/// address space to share the mapping.
+pub fn map_device_memory(physical_address: PhysAddr, length: u64) -> Result<VirtAddr, MemoryError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(physical_address);
+    let last_frame = PhysFrame::containing_address(physical_address + length.max(1) - 1u64);
+    let frame_count = last_frame - first_frame + 1;
+    let start = {
+        let mut next = NEXT_DEVICE_ADDRESS.lock();
+        let start = *next;
+        *next += frame_count * Size4KiB::SIZE;
+        VirtAddr::new(start)
+    };
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    let mut page_table = PAGE_TABLE
+        .get()
+        .expect("memory::init has not been called")
+        .lock();
+    let mut frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock();
+    let first_page = Page::containing_address(start);
+    for (page, frame) in Page::range(first_page, first_page + frame_count)
+        .zip(PhysFrame::range_inclusive(first_frame, last_frame))
+    {
+        unsafe {
+            page_table
+                .map_to(page, frame, flags, &mut *frame_allocator)
+                .map_err(|_| MemoryError::AlreadyMapped)?
+                .flush();
+        }
+    }
+    Ok(start + physical_address.as_u64() % Size4KiB::SIZE)
+}
+
+/// A set of user mappings in the lower half, sharing every kernel mapping with the boot page
+/// table.
+///
+/// Address spaces created with [`AddressSpace::new`] own their user mappings and free every
+/// frame behind them when dropped; handles obtained any other way never free anything.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    owned: bool,
+}
+
+impl AddressSpace {
+    /// Creates an address space with no user mappings.
+    pub fn new() -> Result<Self, MemoryError> {
+        let level_4_frame = allocate_frame().ok_or(MemoryError::OutOfFrames)?;
+        let address_space = Self {
+            level_4_frame,
+            owned: true,
+        };
+        let kernel_table = Self::active();
+        let table = unsafe { address_space.level_4_table() };
+        table.zero();
+        for (index, entry) in unsafe { kernel_table.level_4_table() }.iter().enumerate() {
+            if !Self::is_user_level_4_index(index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(address_space)
+    }
+
+    /// Returns a handle to the address space that is currently loaded in CR3.
+    pub fn active() -> Self {
+        let (level_4_frame, _) = Cr3::read();
+        Self {
+            level_4_frame,
+            owned: false,
+        }
+    }
+
+    /// Returns a handle to the address space rooted at the given level 4 table.
+    ///
+    /// # Safety
+    /// The frame must hold a level 4 table that maps the kernel like the boot page table.
+    pub unsafe fn from_level_4_frame(level_4_frame: PhysFrame) -> Self {
+        Self {
+            level_4_frame,
+            owned: false,
+        }
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Loads this address space into CR3, returning the frame of the previously active table.
+    ///
+    /// # Safety
+    /// The caller must ensure the currently executing code and stack stay mapped.
+    pub unsafe fn activate(&self) -> PhysFrame {
+        let (previous_frame, flags) = Cr3::read();
+        if previous_frame != self.level_4_frame {
+            unsafe { Cr3::write(self.level_4_frame, flags) };
+        }
+        previous_frame
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Creates an address space sharing every user page with this one.
+    ///
+    /// Writable pages become read-only and [`COPY_ON_WRITE`] in both address spaces, and are
+    /// copied by [`AddressSpace::resolve_copy_on_write`] on the first write.
+    pub fn fork(&mut self) -> Result<Self, MemoryError> {
+        let mut child = Self::new()?;
+        let mut result = Ok(());
+        self.for_each_user_page(|page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let frame = entry.frame().expect("user pages are 4 KiB");
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+            result = child.map_frame(page, frame, flags);
+            if result.is_ok() {
+                share_frame(frame);
+            }
+        });
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        let areas = self.with_areas(|areas| areas.clone());
+        child.with_areas(|child_areas| *child_areas = areas);
+        result.map(|()| child)
+    }
+
+    /// Runs `function` on the areas of this address space.
+    pub fn with_areas<T>(&self, function: impl FnOnce(&mut VmaTree) -> T) -> T {
+        interrupts::without_interrupts(|| {
+            function(AREAS.lock().entry(self.level_4_frame).or_default())
+        })
+    }
+
+    /// Adds an area whose pages are populated on first access, failing if it overlaps another.
+    pub fn map_area(&mut self, area: VirtualMemoryArea) -> Result<(), MemoryError> {
+        if !is_user_range(area.start, area.end - area.start) {
+            return Err(MemoryError::NotUserAddress);
+        }
+        self.with_areas(|areas| areas.insert(area))
+    }
+
+    /// Finds the lowest free range of `length` bytes at or above `minimum`.
+    pub fn find_free_range(&self, length: u64, minimum: VirtAddr) -> Option<VirtAddr> {
+        self.with_areas(|areas| areas.find_free(length, minimum, VirtAddr::new(USER_SPACE_END)))
+    }
+
+    /// Removes the areas covering a range, splitting those that extend beyond it, and frees the
+    /// pages mapped in it.
+    pub fn unmap_range(&mut self, start: Page, page_count: u64) -> Result<(), MemoryError> {
+        if !is_user_range(start.start_address(), page_count * Page::<Size4KiB>::SIZE) {
+            return Err(MemoryError::NotUserAddress);
+        }
+        let end = start + page_count;
+        self.with_areas(|areas| areas.remove(start.start_address(), end.start_address()));
+        for page in Page::range(start, end) {
+            self.unmap_page(page);
+        }
+        Ok(())
+    }
+
+    /// Unmaps `page` if it is mapped, releasing its frame.
+    fn unmap_page(&mut self, page: Page) {
+        let is_active = self.is_active();
+        if let Some(entry) = self.level_1_entry(page)
+            && let Ok(frame) = entry.frame()
+        {
+            entry.set_unused();
+            unsafe { release_frame(frame) };
+            if is_active {
+                tlb::flush(page.start_address());
+            }
+        }
+    }
+
+    /// Changes the protection of a range that is entirely covered by areas, including the pages
+    /// already mapped in it.
+    pub fn protect_range(
+        &mut self,
+        start: Page,
+        page_count: u64,
+        protection: Protection,
+    ) -> Result<(), MemoryError> {
+        if !is_user_range(start.start_address(), page_count * Page::<Size4KiB>::SIZE) {
+            return Err(MemoryError::NotUserAddress);
+        }
+        let end = start + page_count;
+        self.with_areas(|areas| {
+            areas.protect(start.start_address(), end.start_address(), protection)
+        })?;
+        let is_active = self.is_active();
+        for page in Page::range(start, end) {
+            if let Some(entry) = self.level_1_entry(page)
+                && let Ok(frame) = entry.frame()
+            {
+                let mut flags = protection.page_table_flags();
+                // Frames still shared after a fork must stay read-only until they are copied.
+                if flags.contains(PageTableFlags::WRITABLE) && frame_reference_count(frame) > 1 {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                }
+                entry.set_flags(flags);
+                if is_active {
+                    tlb::flush(page.start_address());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a page fault at a user address: copies a copy-on-write page on a write and
+    /// populates a page of an area on its first access. Returns `false` if the access is not
+    /// allowed, in which case the fault is a genuine error.
+    pub fn handle_fault(&mut self, address: VirtAddr, access: Access) -> Result<bool, MemoryError> {
+        let page = Page::containing_address(address);
+        if access == Access::Write && self.resolve_copy_on_write(page)? {
+            return Ok(true);
+        }
+        let Some(area) = self.with_areas(|areas| areas.find(address).cloned()) else {
+            return Ok(false);
+        };
+        if !area.protection.allows(access) || self.translate(page.start_address()).is_some() {
+            return Ok(false);
+        }
+        self.map_user_pages(page, 1, area.protection.page_table_flags())?;
+        if let Backing::File { inode, offset } = &area.backing {
+            let (physical_address, _) = self.translate(page.start_address()).unwrap();
+            let contents = unsafe {
+                core::slice::from_raw_parts_mut(
+                    physical_to_virtual(physical_address).as_mut_ptr::<u8>(),
+                    Page::<Size4KiB>::SIZE as usize,
+                )
+            };
+            // The rest of the page stays zeroed if the file ends inside it.
+            let offset = offset + (page.start_address() - area.start);
+            let mut filled = 0;
+            while filled < contents.len() {
+                match inode.read_at(offset + filled as u64, &mut contents[filled..]) {
+                    Ok(0) => break,
+                    Ok(count) => filled += count,
+                    Err(_) => {
+                        self.unmap_page(page);
+                        return Err(MemoryError::Io);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Makes a [`COPY_ON_WRITE`] page writable again, copying its frame first if another address
+    /// space still maps it. Returns `false` if the page is not copy-on-write.
+    pub fn resolve_copy_on_write(&mut self, page: Page) -> Result<bool, MemoryError> {
+        let is_active = self.is_active();
+        let Some(entry) = self.level_1_entry(page) else {
+            return Ok(false);
+        };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | COPY_ON_WRITE) {
+            return Ok(false);
+        }
+        let frame = entry.frame().expect("user pages are 4 KiB");
+        let writable_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if frame_reference_count(frame) == 1 {
+            entry.set_flags(writable_flags);
+        } else {
+            let copy = allocate_frame().ok_or(MemoryError::OutOfFrames)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    physical_to_virtual(frame.start_address()).as_ptr::<u8>(),
+                    physical_to_virtual(copy.start_address()).as_mut_ptr::<u8>(),
+                    Page::<Size4KiB>::SIZE as usize,
+                );
+            }
+            entry.set_addr(copy.start_address(), writable_flags);
+            unsafe { release_frame(frame) };
+        }
+        if is_active {
+            tlb::flush(page.start_address());
+        }
+        Ok(true)
+    }
+
+    /// Returns the level 1 entry for `page`, if the tables leading to it exist.
+    fn level_1_entry(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut table = unsafe { self.level_4_table() };
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let frame = table[index].frame().ok()?;
+            table = unsafe { page_table_at(frame) };
+        }
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// Calls `function` with every mapped user page and the level 1 entry mapping it.
+    fn for_each_user_page(&mut self, mut function: impl FnMut(Page, &mut PageTableEntry)) {
+        const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+        let level_4_table = unsafe { self.level_4_table() };
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if Self::is_user_level_4_index(index)
+                && let Ok(frame) = entry.frame()
+            {
+                let start = index as u64 * LEVEL_4_ENTRY_SIZE;
+                unsafe { for_each_page_in_table(frame, 3, start, &mut function) };
+            }
+        }
+    }
+
+    /// Maps `page` to an existing `frame`.
+    fn map_frame(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut frame_allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("memory::init has not been called")
+            .lock();
+        unsafe {
+            self.page_table()
+                .map_to_with_table_flags(page, frame, flags, parent_flags, &mut *frame_allocator)
+                .map_err(|_| MemoryError::AlreadyMapped)?
+                .ignore();
+        }
+        Ok(())
+    }
+
+    /// Unmaps every user page, freeing the frames behind them and the page tables themselves.
+    fn free_user_mappings(&mut self) {
+        let level_4_table = unsafe { self.level_4_table() };
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if Self::is_user_level_4_index(index) && !entry.is_unused() {
+                unsafe { free_page_table(entry.frame().expect("huge level 4 entry"), 3) };
+                entry.set_unused();
+            }
+        }
+    }
+
+    fn is_user_level_4_index(index: usize) -> bool {
+        const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+        let start = index as u64 * LEVEL_4_ENTRY_SIZE;
+        (USER_SPACE_START..USER_SPACE_END).contains(&start)
+    }
+
+    /// # Safety
+    /// The returned reference aliases the table, so it must not be held across other accesses.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn level_4_table(&self) -> &mut PageTable {
+        let virtual_address = physical_to_virtual(self.level_4_frame.start_address());
+        unsafe { &mut *virtual_address.as_mut_ptr() }
+    }
+
+    fn page_table(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET
+            .get()
+            .expect("memory::init has not been called");
+        unsafe { OffsetPageTable::new(self.level_4_table(), physical_memory_offset) }
+    }
+
+    /// Returns the frame and flags the given page is mapped to, if any.
+    pub fn translate(&mut self, address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.page_table().translate(address) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    /// Checks that every page in the given range is mapped and accessible from ring 3.
+    pub fn is_user_accessible(&mut self, start: VirtAddr, length: u64, writable: bool) -> bool {
+        if !is_user_range(start, length) {
+            return false;
+        }
+        if length == 0 {
+            return true;
+        }
+        let mut required_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            required_flags |= PageTableFlags::WRITABLE;
+        }
+        let first_page: Page = Page::containing_address(start);
+        let last_page: Page = Page::containing_address(start + (length - 1));
+        Page::range_inclusive(first_page, last_page).all(|page| {
+            self.translate(page.start_address())
+                .is_some_and(|(_, flags)| flags.contains(required_flags))
+        })
+    }
+
+    /// Maps `page_count` zeroed pages starting at `start`. On failure, the pages this call mapped
+    /// are unmapped and their frames freed.
+    pub fn map_user_pages(
+        &mut self,
+        start: Page,
+        page_count: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        if !is_user_range(start.start_address(), page_count * Page::<Size4KiB>::SIZE) {
+            return Err(MemoryError::NotUserAddress);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut mapped = 0;
+        let result = {
+            let mut page_table = self.page_table();
+            let mut frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("memory::init has not been called")
+                .lock();
+            Page::range(start, start + page_count).try_for_each(|page| {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MemoryError::OutOfFrames)?;
+                unsafe {
+                    core::ptr::write_bytes(
+                        physical_to_virtual(frame.start_address()).as_mut_ptr::<u8>(),
+                        0,
+                        Page::<Size4KiB>::SIZE as usize,
+                    );
+                    match page_table.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        parent_flags,
+                        &mut *frame_allocator,
+                    ) {
+                        Ok(flush) => flush.flush(),
+                        Err(_) => {
+                            frame_allocator.deallocate_frame(frame);
+                            return Err(MemoryError::AlreadyMapped);
+                        }
+                    }
+                }
+                mapped += 1;
+                Ok(())
+            })
+        };
+        // A range that cannot be mapped entirely is not mapped at all.
+        if result.is_err() {
+            for page in Page::range(start, start + mapped) {
+                self.unmap_page(page);
+            }
+        }
+        result
+    }
+
+    /// Replaces the flags of an already mapped page.
+    pub fn update_page_flags(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe {
+            self.page_table()
+                .update_flags(page, flags)
+                .map_err(|_| MemoryError::NotMapped)?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `address` through the physical memory mapping, so the address space does
+    /// not need to be active. The destination must already be mapped.
+    pub fn write(&mut self, address: VirtAddr, data: &[u8]) -> Result<(), MemoryError> {
+        let mut written = 0;
+        while written < data.len() {
+            let current = address + written as u64;
+            let (physical_address, _) = self.translate(current).ok_or(MemoryError::NotMapped)?;
+            let page_remaining =
+                (Page::<Size4KiB>::SIZE - u64::from(current.page_offset())) as usize;
+            let chunk = page_remaining.min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    physical_to_virtual(physical_address).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    /// Zeroes `length` bytes at `address`; see [`AddressSpace::write`].
+    pub fn zero(&mut self, address: VirtAddr, length: u64) -> Result<(), MemoryError> {
+        let mut zeroed = 0;
+        while zeroed < length {
+            let current = address + zeroed;
+            let (physical_address, _) = self.translate(current).ok_or(MemoryError::NotMapped)?;
+            let chunk =
+                (Page::<Size4KiB>::SIZE - u64::from(current.page_offset())).min(length - zeroed);
+            unsafe {
+                core::ptr::write_bytes(
+                    physical_to_virtual(physical_address).as_mut_ptr::<u8>(),
+                    0,
+                    chunk as usize,
+                );
+            }
+            zeroed += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        assert_ne!(
+            Cr3::read().0,
+            self.level_4_frame,
+            "dropping the active address space"
+        );
+        self.free_user_mappings();
+        interrupts::without_interrupts(|| AREAS.lock().remove(&self.level_4_frame));
+        unsafe { deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// # Safety
+/// The frame must hold a page table, and the returned reference must not outlive it or alias
+/// another reference to it.
+unsafe fn page_table_at(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *physical_to_virtual(frame.start_address()).as_mut_ptr() }
+}
+
+/// Calls `function` with every page mapped by a page table of the given level (3 to 1), whose
+/// first entry maps `start`.
+///
+/// # Safety
+/// The frame must hold a page table of that level.
+unsafe fn for_each_page_in_table(
+    table_frame: PhysFrame,
+    level: u8,
+    start: u64,
+    function: &mut impl FnMut(Page, &mut PageTableEntry),
+) {
+    let entry_size = Page::<Size4KiB>::SIZE << (9 * (level - 1));
+    let table = unsafe { page_table_at(table_frame) };
+    for (index, entry) in table.iter_mut().enumerate() {
+        let address = start + index as u64 * entry_size;
+        if let Ok(frame) = entry.frame() {
+            if level > 1 {
+                unsafe { for_each_page_in_table(frame, level - 1, address, function) };
+            } else {
+                function(Page::containing_address(VirtAddr::new(address)), entry);
+            }
+        }
+    }
+}
+
+/// Frees a page table of the given level (3 to 1) together with everything it maps.
+///
+/// # Safety
+/// The table must only be reachable from the entry that is being cleared by the caller.
+unsafe fn free_page_table(table_frame: PhysFrame, level: u8) {
+    let table = unsafe { page_table_at(table_frame) };
+    for entry in table.iter_mut() {
+        if let Ok(frame) = entry.frame() {
+            if level > 1 {
+                unsafe { free_page_table(frame, level - 1) };
+            } else {
+                unsafe { release_frame(frame) };
+            }
+        }
+        entry.set_unused();
+    }
+    unsafe { deallocate_frame(table_frame) };
+}
+
+/// A frame allocator that hands out the usable frames from the bootloader's memory map.
+///
+/// Freed frames are kept on an intrusive list: the first word of every free frame holds the
+/// physical address of the next one.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    region_index: usize,
+    next_frame_number: u64,
+    free_list_head: Option<PhysFrame>,
+    allocated_frames: u64,
+}
+
+impl BootInfoFrameAllocator {
+    pub fn new(memory_map: &'static MemoryMap) -> Self {
+        Self {
+            memory_map,
+            region_index: 0,
+            next_frame_number: 0,
+            free_list_head: None,
+            allocated_frames: 0,
+        }
+    }
+
+    /// The number of frames currently handed out.
+    pub fn allocated_frames(&self) -> u64 {
+        self.allocated_frames
+    }
+
+    /// The number of usable frames in the memory map.
+    pub fn total_frames(&self) -> u64 {
+        self.memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| region.range.end_frame_number - region.range.start_frame_number)
+            .sum()
+    }
+
+    /// Takes `count` consecutive frames from the memory map. Freed frames are never contiguous
+    /// as far as the allocator knows, so the free list is not searched; frames skipped at the end
+    /// of a region that is too small are put on it instead.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrameRange> {
+        while let Some(region) = self.memory_map.get(self.region_index) {
+            if region.region_type == MemoryRegionType::Usable {
+                let first = self.next_frame_number.max(region.range.start_frame_number);
+                if first + count <= region.range.end_frame_number {
+                    self.next_frame_number = first + count;
+                    self.allocated_frames += count;
+                    let frame = |number: u64| {
+                        PhysFrame::containing_address(PhysAddr::new(number * Size4KiB::SIZE))
+                    };
+                    return Some(PhysFrame::range(frame(first), frame(first + count)));
+                }
+                for number in first..region.range.end_frame_number {
+                    self.allocated_frames += 1;
+                    let frame =
+                        PhysFrame::containing_address(PhysAddr::new(number * Size4KiB::SIZE));
+                    unsafe { self.deallocate_frame(frame) };
+                }
+                self.next_frame_number = region.range.end_frame_number;
+            }
+            self.region_index += 1;
+        }
+        None
+    }
+
+    fn allocate_from_free_list(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_list_head?;
+        let next = unsafe {
+            physical_to_virtual(frame.start_address())
+                .as_ptr::<u64>()
+                .read()
+        };
+        self.free_list_head = match next {
+            0 => None,
+            address => Some(PhysFrame::containing_address(PhysAddr::new(address))),
+        };
+        Some(frame)
+    }
+
+    fn allocate_from_memory_map(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region_index) {
+            if region.region_type == MemoryRegionType::Usable {
+                let frame_number = self.next_frame_number.max(region.range.start_frame_number);
+                if frame_number < region.range.end_frame_number {
+                    self.next_frame_number = frame_number + 1;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(
+                        frame_number * Page::<Size4KiB>::SIZE,
+                    )));
+                }
+            }
+            self.region_index += 1;
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self
+            .allocate_from_free_list()
+            .or_else(|| self.allocate_from_memory_map())?;
+        self.allocated_frames += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list_head
+            .map_or(0, |head| head.start_address().as_u64());
+        unsafe {
+            physical_to_virtual(frame.start_address())
+                .as_mut_ptr::<u64>()
+                .write(next)
+        };
+        self.free_list_head = Some(frame);
+        self.allocated_frames -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressSpace, MemoryError, USER_SPACE_START, frame_usage};
+    use x86_64::{
+        VirtAddr,
+        structures::paging::{Page, PageTableFlags},
+    };
+
+    #[test_case]
+    fn test_failed_mapping_is_rolled_back() {
+        let mut address_space = AddressSpace::new().unwrap();
+        let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+        address_space
+            .map_user_pages(start + 2, 1, PageTableFlags::WRITABLE)
+            .unwrap();
+        let (frames_before, _) = frame_usage();
+
+        assert_eq!(
+            address_space.map_user_pages(start, 3, PageTableFlags::WRITABLE),
+            Err(MemoryError::AlreadyMapped)
+        );
+        assert_eq!(address_space.translate(start.start_address()), None);
+        assert_eq!(address_space.translate((start + 1).start_address()), None);
+        assert!(
+            address_space
+                .translate((start + 2).start_address())
+                .is_some()
+        );
+        assert_eq!(frame_usage().0, frames_before);
+    }
+}
@@ -0,0 +1,352 @@
+//! Virtual memory areas: the regions of a user address space that may be accessed, and how the
+//! pages in them are populated when they are first touched.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+use super::MemoryError;
+use crate::fs::Inode;
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The access rights of an area, as given to `mmap` and `mprotect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Protection {
+    pub const NONE: Self = Self {
+        read: false,
+        write: false,
+        execute: false,
+    };
+    pub const READ_WRITE: Self = Self {
+        read: true,
+        write: true,
+        execute: false,
+    };
+
+    pub fn allows(self, access: Access) -> bool {
+        // x86 cannot map a page writable or executable without making it readable.
+        match access {
+            Access::Read => self.read || self.write || self.execute,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            execute: self.execute || other.execute,
+        }
+    }
+
+    /// The flags for a page of this area. Pages of an area without any access rights stay mapped
+    /// but are not accessible from ring 3.
+    pub fn page_table_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self != Self::NONE {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Where the contents of an area's pages come from.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero-filled pages.
+    Anonymous,
+    /// A private copy of the file contents starting at `offset`, zero-filled past its end.
+    File { inode: Arc<dyn Inode>, offset: u64 },
+}
+
+impl Backing {
+    /// The backing of the part of an area starting `distance` bytes into it.
+    fn advanced_by(&self, distance: u64) -> Self {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::File { inode, offset } => Self::File {
+                inode: inode.clone(),
+                offset: offset + distance,
+            },
+        }
+    }
+}
+
+impl PartialEq for Backing {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Anonymous, Self::Anonymous) => true,
+            (
+                Self::File { inode, offset },
+                Self::File {
+                    inode: other_inode,
+                    offset: other_offset,
+                },
+            ) => Arc::ptr_eq(inode, other_inode) && offset == other_offset,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Backing {}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Anonymous => formatter.write_str("Anonymous"),
+            Self::File { inode, offset } => formatter
+                .debug_struct("File")
+                .field("inode", &inode.metadata().inode)
+                .field("offset", offset)
+                .finish(),
+        }
+    }
+}
+
+/// A page-aligned range `[start, end)` of user addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualMemoryArea {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl VirtualMemoryArea {
+    pub fn new(start: VirtAddr, end: VirtAddr, protection: Protection, backing: Backing) -> Self {
+        debug_assert!(start < end, "empty virtual memory area");
+        Self {
+            start,
+            end,
+            protection,
+            backing,
+        }
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    /// Shrinks this area to end at `address` and returns the part above it.
+    fn split_off(&mut self, address: VirtAddr) -> Self {
+        let upper = Self {
+            start: address,
+            end: self.end,
+            protection: self.protection,
+            backing: self.backing.advanced_by(address - self.start),
+        };
+        self.end = address;
+        upper
+    }
+}
+
+/// The areas of one address space, ordered by start address and never overlapping.
+#[derive(Debug, Clone, Default)]
+pub struct VmaTree {
+    areas: BTreeMap<VirtAddr, VirtualMemoryArea>,
+}
+
+impl VmaTree {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the area containing `address`, if any.
+    pub fn find(&self, address: VirtAddr) -> Option<&VirtualMemoryArea> {
+        self.areas
+            .range(..=address)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(address))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualMemoryArea> {
+        self.areas.values()
+    }
+
+    /// Checks that no area overlaps `[start, end)`.
+    pub fn is_free(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.overlapping(start, end).next().is_none()
+    }
+
+    /// Adds an area, failing if it overlaps an existing one.
+    pub fn insert(&mut self, area: VirtualMemoryArea) -> Result<(), MemoryError> {
+        if !self.is_free(area.start, area.end) {
+            return Err(MemoryError::AlreadyMapped);
+        }
+        self.areas.insert(area.start, area);
+        Ok(())
+    }
+
+    /// Finds the lowest gap of `length` bytes starting at or above `minimum` and below `limit`.
+    pub fn find_free(&self, length: u64, minimum: VirtAddr, limit: VirtAddr) -> Option<VirtAddr> {
+        let mut candidate = minimum.as_u64();
+        for area in self.areas.values() {
+            if area.end.as_u64() <= candidate {
+                continue;
+            }
+            if area.start.as_u64() >= candidate.checked_add(length)? {
+                break;
+            }
+            candidate = area.end.as_u64();
+        }
+        let end = candidate.checked_add(length)?;
+        (end <= limit.as_u64()).then(|| VirtAddr::new(candidate))
+    }
+
+    /// Removes `[start, end)` from the tree, splitting areas that straddle its bounds, and
+    /// returns the removed parts.
+    pub fn remove(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<VirtualMemoryArea> {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<VirtAddr> = self
+            .overlapping(start, end)
+            .map(|area| area.start)
+            .collect();
+        starts
+            .into_iter()
+            .filter_map(|start| self.areas.remove(&start))
+            .collect()
+    }
+
+    /// Changes the protection of `[start, end)`, splitting areas as needed. Fails without
+    /// changing anything unless the whole range is covered by areas.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        protection: Protection,
+    ) -> Result<(), MemoryError> {
+        let mut covered_until = start;
+        for area in self.overlapping(start, end) {
+            if area.start > covered_until {
+                return Err(MemoryError::NotMapped);
+            }
+            covered_until = area.end;
+        }
+        if covered_until < end {
+            return Err(MemoryError::NotMapped);
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for (_, area) in self.areas.range_mut(start..end) {
+            area.protection = protection;
+        }
+        Ok(())
+    }
+
+    fn overlapping(
+        &self,
+        start: VirtAddr,
+        end: VirtAddr,
+    ) -> impl Iterator<Item = &VirtualMemoryArea> {
+        // Only the last area starting below `start` can reach into the range.
+        let straddling = self
+            .areas
+            .range(..start)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.end > start);
+        straddling.into_iter().chain(
+            self.areas
+                .range(start..)
+                .map(|(_, area)| area)
+                .take_while(move |area| area.start < end),
+        )
+    }
+
+    /// Makes `address` the boundary between two areas if it falls inside one.
+    fn split_at(&mut self, address: VirtAddr) {
+        let Some((_, area)) = self.areas.range_mut(..address).next_back() else {
+            return;
+        };
+        if area.end > address {
+            let upper = area.split_off(address);
+            self.areas.insert(address, upper);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(start: u64, end: u64) -> VirtualMemoryArea {
+        VirtualMemoryArea::new(
+            VirtAddr::new(start),
+            VirtAddr::new(end),
+            Protection::READ_WRITE,
+            Backing::Anonymous,
+        )
+    }
+
+    #[test_case]
+    fn test_remove_splits_areas() {
+        let mut tree = VmaTree::new();
+        tree.insert(area(0x1000, 0x5000)).unwrap();
+        assert_eq!(
+            tree.insert(area(0x4000, 0x6000)),
+            Err(MemoryError::AlreadyMapped)
+        );
+
+        let removed = tree.remove(VirtAddr::new(0x2000), VirtAddr::new(0x3000));
+        assert_eq!(removed, [area(0x2000, 0x3000)]);
+        assert_eq!(
+            tree.find(VirtAddr::new(0x1fff)),
+            Some(&area(0x1000, 0x2000))
+        );
+        assert_eq!(tree.find(VirtAddr::new(0x2000)), None);
+        assert_eq!(
+            tree.find(VirtAddr::new(0x3000)),
+            Some(&area(0x3000, 0x5000))
+        );
+        assert_eq!(
+            tree.find_free(0x1000, VirtAddr::new(0x1000), VirtAddr::new(0x10000)),
+            Some(VirtAddr::new(0x2000))
+        );
+    }
+
+    #[test_case]
+    fn test_protect_requires_covered_range() {
+        let mut tree = VmaTree::new();
+        tree.insert(area(0x1000, 0x3000)).unwrap();
+        tree.insert(area(0x4000, 0x5000)).unwrap();
+        let start = VirtAddr::new(0x2000);
+        assert_eq!(
+            tree.protect(start, VirtAddr::new(0x5000), Protection::NONE),
+            Err(MemoryError::NotMapped)
+        );
+        assert_eq!(
+            tree.protect(start, VirtAddr::new(0x3000), Protection::NONE),
+            Ok(())
+        );
+        assert_eq!(tree.iter().count(), 3);
+        assert_eq!(tree.find(start).unwrap().protection, Protection::NONE);
+        assert_eq!(
+            tree.find(VirtAddr::new(0x1000)).unwrap().protection,
+            Protection::READ_WRITE
+        );
+    }
+}
@@ -0,0 +1,491 @@
+//! PCI bus enumeration, configuration space access and a registry binding drivers to devices.
+//!
+//! Configuration space is reached through the memory-mapped ECAM window when the firmware
+//! describes one in its ACPI MCFG table, and through the legacy ports of configuration mechanism
+//! #1 otherwise.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr, instructions::port::Port};
+
+use crate::{acpi, memory};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+/// Configuration mechanism #1 only reaches the legacy 256 bytes of each function.
+const LEGACY_CONFIG_SIZE: u16 = 256;
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+const OFFSET_VENDOR_ID: u16 = 0x00;
+const OFFSET_DEVICE_ID: u16 = 0x02;
+const OFFSET_COMMAND: u16 = 0x04;
+const OFFSET_STATUS: u16 = 0x06;
+const OFFSET_REVISION: u16 = 0x08;
+const OFFSET_PROG_IF: u16 = 0x09;
+const OFFSET_SUBCLASS: u16 = 0x0A;
+const OFFSET_CLASS: u16 = 0x0B;
+const OFFSET_HEADER_TYPE: u16 = 0x0E;
+const OFFSET_BAR_0: u16 = 0x10;
+const OFFSET_SECONDARY_BUS: u16 = 0x19;
+const OFFSET_CAPABILITIES: u16 = 0x34;
+const OFFSET_INTERRUPT_LINE: u16 = 0x3C;
+const OFFSET_INTERRUPT_PIN: u16 = 0x3D;
+
+const NO_DEVICE: u16 = 0xFFFF;
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+const BAR_MEMORY_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+static CONFIG_PORTS: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS_PORT), Port::new(CONFIG_DATA_PORT)));
+static ECAM: Once<Option<Ecam>> = Once::new();
+static DEVICES: Mutex<Vec<Arc<PciDevice>>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// The memory-mapped configuration space of PCI segment 0.
+#[derive(Debug)]
+struct Ecam {
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+    /// The buses mapped so far. Enumeration maps every bus it visits, so devices found at boot
+    /// never need a mapping made after address spaces have been created.
+    buses: Mutex<BTreeMap<u8, VirtAddr>>,
+}
+
+impl Ecam {
+    /// Reads the first segment 0 entry of the MCFG table.
+    fn from_mcfg() -> Option<Self> {
+        let table = acpi::find_table(b"MCFG")?;
+        // A 36-byte header and 8 reserved bytes precede the 16-byte entries.
+        table.get(44..)?.chunks_exact(16).find_map(|entry| {
+            let segment = u16::from_le_bytes([entry[8], entry[9]]);
+            (segment == 0).then(|| Self {
+                base: PhysAddr::new(acpi::read_u64(entry, 0)),
+                start_bus: entry[10],
+                end_bus: entry[11],
+                buses: Mutex::new(BTreeMap::new()),
+            })
+        })
+    }
+
+    fn address(&self, address: PciAddress, offset: u16) -> Option<VirtAddr> {
+        if !(self.start_bus..=self.end_bus).contains(&address.bus) {
+            return None;
+        }
+        let mut buses = self.buses.lock();
+        let bus = match buses.get(&address.bus) {
+            Some(&bus) => bus,
+            None => {
+                let physical = self.base + u64::from(address.bus - self.start_bus) * ECAM_BUS_SIZE;
+                let bus = memory::map_device_memory(physical, ECAM_BUS_SIZE).ok()?;
+                buses.insert(address.bus, bus);
+                bus
+            }
+        };
+        let function = (u64::from(address.device) << 15) | (u64::from(address.function) << 12);
+        Some(bus + function + u64::from(offset))
+    }
+}
+
+fn ecam() -> Option<&'static Ecam> {
+    ECAM.call_once(Ecam::from_mcfg).as_ref()
+}
+
+/// The location of a function on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    pub fn read_u8(self, offset: u16) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Reads a dword of configuration space; `offset` must be dword aligned. Registers that
+    /// cannot be reached read as all ones, like those of a missing device.
+    pub fn read_u32(self, offset: u16) -> u32 {
+        if let Some(register) = ecam().and_then(|ecam| ecam.address(self, offset)) {
+            return unsafe { register.as_ptr::<u32>().read_volatile() };
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return u32::MAX;
+        }
+        let mut ports = CONFIG_PORTS.lock();
+        unsafe {
+            ports.0.write(self.config_address(offset));
+            ports.1.read()
+        }
+    }
+
+    /// Writes a word of configuration space without touching its neighbour, which matters for
+    /// registers such as the status register whose bits are cleared by writing ones.
+    pub fn write_u16(self, offset: u16, value: u16) {
+        if let Some(register) = ecam().and_then(|ecam| ecam.address(self, offset)) {
+            unsafe { register.as_mut_ptr::<u16>().write_volatile(value) };
+            return;
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        let mut ports = CONFIG_PORTS.lock();
+        unsafe {
+            ports.0.write(self.config_address(offset));
+            Port::new(CONFIG_DATA_PORT + (offset & 2)).write(value);
+        }
+    }
+
+    pub fn write_u32(self, offset: u16, value: u32) {
+        if let Some(register) = ecam().and_then(|ecam| ecam.address(self, offset)) {
+            unsafe { register.as_mut_ptr::<u32>().write_volatile(value) };
+            return;
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        let mut ports = CONFIG_PORTS.lock();
+        unsafe {
+            ports.0.write(self.config_address(offset));
+            ports.1.write(value);
+        }
+    }
+
+    fn config_address(self, offset: u16) -> u32 {
+        CONFIG_ENABLE
+            | (u32::from(self.bus) << 16)
+            | (u32::from(self.device) << 11)
+            | (u32::from(self.function) << 8)
+            | u32::from(offset & 0xFC)
+    }
+
+    fn is_present(self) -> bool {
+        self.read_u16(OFFSET_VENDOR_ID) != NO_DEVICE
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "{:02x}:{:02x}.{}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the address spans this register and the next one.
+        is_64_bit: bool,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Io { size, .. } => u64::from(size),
+            Bar::Memory { size, .. } => size,
+        }
+    }
+}
+
+/// An entry of a function's capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in configuration space.
+    pub offset: u8,
+}
+
+/// A function found on the bus.
+#[derive(Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    driver: Mutex<Option<&'static str>>,
+}
+
+impl PciDevice {
+    fn new(address: PciAddress) -> Self {
+        let header_type = address.read_u8(OFFSET_HEADER_TYPE) & HEADER_TYPE_MASK;
+        let mut device = Self {
+            address,
+            vendor_id: address.read_u16(OFFSET_VENDOR_ID),
+            device_id: address.read_u16(OFFSET_DEVICE_ID),
+            class: address.read_u8(OFFSET_CLASS),
+            subclass: address.read_u8(OFFSET_SUBCLASS),
+            prog_if: address.read_u8(OFFSET_PROG_IF),
+            revision: address.read_u8(OFFSET_REVISION),
+            header_type,
+            interrupt_line: address.read_u8(OFFSET_INTERRUPT_LINE),
+            interrupt_pin: address.read_u8(OFFSET_INTERRUPT_PIN),
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            driver: Mutex::new(None),
+        };
+        device.read_bars();
+        device.read_capabilities();
+        device
+    }
+
+    /// Decodes the base address registers, sizing each by writing all ones to it with decoding
+    /// turned off.
+    fn read_bars(&mut self) {
+        let count = match self.header_type {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let command = self.address.read_u16(OFFSET_COMMAND);
+        self.address.write_u16(
+            OFFSET_COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+        let mut index = 0;
+        while index < count {
+            let bar = self.read_bar(index);
+            self.bars[index] = bar;
+            index += match bar {
+                Some(Bar::Memory {
+                    is_64_bit: true, ..
+                }) => 2,
+                _ => 1,
+            };
+        }
+        self.address.write_u16(OFFSET_COMMAND, command);
+    }
+
+    fn read_bar(&self, index: usize) -> Option<Bar> {
+        let offset = OFFSET_BAR_0 + index as u16 * 4;
+        let size_mask = |offset: u16| {
+            let original = self.address.read_u32(offset);
+            self.address.write_u32(offset, u32::MAX);
+            let mask = self.address.read_u32(offset);
+            self.address.write_u32(offset, original);
+            (original, mask)
+        };
+        let (low, low_mask) = size_mask(offset);
+        if low & BAR_IO != 0 {
+            let mask = low_mask & !0b11 & 0xFFFF;
+            return (mask != 0).then(|| Bar::Io {
+                port: (low & !0b11) as u16,
+                size: (!mask & 0xFFFF) + 1,
+            });
+        }
+        let is_64_bit = low & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_64 && index < 5;
+        let (high, high_mask) = match is_64_bit {
+            true => size_mask(offset + 4),
+            false => (0, u32::MAX),
+        };
+        let address = (u64::from(high) << 32) | u64::from(low & !0b1111);
+        let mask = (u64::from(high_mask) << 32) | u64::from(low_mask & !0b1111);
+        (low_mask & !0b1111 != 0).then(|| Bar::Memory {
+            address: PhysAddr::new(address),
+            size: !mask + 1,
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+            is_64_bit,
+        })
+    }
+
+    fn read_capabilities(&mut self) {
+        if self.address.read_u16(OFFSET_STATUS) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        let mut offset = self.address.read_u8(OFFSET_CAPABILITIES) & !0b11;
+        // The list lives in the 192 bytes after the header, which bounds a corrupt list's loop.
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            let id = self.address.read_u8(u16::from(offset));
+            self.capabilities.push(Capability { id, offset });
+            offset = self.address.read_u8(u16::from(offset) + 1) & !0b11;
+        }
+    }
+
+    /// The first capability with the given ID.
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .copied()
+            .find(|capability| capability.id == id)
+    }
+
+    /// Sets bits of the command register, such as [`COMMAND_BUS_MASTER`].
+    pub fn enable(&self, command: u16) {
+        let current = self.address.read_u16(OFFSET_COMMAND);
+        self.address.write_u16(OFFSET_COMMAND, current | command);
+    }
+
+    /// The name of the driver bound to this device, if any.
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+}
+
+/// Which devices a driver handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Id {
+        vendor: u16,
+        device: u16,
+    },
+    /// Every device of a class and subclass.
+    Class {
+        class: u8,
+        subclass: u8,
+    },
+}
+
+impl DeviceMatch {
+    fn matches(self, device: &PciDevice) -> bool {
+        match self {
+            DeviceMatch::Id { vendor, device: id } => {
+                device.vendor_id == vendor && device.device_id == id
+            }
+            DeviceMatch::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Sets up a matching device, returning whether the driver took it.
+    pub probe: fn(&Arc<PciDevice>) -> bool,
+}
+
+/// Enumerates the bus and binds the drivers registered so far.
+pub fn init() {
+    let mut found = Vec::new();
+    let host = PciAddress::new(0, 0, 0);
+    if host.read_u8(OFFSET_HEADER_TYPE) & HEADER_MULTI_FUNCTION == 0 {
+        scan_bus(0, &mut found);
+    } else {
+        // Each function of a multi-function host bridge is the controller of the bus of the
+        // same number.
+        for function in 0..8 {
+            if PciAddress::new(0, 0, function).is_present() {
+                scan_bus(function, &mut found);
+            }
+        }
+    }
+    found.sort_by_key(|device| device.address);
+    *DEVICES.lock() = found.into_iter().map(Arc::new).collect();
+
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        bind(driver);
+    }
+}
+
+/// Adds a driver and binds it to every matching device that has no driver yet.
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    bind(driver);
+}
+
+/// Every function found, ordered by address.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    DEVICES.lock().clone()
+}
+
+fn bind(driver: &'static PciDriver) {
+    for device in devices() {
+        let matches = driver
+            .matches
+            .iter()
+            .any(|pattern| pattern.matches(&device));
+        if matches && device.driver().is_none() && (driver.probe)(&device) {
+            *device.driver.lock() = Some(driver.name);
+        }
+    }
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let address = PciAddress::new(bus, device, 0);
+        if !address.is_present() {
+            continue;
+        }
+        scan_function(address, found);
+        if address.read_u8(OFFSET_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+            for function in 1..8 {
+                let address = PciAddress::new(bus, device, function);
+                if address.is_present() {
+                    scan_function(address, found);
+                }
+            }
+        }
+    }
+}
+
+fn scan_function(address: PciAddress, found: &mut Vec<PciDevice>) {
+    let device = PciDevice::new(address);
+    let is_bridge = device.class == CLASS_BRIDGE && device.subclass == SUBCLASS_PCI_BRIDGE;
+    found.push(device);
+    if is_bridge {
+        let secondary = address.read_u8(OFFSET_SECONDARY_BUS);
+        // Buses behind a bridge are numbered above it; anything else is unconfigured.
+        if secondary > address.bus {
+            scan_bus(secondary, found);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Restarting and switching off the machine.
+
+use core::fmt;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+};
+
+use crate::{
+    acpi::{self, read_u32, read_u64},
+    hlt_loop,
+};
+
+const KEYBOARD_CONTROLLER_COMMAND_PORT: u16 = 0x64;
+/// Set in the controller's status register while it has not taken the last command yet.
+const INPUT_BUFFER_FULL: u8 = 1 << 1;
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+/// Fields of the FADT, the table describing the ACPI power management hardware.
+const FADT_DSDT: usize = 40;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+/// The 64-bit DSDT address, present from ACPI 2.0 on.
+const FADT_X_DSDT: usize = 140;
+
+/// Bits of the PM1 control registers.
+const SCI_ENABLED: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// AML opcodes around the `\_S5_` object that holds the sleep type values for soft-off.
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0A;
+const ROOT_PREFIX: u8 = b'\\';
+
+/// How long to poll for ACPI mode and for the machine to switch off.
+const POLL_ITERATIONS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownError {
+    /// The firmware provides no FADT or DSDT.
+    NoAcpiTables,
+    /// The DSDT does not say how to enter the soft-off state.
+    NoSoftOffState,
+    /// The firmware did not hand the power management hardware to the kernel.
+    AcpiModeUnavailable,
+    /// The machine was told to switch off but kept running.
+    StillRunning,
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            ShutdownError::NoAcpiTables => "no ACPI tables",
+            ShutdownError::NoSoftOffState => "no soft-off state in the DSDT",
+            ShutdownError::AcpiModeUnavailable => "ACPI mode cannot be enabled",
+            ShutdownError::StillRunning => "the machine kept running",
+        })
+    }
+}
+
+/// Resets the machine through the keyboard controller, falling back to a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    let mut command: Port<u8> = Port::new(KEYBOARD_CONTROLLER_COMMAND_PORT);
+    unsafe {
+        for _ in 0..POLL_ITERATIONS {
+            if command.read() & INPUT_BUFFER_FULL == 0 {
+                break;
+            }
+        }
+        command.write(PULSE_RESET_LINE);
+    }
+
+    // With no interrupt descriptor table, the breakpoint faults, and so does every attempt to
+    // report it, which resets the CPU.
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    hlt_loop()
+}
+
+/// Puts the machine into the ACPI soft-off state. Returns only if that failed.
+pub fn shutdown() -> ShutdownError {
+    let Some(fadt) = acpi::find_table(b"FACP") else {
+        return ShutdownError::NoAcpiTables;
+    };
+    let extended_dsdt = (fadt.len() >= FADT_X_DSDT + 8)
+        .then(|| read_u64(fadt, FADT_X_DSDT))
+        .filter(|&address| address != 0);
+    let dsdt_address = extended_dsdt.unwrap_or_else(|| u64::from(read_u32(fadt, FADT_DSDT)));
+    let Some(dsdt) = acpi::table_at(PhysAddr::new(dsdt_address)) else {
+        return ShutdownError::NoAcpiTables;
+    };
+    let Some((sleep_type_a, sleep_type_b)) = soft_off_sleep_types(dsdt) else {
+        return ShutdownError::NoSoftOffState;
+    };
+
+    let control_a = read_u32(fadt, FADT_PM1A_CONTROL) as u16;
+    let control_b = read_u32(fadt, FADT_PM1B_CONTROL) as u16;
+    let mut control_a_port: Port<u16> = Port::new(control_a);
+    if unsafe { control_a_port.read() } & SCI_ENABLED == 0 {
+        let smi_command = read_u32(fadt, FADT_SMI_COMMAND) as u16;
+        let acpi_enable = fadt[FADT_ACPI_ENABLE];
+        if smi_command == 0 || acpi_enable == 0 {
+            return ShutdownError::AcpiModeUnavailable;
+        }
+        unsafe { Port::<u8>::new(smi_command).write(acpi_enable) };
+        if !(0..POLL_ITERATIONS).any(|_| unsafe { control_a_port.read() } & SCI_ENABLED != 0) {
+            return ShutdownError::AcpiModeUnavailable;
+        }
+    }
+
+    interrupts::disable();
+    for (port, sleep_type) in [(control_a, sleep_type_a), (control_b, sleep_type_b)] {
+        if port != 0 {
+            let value = u16::from(sleep_type) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE;
+            unsafe { Port::<u16>::new(port).write(value) };
+        }
+    }
+    for _ in 0..POLL_ITERATIONS {
+        core::hint::spin_loop();
+    }
+    interrupts::enable();
+    ShutdownError::StillRunning
+}
+
+/// Finds the `\_S5_` package in AML and returns the sleep types it gives for the PM1a and PM1b
+/// control registers.
+fn soft_off_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+    let named = match position {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => {
+            aml[position - 1] == NAME_OP
+                || (aml[position - 2] == NAME_OP && aml[position - 1] == ROOT_PREFIX)
+        }
+    };
+    let rest = aml.get(position + 4..)?;
+    if !named || rest.first() != Some(&PACKAGE_OP) {
+        return None;
+    }
+    // The top two bits of the first package length byte count the bytes that follow it.
+    let length_bytes = usize::from(*rest.get(1)? >> 6);
+    // Skip the opcode, the package length and the element count.
+    let mut rest = rest.get(2 + length_bytes + 1..)?;
+    let mut values = [0; 2];
+    for value in &mut values {
+        if rest.first() == Some(&BYTE_PREFIX) {
+            rest = &rest[1..];
+        }
+        // Without a prefix the value is a ZeroOp or OneOp, which encode 0 and 1.
+        *value = *rest.first()?;
+        rest = &rest[1..];
+    }
+    Some((values[0], values[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::soft_off_sleep_types;
+
+    #[test_case]
+    fn test_soft_off_sleep_types() {
+        // Name (\_S5_, Package (0x04) { 0x05, 0x05, Zero, Zero }), as QEMU's DSDT has it.
+        let aml = [
+            0x10, 0x08, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A,
+            0x05, 0x00, 0x00,
+        ];
+        assert_eq!(soft_off_sleep_types(&aml), Some((5, 5)));
+        let aml = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x01, 0x00, 0x00,
+        ];
+        assert_eq!(soft_off_sleep_types(&aml), Some((0, 1)));
+        // A method called _S5_ is not the package.
+        assert_eq!(soft_off_sleep_types(b"\x14\x06_S5_\x00"), None);
+    }
+}
@@ -0,0 +1,228 @@
+//! User processes: an address space, a file table and the threads running in it.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, registers::rflags::RFlags};
+
+use crate::{
+    loader::{self, LoadError},
+    memory::{self, AddressSpace, MemoryError},
+    scheduler::{self, ThreadId},
+    syscall::{self, SyscallFrame},
+};
+
+pub mod file_table;
+
+pub use file_table::{FileTable, OpenFile};
+
+pub type Pid = u64;
+
+/// The exit code of a process killed by a fault, following the shell convention of 128 plus
+/// the signal number (`SIGSEGV`).
+pub const FAULT_EXIT_CODE: i64 = 128 + 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Exited with the given code but not yet reaped by [`wait`].
+    Exited(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkError {
+    /// The calling thread does not belong to a process.
+    NotAProcess,
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for ForkError {
+    fn from(error: MemoryError) -> Self {
+        Self::Memory(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    NoSuchProcess,
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub pid: Pid,
+    /// `None` for processes started by the kernel and for orphans.
+    pub parent: Option<Pid>,
+    pub state: ProcessState,
+    /// Set when the parent exits first. Nobody is left to wait for an orphan, so it is reaped as
+    /// soon as it exits.
+    orphan: bool,
+    pub files: FileTable,
+    pub threads: Vec<ThreadId>,
+    /// `None` once the process has exited and its memory has been freed.
+    address_space: Option<AddressSpace>,
+    /// The registers the main thread enters ring 3 with.
+    start_frame: SyscallFrame,
+    waiters: Vec<ThreadId>,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+/// Loads `elf_bytes` into a new process and schedules its main thread.
+pub fn spawn(elf_bytes: &[u8], arguments: &[&str], environment: &[&str]) -> Result<Pid, LoadError> {
+    let program = loader::load(elf_bytes, arguments, environment)?;
+    let start_frame = SyscallFrame {
+        rcx: program.entry_point.as_u64(),
+        r11: RFlags::INTERRUPT_FLAG.bits(),
+        rsp: program.stack_pointer.as_u64(),
+        ..SyscallFrame::default()
+    };
+    Ok(insert(
+        program.address_space,
+        start_frame,
+        FileTable::with_console(),
+    ))
+}
+
+/// Registers a process owning `address_space` and starts a thread entering ring 3 with the
+/// registers in `start_frame`.
+pub fn insert(address_space: AddressSpace, start_frame: SyscallFrame, files: FileTable) -> Pid {
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let level_4_frame = address_space.level_4_frame();
+    let parent = current_pid();
+    interrupts::without_interrupts(|| {
+        PROCESSES.lock().insert(
+            pid,
+            Process {
+                pid,
+                parent,
+                state: ProcessState::Running,
+                orphan: false,
+                files,
+                threads: Vec::new(),
+                address_space: Some(address_space),
+                start_frame,
+                waiters: Vec::new(),
+            },
+        );
+    });
+    let thread = scheduler::spawn(main_thread, pid, Some(pid), level_4_frame);
+    with_process(pid, |process| process.threads.push(thread));
+    pid
+}
+
+/// Duplicates the current process. The child shares the parent's memory copy-on-write, inherits
+/// its open files and resumes from `frame` with `rax` set to 0.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, ForkError> {
+    let pid = current_pid().ok_or(ForkError::NotAProcess)?;
+    let (address_space, files) = with_process(pid, |process| {
+        let address_space = process.address_space.as_mut().map(AddressSpace::fork);
+        (address_space, process.files.clone())
+    })
+    .ok_or(ForkError::NotAProcess)?;
+    let address_space = address_space.ok_or(ForkError::NotAProcess)??;
+    let child_frame = SyscallFrame { rax: 0, ..*frame };
+    Ok(insert(address_space, child_frame, files))
+}
+
+fn main_thread(pid: u64) -> ! {
+    let start_frame = with_process(pid, |process| process.start_frame)
+        .expect("process disappeared before it started");
+    let exit_code = unsafe { syscall::enter_user_mode_with_frame(&start_frame) };
+    exit(exit_code)
+}
+
+/// The process the current thread belongs to.
+pub fn current_pid() -> Option<Pid> {
+    scheduler::current_process()
+}
+
+/// Runs `function` on the process with the given pid, if it exists.
+pub fn with_process<T>(pid: Pid, function: impl FnOnce(&mut Process) -> T) -> Option<T> {
+    interrupts::without_interrupts(|| PROCESSES.lock().get_mut(&pid).map(function))
+}
+
+/// Runs `function` on the address space of the given process, if it is still running.
+pub fn with_address_space<T>(pid: Pid, function: impl FnOnce(&mut AddressSpace) -> T) -> Option<T> {
+    with_process(pid, |process| process.address_space.as_mut().map(function)).flatten()
+}
+
+/// Looks up a file descriptor of the current process. Kernel threads see the console on the
+/// standard descriptors.
+pub fn current_file(file_descriptor: u64) -> Option<OpenFile> {
+    match current_pid() {
+        Some(pid) => with_process(pid, |process| process.files.get(file_descriptor)).flatten(),
+        None => FileTable::with_console().get(file_descriptor),
+    }
+}
+
+/// Returns the pids of every process that has not been reaped yet.
+pub fn pids() -> Vec<Pid> {
+    interrupts::without_interrupts(|| PROCESSES.lock().keys().copied().collect())
+}
+
+/// Terminates the current process: frees its memory, records `exit_code` for [`wait`] and
+/// stops the calling thread. Children that already exited are reaped, as their parent can no
+/// longer wait for them, and the others become orphans.
+pub fn exit(exit_code: i64) -> ! {
+    let pid = current_pid().expect("exit called outside of a process");
+    unsafe { scheduler::switch_address_space(memory::kernel_level_4_frame()) };
+    let (address_space, waiters, reaped) = interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).unwrap();
+        process.state = ProcessState::Exited(exit_code);
+        let address_space = process.address_space.take();
+        let waiters = core::mem::take(&mut process.waiters);
+        let mut reaped = Vec::new();
+        // A kernel thread waiting for an orphan still gets to reap it.
+        if process.orphan && waiters.is_empty() {
+            reaped.extend(processes.remove(&pid));
+        }
+        let children: Vec<Pid> = processes
+            .values()
+            .filter(|child| child.parent == Some(pid))
+            .map(|child| child.pid)
+            .collect();
+        for child in children {
+            let process = processes.get_mut(&child).unwrap();
+            match process.state {
+                ProcessState::Exited(_) => reaped.extend(processes.remove(&child)),
+                ProcessState::Running => {
+                    process.parent = None;
+                    process.orphan = true;
+                }
+            }
+        }
+        (address_space, waiters, reaped)
+    });
+    drop(address_space);
+    drop(reaped);
+    for waiter in waiters {
+        scheduler::wake(waiter);
+    }
+    scheduler::exit_current()
+}
+
+/// Blocks until the given process has exited, then reaps it and returns its exit code.
+pub fn wait(pid: Pid) -> Result<i64, WaitError> {
+    loop {
+        let exited = interrupts::without_interrupts(|| {
+            let mut processes = PROCESSES.lock();
+            let process = processes.get_mut(&pid).ok_or(WaitError::NoSuchProcess)?;
+            match process.state {
+                ProcessState::Exited(exit_code) => {
+                    processes.remove(&pid);
+                    Ok(Some(exit_code))
+                }
+                ProcessState::Running => {
+                    process.waiters.push(scheduler::current_thread_id());
+                    Ok(None)
+                }
+            }
+        })?;
+        match exited {
+            Some(exit_code) => return Ok(exit_code),
+            None => scheduler::block_current(),
+        }
+    }
+}
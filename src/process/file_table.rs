@@ -0,0 +1,65 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use crate::fs::{Console, File};
+
+/// What a file descriptor refers to. Descriptors duplicated by `fork` share the same file and
+/// therefore its offset.
+pub type OpenFile = Arc<dyn File>;
+
+/// The open files of a process, indexed by file descriptor.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FileTable {
+    /// A table with stdin, stdout and stderr attached to the console.
+    pub fn with_console() -> Self {
+        Self {
+            files: vec![
+                Some(Arc::new(Console)),
+                Some(Arc::new(Console)),
+                Some(Arc::new(Console)),
+            ],
+        }
+    }
+
+    pub fn get(&self, file_descriptor: u64) -> Option<OpenFile> {
+        self.files.get(file_descriptor as usize).cloned().flatten()
+    }
+
+    /// Stores `file` under the lowest free descriptor and returns that descriptor.
+    pub fn insert(&mut self, file: OpenFile) -> u64 {
+        match self.files.iter().position(Option::is_none) {
+            Some(index) => {
+                self.files[index] = Some(file);
+                index as u64
+            }
+            None => {
+                self.files.push(Some(file));
+                (self.files.len() - 1) as u64
+            }
+        }
+    }
+
+    /// The descriptors that refer to an open file, in ascending order.
+    pub fn descriptors(&self) -> Vec<u64> {
+        (0..self.files.len() as u64)
+            .filter(|&index| self.files[index as usize].is_some())
+            .collect()
+    }
+
+    pub fn close(&mut self, file_descriptor: u64) -> Option<OpenFile> {
+        self.files.get_mut(file_descriptor as usize)?.take()
+    }
+}
+
+impl fmt::Debug for FileTable {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("FileTable")
+            .field("open_descriptors", &self.descriptors())
+            .finish()
+    }
+}
@@ -0,0 +1,75 @@
+/// A fixed-capacity FIFO queue that drops new items once it is full.
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: [T; N],
+    head: usize,
+    length: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Creates an empty buffer; `fill` is only used to initialise the backing storage.
+    pub const fn new(fill: T) -> Self {
+        Self {
+            items: [fill; N],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    /// Appends an item, returning `false` if the buffer is full and the item was dropped.
+    pub fn push(&mut self, item: T) -> bool {
+        if self.length == N {
+            return false;
+        }
+        self.items[(self.head + self.length) % N] = item;
+        self.length += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.length == 0 {
+            return None;
+        }
+        let item = self.items[self.head];
+        self.head = (self.head + 1) % N;
+        self.length -= 1;
+        Some(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.length == N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.length = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+
+    #[test_case]
+    fn test_ring_buffer_wraps_around() {
+        let mut buffer: RingBuffer<u8, 3> = RingBuffer::new(0);
+        assert!(buffer.push(1));
+        assert!(buffer.push(2));
+        assert_eq!(buffer.pop(), Some(1));
+        assert!(buffer.push(3));
+        assert!(buffer.push(4));
+        assert!(!buffer.push(5));
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), Some(3));
+        assert_eq!(buffer.pop(), Some(4));
+        assert_eq!(buffer.pop(), None);
+    }
+}
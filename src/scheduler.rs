@@ -0,0 +1,327 @@
+//! A round-robin scheduler for kernel threads.
+//!
+//! Kernel code is cooperative: a thread only gives up the CPU by yielding, sleeping, blocking or
+//! exiting. Threads running in ring 3 are additionally preempted by the timer interrupt, which
+//! keeps spin locks from ever being held across a preemption.
+
+use alloc::{boxed::Box, collections::BTreeMap, collections::VecDeque, vec, vec::Vec};
+use core::{
+    arch::naked_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Lazy, Mutex};
+use x86_64::{instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame};
+
+use crate::{interupt::pit, process::Pid, syscall};
+
+pub type ThreadId = u64;
+
+const KERNEL_STACK_SIZE: usize = 4096 * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    /// Waiting for the timer to reach the given tick.
+    Sleeping(u64),
+    /// Waiting for another thread to call [`wake`].
+    Blocked,
+    Exited,
+}
+
+#[derive(Debug)]
+pub struct Thread {
+    pub id: ThreadId,
+    pub process: Option<Pid>,
+    pub state: ThreadState,
+    /// The saved stack pointer while the thread is switched out.
+    stack_pointer: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack. Only held so the stack
+    /// lives exactly as long as the thread.
+    #[allow(dead_code)]
+    kernel_stack: Option<Box<[u8]>>,
+    level_4_frame: PhysFrame,
+    user_return_stack_pointer: u64,
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready_queue: VecDeque<ThreadId>,
+    current: ThreadId,
+    next_id: ThreadId,
+    /// Exited threads whose stacks are freed once another thread is running. Boxed because
+    /// `switch_context` writes through a pointer into the exiting thread.
+    #[allow(clippy::vec_box)]
+    exited: Vec<Box<Thread>>,
+}
+
+/// The timer ticks spent halted because no thread was ready to run.
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(|| {
+    let boot_thread = Box::new(Thread {
+        id: 0,
+        process: None,
+        state: ThreadState::Running,
+        stack_pointer: 0,
+        kernel_stack: None,
+        level_4_frame: Cr3::read().0,
+        user_return_stack_pointer: 0,
+    });
+    let mut threads = BTreeMap::new();
+    threads.insert(0, boot_thread);
+    Mutex::new(Scheduler {
+        threads,
+        ready_queue: VecDeque::new(),
+        current: 0,
+        next_id: 1,
+        exited: Vec::new(),
+    })
+});
+
+/// Registers the currently running code as the boot thread.
+pub fn init() {
+    Lazy::force(&SCHEDULER);
+}
+
+/// Creates a thread that calls `entry(argument)` on its own kernel stack with the given level 4
+/// table loaded.
+pub fn spawn(
+    entry: fn(u64) -> !,
+    argument: u64,
+    process: Option<Pid>,
+    level_4_frame: PhysFrame,
+) -> ThreadId {
+    let mut kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+    let stack_top = (kernel_stack.as_mut_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xf;
+    // The initial frame popped by `switch_context`: r15, r14, r13, r12, rbx, rbp, return address.
+    let initial_frame = [
+        0,
+        0,
+        argument,
+        entry as usize as u64,
+        0,
+        0,
+        thread_trampoline as *const () as u64,
+    ];
+    let stack_pointer = stack_top - (initial_frame.len() as u64) * 8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            initial_frame.as_ptr(),
+            stack_pointer as *mut u64,
+            initial_frame.len(),
+        );
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let id = scheduler.next_id;
+        scheduler.next_id += 1;
+        scheduler.threads.insert(
+            id,
+            Box::new(Thread {
+                id,
+                process,
+                state: ThreadState::Ready,
+                stack_pointer,
+                kernel_stack: Some(kernel_stack),
+                level_4_frame,
+                user_return_stack_pointer: 0,
+            }),
+        );
+        scheduler.ready_queue.push_back(id);
+        id
+    })
+}
+
+/// Spawns a kernel thread running in the kernel address space.
+pub fn spawn_kernel_thread(entry: fn(u64) -> !, argument: u64) -> ThreadId {
+    spawn(entry, argument, None, crate::memory::kernel_level_4_frame())
+}
+
+pub fn current_thread_id() -> ThreadId {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current)
+}
+
+/// The process the current thread belongs to, or `None` for kernel threads.
+pub fn current_process() -> Option<Pid> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[&scheduler.current].process
+    })
+}
+
+/// Returns the state of every thread, in thread id order.
+pub fn thread_states() -> Vec<(ThreadId, Option<Pid>, ThreadState)> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .threads
+            .values()
+            .map(|thread| (thread.id, thread.process, thread.state))
+            .collect()
+    })
+}
+
+/// The number of timer ticks the CPU has spent idle since boot.
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Loads a different level 4 table for the current thread.
+///
+/// # Safety
+/// The table must map the kernel like the boot page table.
+pub unsafe fn switch_address_space(level_4_frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().level_4_frame = level_4_frame;
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(level_4_frame, flags) };
+    });
+}
+
+/// Lets every other ready thread run before returning.
+pub fn yield_now() {
+    schedule(ThreadState::Ready);
+}
+
+/// Blocks the current thread for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    schedule(ThreadState::Sleeping(pit::ticks().saturating_add(ticks)));
+}
+
+/// Blocks the current thread until [`wake`] is called for it.
+pub fn block_current() {
+    schedule(ThreadState::Blocked);
+}
+
+/// Makes a blocked or sleeping thread ready to run again.
+pub fn wake(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(thread) = scheduler.threads.get_mut(&id)
+            && matches!(
+                thread.state,
+                ThreadState::Blocked | ThreadState::Sleeping(_)
+            )
+        {
+            thread.state = ThreadState::Ready;
+            scheduler.ready_queue.push_back(id);
+        }
+    });
+}
+
+/// Terminates the current thread. Its stack is freed after the next thread switch.
+pub fn exit_current() -> ! {
+    schedule(ThreadState::Exited);
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Moves the current thread into `state` and switches to the next ready thread, halting until
+/// one becomes ready if necessary. Returns once the current thread is running again.
+fn schedule(state: ThreadState) {
+    let interrupts_were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    loop {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.exited.retain(|thread| thread.id == current);
+        scheduler.wake_sleepers();
+
+        let Some(next) = scheduler.ready_queue.pop_front() else {
+            if state == ThreadState::Ready {
+                break;
+            }
+            drop(scheduler);
+            let halted_at = pit::ticks();
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+            IDLE_TICKS.fetch_add(pit::ticks() - halted_at, Ordering::Relaxed);
+            continue;
+        };
+
+        let (old_stack_pointer, new_stack_pointer) = scheduler.switch(state, next);
+        drop(scheduler);
+        unsafe { switch_context(old_stack_pointer, new_stack_pointer) };
+        break;
+    }
+    if interrupts_were_enabled {
+        interrupts::enable();
+    }
+}
+
+impl Scheduler {
+    fn wake_sleepers(&mut self) {
+        let now = pit::ticks();
+        for thread in self.threads.values_mut() {
+            if let ThreadState::Sleeping(wake_tick) = thread.state
+                && wake_tick <= now
+            {
+                thread.state = ThreadState::Ready;
+                self.ready_queue.push_back(thread.id);
+            }
+        }
+    }
+
+    /// Records the switch from the current thread to `next` and returns the slot to save the
+    /// current stack pointer into together with the stack pointer to resume.
+    fn switch(&mut self, state: ThreadState, next: ThreadId) -> (*mut u64, u64) {
+        let current = self.current;
+        // Taken out of the map so both threads can be borrowed; the box keeps its address.
+        let mut current_thread = self.threads.remove(&current).unwrap();
+        current_thread.state = state;
+        current_thread.user_return_stack_pointer = syscall::user_return_stack_pointer();
+        if state == ThreadState::Ready {
+            self.ready_queue.push_back(current);
+        }
+
+        let next_thread = self.threads.get_mut(&next).unwrap();
+        next_thread.state = ThreadState::Running;
+        unsafe { syscall::set_user_return_stack_pointer(next_thread.user_return_stack_pointer) };
+        let (active_frame, flags) = Cr3::read();
+        if active_frame != next_thread.level_4_frame {
+            unsafe { Cr3::write(next_thread.level_4_frame, flags) };
+        }
+        let new_stack_pointer = next_thread.stack_pointer;
+        self.current = next;
+
+        let old_stack_pointer = &raw mut current_thread.stack_pointer;
+        if state == ThreadState::Exited {
+            self.exited.push(current_thread);
+        } else {
+            self.threads.insert(current, current_thread);
+        }
+        (old_stack_pointer, new_stack_pointer)
+    }
+}
+
+/// Saves the callee-saved registers on the current stack, stores the stack pointer in
+/// `old_stack_pointer` and resumes the thread whose stack pointer is `new_stack_pointer`.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// The first code a new thread runs: `r12` holds the entry point and `r13` its argument.
+#[unsafe(naked)]
+unsafe extern "C" fn thread_trampoline() -> ! {
+    naked_asm!("sti", "mov rdi, r13", "call r12", "ud2")
+}
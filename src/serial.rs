@@ -2,8 +2,19 @@ use core::fmt;
 use spin::Lazy;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{interupt::pic, ring_buffer::RingBuffer, scheduler};
 
 const SERIAL_PORT_ADDRESS: u16 = 0x3F8;
+const SERIAL_IRQ: u8 = 4;
+
+const MODEM_CONTROL_PORT_ADDRESS: u16 = SERIAL_PORT_ADDRESS + 4;
+/// Data terminal ready, request to send and OUT2, which gates the interrupt line, as
+/// `SerialPort::init` sets them.
+const MODEM_CONTROL_DEFAULT: u8 = 0x0B;
+/// Feeds transmitted bytes straight back into the receiver.
+const MODEM_CONTROL_LOOPBACK: u8 = 1 << 4;
 
 #[allow(dead_code)]
 pub static SERIAL_PORT: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
@@ -12,6 +23,62 @@ pub static SERIAL_PORT: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
     Mutex::new(serial_port)
 });
 
+/// Bytes received on COM1 that have not yet been consumed by a reader.
+pub static SERIAL_INPUT: Mutex<RingBuffer<u8, 256>> = Mutex::new(RingBuffer::new(0));
+
+/// Moves received bytes into the input buffer on the receive interrupt, which
+/// `SerialPort::init` enables.
+pub fn init() {
+    Lazy::force(&SERIAL_PORT);
+    pic::register_irq_handler(SERIAL_IRQ, receive_interrupt_handler);
+}
+
+fn receive_interrupt_handler() {
+    let mut port = SERIAL_PORT.lock();
+    let mut input = SERIAL_INPUT.lock();
+    while let Ok(byte) = port.try_receive() {
+        input.push(byte);
+    }
+}
+
+/// Moves received bytes into `buffer`, blocking until at least one has arrived.
+pub fn read(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    loop {
+        let count = interrupts::without_interrupts(|| {
+            let mut input = SERIAL_INPUT.lock();
+            let mut count = 0;
+            while count < buffer.len()
+                && let Some(byte) = input.pop()
+            {
+                buffer[count] = byte;
+                count += 1;
+            }
+            count
+        });
+        if count > 0 {
+            return count;
+        }
+        scheduler::sleep(1);
+    }
+}
+
+/// Connects the transmitter to the receiver, so that everything sent is received again instead
+/// of leaving the machine.
+pub fn set_loopback(enabled: bool) {
+    let value = if enabled {
+        MODEM_CONTROL_DEFAULT | MODEM_CONTROL_LOOPBACK
+    } else {
+        MODEM_CONTROL_DEFAULT
+    };
+    interrupts::without_interrupts(|| {
+        let _port = SERIAL_PORT.lock();
+        unsafe { Port::<u8>::new(MODEM_CONTROL_PORT_ADDRESS).write(value) };
+    });
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {{
@@ -0,0 +1,447 @@
+//! A command shell running as a kernel thread on the console.
+
+pub mod line_editor;
+
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::Ordering,
+};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    console::{self, Console},
+    fs::{self, FileType, FsError, OpenFlags},
+    interupt::pit,
+    power::{self, ShutdownError},
+    serial::{self, SERIAL_PORT},
+    vga_buffer::TextMode,
+};
+pub use line_editor::{LineEditor, Terminal};
+
+type Command = fn(&mut Shell, &[&str], &mut dyn Terminal) -> Result<(), CommandError>;
+
+/// The built-in commands, their usage and what they do.
+const COMMANDS: [(&str, &str, &str, Command); 14] = [
+    ("help", "", "list the commands", help),
+    ("clear", "", "clear the screen", clear),
+    ("echo", "[text...]", "print the arguments", echo),
+    ("mem", "", "show memory usage", mem),
+    ("irq", "", "show interrupt counts", irq),
+    ("uptime", "", "show the time since boot", uptime),
+    ("ls", "[path...]", "list directory contents", ls),
+    ("cat", "path...", "print files", cat),
+    ("cd", "[path]", "change the working directory", cd),
+    ("pwd", "", "print the working directory", pwd),
+    ("mounts", "", "list mounted file systems", mounts),
+    (
+        "mode",
+        "[size]",
+        "show or set the text mode: 80x25, 80x50 or 90x60",
+        mode,
+    ),
+    ("reboot", "", "restart the machine", reboot),
+    ("shutdown", "", "switch the machine off", shutdown),
+];
+
+#[derive(Debug)]
+pub enum CommandError {
+    Fs(FsError),
+    /// The arguments did not match the usage of the command.
+    Usage,
+    Shutdown(ShutdownError),
+    /// Writing to the terminal failed.
+    Output,
+}
+
+impl From<FsError> for CommandError {
+    fn from(error: FsError) -> Self {
+        CommandError::Fs(error)
+    }
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Output
+    }
+}
+
+/// The state of one shell session.
+#[derive(Debug)]
+pub struct Shell {
+    working_directory: String,
+    editor: LineEditor,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self {
+            working_directory: String::from("/"),
+            editor: LineEditor::new(),
+        }
+    }
+
+    pub fn working_directory(&self) -> &str {
+        &self.working_directory
+    }
+
+    /// Reads and runs commands forever.
+    pub fn run(&mut self, terminal: &mut dyn Terminal) -> ! {
+        loop {
+            let prompt = format!("{}> ", self.working_directory);
+            let mut editor = core::mem::take(&mut self.editor);
+            let line = editor.read_line(terminal, &prompt, &|before| self.complete(before));
+            self.editor = editor;
+            if let Ok(Some(line)) = line {
+                self.execute(&line, terminal);
+            }
+        }
+    }
+
+    /// Runs one command line, printing errors to `terminal`.
+    pub fn execute(&mut self, line: &str, terminal: &mut dyn Terminal) {
+        let arguments: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, arguments)) = arguments.split_first() else {
+            return;
+        };
+        let Some((_, usage, _, command)) = COMMANDS.iter().find(|command| command.0 == name) else {
+            let _ = writeln!(terminal, "{name}: command not found");
+            return;
+        };
+        let _ = match command(self, arguments, terminal) {
+            Ok(()) | Err(CommandError::Output) => Ok(()),
+            Err(CommandError::Fs(error)) => writeln!(terminal, "{name}: {error}"),
+            Err(CommandError::Usage) => writeln!(terminal, "usage: {name} {usage}"),
+            Err(CommandError::Shutdown(error)) => {
+                writeln!(terminal, "{name}: cannot switch off: {error}")
+            }
+        };
+    }
+
+    /// The completions of the last word of `before`: command names for the first word and
+    /// paths after that.
+    pub fn complete(&self, before: &str) -> Vec<String> {
+        let Some((_, word)) = before.rsplit_once(' ') else {
+            let mut names: Vec<String> = COMMANDS
+                .iter()
+                .map(|command| command.0)
+                .filter(|name| name.starts_with(before))
+                .map(String::from)
+                .collect();
+            names.sort();
+            return names;
+        };
+        let (directory, prefix) = match word.rsplit_once('/') {
+            Some((directory, prefix)) => (format!("{directory}/"), prefix),
+            None => (String::new(), word),
+        };
+        let Ok(entries) = fs::read_dir(&self.absolute_path(&directory)) else {
+            return Vec::new();
+        };
+        let mut candidates: Vec<String> = entries
+            .into_iter()
+            .filter(|entry| entry.name.starts_with(prefix))
+            .map(|entry| {
+                let suffix = if entry.file_type == FileType::Directory {
+                    "/"
+                } else {
+                    ""
+                };
+                format!("{directory}{}{suffix}", entry.name)
+            })
+            .collect();
+        candidates.sort();
+        candidates
+    }
+
+    /// Turns a path typed by the user into an absolute one.
+    fn absolute_path(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            String::from(path)
+        } else {
+            format!("{}/{}", self.working_directory, path)
+        }
+    }
+}
+
+fn help(_: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    for (name, usage, description, _) in COMMANDS {
+        let usage = format!("{name} {usage}");
+        writeln!(terminal, "  {usage:<20}{description}")?;
+    }
+    Ok(())
+}
+
+fn clear(_: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    terminal.clear_screen();
+    Ok(())
+}
+
+fn echo(
+    _: &mut Shell,
+    arguments: &[&str],
+    terminal: &mut dyn Terminal,
+) -> Result<(), CommandError> {
+    writeln!(terminal, "{}", arguments.join(" "))?;
+    Ok(())
+}
+
+fn mem(shell: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    cat(shell, &["/proc/meminfo"], terminal)
+}
+
+fn irq(shell: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    cat(shell, &["/proc/interrupts"], terminal)
+}
+
+fn uptime(_: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    let seconds = pit::uptime_milliseconds() / 1000;
+    writeln!(
+        terminal,
+        "up {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )?;
+    Ok(())
+}
+
+fn ls(
+    shell: &mut Shell,
+    arguments: &[&str],
+    terminal: &mut dyn Terminal,
+) -> Result<(), CommandError> {
+    let paths = if arguments.is_empty() {
+        &["."][..]
+    } else {
+        arguments
+    };
+    for (index, &path) in paths.iter().enumerate() {
+        let absolute = shell.absolute_path(path);
+        if fs::stat(&absolute)?.file_type != FileType::Directory {
+            writeln!(terminal, "{path}")?;
+            continue;
+        }
+        if paths.len() > 1 {
+            let separator = if index > 0 { "\n" } else { "" };
+            writeln!(terminal, "{separator}{path}:")?;
+        }
+        let mut entries = fs::read_dir(&absolute)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let suffix = match entry.file_type {
+                FileType::Directory => "/",
+                FileType::Symlink => "@",
+                _ => "",
+            };
+            writeln!(terminal, "{}{suffix}", entry.name)?;
+        }
+    }
+    Ok(())
+}
+
+fn cat(
+    shell: &mut Shell,
+    arguments: &[&str],
+    terminal: &mut dyn Terminal,
+) -> Result<(), CommandError> {
+    if arguments.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    for path in arguments {
+        let file = fs::open(&shell.absolute_path(path), OpenFlags::READ_ONLY)?;
+        let mut buffer = [0; 512];
+        // The bytes of a character cut off at the end of the previous read.
+        let mut pending = 0;
+        loop {
+            let count = file.read(&mut buffer[pending..])?;
+            if count == 0 {
+                if pending > 0 {
+                    terminal.write_char(char::REPLACEMENT_CHARACTER)?;
+                }
+                break;
+            }
+            let end = pending + count;
+            let written = write_utf8(terminal, &buffer[..end])?;
+            buffer.copy_within(written..end, 0);
+            pending = end - written;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bytes` as UTF-8 text, replacing malformed sequences with U+FFFD. Returns how many bytes
+/// were written, which leaves out a character cut off at the end.
+fn write_utf8(terminal: &mut dyn Terminal, mut bytes: &[u8]) -> Result<usize, fmt::Error> {
+    let length = bytes.len();
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                terminal.write_str(text)?;
+                return Ok(length);
+            }
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                terminal.write_str(core::str::from_utf8(valid).unwrap_or_default())?;
+                let Some(invalid) = error.error_len() else {
+                    return Ok(length - rest.len());
+                };
+                terminal.write_char(char::REPLACEMENT_CHARACTER)?;
+                bytes = &rest[invalid..];
+            }
+        }
+    }
+}
+
+fn cd(shell: &mut Shell, arguments: &[&str], _: &mut dyn Terminal) -> Result<(), CommandError> {
+    let path = match arguments {
+        [] => String::from("/"),
+        [path] => shell.absolute_path(path),
+        _ => return Err(CommandError::Usage),
+    };
+    let path = fs::canonicalize(&path)?;
+    if fs::stat(&path)?.file_type != FileType::Directory {
+        return Err(FsError::NotADirectory.into());
+    }
+    shell.working_directory = path;
+    Ok(())
+}
+
+fn pwd(shell: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    writeln!(terminal, "{}", shell.working_directory)?;
+    Ok(())
+}
+
+fn mounts(_: &mut Shell, _: &[&str], terminal: &mut dyn Terminal) -> Result<(), CommandError> {
+    for (path, name) in fs::mounts() {
+        writeln!(terminal, "{name} on {path}")?;
+    }
+    Ok(())
+}
+
+fn mode(
+    _: &mut Shell,
+    arguments: &[&str],
+    terminal: &mut dyn Terminal,
+) -> Result<(), CommandError> {
+    match arguments {
+        [] => writeln!(terminal, "{}", console::text_mode())?,
+        [name] => {
+            let mode = TextMode::ALL
+                .into_iter()
+                .find(|mode| format!("{mode}") == *name)
+                .ok_or(CommandError::Usage)?;
+            console::set_text_mode(mode);
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn reboot(_: &mut Shell, _: &[&str], _: &mut dyn Terminal) -> Result<(), CommandError> {
+    power::reboot()
+}
+
+fn shutdown(_: &mut Shell, _: &[&str], _: &mut dyn Terminal) -> Result<(), CommandError> {
+    Err(CommandError::Shutdown(power::shutdown()))
+}
+
+/// A virtual console: the keyboard while it is shown and its screen.
+pub struct VgaTerminal {
+    console: &'static Console,
+}
+
+impl VgaTerminal {
+    /// Takes over the keyboard input of the console numbered `index`: typed keys are no longer
+    /// echoed, as the shell prints them itself.
+    pub fn new(index: usize) -> Self {
+        let console = console::get(index);
+        console.echo.store(false, Ordering::Relaxed);
+        Self { console }
+    }
+}
+
+impl fmt::Write for VgaTerminal {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        interrupts::without_interrupts(|| self.console.writer.lock().write_string(text));
+        Ok(())
+    }
+}
+
+impl Terminal for VgaTerminal {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.console.read(&mut byte);
+        byte[0]
+    }
+
+    fn clear_screen(&mut self) {
+        interrupts::without_interrupts(|| self.console.writer.lock().clear());
+    }
+
+    /// Backspace erases on the VGA console, so the cursor is moved directly.
+    fn move_left(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let mut writer = self.console.writer.lock();
+            let (row, column) = writer.position();
+            writer.set_position(row, column.saturating_sub(count));
+        });
+    }
+}
+
+/// A VT100-compatible terminal attached to COM1.
+#[derive(Debug, Default)]
+pub struct SerialTerminal;
+
+impl fmt::Write for SerialTerminal {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL_PORT.lock();
+            // `send` would turn backspace into a sequence that also erases, which the line editor
+            // does not expect.
+            for byte in text.bytes() {
+                if byte == b'\n' {
+                    port.send_raw(b'\r');
+                }
+                port.send_raw(byte);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Terminal for SerialTerminal {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        serial::read(&mut byte);
+        byte[0]
+    }
+
+    fn clear_screen(&mut self) {
+        let _ = self.write_str("\x1b[2J\x1b[H");
+    }
+}
+
+/// The entry point of the shell thread on the virtual console numbered `console`.
+pub fn run_on_console(console: u64) -> ! {
+    run(&mut VgaTerminal::new(console as usize))
+}
+
+/// The entry point of the shell thread on the serial line.
+pub fn run_on_serial(_: u64) -> ! {
+    run(&mut SerialTerminal)
+}
+
+fn run(terminal: &mut dyn Terminal) -> ! {
+    writeln!(terminal, "rust_os shell; type `help` for the commands").ok();
+    Shell::new().run(terminal)
+}
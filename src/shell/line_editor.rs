@@ -0,0 +1,445 @@
+//! Reading a line of input with editing, history and completion on a terminal that only knows
+//! how to print and move the cursor left.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+/// How many entered lines are remembered.
+const HISTORY_LENGTH: usize = 100;
+/// The width of the screen. Lines are kept short enough not to wrap, as backspace cannot move
+/// the cursor back to the previous row.
+const SCREEN_WIDTH: usize = 80;
+
+const CONTROL_A: u8 = 0x01;
+const CONTROL_C: u8 = 0x03;
+const CONTROL_E: u8 = 0x05;
+const CONTROL_L: u8 = 0x0C;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const ESCAPE: u8 = 0x1B;
+
+/// Where the shell reads keys from and writes its output to.
+pub trait Terminal: Write {
+    /// Blocks until a byte of input is available.
+    fn read_byte(&mut self) -> u8;
+
+    fn clear_screen(&mut self);
+
+    /// Moves the cursor `count` columns to the left without erasing anything.
+    fn move_left(&mut self, count: usize) {
+        for _ in 0..count {
+            let _ = self.write_char(BACKSPACE as char);
+        }
+    }
+}
+
+/// A key after escape sequences have been decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Character(u8),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Cancel,
+    ClearScreen,
+    Ignored,
+}
+
+/// Reads one key, decoding the VT100 sequences sent for the cursor and editing keys.
+fn read_key(terminal: &mut dyn Terminal) -> Key {
+    match terminal.read_byte() {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        BACKSPACE | DELETE => Key::Backspace,
+        CONTROL_A => Key::Home,
+        CONTROL_E => Key::End,
+        CONTROL_C => Key::Cancel,
+        CONTROL_L => Key::ClearScreen,
+        ESCAPE => read_escape_sequence(terminal),
+        byte @ 0x20..=0x7E => Key::Character(byte),
+        _ => Key::Ignored,
+    }
+}
+
+/// Decodes `ESC [ parameter final` and `ESC O final` after the escape byte has been read.
+fn read_escape_sequence(terminal: &mut dyn Terminal) -> Key {
+    let introducer = terminal.read_byte();
+    if introducer != b'[' && introducer != b'O' {
+        return Key::Ignored;
+    }
+    let mut parameter = 0u32;
+    loop {
+        let byte = terminal.read_byte();
+        match byte {
+            b'0'..=b'9' => parameter = parameter.saturating_mul(10) + u32::from(byte - b'0'),
+            b';' => {}
+            b'A' => return Key::Up,
+            b'B' => return Key::Down,
+            b'C' => return Key::Right,
+            b'D' => return Key::Left,
+            b'H' => return Key::Home,
+            b'F' => return Key::End,
+            b'~' => {
+                return match parameter {
+                    1 | 7 => Key::Home,
+                    3 => Key::Delete,
+                    4 | 8 => Key::End,
+                    _ => Key::Ignored,
+                };
+            }
+            _ => return Key::Ignored,
+        }
+    }
+}
+
+/// Reads lines and remembers the ones entered.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    history: VecDeque<String>,
+}
+
+/// The line being edited and where the cursor is in it, both on screen and in the text.
+struct Edit<'a> {
+    terminal: &'a mut dyn Terminal,
+    prompt: &'a str,
+    line: String,
+    cursor: usize,
+}
+
+impl Edit<'_> {
+    fn capacity(&self) -> usize {
+        SCREEN_WIDTH - 1 - self.prompt.len().min(SCREEN_WIDTH - 1)
+    }
+
+    /// Moves the cursor on screen from `self.cursor` to `position`.
+    fn move_to(&mut self, position: usize) -> fmt::Result {
+        if position < self.cursor {
+            self.terminal.move_left(self.cursor - position);
+        } else {
+            self.terminal.write_str(&self.line[self.cursor..position])?;
+        }
+        self.cursor = position;
+        Ok(())
+    }
+
+    /// Reprints the line from the cursor on after it changed there, blanking the `old_length`
+    /// columns it covered before, and puts the cursor at `position`.
+    fn redraw(&mut self, old_length: usize, position: usize) -> fmt::Result {
+        self.terminal.write_str(&self.line[self.cursor..])?;
+        let blanks = old_length.saturating_sub(self.line.len());
+        for _ in 0..blanks {
+            self.terminal.write_char(' ')?;
+        }
+        self.terminal.move_left(self.line.len() + blanks - position);
+        self.cursor = position;
+        Ok(())
+    }
+
+    /// Replaces the text between `start` and the cursor, leaving the cursor after `text`.
+    fn replace(&mut self, start: usize, text: &str) -> fmt::Result {
+        let old_length = self.line.len();
+        let end = self.cursor;
+        self.move_to(start)?;
+        let room = self.capacity() - (old_length - (end - start));
+        let text = &text[..text.len().min(room)];
+        self.line.replace_range(start..end, text);
+        self.redraw(old_length, start + text.len())
+    }
+
+    /// Replaces the whole line, as when going through the history.
+    fn set_line(&mut self, line: &str) -> fmt::Result {
+        let length = self.line.len();
+        self.move_to(length)?;
+        self.replace(0, line)
+    }
+
+    fn print_prompt(&mut self) -> fmt::Result {
+        self.terminal.write_str(self.prompt)?;
+        self.terminal.write_str(&self.line)?;
+        self.cursor = self.line.len();
+        Ok(())
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The remembered lines, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Prints `prompt` and reads a line, which is returned without its line ending. Returns
+    /// `None` when the line is cancelled with Ctrl-C.
+    ///
+    /// On Tab, `complete` is given the text before the cursor and returns what the word being
+    /// typed may be completed to.
+    pub fn read_line(
+        &mut self,
+        terminal: &mut dyn Terminal,
+        prompt: &str,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> Result<Option<String>, fmt::Error> {
+        let mut edit = Edit {
+            terminal,
+            prompt,
+            line: String::new(),
+            cursor: 0,
+        };
+        // The entry shown from the history, and the line typed before going there.
+        let mut history_index = self.history.len();
+        let mut draft = String::new();
+        edit.print_prompt()?;
+        loop {
+            match read_key(edit.terminal) {
+                Key::Character(byte) => {
+                    if edit.line.len() < edit.capacity() {
+                        let old_length = edit.line.len();
+                        edit.line.insert(edit.cursor, byte as char);
+                        let position = edit.cursor + 1;
+                        edit.redraw(old_length, position)?;
+                    }
+                }
+                Key::Backspace if edit.cursor > 0 => {
+                    let start = edit.cursor - 1;
+                    edit.replace(start, "")?;
+                }
+                Key::Delete if edit.cursor < edit.line.len() => {
+                    let old_length = edit.line.len();
+                    edit.line.remove(edit.cursor);
+                    let position = edit.cursor;
+                    edit.redraw(old_length, position)?;
+                }
+                Key::Left if edit.cursor > 0 => edit.move_to(edit.cursor - 1)?,
+                Key::Right if edit.cursor < edit.line.len() => edit.move_to(edit.cursor + 1)?,
+                Key::Home => edit.move_to(0)?,
+                Key::End => edit.move_to(edit.line.len())?,
+                Key::Up if history_index > 0 => {
+                    if history_index == self.history.len() {
+                        draft = edit.line.clone();
+                    }
+                    history_index -= 1;
+                    edit.set_line(&self.history[history_index])?;
+                }
+                Key::Down if history_index < self.history.len() => {
+                    history_index += 1;
+                    let line = self.history.get(history_index).unwrap_or(&draft);
+                    edit.set_line(line)?;
+                }
+                Key::Tab => complete_word(&mut edit, complete)?,
+                Key::ClearScreen => {
+                    edit.terminal.clear_screen();
+                    edit.print_prompt()?;
+                }
+                Key::Cancel => {
+                    edit.terminal.write_str("^C\n")?;
+                    return Ok(None);
+                }
+                Key::Enter => {
+                    edit.move_to(edit.line.len())?;
+                    edit.terminal.write_char('\n')?;
+                    self.remember(&edit.line);
+                    return Ok(Some(edit.line));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds `line` to the history unless it is blank or the same as the last entry.
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+}
+
+/// Completes the word before the cursor if there is one candidate, extends it to the prefix
+/// all candidates share, or else lists them.
+fn complete_word(edit: &mut Edit, complete: &dyn Fn(&str) -> Vec<String>) -> fmt::Result {
+    let before = &edit.line[..edit.cursor];
+    let start = before.rfind(' ').map_or(0, |space| space + 1);
+    let word = String::from(&before[start..]);
+    let candidates = complete(before);
+    match candidates.as_slice() {
+        [] => Ok(()),
+        [only] => {
+            let mut completed = only.clone();
+            // A directory is left open so that its entries can be completed next.
+            if !completed.ends_with('/') {
+                completed.push(' ');
+            }
+            edit.replace(start, &completed)
+        }
+        [first, rest @ ..] => {
+            let common = rest.iter().fold(first.len(), |length, candidate| {
+                first
+                    .bytes()
+                    .zip(candidate.bytes())
+                    .take(length)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+            if common > word.len() {
+                return edit.replace(start, &first[..common]);
+            }
+            let position = edit.cursor;
+            let length = edit.line.len();
+            edit.move_to(length)?;
+            edit.terminal.write_char('\n')?;
+            for candidate in &candidates {
+                write!(edit.terminal, "{candidate}  ")?;
+            }
+            edit.terminal.write_char('\n')?;
+            edit.print_prompt()?;
+            edit.move_to(position)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineEditor, Terminal};
+    use alloc::{collections::VecDeque, string::String, vec, vec::Vec};
+    use core::fmt;
+
+    /// Replays scripted input and keeps the output as rows, moving back on backspace like a
+    /// screen does.
+    struct ScriptedTerminal {
+        input: VecDeque<u8>,
+        rows: Vec<Vec<u8>>,
+        column: usize,
+    }
+
+    impl ScriptedTerminal {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: input.iter().copied().collect(),
+                rows: vec![Vec::new()],
+                column: 0,
+            }
+        }
+
+        /// The row `from_end` rows above the last one, without trailing blanks.
+        fn row(&self, from_end: usize) -> String {
+            let row = &self.rows[self.rows.len() - 1 - from_end];
+            String::from(String::from_utf8_lossy(row).trim_end())
+        }
+    }
+
+    impl fmt::Write for ScriptedTerminal {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            for byte in text.bytes() {
+                let row = self.rows.last_mut().unwrap();
+                match byte {
+                    0x08 => self.column = self.column.saturating_sub(1),
+                    b'\n' => {
+                        self.rows.push(Vec::new());
+                        self.column = 0;
+                    }
+                    _ if self.column < row.len() => {
+                        row[self.column] = byte;
+                        self.column += 1;
+                    }
+                    _ => {
+                        row.push(byte);
+                        self.column += 1;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Terminal for ScriptedTerminal {
+        fn read_byte(&mut self) -> u8 {
+            self.input
+                .pop_front()
+                .expect("the line editor read past the script")
+        }
+
+        fn clear_screen(&mut self) {
+            self.rows = vec![Vec::new()];
+            self.column = 0;
+        }
+    }
+
+    fn no_completion(_: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn read(editor: &mut LineEditor, input: &[u8]) -> (Option<String>, ScriptedTerminal) {
+        let mut terminal = ScriptedTerminal::new(input);
+        let line = editor
+            .read_line(&mut terminal, "> ", &no_completion)
+            .unwrap();
+        (line, terminal)
+    }
+
+    #[test_case]
+    fn test_cursor_movement_and_editing() {
+        let mut editor = LineEditor::new();
+        // Type "helo", go left, insert "l", then Home, Delete and End.
+        let (line, _) = read(&mut editor, b"helo\x1b[Dl\x1b[H\x1b[3~X\x1b[Fs\n");
+        assert_eq!(line.as_deref(), Some("Xellos"));
+        let (line, _) = read(&mut editor, b"abc\x08\x7fd\x01e\x05f\r");
+        assert_eq!(line.as_deref(), Some("eadf"));
+        let (line, terminal) = read(&mut editor, b"gone\x03");
+        assert_eq!(line, None);
+        assert_eq!(terminal.row(1), "> gone^C");
+    }
+
+    #[test_case]
+    fn test_history() {
+        let mut editor = LineEditor::new();
+        read(&mut editor, b"first\n");
+        read(&mut editor, b"second\n");
+        read(&mut editor, b"second\n");
+        read(&mut editor, b"   \n");
+        assert_eq!(editor.history().collect::<Vec<_>>(), ["first", "second"]);
+
+        let (line, terminal) = read(&mut editor, b"dra\x1b[A\x1b[A\x1b[A\n");
+        assert_eq!(line.as_deref(), Some("first"));
+        // The longer entry shown before was blanked out.
+        assert_eq!(terminal.row(1), "> first");
+        let (line, _) = read(&mut editor, b"dra\x1b[A\x1b[B\x1b[Bft\n");
+        assert_eq!(line.as_deref(), Some("draft"));
+    }
+
+    #[test_case]
+    fn test_tab_completion() {
+        let complete = |before: &str| -> Vec<String> {
+            let word = before.rsplit(' ').next().unwrap();
+            ["docs/", "download", "hello.txt"]
+                .into_iter()
+                .filter(|name| name.starts_with(word))
+                .map(String::from)
+                .collect()
+        };
+        let mut editor = LineEditor::new();
+        let mut terminal = ScriptedTerminal::new(b"cat h\t\n");
+        let line = editor.read_line(&mut terminal, "> ", &complete).unwrap();
+        assert_eq!(line.as_deref(), Some("cat hello.txt "));
+
+        // "d" extends to the shared "do", then a second Tab lists both candidates.
+        let mut terminal = ScriptedTerminal::new(b"ls d\t\t\tc\t\n");
+        let line = editor.read_line(&mut terminal, "> ", &complete).unwrap();
+        assert_eq!(line.as_deref(), Some("ls docs/"));
+        assert_eq!(terminal.row(4), "docs/  download");
+        assert_eq!(terminal.row(1), "> ls docs/");
+        assert_eq!(editor.history().last(), Some("ls docs/"));
+    }
+}
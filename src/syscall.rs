@@ -0,0 +1,567 @@
+use core::arch::naked_asm;
+use x86_64::{
+    VirtAddr,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{Page, Size4KiB},
+};
+
+use crate::{
+    fs::{FileType, FsError},
+    gdt, interupt,
+    memory::{self, Access, AddressSpace, Backing, Protection, VirtualMemoryArea},
+    process, scheduler,
+};
+
+/// Linux-compatible system call numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallNumber {
+    Read = 0,
+    Write = 1,
+    Mmap = 9,
+    Mprotect = 10,
+    Munmap = 11,
+    Yield = 24,
+    NanoSleep = 35,
+    GetPid = 39,
+    Fork = 57,
+    Exit = 60,
+    Wait4 = 61,
+}
+
+/// Linux-compatible error numbers, returned to user space negated in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    NoEntry = 2,
+    Io = 5,
+    BadFileDescriptor = 9,
+    NoChild = 10,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    Busy = 16,
+    Exists = 17,
+    CrossDevice = 18,
+    NoDevice = 19,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    NoSpace = 28,
+    IllegalSeek = 29,
+    ReadOnlyFileSystem = 30,
+    NameTooLong = 36,
+    NotImplemented = 38,
+    DirectoryNotEmpty = 39,
+    TooManyLinks = 40,
+    NotSupported = 95,
+}
+
+impl From<FsError> for Errno {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotFound => Errno::NoEntry,
+            FsError::NotADirectory => Errno::NotADirectory,
+            FsError::IsADirectory => Errno::IsADirectory,
+            FsError::AlreadyExists => Errno::Exists,
+            FsError::DirectoryNotEmpty => Errno::DirectoryNotEmpty,
+            FsError::InvalidPath | FsError::InvalidArgument => Errno::InvalidArgument,
+            FsError::NameTooLong => Errno::NameTooLong,
+            FsError::AccessMode => Errno::BadFileDescriptor,
+            FsError::ReadOnly => Errno::ReadOnlyFileSystem,
+            FsError::NotSeekable => Errno::IllegalSeek,
+            FsError::NoSpace => Errno::NoSpace,
+            FsError::Busy => Errno::Busy,
+            FsError::CrossDevice => Errno::CrossDevice,
+            FsError::TooManyLinks => Errno::TooManyLinks,
+            FsError::Io => Errno::Io,
+            FsError::Unsupported => Errno::NotSupported,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Where `mmap` places mappings when the caller does not ask for a fixed address.
+const MMAP_BASE: u64 = 0x0000_6000_0000_0000;
+
+const SYSCALL_TABLE_SIZE: usize = 64;
+static SYSCALL_TABLE: [Option<SyscallHandler>; SYSCALL_TABLE_SIZE] = {
+    let mut table: [Option<SyscallHandler>; SYSCALL_TABLE_SIZE] = [None; SYSCALL_TABLE_SIZE];
+    table[SyscallNumber::Read as usize] = Some(sys_read);
+    table[SyscallNumber::Write as usize] = Some(sys_write);
+    table[SyscallNumber::Mmap as usize] = Some(sys_mmap);
+    table[SyscallNumber::Mprotect as usize] = Some(sys_mprotect);
+    table[SyscallNumber::Munmap as usize] = Some(sys_munmap);
+    table[SyscallNumber::Yield as usize] = Some(sys_yield);
+    table[SyscallNumber::NanoSleep as usize] = Some(sys_nanosleep);
+    table[SyscallNumber::GetPid as usize] = Some(sys_getpid);
+    table[SyscallNumber::Fork as usize] = Some(sys_fork);
+    table[SyscallNumber::Exit as usize] = Some(sys_exit);
+    table[SyscallNumber::Wait4 as usize] = Some(sys_wait4);
+    table
+};
+
+/// The user registers saved by [`syscall_entry`], in the order they are pushed.
+///
+/// `rcx` holds the user `rip` and `r11` the user `rflags`, as stored by the `syscall` instruction.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/// The kernel stack pointer saved when the current thread entered ring 3. System calls and
+/// interrupts from ring 3 run on the stack below it, and `exit` unwinds back to it.
+static mut USER_RETURN_STACK_POINTER: u64 = 0;
+/// Scratch slot for the user stack pointer while switching to the kernel stack.
+static mut USER_STACK_POINTER: u64 = 0;
+
+/// The only `rflags` bits user code may choose; interrupts are always enabled in ring 3.
+const USER_RFLAGS_MASK: u64 = RFlags::CARRY_FLAG.bits()
+    | RFlags::PARITY_FLAG.bits()
+    | RFlags::AUXILIARY_CARRY_FLAG.bits()
+    | RFlags::ZERO_FLAG.bits()
+    | RFlags::SIGN_FLAG.bits()
+    | RFlags::DIRECTION_FLAG.bits()
+    | RFlags::OVERFLOW_FLAG.bits();
+const USER_RFLAGS: u64 = RFlags::INTERRUPT_FLAG.bits() | 0x2;
+
+pub fn init() {
+    let selectors = &*gdt::GLOBAL_DESCRIPTOR_TABLE;
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT segment order is incompatible with syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Interrupts stay disabled until the handler has switched to the kernel stack; it enables
+    // them while the system call runs and disables them again before returning to the user stack.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// The stack pointer [`enter_user_mode`] will unwind to for the current thread.
+pub fn user_return_stack_pointer() -> u64 {
+    unsafe { USER_RETURN_STACK_POINTER }
+}
+
+/// Switches the kernel stack used for entries from ring 3, when switching threads.
+///
+/// # Safety
+/// The value must have been returned by [`user_return_stack_pointer`] for the thread about to
+/// run, or be zero for a thread that has never entered ring 3.
+pub unsafe fn set_user_return_stack_pointer(stack_pointer: u64) {
+    unsafe { USER_RETURN_STACK_POINTER = stack_pointer };
+    if stack_pointer != 0 {
+        gdt::set_kernel_privilege_stack(VirtAddr::new(stack_pointer));
+    }
+}
+
+/// Runs user code at `entry` on `user_stack` until it exits, returning its exit code.
+///
+/// # Safety
+/// `entry` and `user_stack` must point into pages mapped with `USER_ACCESSIBLE` in the active
+/// page table.
+pub unsafe fn enter_user_mode(entry: VirtAddr, user_stack: VirtAddr) -> i64 {
+    let frame = SyscallFrame {
+        rcx: entry.as_u64(),
+        r11: USER_RFLAGS,
+        rsp: user_stack.as_u64(),
+        ..SyscallFrame::default()
+    };
+    unsafe { enter_user_mode_with_frame(&frame) }
+}
+
+/// Resumes user code with every register taken from `frame`, returning the exit code once it
+/// exits. `frame.rcx` is the instruction pointer and `frame.r11` the flags.
+///
+/// # Safety
+/// See [`enter_user_mode`].
+pub unsafe fn enter_user_mode_with_frame(frame: &SyscallFrame) -> i64 {
+    let entry = VirtAddr::new(frame.rcx);
+    let user_stack = VirtAddr::new(frame.rsp);
+    assert!(
+        memory::is_user_range(entry, 1),
+        "entry point outside of user space"
+    );
+    assert!(
+        memory::is_user_range(user_stack - 1u64, 1),
+        "stack outside of user space"
+    );
+    let mut frame = *frame;
+    frame.r11 = (frame.r11 & USER_RFLAGS_MASK) | USER_RFLAGS;
+
+    let interrupts_were_enabled = x86_64::instructions::interrupts::are_enabled();
+    let exit_code = unsafe { enter_user_mode_raw(&frame) };
+    if interrupts_were_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+    exit_code
+}
+
+/// Stops the user code running on the current thread and makes its [`enter_user_mode`] call
+/// return `exit_code`. Used by `exit` and by fault handlers.
+///
+/// # Safety
+/// Must only be called while handling a system call or exception raised in ring 3.
+pub unsafe fn exit_user_mode(exit_code: i64) -> ! {
+    unsafe { return_to_kernel(exit_code) }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user_mode_raw(frame: *const SyscallFrame) -> i64 {
+    naked_asm!(
+        "cli",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // Padding so the stack stays 16-byte aligned below the saved stack pointer.
+        "push 0",
+        "mov [rip + {return_stack_pointer}], rsp",
+        "mov [rip + {task_state_segment} + {privilege_stack_offset}], rsp",
+        "mov r15, [rdi + 0x00]",
+        "mov r14, [rdi + 0x08]",
+        "mov r13, [rdi + 0x10]",
+        "mov r12, [rdi + 0x18]",
+        "mov r11, [rdi + 0x20]",
+        "mov r10, [rdi + 0x28]",
+        "mov r9, [rdi + 0x30]",
+        "mov r8, [rdi + 0x38]",
+        "mov rbp, [rdi + 0x40]",
+        "mov rsi, [rdi + 0x50]",
+        "mov rdx, [rdi + 0x58]",
+        "mov rcx, [rdi + 0x60]",
+        "mov rbx, [rdi + 0x68]",
+        "mov rax, [rdi + 0x70]",
+        "mov rsp, [rdi + 0x78]",
+        "mov rdi, [rdi + 0x48]",
+        "sysretq",
+        return_stack_pointer = sym USER_RETURN_STACK_POINTER,
+        task_state_segment = sym gdt::TASK_STATE_SEGMENT,
+        privilege_stack_offset = const gdt::KERNEL_PRIVILEGE_STACK_OFFSET,
+    )
+}
+
+/// Abandons the current syscall and resumes the kernel code that called [`enter_user_mode`].
+#[unsafe(naked)]
+unsafe extern "C" fn return_to_kernel(exit_code: i64) -> ! {
+    naked_asm!(
+        "cli",
+        "mov rsp, [rip + {return_stack_pointer}]",
+        "mov rax, rdi",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        return_stack_pointer = sym USER_RETURN_STACK_POINTER,
+    )
+}
+
+/// The `LSTAR` target: saves the user registers on the kernel stack and calls the dispatcher with
+/// interrupts enabled, so that timer ticks and keystrokes arriving during a long system call are
+/// not lost. Kernel code is not preempted, so the system call still runs until it blocks or
+/// returns.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() -> ! {
+    naked_asm!(
+        "mov [rip + {user_stack_pointer}], rsp",
+        "mov rsp, [rip + {return_stack_pointer}]",
+        "push qword ptr [rip + {user_stack_pointer}]",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sti",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "cli",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "pop rsp",
+        "sysretq",
+        user_stack_pointer = sym USER_STACK_POINTER,
+        return_stack_pointer = sym USER_RETURN_STACK_POINTER,
+        dispatch = sym syscall_dispatch,
+    )
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let handler = SYSCALL_TABLE.get(frame.rax as usize).copied().flatten();
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => Err(Errno::NotImplemented),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+}
+
+fn user_buffer(address: u64, length: u64, writable: bool) -> Result<&'static mut [u8], Errno> {
+    let start = VirtAddr::try_new(address).map_err(|_| Errno::BadAddress)?;
+    if length > 0 && memory::is_user_range(start, length) {
+        // Populate lazy pages and copy shared ones up front, so the kernel never writes to a
+        // frame another process still maps.
+        let access = if writable {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        let first_page: Page = Page::containing_address(start);
+        let last_page: Page = Page::containing_address(start + (length - 1));
+        for page in Page::range_inclusive(first_page, last_page) {
+            if !memory::is_user_accessible(page.start_address(), 1, writable) {
+                memory::handle_user_fault(page.start_address(), access)
+                    .map_err(|_| Errno::OutOfMemory)?;
+            }
+        }
+    }
+    if !memory::is_user_accessible(start, length, writable) {
+        return Err(Errno::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), length as usize) })
+}
+
+fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let [file_descriptor, address, length, ..] = frame.arguments();
+    let file = process::current_file(file_descriptor).ok_or(Errno::BadFileDescriptor)?;
+    let buffer = user_buffer(address, length, true)?;
+    Ok(file.read(buffer)? as u64)
+}
+
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let [file_descriptor, address, length, ..] = frame.arguments();
+    let file = process::current_file(file_descriptor).ok_or(Errno::BadFileDescriptor)?;
+    let buffer = user_buffer(address, length, false)?;
+    Ok(file.write(buffer)? as u64)
+}
+
+fn protection(bits: u64) -> Result<Protection, Errno> {
+    if bits & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    Ok(Protection {
+        read: bits & PROT_READ != 0,
+        write: bits & PROT_WRITE != 0,
+        execute: bits & PROT_EXEC != 0,
+    })
+}
+
+/// Validates the page-aligned range passed to `munmap` and `mprotect`.
+fn user_pages(address: u64, length: u64) -> Result<(Page, u64), Errno> {
+    let start = VirtAddr::try_new(address).map_err(|_| Errno::InvalidArgument)?;
+    if length == 0 || !start.is_aligned(Page::<Size4KiB>::SIZE) {
+        return Err(Errno::InvalidArgument);
+    }
+    let page_count = length.div_ceil(Page::<Size4KiB>::SIZE);
+    if !memory::is_user_range(start, page_count * Page::<Size4KiB>::SIZE) {
+        return Err(Errno::InvalidArgument);
+    }
+    Ok((Page::containing_address(start), page_count))
+}
+
+/// Reserves an area whose pages are populated on first access, either zero-filled or read from
+/// a file. Exactly one of `MAP_PRIVATE` and `MAP_SHARED` must be given, and only private
+/// mappings are supported.
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [
+        address,
+        length,
+        protection_bits,
+        flags,
+        file_descriptor,
+        offset,
+    ] = frame.arguments();
+    if length == 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    match flags & (MAP_PRIVATE | MAP_SHARED) {
+        MAP_PRIVATE => {}
+        MAP_SHARED => return Err(Errno::NotSupported),
+        _ => return Err(Errno::InvalidArgument),
+    }
+    let protection = protection(protection_bits)?;
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        Backing::Anonymous
+    } else {
+        if offset % Page::<Size4KiB>::SIZE != 0 {
+            return Err(Errno::InvalidArgument);
+        }
+        let file = process::current_file(file_descriptor).ok_or(Errno::BadFileDescriptor)?;
+        let inode = file.inode().ok_or(Errno::NoDevice)?;
+        if inode.metadata().file_type != FileType::Regular {
+            return Err(Errno::NoDevice);
+        }
+        Backing::File { inode, offset }
+    };
+    let mut address_space = AddressSpace::active();
+    let start = if flags & MAP_FIXED != 0 {
+        let (start, page_count) = user_pages(address, length)?;
+        address_space
+            .unmap_range(start, page_count)
+            .map_err(|_| Errno::InvalidArgument)?;
+        start.start_address()
+    } else {
+        let length = length
+            .checked_next_multiple_of(Page::<Size4KiB>::SIZE)
+            .ok_or(Errno::OutOfMemory)?;
+        address_space
+            .find_free_range(length, VirtAddr::new(MMAP_BASE))
+            .ok_or(Errno::OutOfMemory)?
+    };
+    let end = (start + length).align_up(Page::<Size4KiB>::SIZE);
+    address_space
+        .map_area(VirtualMemoryArea::new(start, end, protection, backing))
+        .map_err(|_| Errno::OutOfMemory)?;
+    Ok(start.as_u64())
+}
+
+fn sys_mprotect(frame: &mut SyscallFrame) -> SyscallResult {
+    let [address, length, protection_bits, ..] = frame.arguments();
+    let (start, page_count) = user_pages(address, length)?;
+    let protection = protection(protection_bits)?;
+    AddressSpace::active()
+        .protect_range(start, page_count, protection)
+        .map_err(|_| Errno::OutOfMemory)?;
+    Ok(0)
+}
+
+fn sys_munmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [address, length, ..] = frame.arguments();
+    let (start, page_count) = user_pages(address, length)?;
+    AddressSpace::active()
+        .unmap_range(start, page_count)
+        .map_err(|_| Errno::InvalidArgument)?;
+    Ok(0)
+}
+
+fn sys_yield(_frame: &mut SyscallFrame) -> SyscallResult {
+    scheduler::yield_now();
+    Ok(0)
+}
+
+/// Sleeps for the `struct timespec` at `arg0`, rounded up to whole timer ticks. A sleep is never
+/// cut short, so the remaining time `arg1` would receive is always zero and is not written.
+fn sys_nanosleep(frame: &mut SyscallFrame) -> SyscallResult {
+    let [request, ..] = frame.arguments();
+    let request = user_buffer(request, 16, false)?;
+    let seconds = i64::from_ne_bytes(request[..8].try_into().unwrap());
+    let nanoseconds = i64::from_ne_bytes(request[8..].try_into().unwrap());
+    if seconds < 0 || !(0..1_000_000_000).contains(&nanoseconds) {
+        return Err(Errno::InvalidArgument);
+    }
+    let milliseconds = (seconds as u64)
+        .saturating_mul(1000)
+        .saturating_add((nanoseconds as u64).div_ceil(1_000_000));
+    scheduler::sleep(interupt::pit::milliseconds_to_ticks(milliseconds));
+    Ok(0)
+}
+
+/// Kernel threads that entered ring 3 directly report pid 0.
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::current_pid().unwrap_or(0))
+}
+
+/// Returns the child's pid to the parent and 0 to the child, which resumes from the same frame.
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    match process::fork(frame) {
+        Ok(pid) => Ok(pid),
+        Err(process::ForkError::NotAProcess) => Err(Errno::InvalidArgument),
+        Err(process::ForkError::Memory(_)) => Err(Errno::OutOfMemory),
+    }
+}
+
+/// Waits for a child to exit. Only waiting for a specific pid is supported; the status word
+/// follows Linux, with the exit code in bits 8 to 15.
+fn sys_wait4(frame: &mut SyscallFrame) -> SyscallResult {
+    let [pid, status_address, options, ..] = frame.arguments();
+    if options != 0 || pid as i64 <= 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let parent = process::with_process(pid, |process| process.parent).flatten();
+    if parent.is_none() || parent != process::current_pid() {
+        return Err(Errno::NoChild);
+    }
+    let exit_code = process::wait(pid).map_err(|_| Errno::NoChild)?;
+    if status_address != 0 {
+        let status = ((exit_code as u32 & 0xff) << 8).to_ne_bytes();
+        user_buffer(status_address, status.len() as u64, true)?.copy_from_slice(&status);
+    }
+    Ok(pid)
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    let [exit_code, ..] = frame.arguments();
+    unsafe { return_to_kernel(exit_code as i64) }
+}
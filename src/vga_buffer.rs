@@ -1,13 +1,22 @@
+mod ansi;
+mod cp437;
+mod text_mode;
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
 use core::fmt;
 use spin::Lazy;
 use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use ansi::{Action, ControlSequence, Parser};
+pub use text_mode::TextMode;
 
 #[allow(dead_code)]
 pub static VGA_WRITER: Lazy<Mutex<VgaWriter>> = Lazy::new(|| {
-    Mutex::new(VgaWriter::new(ColorCode::new(
-        Color::LightBlue,
-        Color::Black,
-    )))
+    let mut writer = VgaWriter::new(DEFAULT_COLOR_CODE);
+    writer.enable_cursor(CursorShape::Underline);
+    disable_blinking();
+    Mutex::new(writer)
 });
 
 /// The standard color palette in VGA text mode.
@@ -38,12 +47,49 @@ pub enum Color {
 #[repr(transparent)]
 pub struct ColorCode(u8);
 
+/// The colors of the kernel's consoles.
+pub const DEFAULT_COLOR_CODE: ColorCode = ColorCode::new(Color::LightBlue, Color::Black);
+/// The colors of error messages printed with `eprint!` and `eprintln!`.
+pub const ERROR_COLOR_CODE: ColorCode = ColorCode::new(Color::LightRed, Color::Black);
+/// The colors of the message of a kernel panic.
+pub const PANIC_COLOR_CODE: ColorCode = ColorCode::new(Color::White, Color::Red);
+
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn foreground(self) -> u8 {
+        self.0 & 0x0F
+    }
+
+    fn background(self) -> u8 {
+        self.0 >> 4
+    }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode(self.0 & 0xF0 | foreground & 0x0F)
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode((background & 0x0F) << 4 | self.0 & 0x0F)
+    }
 }
 
+/// The eight ANSI colors in the order of their SGR codes. Adding [`BRIGHT`] gives the bright
+/// variant of each.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::Gray,
+];
+const BRIGHT: u8 = 8;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct VgaCharacter {
@@ -51,107 +97,841 @@ pub struct VgaCharacter {
     pub character_color: ColorCode,
 }
 
-/// The height of the text buffer (normally 25 lines).
+/// The height of the text buffer in the 80x25 mode the BIOS leaves the screen in.
 const BUFFER_HEIGHT: usize = 25;
-/// The width of the text buffer (normally 80 columns).
+/// The width of the text buffer in the 80x25 mode.
 const BUFFER_WIDTH: usize = 80;
+/// The largest text mode, 90x60, sets how much of the text buffer writers use.
+const MAX_WIDTH: usize = 90;
+const MAX_HEIGHT: usize = 60;
+const BUFFER_SIZE: usize = MAX_WIDTH * MAX_HEIGHT;
 const VGA_BUFFER_ADDRESS: u32 = 0xb8000;
 
+/// How many rows that scrolled off the screen are kept once the heap is available.
+pub const DEFAULT_SCROLLBACK_ROWS: usize = 500;
+
+/// A row of the screen, of which only the first `width` characters are shown.
+type Row = [VgaCharacter; MAX_WIDTH];
+
+/// Columns between tab stops.
+const TAB_WIDTH: usize = 8;
+const BACKSPACE: u8 = 0x08;
+const FORM_FEED: u8 = 0x0C;
+
+/// The CRT controller registers are reached by writing their index to the first port and then
+/// accessing the second.
+const CRTC_INDEX_PORT_ADDRESS: u16 = 0x3D4;
+const CRTC_DATA_PORT_ADDRESS: u16 = 0x3D5;
+const CRTC_MAXIMUM_SCAN_LINE: u8 = 0x09;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+/// The scan line fields of the cursor and maximum scan line registers.
+const SCAN_LINE_MASK: u8 = 0x1F;
+/// Set in the cursor start register to hide the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Which scan lines of a character cell the blinking cursor covers.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(C)]
-struct VgaBuffer {
-    pub chars: [[VgaCharacter; BUFFER_WIDTH]; BUFFER_HEIGHT],
+pub enum CursorShape {
+    /// The bottom two scan lines.
+    Underline,
+    /// The whole cell.
+    Block,
+}
+
+fn read_crtc(index: u8) -> u8 {
+    unsafe {
+        Port::new(CRTC_INDEX_PORT_ADDRESS).write(index);
+        Port::new(CRTC_DATA_PORT_ADDRESS).read()
+    }
+}
+
+fn write_crtc(index: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_INDEX_PORT_ADDRESS).write(index);
+        Port::new(CRTC_DATA_PORT_ADDRESS).write(value);
+    }
+}
+
+/// Reading the input status register resets the attribute controller to expect an index.
+const INPUT_STATUS_1_PORT_ADDRESS: u16 = 0x3DA;
+const ATTRIBUTE_PORT_ADDRESS: u16 = 0x3C0;
+const ATTRIBUTE_DATA_READ_PORT_ADDRESS: u16 = 0x3C1;
+const ATTRIBUTE_MODE_CONTROL: u8 = 0x10;
+/// Kept set in the attribute index, or the screen goes blank.
+const PALETTE_ADDRESS_SOURCE: u8 = 1 << 5;
+const BLINK_ENABLE: u8 = 1 << 3;
+
+/// Makes the top bit of the background color select the bright colors instead of blinking.
+fn disable_blinking() {
+    unsafe {
+        let mut attribute: Port<u8> = Port::new(ATTRIBUTE_PORT_ADDRESS);
+        Port::<u8>::new(INPUT_STATUS_1_PORT_ADDRESS).read();
+        attribute.write(ATTRIBUTE_MODE_CONTROL | PALETTE_ADDRESS_SOURCE);
+        let mode = Port::<u8>::new(ATTRIBUTE_DATA_READ_PORT_ADDRESS).read();
+        attribute.write(mode & !BLINK_ENABLE);
+    }
+}
+
+/// The cursor and colors saved by `ESC 7` and restored by `ESC 8`.
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    row: usize,
+    column: usize,
+    color_code: ColorCode,
+    bold: bool,
+}
+
+/// The characters of a screen, row after row, each row as wide as the text mode.
+type VgaBuffer = [VgaCharacter];
+
+/// Copies the first `count` characters of `from` with volatile accesses, as either may be the
+/// text buffer.
+fn copy_cells(from: &VgaBuffer, to: &mut VgaBuffer, count: usize) {
+    for (from, to) in from[..count].iter().zip(&mut to[..count]) {
+        unsafe { core::ptr::write_volatile(to, core::ptr::read_volatile(from)) };
+    }
+}
+
+fn vga_memory() -> &'static mut VgaBuffer {
+    unsafe { core::slice::from_raw_parts_mut(VGA_BUFFER_ADDRESS as *mut VgaCharacter, BUFFER_SIZE) }
 }
 
 pub struct VgaWriter {
+    /// The VGA text buffer while the writer is shown, its own buffer otherwise.
     buffer: &'static mut VgaBuffer,
+    /// Whether the writer draws on the screen, rather than into a buffer of its own.
+    visible: bool,
+    /// The buffer the writer draws into while hidden, from [`VgaWriter::reserve_off_screen`] for
+    /// a writer created on the screen.
+    off_screen: Option<&'static mut VgaBuffer>,
+    /// The shape of the hardware cursor, or `None` while it is hidden.
+    cursor: Option<CursorShape>,
+    cursor_location: (usize, usize),
+    mode: TextMode,
+    width: usize,
+    height: usize,
+    row: usize,
     column: usize,
     color_code: ColorCode,
+    /// The colors the writer was created with, which SGR 0 returns to.
+    default_color_code: ColorCode,
+    /// Whether SGR 1 asked for bright foreground colors.
+    bold: bool,
+    /// The first and last row that scroll, both inclusive.
+    scroll_region: (usize, usize),
+    saved_cursor: SavedCursor,
+    parser: Parser,
+    /// Rows that scrolled off the top of the screen, oldest first.
+    scrollback: VecDeque<Row>,
+    scrollback_limit: usize,
+    /// How many rows back into the scrollback the screen shows; zero for the live view.
+    view_offset: usize,
+    /// The live screen, put aside while the scrollback is shown. Its space is reserved along with
+    /// the scrollback.
+    live_screen: Vec<Row>,
 }
 
 #[allow(dead_code)]
 impl VgaWriter {
+    /// Creates a writer that starts on the bottom row, so that output scrolls up from there.
     pub fn new(color_code: ColorCode) -> Self {
+        let row = BUFFER_HEIGHT - 1;
         Self {
-            buffer: unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut VgaBuffer) },
+            buffer: vga_memory(),
+            visible: true,
+            off_screen: None,
+            cursor: None,
+            cursor_location: (row, 0),
+            mode: TextMode::Text80x25,
+            width: BUFFER_WIDTH,
+            height: BUFFER_HEIGHT,
             color_code,
+            default_color_code: color_code,
+            bold: false,
+            row,
             column: 0,
+            scroll_region: (0, BUFFER_HEIGHT - 1),
+            saved_cursor: SavedCursor {
+                row,
+                column: 0,
+                color_code,
+                bold: false,
+            },
+            parser: Parser::new(),
+            scrollback: VecDeque::new(),
+            scrollback_limit: 0,
+            view_offset: 0,
+            live_screen: Vec::new(),
         }
     }
 
-    pub fn write_byte(&mut self, ascii_character: u8) {
-        if ascii_character == b'\n' {
-            self.new_line();
+    /// Creates a writer for a console in the background: it draws into a blank buffer of its own
+    /// until it is shown.
+    pub fn new_off_screen() -> Self {
+        let blank = VgaCharacter {
+            ascii_character: b' ',
+            character_color: DEFAULT_COLOR_CODE,
+        };
+        let buffer = vec![blank; BUFFER_SIZE].leak();
+        Self {
+            buffer,
+            visible: false,
+            cursor: Some(CursorShape::Underline),
+            ..Self::new(DEFAULT_COLOR_CODE)
+        }
+    }
+
+    /// Whether the writer draws on the screen.
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Allocates the buffer a writer created on the screen draws into once hidden, so that
+    /// [`VgaWriter::hide`] can run in the keyboard interrupt, where the heap must not be used.
+    pub fn reserve_off_screen(&mut self) {
+        if self.visible && self.off_screen.is_none() {
+            self.off_screen = Some(vec![self.blank(); BUFFER_SIZE].leak());
+        }
+    }
+
+    /// Copies the screen into a buffer of the writer's own and keeps drawing there, leaving the
+    /// screen to another writer.
+    ///
+    /// # Panics
+    ///
+    /// If the writer was created on the screen and [`VgaWriter::reserve_off_screen`] has not been
+    /// called.
+    pub fn hide(&mut self) {
+        if !self.visible {
             return;
         }
+        self.show_live_view();
+        let off_screen = self
+            .off_screen
+            .take()
+            .expect("the off-screen buffer should be reserved before hiding the writer");
+        copy_cells(self.buffer, off_screen, self.width * self.height);
+        self.buffer = off_screen;
+        self.visible = false;
+    }
 
-        if self.column >= BUFFER_WIDTH {
-            self.new_line();
+    /// Puts the writer's buffer on the screen, along with its hardware cursor, and draws there
+    /// from now on.
+    pub fn show(&mut self) {
+        if self.visible {
+            return;
+        }
+        let screen = vga_memory();
+        copy_cells(self.buffer, screen, self.width * self.height);
+        self.off_screen = Some(core::mem::replace(&mut self.buffer, screen));
+        self.visible = true;
+        match self.cursor {
+            Some(shape) => self.enable_cursor(shape),
+            None => self.disable_cursor(),
         }
+        self.move_cursor();
+    }
 
-        let row: usize = BUFFER_HEIGHT - 1;
-        unsafe {
-            core::ptr::write_volatile(
-                &mut self.buffer.chars[row][self.column] as *mut VgaCharacter,
-                VgaCharacter {
-                    ascii_character,
-                    character_color: self.color_code.clone(),
-                },
-            );
+    /// Writes `byte` of UTF-8 text at the cursor. Control characters and VT100 escape sequences
+    /// move the cursor, change colors or erase parts of the screen as on a terminal.
+    pub fn write_byte(&mut self, byte: u8) {
+        self.show_live_view();
+        for action in self.parser.advance(byte) {
+            match action {
+                Action::Byte(byte) => self.perform(byte),
+                Action::Character(character) => {
+                    self.draw_glyph(cp437::encode(character).unwrap_or(cp437::UNMAPPABLE))
+                }
+                Action::Escape(final_byte) => self.escape(final_byte),
+                Action::ControlSequence(sequence) => self.control_sequence(&sequence),
+            }
+        }
+        self.move_cursor();
+    }
+
+    /// Writes an ASCII character or carries out one of the control characters line feed,
+    /// carriage return, tab, backspace and form feed. Other control characters are ignored.
+    fn perform(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            b'\t' => {
+                if self.column < self.width - 1 {
+                    let next_stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                    self.column = next_stop.min(self.width - 1);
+                }
+            }
+            BACKSPACE => {
+                if self.column > 0 {
+                    self.column = self.column.min(self.width) - 1;
+                    self.put(self.row, self.column, b' ');
+                }
+            }
+            FORM_FEED => {
+                self.clear();
+                self.row = 0;
+            }
+            0x00..=0x1F => {}
+            _ => self.draw_glyph(byte),
         }
+    }
+
+    /// Writes the glyph numbered `glyph` in code page 437 at the cursor.
+    fn draw_glyph(&mut self, glyph: u8) {
+        // The cursor stays past the last column until something is written there, so that a
+        // line of exactly the screen width is not followed by an empty one.
+        if self.column >= self.width {
+            self.new_line();
+        }
+        self.put(self.row, self.column, glyph);
         self.column += 1;
     }
 
-    pub fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for column in 0..BUFFER_WIDTH {
-                unsafe {
-                    let character_to_move: VgaCharacter = core::ptr::read_volatile(
-                        &mut self.buffer.chars[row][column] as *const VgaCharacter,
-                    );
-                    core::ptr::write_volatile(
-                        &mut self.buffer.chars[row - 1][column],
-                        character_to_move,
-                    );
+    fn escape(&mut self, final_byte: u8) {
+        match final_byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            // Index, next line and reverse index.
+            b'D' => self.index(),
+            b'E' => self.new_line(),
+            b'M' => {
+                if self.row == self.scroll_region.0 {
+                    self.scroll_down(1);
+                } else {
+                    self.row = self.row.saturating_sub(1);
+                }
+            }
+            // Full reset.
+            b'c' => {
+                self.color_code = self.default_color_code;
+                self.bold = false;
+                self.scroll_region = (0, self.height - 1);
+                self.clear();
+                self.set_position(0, 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn control_sequence(&mut self, sequence: &ControlSequence) {
+        let count = usize::from(sequence.parameter(0, 1));
+        let column = self.column.min(self.width - 1);
+        if sequence.private {
+            // Only showing and hiding the cursor, DECTCEM, is supported.
+            match (sequence.parameters(), sequence.final_byte) {
+                ([25], b'h') => self.enable_cursor(CursorShape::Underline),
+                ([25], b'l') => self.disable_cursor(),
+                _ => {}
+            }
+            return;
+        }
+        match sequence.final_byte {
+            b'A' => self.set_position(self.row.saturating_sub(count), column),
+            b'B' => self.set_position(self.row + count, column),
+            b'C' => self.set_position(self.row, column + count),
+            b'D' => self.set_position(self.row, column.saturating_sub(count)),
+            b'E' => self.set_position(self.row + count, 0),
+            b'F' => self.set_position(self.row.saturating_sub(count), 0),
+            b'G' => self.set_position(self.row, count - 1),
+            b'd' => self.set_position(count - 1, column),
+            b'H' | b'f' => {
+                let column = usize::from(sequence.parameter(1, 1));
+                self.set_position(count - 1, column - 1);
+            }
+            b'J' => {
+                let (first_row, last_row) = match sequence.parameter(0, 0) {
+                    0 => (self.row + 1, self.height),
+                    1 => (0, self.row),
+                    _ => (0, self.height),
+                };
+                for row in first_row..last_row {
+                    self.clear_line(row);
+                }
+                if sequence.parameter(0, 0) < 2 {
+                    self.erase_in_line(sequence.parameter(0, 0));
                 }
             }
+            b'K' => self.erase_in_line(sequence.parameter(0, 0)),
+            b'm' => self.select_graphic_rendition(sequence),
+            b'r' => {
+                let top = usize::from(sequence.parameter(0, 1)) - 1;
+                let bottom = usize::from(sequence.parameter(1, self.height as u16)) - 1;
+                if top < bottom && bottom < self.height {
+                    self.scroll_region = (top, bottom);
+                    self.set_position(0, 0);
+                }
+            }
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            b'S' => self.scroll_up(count),
+            b'T' => self.scroll_down(count),
+            _ => {}
+        }
+    }
+
+    /// Blanks the cursor's row after the cursor for mode 0, up to and including it for mode 1
+    /// and entirely for mode 2.
+    fn erase_in_line(&mut self, mode: u16) {
+        let columns = match mode {
+            0 => self.column.min(self.width)..self.width,
+            1 => 0..(self.column + 1).min(self.width),
+            _ => 0..self.width,
+        };
+        for column in columns {
+            self.put(self.row, column, b' ');
         }
+    }
+
+    fn select_graphic_rendition(&mut self, sequence: &ControlSequence) {
+        let parameters = sequence.parameters();
+        if parameters.is_empty() {
+            self.reset_colors();
+        }
+        let mut index = 0;
+        while index < parameters.len() {
+            let bright = if self.bold { BRIGHT } else { 0 };
+            match parameters[index] {
+                0 => self.reset_colors(),
+                1 => {
+                    self.bold = true;
+                    let foreground = self.color_code.foreground() | BRIGHT;
+                    self.color_code = self.color_code.with_foreground(foreground);
+                }
+                22 => {
+                    self.bold = false;
+                    let foreground = self.color_code.foreground() & !BRIGHT;
+                    self.color_code = self.color_code.with_foreground(foreground);
+                }
+                code @ 30..=37 => {
+                    let foreground = ANSI_COLORS[usize::from(code - 30)] as u8 | bright;
+                    self.color_code = self.color_code.with_foreground(foreground);
+                }
+                39 => {
+                    let foreground = self.default_color_code.foreground() | bright;
+                    self.color_code = self.color_code.with_foreground(foreground);
+                }
+                code @ 40..=47 => {
+                    let background = ANSI_COLORS[usize::from(code - 40)] as u8;
+                    self.color_code = self.color_code.with_background(background);
+                }
+                49 => {
+                    let background = self.default_color_code.background();
+                    self.color_code = self.color_code.with_background(background);
+                }
+                code @ 90..=97 => {
+                    let foreground = ANSI_COLORS[usize::from(code - 90)] as u8 | BRIGHT;
+                    self.color_code = self.color_code.with_foreground(foreground);
+                }
+                code @ 100..=107 => {
+                    let background = ANSI_COLORS[usize::from(code - 100)] as u8 | BRIGHT;
+                    self.color_code = self.color_code.with_background(background);
+                }
+                // 256-color and true color selections, of which only the first 16 colors of
+                // the 256 can be shown.
+                code @ (38 | 48) => match parameters.get(index + 1) {
+                    Some(5) => {
+                        if let Some(&color @ 0..=15) = parameters.get(index + 2) {
+                            let color = ANSI_COLORS[usize::from(color % 8)] as u8
+                                | if color >= 8 { BRIGHT } else { 0 };
+                            self.color_code = if code == 38 {
+                                self.color_code.with_foreground(color)
+                            } else {
+                                self.color_code.with_background(color)
+                            };
+                        }
+                        index += 2;
+                    }
+                    Some(2) => index += 4,
+                    _ => {}
+                },
+                _ => {}
+            }
+            index += 1;
+        }
+    }
+
+    /// The colors the next characters are written in.
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    pub fn set_foreground(&mut self, foreground: Color) {
+        self.color_code = self.color_code.with_foreground(foreground as u8);
+    }
+
+    pub fn set_background(&mut self, background: Color) {
+        self.color_code = self.color_code.with_background(background as u8);
+    }
+
+    /// Returns to the colors the writer was created with.
+    pub fn reset_colors(&mut self) {
+        self.color_code = self.default_color_code;
+        self.bold = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = SavedCursor {
+            row: self.row,
+            column: self.column,
+            color_code: self.color_code,
+            bold: self.bold,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor;
+        self.row = saved.row;
+        self.column = saved.column;
+        self.color_code = saved.color_code;
+        self.bold = saved.bold;
+    }
+
+    /// Moves to the start of the next row, scrolling the scroll region up when on its last row.
+    pub fn new_line(&mut self) {
+        self.index();
         self.column = 0;
-        self.clear_last_line();
+        self.move_cursor();
+    }
+
+    /// Moves down a row, scrolling the scroll region up when on its last row.
+    fn index(&mut self) {
+        if self.row == self.scroll_region.1 {
+            self.scroll_up(1);
+        } else if self.row < self.height - 1 {
+            self.row += 1;
+        }
+    }
+
+    /// Moves the rows of the scroll region up by `count`, blanking the rows freed at its bottom.
+    /// Rows leaving the top of the screen go to the scrollback.
+    fn scroll_up(&mut self, count: usize) {
+        let (top, bottom) = self.scroll_region;
+        let count = count.min(bottom + 1 - top);
+        if top == 0 {
+            for row in 0..count {
+                let row = self.read_row(row);
+                self.push_scrollback(row);
+            }
+        }
+        for row in top..bottom + 1 - count {
+            self.copy_row(row + count, row);
+        }
+        for row in bottom + 1 - count..=bottom {
+            self.clear_line(row);
+        }
+    }
+
+    /// Moves the rows of the scroll region down by `count`, blanking the rows freed at its top.
+    fn scroll_down(&mut self, count: usize) {
+        let (top, bottom) = self.scroll_region;
+        let count = count.min(bottom + 1 - top);
+        for row in (top + count..=bottom).rev() {
+            self.copy_row(row - count, row);
+        }
+        for row in top..top + count {
+            self.clear_line(row);
+        }
+    }
+
+    fn copy_row(&mut self, from: usize, to: usize) {
+        let row = self.read_row(from);
+        self.write_row(to, &row);
+    }
+
+    /// Reads the characters of `row`, padded with blanks to the widest text mode.
+    fn read_row(&self, row: usize) -> Row {
+        let mut characters = [self.blank(); MAX_WIDTH];
+        let start = row * self.width;
+        copy_cells(
+            &self.buffer[start..start + self.width],
+            &mut characters,
+            self.width,
+        );
+        characters
+    }
+
+    fn write_row(&mut self, row: usize, characters: &Row) {
+        let start = row * self.width;
+        copy_cells(characters, &mut self.buffer[start..], self.width);
     }
 
+    fn blank(&self) -> VgaCharacter {
+        VgaCharacter {
+            ascii_character: b' ',
+            character_color: self.color_code,
+        }
+    }
+
+    /// Adds `row` to the scrollback, dropping the oldest row first when it is full so that the
+    /// reserved space is never outgrown.
+    fn push_scrollback(&mut self, row: Row) {
+        if self.scrollback_limit == 0 {
+            return;
+        }
+        if self.scrollback.len() == self.scrollback_limit {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(row);
+    }
+
+    /// Keeps up to `rows` of the rows that scroll off the top of the screen, dropping the oldest
+    /// beyond that. The space for them is allocated here, as the keyboard interrupt both writes
+    /// and scrolls through the scrollback and must not use the heap, so it is empty until this is
+    /// called.
+    pub fn set_scrollback_limit(&mut self, rows: usize) {
+        self.show_live_view();
+        self.scrollback_limit = rows;
+        while self.scrollback.len() > rows {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.shrink_to(rows);
+        self.scrollback.reserve_exact(rows - self.scrollback.len());
+        if rows > 0 {
+            self.live_screen.reserve_exact(MAX_HEIGHT);
+        }
+    }
+
+    /// How many rows of scrollback are kept.
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    /// How many rows back into the scrollback the screen shows; zero for the live view.
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    /// Shows rows from further back in the scrollback, stopping at its oldest row.
+    pub fn scroll_view_up(&mut self, rows: usize) {
+        let offset = (self.view_offset + rows).min(self.scrollback.len());
+        self.show_view(offset);
+    }
+
+    /// Shows more recent rows, up to the live view.
+    pub fn scroll_view_down(&mut self, rows: usize) {
+        self.show_view(self.view_offset.saturating_sub(rows));
+    }
+
+    fn show_live_view(&mut self) {
+        if self.view_offset > 0 {
+            self.show_view(0);
+        }
+    }
+
+    /// Fills the screen with the rows `offset` rows back from the live view, putting the live
+    /// screen aside when leaving it and putting it back when returning.
+    fn show_view(&mut self, offset: usize) {
+        if offset == self.view_offset {
+            return;
+        }
+        if self.view_offset == 0 {
+            self.live_screen.clear();
+            for row in 0..self.height {
+                let characters = self.read_row(row);
+                self.live_screen.push(characters);
+            }
+        }
+        self.view_offset = offset;
+        let first = self.scrollback.len() - offset;
+        for row in 0..self.height {
+            let characters = match self.scrollback.get(first + row) {
+                Some(characters) => *characters,
+                None => self.live_screen[first + row - self.scrollback.len()],
+            };
+            self.write_row(row, &characters);
+        }
+        self.move_cursor();
+    }
+
+    /// The number of rows on the screen.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The number of columns on the screen.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn text_mode(&self) -> TextMode {
+        self.mode
+    }
+
+    /// Changes the number of rows and columns, reprogramming the VGA card if the writer is on the
+    /// screen. The text is kept where it fits; rows pushed off the top to keep the cursor's row
+    /// on the screen go to the scrollback.
+    pub fn set_text_mode(&mut self, mode: TextMode) {
+        if mode == self.mode {
+            return;
+        }
+        self.show_live_view();
+        let rows: Vec<Row> = (0..self.height).map(|row| self.read_row(row)).collect();
+        let (width, height) = mode.size();
+        let dropped = (self.row + 1).saturating_sub(height);
+        for &row in &rows[..dropped] {
+            self.push_scrollback(row);
+        }
+        if self.visible {
+            text_mode::program(mode);
+        }
+        self.mode = mode;
+        self.width = width;
+        self.height = height;
+        let blank = [self.blank(); MAX_WIDTH];
+        for row in 0..height {
+            let characters = rows.get(row + dropped).unwrap_or(&blank);
+            self.write_row(row, characters);
+        }
+        self.row -= dropped;
+        self.column = self.column.min(width);
+        self.scroll_region = (0, height - 1);
+        self.saved_cursor.row = self.saved_cursor.row.min(height - 1);
+        self.saved_cursor.column = self.saved_cursor.column.min(width - 1);
+        // The cursor's scan lines depend on the font height.
+        if self.visible {
+            match self.cursor {
+                Some(shape) => self.enable_cursor(shape),
+                None => self.disable_cursor(),
+            }
+        }
+        self.move_cursor();
+    }
+
+    /// Writes `string`, showing each character with its glyph in code page 437 or a square if
+    /// the font has none.
     pub fn write_string(&mut self, string: &str) {
         for byte in string.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
-            }
+            self.write_byte(byte);
+        }
+    }
+
+    /// Writes `string` starting at `row` and `column` without moving the cursor.
+    pub fn write_string_at(&mut self, row: usize, column: usize, string: &str) {
+        let position = self.position();
+        self.set_position(row, column);
+        self.write_string(string);
+        self.set_position(position.0, position.1);
+    }
+
+    /// The row and column the next character is written to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// Moves the cursor, clamped to the screen.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.row = row.min(self.height - 1);
+        self.column = column.min(self.width - 1);
+        self.move_cursor();
+    }
+
+    fn put(&mut self, row: usize, column: usize, byte: u8) {
+        unsafe {
+            core::ptr::write_volatile(
+                &mut self.buffer[row * self.width + column] as *mut VgaCharacter,
+                VgaCharacter {
+                    ascii_character: byte,
+                    character_color: self.color_code,
+                },
+            );
         }
     }
 
     pub fn clear_line(&mut self, line_number: usize) {
-        let color = self.color_code.clone();
-        for column in 0..BUFFER_WIDTH {
-            unsafe {
-                core::ptr::write_volatile(
-                    &mut self.buffer.chars[line_number][column],
-                    VgaCharacter {
-                        ascii_character: b' ',
-                        character_color: color,
-                    },
-                );
-            }
+        self.show_live_view();
+        for column in 0..self.width {
+            self.put(line_number, column, b' ');
         }
-        self.column = 0;
     }
 
+    /// Blanks the screen and returns to the start of the cursor's row.
     pub fn clear(&mut self) {
-        for row in (0..BUFFER_HEIGHT).into_iter().rev() {
+        for row in (0..self.height).rev() {
             self.clear_line(row);
         }
+        self.column = 0;
+        self.move_cursor();
     }
+
     pub fn clear_last_line(&mut self) {
-        self.clear_line(BUFFER_HEIGHT - 1);
+        self.clear_line(self.height - 1);
+    }
+
+    /// Shows the blinking cursor with the given shape whenever the writer is on the screen.
+    pub fn enable_cursor(&mut self, shape: CursorShape) {
+        self.cursor = Some(shape);
+        if !self.visible {
+            return;
+        }
+        let last_scan_line = read_crtc(CRTC_MAXIMUM_SCAN_LINE) & SCAN_LINE_MASK;
+        let first_scan_line = match shape {
+            CursorShape::Underline => last_scan_line.saturating_sub(1),
+            CursorShape::Block => 0,
+        };
+        let start = read_crtc(CRTC_CURSOR_START) & !(CURSOR_DISABLE | SCAN_LINE_MASK);
+        write_crtc(CRTC_CURSOR_START, start | first_scan_line);
+        let end = read_crtc(CRTC_CURSOR_END) & !SCAN_LINE_MASK;
+        write_crtc(CRTC_CURSOR_END, end | last_scan_line);
+    }
+
+    pub fn disable_cursor(&mut self) {
+        self.cursor = None;
+        if !self.visible {
+            return;
+        }
+        let start = read_crtc(CRTC_CURSOR_START);
+        write_crtc(CRTC_CURSOR_START, start | CURSOR_DISABLE);
+    }
+
+    pub fn cursor_enabled(&self) -> bool {
+        if !self.visible {
+            return self.cursor.is_some();
+        }
+        read_crtc(CRTC_CURSOR_START) & CURSOR_DISABLE == 0
+    }
+
+    /// The row and column the hardware cursor is shown at, or will be once the writer is shown.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        if !self.visible {
+            return self.cursor_location;
+        }
+        let high = read_crtc(CRTC_CURSOR_LOCATION_HIGH);
+        let low = read_crtc(CRTC_CURSOR_LOCATION_LOW);
+        let location = usize::from(u16::from_be_bytes([high, low]));
+        (location / self.width, location % self.width)
+    }
+
+    /// Shows the hardware cursor at `row` and `column`. It moves back to where the next character
+    /// will be written on the next write.
+    pub fn set_cursor_position(&mut self, row: usize, column: usize) {
+        let row = row.min(self.height - 1);
+        let column = column.min(self.width - 1);
+        self.cursor_location = (row, column);
+        if !self.visible {
+            return;
+        }
+        let [high, low] = ((row * self.width + column) as u16).to_be_bytes();
+        write_crtc(CRTC_CURSOR_LOCATION_HIGH, high);
+        write_crtc(CRTC_CURSOR_LOCATION_LOW, low);
+    }
+
+    /// Keeps the hardware cursor on the cell the next character goes to. After the last column
+    /// it stays there until the line wraps.
+    fn move_cursor(&mut self) {
+        // The cursor belongs to the live view, below the rows shown while scrolled back.
+        let row = self.row + self.view_offset;
+        if row < self.height {
+            self.set_cursor_position(row, self.column);
+        } else {
+            self.set_cursor_position(self.height - 1, self.width - 1);
+        }
     }
 }
 
@@ -175,6 +955,60 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints in the colors of the given `ColorCode`, then returns to the colors from before.
+#[macro_export]
+macro_rules! print_colored {
+    ($color_code:expr, $($arg:tt)*) => {{
+        $crate::vga_buffer::_print_colored($color_code, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! println_colored {
+    ($color_code:expr) => ($crate::print_colored!($color_code, "\n"));
+    ($color_code:expr, $($arg:tt)*) => (
+        $crate::print_colored!($color_code, "{}\n", format_args!($($arg)*))
+    );
+}
+
+/// Prints an error message, in red.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::print_colored!($crate::vga_buffer::ERROR_COLOR_CODE, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+/// Changes the colors of everything printed to `VGA_WRITER` until it is dropped, when the colors
+/// from before come back. Output from other threads running meanwhile is colored as well.
+#[must_use = "the colors are restored as soon as the guard is dropped"]
+pub struct ColorGuard {
+    previous: ColorCode,
+}
+
+impl ColorGuard {
+    pub fn new(color_code: ColorCode) -> Self {
+        use x86_64::instructions::interrupts;
+        interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            let previous = writer.color_code();
+            writer.set_color_code(color_code);
+            Self { previous }
+        })
+    }
+}
+
+impl Drop for ColorGuard {
+    fn drop(&mut self) {
+        use x86_64::instructions::interrupts;
+        interrupts::without_interrupts(|| VGA_WRITER.lock().set_color_code(self.previous));
+    }
+}
+
 /// Prints the given formatted string to the VGA text buffer through the global `VGA_WRITER` instance.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
@@ -183,12 +1017,35 @@ pub fn _print(args: fmt::Arguments) {
     interrupts::without_interrupts(|| VGA_WRITER.lock().write_fmt(args).unwrap());
 }
 
+/// Prints the given formatted string in the colors of `color_code`, without letting other output
+/// in between.
+#[doc(hidden)]
+pub fn _print_colored(color_code: ColorCode, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let previous = writer.color_code();
+        writer.set_color_code(color_code);
+        writer.write_fmt(args).unwrap();
+        writer.set_color_code(previous);
+    });
+}
+
 #[cfg(test)]
 mod test {
     use crate::vga_buffer::{
-        BUFFER_HEIGHT, BUFFER_WIDTH, VGA_BUFFER_ADDRESS, VGA_WRITER, VgaBuffer,
+        BUFFER_HEIGHT, BUFFER_WIDTH, Color, ColorCode, ColorGuard, CursorShape, DEFAULT_COLOR_CODE,
+        DEFAULT_SCROLLBACK_ROWS, ERROR_COLOR_CODE, PANIC_COLOR_CODE, TextMode, VGA_BUFFER_ADDRESS,
+        VGA_WRITER, VgaCharacter, VgaWriter,
     };
 
+    /// Reads the text buffer, as laid out in the 80x25 mode.
+    fn character_at(row: usize, column: usize) -> VgaCharacter {
+        let address = VGA_BUFFER_ADDRESS as *const VgaCharacter;
+        unsafe { core::ptr::read_volatile(address.add(row * BUFFER_WIDTH + column)) }
+    }
+
     #[test_case]
     fn test_vga_write() {
         let interrupts_are_enabled = x86_64::instructions::interrupts::are_enabled();
@@ -196,11 +1053,8 @@ mod test {
         VGA_WRITER.lock().clear();
         let string_to_write = "Hello World!";
         VGA_WRITER.lock().write_string(string_to_write);
-        let buffer: VgaBuffer =
-            unsafe { core::ptr::read_volatile(VGA_BUFFER_ADDRESS as *const VgaBuffer) };
-
         for (i, expected) in string_to_write.as_bytes().iter().enumerate() {
-            let actual = buffer.chars[BUFFER_HEIGHT - 1][i].ascii_character as char;
+            let actual = character_at(BUFFER_HEIGHT - 1, i).ascii_character as char;
             assert_eq!(*expected as char, actual);
         }
         if interrupts_are_enabled {
@@ -215,15 +1069,12 @@ mod test {
         VGA_WRITER.lock().clear();
         let string_to_write = "HelloWorld\n!";
         VGA_WRITER.lock().write_string(string_to_write);
-        let buffer: VgaBuffer =
-            unsafe { core::ptr::read_volatile(VGA_BUFFER_ADDRESS as *const VgaBuffer) };
-
         for (i, expected) in "HelloWorld".as_bytes().iter().enumerate() {
-            let actual = buffer.chars[BUFFER_HEIGHT - 2][i].ascii_character as char;
+            let actual = character_at(BUFFER_HEIGHT - 2, i).ascii_character as char;
             assert_eq!(*expected as char, actual);
         }
         for (i, expected) in "!".as_bytes().iter().enumerate() {
-            let actual = buffer.chars[BUFFER_HEIGHT - 1][i].ascii_character as char;
+            let actual = character_at(BUFFER_HEIGHT - 1, i).ascii_character as char;
             assert_eq!(*expected as char, actual);
         }
         if interrupts_are_enabled {
@@ -240,16 +1091,13 @@ mod test {
         for char in string_to_write {
             VGA_WRITER.lock().write_byte(char);
         }
-        let buffer: VgaBuffer =
-            unsafe { core::ptr::read_volatile(VGA_BUFFER_ADDRESS as *const VgaBuffer) };
-
         for (i, expected) in string_to_write[0..BUFFER_WIDTH].iter().enumerate() {
-            let actual = buffer.chars[BUFFER_HEIGHT - 2][i].ascii_character as char;
+            let actual = character_at(BUFFER_HEIGHT - 2, i).ascii_character as char;
             assert_eq!(*expected as char, actual);
         }
 
         for (i, expected) in string_to_write[0..BUFFER_WIDTH].iter().enumerate() {
-            let actual = buffer.chars[BUFFER_HEIGHT - 1][i].ascii_character as char;
+            let actual = character_at(BUFFER_HEIGHT - 1, i).ascii_character as char;
             assert_eq!(
                 *expected as char,
                 actual,
@@ -259,10 +1107,232 @@ mod test {
             );
         }
 
-        let actual = buffer.chars[BUFFER_HEIGHT - 1][0].ascii_character as char;
+        let actual = character_at(BUFFER_HEIGHT - 1, 0).ascii_character as char;
         assert_eq!('x', actual);
         if interrupts_are_enabled {
             x86_64::instructions::interrupts::enable();
         }
     }
+
+    #[test_case]
+    fn test_hardware_cursor_follows_writes() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.clear();
+            assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 0));
+            writer.write_string("abc");
+            assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 3));
+            writer.write_string("\x08\n12");
+            assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 2));
+
+            writer.set_cursor_position(3, 7);
+            assert_eq!(writer.cursor_position(), (3, 7));
+            writer.write_byte(b'x');
+            assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 3));
+
+            writer.disable_cursor();
+            assert!(!writer.cursor_enabled());
+            writer.enable_cursor(CursorShape::Block);
+            assert!(writer.cursor_enabled());
+            writer.enable_cursor(CursorShape::Underline);
+        });
+    }
+
+    #[test_case]
+    fn test_control_characters_and_positioning() {
+        let glyph_at = |row: usize, column: usize| character_at(row, column).ascii_character;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.clear();
+            writer.write_string("ab\tc\rX");
+            assert_eq!(glyph_at(BUFFER_HEIGHT - 1, 8), b'c');
+            assert_eq!(glyph_at(BUFFER_HEIGHT - 1, 0), b'X');
+            assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 1));
+            writer.write_string("\t\t\t\t\t\t\t\t\t\t");
+            assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1));
+
+            // Backspace erases the character before the cursor.
+            writer.set_position(BUFFER_HEIGHT - 1, 2);
+            writer.write_byte(0x08);
+            assert_eq!(glyph_at(BUFFER_HEIGHT - 1, 1), b' ');
+            assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 1));
+
+            // Rows above the bottom one are written without scrolling.
+            writer.set_position(3, 5);
+            writer.write_string("hi\nyo");
+            assert_eq!(glyph_at(3, 6), b'i');
+            assert_eq!(glyph_at(4, 1), b'o');
+            assert_eq!(writer.position(), (4, 2));
+            assert_eq!(writer.cursor_position(), (4, 2));
+            writer.write_string_at(10, 0, "status");
+            assert_eq!(glyph_at(10, 5), b's');
+            assert_eq!(writer.position(), (4, 2));
+
+            // Form feed clears the screen and starts again at the top.
+            writer.write_byte(0x0C);
+            assert_eq!(writer.position(), (0, 0));
+            assert_eq!(glyph_at(3, 6), b' ');
+            writer.set_position(BUFFER_HEIGHT - 1, 0);
+        });
+    }
+
+    #[test_case]
+    fn test_escape_sequences() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.clear();
+            writer.write_string("\x1b[2;3Hab\x1b[31;44mX\x1b[1;32mY\x1b[0mZ");
+            assert_eq!(character_at(1, 2).ascii_character, b'a');
+            let colors = [2, 3, 4, 5, 6].map(|column| character_at(1, column).character_color);
+            assert_eq!(colors[0], colors[4]);
+            assert_eq!(colors[2], ColorCode::new(Color::Red, Color::Blue));
+            assert_eq!(colors[3], ColorCode::new(Color::LightGreen, Color::Blue));
+            writer.write_string("\x1b[97;100mW\x1b[m");
+            let bright = character_at(1, 7).character_color;
+            assert_eq!(bright, ColorCode::new(Color::White, Color::DarkGray));
+
+            // Erasing to the end of the line, saving and restoring the cursor.
+            writer.write_string("\x1b[2;4H\x1b7\x1b[20;20H\x1b8\x1b[K");
+            assert_eq!(writer.position(), (1, 3));
+            assert_eq!(character_at(1, 2).ascii_character, b'a');
+            assert_eq!(character_at(1, 3).ascii_character, b' ');
+            writer.write_string("\x1b[2A\x1b[5C");
+            assert_eq!(writer.position(), (0, 8));
+
+            // Only the rows of the scroll region move.
+            writer.write_string("\x1b[5;7r");
+            assert_eq!(writer.position(), (0, 0));
+            writer.write_string("\x1b[6;1Hq\x1b[8;1Hkeep\x1b[7;1H\n");
+            assert_eq!(character_at(4, 0).ascii_character, b'q');
+            assert_eq!(character_at(5, 0).ascii_character, b' ');
+            assert_eq!(character_at(7, 0).ascii_character, b'k');
+            assert_eq!(writer.position(), (6, 0));
+            writer.write_string("\x1b[r\x1b[2J");
+            assert_eq!(character_at(4, 0).ascii_character, b' ');
+
+            // Scrolling by more than the whole screen blanks it.
+            writer.write_string("\x1b[1;1Htop\x1b[25;1Hbottom\x1b[99S");
+            assert_eq!(character_at(0, 0).ascii_character, b' ');
+            assert_eq!(character_at(BUFFER_HEIGHT - 1, 0).ascii_character, b' ');
+            writer.write_string("\x1b[1;1Htop\x1b[25;1Hbottom\x1b[99T");
+            assert_eq!(character_at(0, 0).ascii_character, b' ');
+            assert_eq!(character_at(BUFFER_HEIGHT - 1, 0).ascii_character, b' ');
+            writer.set_position(BUFFER_HEIGHT - 1, 0);
+        });
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        let first_character = |row: usize| character_at(row, 0).ascii_character;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.set_scrollback_limit(0);
+            writer.set_scrollback_limit(10);
+            writer.clear();
+            // 30 lines, A to ^, scroll the first 6 off a 25-row screen.
+            for line in b'A'..b'A' + 30 {
+                writer.write_byte(line);
+                writer.write_byte(b'\n');
+            }
+            assert_eq!(writer.scrollback_len(), 10);
+            assert_eq!(first_character(0), b'A' + 6);
+
+            writer.scroll_view_up(4);
+            assert_eq!(writer.view_offset(), 4);
+            assert_eq!(first_character(0), b'A' + 2);
+            assert_eq!(first_character(4), b'A' + 6);
+            writer.scroll_view_up(100);
+            assert_eq!(writer.view_offset(), 10);
+            writer.scroll_view_down(8);
+            assert_eq!(first_character(0), b'A' + 4);
+
+            // New output returns to the live view first.
+            writer.write_byte(b'!');
+            assert_eq!(writer.view_offset(), 0);
+            assert_eq!(first_character(0), b'A' + 6);
+            assert_eq!(first_character(BUFFER_HEIGHT - 1), b'!');
+            writer.set_scrollback_limit(DEFAULT_SCROLLBACK_ROWS);
+        });
+    }
+
+    #[test_case]
+    fn test_unicode() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.clear();
+            writer.write_string("é┌─┐€\x01\x07☺");
+            // A character split across two writes is still decoded.
+            writer.write_byte(0xC2);
+            writer.write_byte(0xB0);
+            let glyphs: [u8; 7] = core::array::from_fn(|column| {
+                character_at(BUFFER_HEIGHT - 1, column).ascii_character
+            });
+            assert_eq!(glyphs, [0x82, 0xDA, 0xC4, 0xBF, 0xFE, 0x01, 0xF8]);
+            assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 7));
+        });
+    }
+
+    #[test_case]
+    fn test_colors() {
+        let color_at = |column: usize| character_at(BUFFER_HEIGHT - 1, column).character_color;
+        let default = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.clear();
+            writer.set_position(BUFFER_HEIGHT - 1, 0);
+            writer.color_code()
+        });
+        {
+            let _guard = ColorGuard::new(ColorCode::new(Color::Yellow, Color::Black));
+            print!("w");
+            crate::eprint!("e");
+            crate::print_colored!(PANIC_COLOR_CODE, "p");
+            print!("w");
+        }
+        print!("d");
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = VGA_WRITER.lock();
+            writer.set_foreground(Color::Green);
+            writer.set_background(Color::Blue);
+            writer.write_byte(b'g');
+            writer.reset_colors();
+        });
+        let yellow = ColorCode::new(Color::Yellow, Color::Black);
+        assert_eq!(color_at(0), yellow);
+        assert_eq!(color_at(1), ERROR_COLOR_CODE);
+        assert_eq!(color_at(2), PANIC_COLOR_CODE);
+        assert_eq!(color_at(3), yellow);
+        assert_eq!(color_at(4), default);
+        assert_eq!(color_at(5), ColorCode::new(Color::Green, Color::Blue));
+        assert_eq!(default, DEFAULT_COLOR_CODE);
+        let color_code =
+            x86_64::instructions::interrupts::without_interrupts(|| VGA_WRITER.lock().color_code());
+        assert_eq!(color_code, DEFAULT_COLOR_CODE);
+    }
+
+    #[test_case]
+    fn test_text_modes() {
+        // A writer off the screen changes its geometry without reprogramming the VGA card.
+        let mut writer = VgaWriter::new_off_screen();
+        writer.set_scrollback_limit(100);
+        writer.set_text_mode(TextMode::Text90x60);
+        assert_eq!((writer.width(), writer.height()), (90, 60));
+        writer.set_position(0, 0);
+        writer.write_string("top");
+        writer.set_position(40, 88);
+        writer.write_string("xy");
+        assert_eq!(writer.position(), (40, 90));
+        assert_eq!(writer.buffer[40 * 90 + 89].ascii_character, b'y');
+
+        // The cursor's row stays on the screen and the rows above it go to the scrollback.
+        writer.set_text_mode(TextMode::Text80x25);
+        assert_eq!(writer.text_mode(), TextMode::Text80x25);
+        assert_eq!((writer.width(), writer.height()), (80, 25));
+        assert_eq!(writer.scrollback_len(), 16);
+        assert_eq!(writer.position(), (24, 80));
+        writer.scroll_view_up(16);
+        assert_eq!(writer.buffer[0].ascii_character, b't');
+        writer.write_string("z");
+        assert_eq!(writer.position(), (24, 1));
+        assert_eq!(writer.scrollback_len(), 17);
+    }
 }
@@ -0,0 +1,311 @@
+//! Splitting output into characters and the VT100/ANSI escape sequences that control the screen.
+//! Text is UTF-8; malformed sequences come out as U+FFFD REPLACEMENT CHARACTER.
+
+use core::{array, iter::Flatten};
+
+/// How many parameters of a control sequence are kept; later ones are ignored.
+const MAX_PARAMETERS: usize = 8;
+
+const ESCAPE: u8 = 0x1B;
+/// Cancel and substitute abort a sequence.
+const CANCEL: u8 = 0x18;
+const SUBSTITUTE: u8 = 0x1A;
+
+/// What a byte of output amounts to once escape sequences are recognized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// An ASCII character to show or a control character, neither part of a sequence.
+    Byte(u8),
+    /// A character beyond ASCII, decoded from UTF-8.
+    Character(char),
+    /// `ESC` followed by a final byte, such as `ESC 7` to save the cursor.
+    Escape(u8),
+    /// A control sequence, `ESC [` followed by parameters and a final byte.
+    ControlSequence(ControlSequence),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ControlSequence {
+    parameters: [u16; MAX_PARAMETERS],
+    count: usize,
+    /// Set when the parameters start with `?`, as for the DEC private modes.
+    pub private: bool,
+    pub final_byte: u8,
+}
+
+impl ControlSequence {
+    /// The parameters given, where an empty one reads as zero.
+    pub fn parameters(&self) -> &[u16] {
+        &self.parameters[..self.count]
+    }
+
+    /// The parameter at `index`, or `default` if it is missing or zero.
+    pub fn parameter(&self, index: usize, default: u16) -> u16 {
+        match self.parameters().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    /// Inside an escape sequence with intermediate bytes, such as `ESC ( B`, none of which are
+    /// supported.
+    EscapeIntermediate,
+    ControlSequence,
+    /// Inside a control sequence that cannot be parsed; it is dropped at its final byte.
+    IgnoredControlSequence,
+}
+
+/// The actions a byte completes: at most a character cut short by the byte and the byte's own.
+pub type Actions = Flatten<array::IntoIter<Option<Action>, 2>>;
+
+/// A UTF-8 sequence being decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Utf8Sequence {
+    code_point: u32,
+    /// The continuation bytes still to come.
+    remaining: u8,
+    /// The smallest code point the sequence's length may encode; smaller ones are overlong.
+    minimum: u32,
+}
+
+/// A state machine fed one byte at a time, following the VT500 parser model closely enough for
+/// the sequences the console handles.
+#[derive(Debug, Default)]
+pub struct Parser {
+    state: State,
+    sequence: ControlSequence,
+    /// The index of the parameter being read.
+    parameter: usize,
+    utf8: Utf8Sequence,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            sequence: ControlSequence {
+                parameters: [0; MAX_PARAMETERS],
+                count: 0,
+                private: false,
+                final_byte: 0,
+            },
+            parameter: 0,
+            utf8: Utf8Sequence {
+                code_point: 0,
+                remaining: 0,
+                minimum: 0,
+            },
+        }
+    }
+
+    /// Consumes `byte`, returning what it completes.
+    pub fn advance(&mut self, byte: u8) -> Actions {
+        // A byte other than a continuation byte cuts a UTF-8 sequence short.
+        let mut interrupted = None;
+        if self.utf8.remaining > 0 && !matches!(byte, 0x80..=0xBF) {
+            self.utf8.remaining = 0;
+            interrupted = Some(Action::Character(char::REPLACEMENT_CHARACTER));
+        }
+        [interrupted, self.advance_byte(byte)].into_iter().flatten()
+    }
+
+    fn advance_byte(&mut self, byte: u8) -> Option<Action> {
+        match (self.state, byte) {
+            (_, ESCAPE) => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, 0x00..=0x7F) => Some(Action::Byte(byte)),
+            (State::Ground, _) => self.decode(byte),
+            (_, CANCEL | SUBSTITUTE) => {
+                self.state = State::Ground;
+                None
+            }
+            // Control characters take effect even in the middle of a sequence.
+            (_, 0x00..=0x1F) => Some(Action::Byte(byte)),
+            (State::Escape, b'[') => {
+                self.state = State::ControlSequence;
+                self.sequence = ControlSequence::default();
+                self.parameter = 0;
+                None
+            }
+            (State::Escape, 0x20..=0x2F) => {
+                self.state = State::EscapeIntermediate;
+                None
+            }
+            (State::Escape, 0x30..=0x7E) => {
+                self.state = State::Ground;
+                Some(Action::Escape(byte))
+            }
+            (State::EscapeIntermediate, 0x20..=0x2F) => None,
+            (State::EscapeIntermediate, 0x30..=0x7E) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::ControlSequence, b'0'..=b'9') => {
+                if let Some(value) = self.sequence.parameters.get_mut(self.parameter) {
+                    *value = value
+                        .saturating_mul(10)
+                        .saturating_add(u16::from(byte - b'0'));
+                }
+                self.sequence.count = (self.parameter + 1).min(MAX_PARAMETERS);
+                None
+            }
+            (State::ControlSequence, b';') => {
+                self.parameter += 1;
+                self.sequence.count = (self.parameter + 1).min(MAX_PARAMETERS);
+                None
+            }
+            (State::ControlSequence, b'?')
+                if self.sequence.count == 0 && !self.sequence.private =>
+            {
+                self.sequence.private = true;
+                None
+            }
+            (State::ControlSequence | State::IgnoredControlSequence, 0x40..=0x7E) => {
+                let complete = self.state == State::ControlSequence;
+                self.state = State::Ground;
+                self.sequence.final_byte = byte;
+                complete.then_some(Action::ControlSequence(self.sequence))
+            }
+            (State::ControlSequence | State::IgnoredControlSequence, 0x20..=0x3F) => {
+                self.state = State::IgnoredControlSequence;
+                None
+            }
+            // Anything else, such as a byte above ASCII, ends the sequence and is shown.
+            _ => {
+                self.state = State::Ground;
+                if byte.is_ascii() {
+                    Some(Action::Byte(byte))
+                } else {
+                    self.decode(byte)
+                }
+            }
+        }
+    }
+
+    /// Consumes a byte of a UTF-8 sequence, returning the character once it is complete.
+    fn decode(&mut self, byte: u8) -> Option<Action> {
+        let (remaining, bits, minimum) = match byte {
+            0x80..=0xBF if self.utf8.remaining > 0 => {
+                self.utf8.code_point = self.utf8.code_point << 6 | u32::from(byte & 0x3F);
+                self.utf8.remaining -= 1;
+                if self.utf8.remaining > 0 {
+                    return None;
+                }
+                // Overlong encodings, surrogates and values past U+10FFFF are all malformed.
+                let character = char::from_u32(self.utf8.code_point)
+                    .filter(|_| self.utf8.code_point >= self.utf8.minimum)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                return Some(Action::Character(character));
+            }
+            0xC2..=0xDF => (1, byte & 0x1F, 0x80),
+            0xE0..=0xEF => (2, byte & 0x0F, 0x800),
+            0xF0..=0xF4 => (3, byte & 0x07, 0x1_0000),
+            // A stray continuation byte, or a byte that never appears in UTF-8.
+            _ => return Some(Action::Character(char::REPLACEMENT_CHARACTER)),
+        };
+        self.utf8 = Utf8Sequence {
+            code_point: u32::from(bits),
+            remaining,
+            minimum,
+        };
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Parser};
+    use alloc::vec::Vec;
+
+    fn parse(bytes: &[u8]) -> Vec<Action> {
+        let mut parser = Parser::new();
+        bytes
+            .iter()
+            .flat_map(|&byte| parser.advance(byte))
+            .collect()
+    }
+
+    #[test_case]
+    fn test_control_sequences() {
+        let actions = parse(b"a\x1b[1;31mb\x1b[H\x1b[?25l\x1b[;5H");
+        assert_eq!(actions.len(), 6);
+        assert_eq!(actions[0], Action::Byte(b'a'));
+        let Action::ControlSequence(color) = actions[1] else {
+            panic!("expected a control sequence, got {:?}", actions[1]);
+        };
+        assert_eq!((color.parameters(), color.final_byte), (&[1, 31][..], b'm'));
+        assert_eq!(actions[2], Action::Byte(b'b'));
+        let Action::ControlSequence(home) = actions[3] else {
+            panic!("expected a control sequence, got {:?}", actions[3]);
+        };
+        assert!(home.parameters().is_empty());
+        assert_eq!(home.parameter(0, 1), 1);
+        let Action::ControlSequence(hide) = actions[4] else {
+            panic!("expected a control sequence, got {:?}", actions[4]);
+        };
+        assert!(hide.private);
+        assert_eq!((hide.parameters(), hide.final_byte), (&[25][..], b'l'));
+        let Action::ControlSequence(position) = actions[5] else {
+            panic!("expected a control sequence, got {:?}", actions[5]);
+        };
+        assert_eq!(position.parameters(), [0, 5]);
+        assert_eq!((position.parameter(0, 1), position.parameter(1, 1)), (1, 5));
+    }
+
+    #[test_case]
+    fn test_escapes_and_interrupted_sequences() {
+        assert_eq!(
+            parse(b"\x1b7\x1b(Bx\x1b8"),
+            [
+                Action::Escape(b'7'),
+                Action::Byte(b'x'),
+                Action::Escape(b'8')
+            ]
+        );
+        // A line feed inside a sequence still moves the cursor, cancel drops the sequence.
+        assert_eq!(
+            parse(b"\x1b[1\n\x18y"),
+            [Action::Byte(b'\n'), Action::Byte(b'y')]
+        );
+        // A sequence with a misplaced `?` is dropped entirely.
+        assert_eq!(parse(b"\x1b[1?2mz"), [Action::Byte(b'z')]);
+        assert_eq!(parse(b"\x1b\x1b[Az").len(), 2);
+    }
+
+    #[test_case]
+    fn test_utf8() {
+        const REPLACEMENT: Action = Action::Character(char::REPLACEMENT_CHARACTER);
+        assert_eq!(
+            parse("é─€😀".as_bytes()),
+            [
+                Action::Character('é'),
+                Action::Character('─'),
+                Action::Character('€'),
+                Action::Character('😀')
+            ]
+        );
+        // A sequence cut short by ASCII or an escape, a stray continuation byte, an overlong
+        // encoding and a surrogate.
+        assert_eq!(
+            parse(b"\xC3a\xE2\x94\x1b[m\x80\xC0\x80\xED\xA0\x80"),
+            [
+                REPLACEMENT,
+                Action::Byte(b'a'),
+                REPLACEMENT,
+                parse(b"\x1b[m")[0],
+                REPLACEMENT,
+                REPLACEMENT,
+                REPLACEMENT,
+                REPLACEMENT,
+            ]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! The characters of code page 437, the character set of the VGA text mode font.
+
+/// The glyph shown for characters the font does not have, a small square.
+pub const UNMAPPABLE: u8 = 0xFE;
+
+/// The glyphs that share their codes with the control characters.
+const LOW_GLYPHS: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyph at 0x7F, in place of DEL.
+const HOUSE: char = '⌂';
+
+/// The glyphs from 0x80 on: accented letters, box drawing, Greek letters and mathematical signs.
+const HIGH_GLYPHS: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Characters drawn with the glyph of a look-alike, as the font's glyphs stand for several
+/// characters each.
+const ALIASES: [(char, u8); 8] = [
+    ('β', 0xE1),
+    ('∑', 0xE4),
+    ('μ', 0xE6),
+    // The ohm sign.
+    ('\u{2126}', 0xEA),
+    ('ϕ', 0xED),
+    ('∅', 0xED),
+    ('∈', 0xEE),
+    ('⋅', 0xFA),
+];
+
+/// The code of the glyph showing `character`, if the font has one.
+pub fn encode(character: char) -> Option<u8> {
+    if character == ' ' || character.is_ascii_graphic() {
+        return Some(character as u8);
+    }
+    if character == HOUSE {
+        return Some(0x7F);
+    }
+    let position = |glyphs: &[char]| glyphs.iter().position(|&glyph| glyph == character);
+    if character != '\0'
+        && let Some(code) = position(&LOW_GLYPHS)
+    {
+        return Some(code as u8);
+    }
+    if let Some(code) = position(&HIGH_GLYPHS) {
+        return Some(0x80 + code as u8);
+    }
+    ALIASES
+        .iter()
+        .find(|&&(alias, _)| alias == character)
+        .map(|&(_, code)| code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+
+    #[test_case]
+    fn test_encode() {
+        assert_eq!(encode('A'), Some(b'A'));
+        assert_eq!(encode(' '), Some(b' '));
+        assert_eq!(encode('☺'), Some(0x01));
+        assert_eq!(encode('⌂'), Some(0x7F));
+        assert_eq!(encode('é'), Some(0x82));
+        assert_eq!(encode('┌'), Some(0xDA));
+        assert_eq!(encode('■'), Some(0xFE));
+        assert_eq!(encode('\u{A0}'), Some(0xFF));
+        assert_eq!(encode('μ'), encode('µ'));
+        assert_eq!(encode('\0'), None);
+        assert_eq!(encode('\n'), None);
+        assert_eq!(encode('€'), None);
+        assert_eq!(encode('😀'), None);
+    }
+}
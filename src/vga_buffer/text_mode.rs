@@ -0,0 +1,241 @@
+//! Programming the VGA registers for the text modes the writer supports.
+//!
+//! The register values follow the well-known tables of the standard VGA modes. The denser modes
+//! use an 8 scan line font, made from the 16 line font the BIOS loaded.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use super::{CRTC_MAXIMUM_SCAN_LINE, SCAN_LINE_MASK, VGA_BUFFER_ADDRESS, read_crtc, write_crtc};
+
+const MISC_OUTPUT_WRITE_PORT_ADDRESS: u16 = 0x3C2;
+const SEQUENCER_INDEX_PORT_ADDRESS: u16 = 0x3C4;
+const SEQUENCER_DATA_PORT_ADDRESS: u16 = 0x3C5;
+const GRAPHICS_INDEX_PORT_ADDRESS: u16 = 0x3CE;
+const GRAPHICS_DATA_PORT_ADDRESS: u16 = 0x3CF;
+
+const SEQUENCER_RESET: u8 = 0x00;
+/// Held in the reset register while the clocks change, then released.
+const SYNCHRONOUS_RESET: u8 = 0x01;
+const SEQUENCER_MAP_MASK: u8 = 0x02;
+const SEQUENCER_MEMORY_MODE: u8 = 0x04;
+/// Set in the memory mode register to turn off odd/even addressing.
+const ODD_EVEN_DISABLE: u8 = 1 << 2;
+const GRAPHICS_READ_MAP_SELECT: u8 = 0x04;
+const GRAPHICS_MODE: u8 = 0x05;
+const GRAPHICS_HOST_ODD_EVEN: u8 = 1 << 4;
+const GRAPHICS_MISCELLANEOUS: u8 = 0x06;
+const GRAPHICS_CHAIN_ODD_EVEN: u8 = 1 << 1;
+/// Character shapes live in plane 2, 32 bytes to a character whatever the font height.
+const FONT_PLANE: u8 = 2;
+const GLYPH_STRIDE: usize = 32;
+const GLYPH_COUNT: usize = 256;
+
+const CRTC_VERTICAL_RETRACE_END: u8 = 0x11;
+const CRTC_END_HORIZONTAL_BLANKING: u8 = 0x03;
+/// Set in the vertical retrace end register to make CRTC registers 0 to 7 read-only.
+const CRTC_PROTECT: u8 = 1 << 7;
+/// Set in the end horizontal blanking register to reach the vertical retrace registers.
+const CRTC_VERTICAL_RETRACE_ACCESS: u8 = 1 << 7;
+
+/// The layouts of the screen in text mode, as columns by rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// The mode the BIOS boots in, with a 9x16 font.
+    Text80x25,
+    /// The 400 line timing of 80x25 with an 8x8 font.
+    Text80x50,
+    /// 480 lines and 720 pixels, divided into 8x8 characters.
+    Text90x60,
+}
+
+/// The values of the miscellaneous output register, the sequencer registers and the CRT
+/// controller registers for a mode.
+struct Registers {
+    miscellaneous: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+}
+
+impl TextMode {
+    pub const ALL: [TextMode; 3] = [
+        TextMode::Text80x25,
+        TextMode::Text80x50,
+        TextMode::Text90x60,
+    ];
+
+    /// The number of columns and rows.
+    pub fn size(self) -> (usize, usize) {
+        match self {
+            TextMode::Text80x25 => (80, 25),
+            TextMode::Text80x50 => (80, 50),
+            TextMode::Text90x60 => (90, 60),
+        }
+    }
+
+    /// The number of scan lines in a character.
+    pub fn font_height(self) -> usize {
+        match self {
+            TextMode::Text80x25 => 16,
+            TextMode::Text80x50 | TextMode::Text90x60 => 8,
+        }
+    }
+
+    fn registers(self) -> Registers {
+        match self {
+            TextMode::Text80x25 => Registers {
+                miscellaneous: 0x67,
+                sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+                crtc: [
+                    0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00,
+                    0x00, 0x00, 0x50, 0x9C, 0x0E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+                ],
+            },
+            TextMode::Text80x50 => Registers {
+                miscellaneous: 0x67,
+                sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+                crtc: [
+                    0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x47, 0x06, 0x07, 0x00,
+                    0x00, 0x01, 0x40, 0x9C, 0x8E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+                ],
+            },
+            // The 28 MHz clock with 8 pixel wide characters, and the vertical timing of 640x480.
+            TextMode::Text90x60 => Registers {
+                miscellaneous: 0xE7,
+                sequencer: [0x03, 0x01, 0x03, 0x00, 0x02],
+                crtc: [
+                    0x6B, 0x59, 0x5A, 0x82, 0x60, 0x8D, 0x0B, 0x3E, 0x00, 0x47, 0x06, 0x07, 0x00,
+                    0x00, 0x00, 0x00, 0xEA, 0x0C, 0xDF, 0x2D, 0x08, 0xE8, 0x05, 0xA3, 0xFF,
+                ],
+            },
+        }
+    }
+}
+
+impl fmt::Display for TextMode {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let (columns, rows) = self.size();
+        write!(formatter, "{columns}x{rows}")
+    }
+}
+
+fn write_sequencer(index: u8, value: u8) {
+    unsafe {
+        Port::new(SEQUENCER_INDEX_PORT_ADDRESS).write(index);
+        Port::new(SEQUENCER_DATA_PORT_ADDRESS).write(value);
+    }
+}
+
+fn read_sequencer(index: u8) -> u8 {
+    unsafe {
+        Port::new(SEQUENCER_INDEX_PORT_ADDRESS).write(index);
+        Port::new(SEQUENCER_DATA_PORT_ADDRESS).read()
+    }
+}
+
+fn write_graphics(index: u8, value: u8) {
+    unsafe {
+        Port::new(GRAPHICS_INDEX_PORT_ADDRESS).write(index);
+        Port::new(GRAPHICS_DATA_PORT_ADDRESS).write(value);
+    }
+}
+
+fn read_graphics(index: u8) -> u8 {
+    unsafe {
+        Port::new(GRAPHICS_INDEX_PORT_ADDRESS).write(index);
+        Port::new(GRAPHICS_DATA_PORT_ADDRESS).read()
+    }
+}
+
+/// Switches the VGA card to `mode` and loads a font of the right height. The text buffer is left
+/// as it is; the caller redraws it.
+pub(super) fn program(mode: TextMode) {
+    let bios_font = bios_font();
+    let registers = mode.registers();
+    write_sequencer(SEQUENCER_RESET, SYNCHRONOUS_RESET);
+    unsafe { Port::new(MISC_OUTPUT_WRITE_PORT_ADDRESS).write(registers.miscellaneous) };
+    for (index, &value) in registers.sequencer.iter().enumerate().skip(1) {
+        write_sequencer(index as u8, value);
+    }
+    write_sequencer(SEQUENCER_RESET, registers.sequencer[0]);
+
+    write_crtc(
+        CRTC_END_HORIZONTAL_BLANKING,
+        read_crtc(CRTC_END_HORIZONTAL_BLANKING) | CRTC_VERTICAL_RETRACE_ACCESS,
+    );
+    write_crtc(
+        CRTC_VERTICAL_RETRACE_END,
+        read_crtc(CRTC_VERTICAL_RETRACE_END) & !CRTC_PROTECT,
+    );
+    for (index, &value) in registers.crtc.iter().enumerate() {
+        let value = match index as u8 {
+            CRTC_END_HORIZONTAL_BLANKING => value | CRTC_VERTICAL_RETRACE_ACCESS,
+            CRTC_VERTICAL_RETRACE_END => value & !CRTC_PROTECT,
+            _ => value,
+        };
+        write_crtc(index as u8, value);
+    }
+    load_font(bios_font, mode.font_height());
+}
+
+/// The font the BIOS loaded and its height, saved before it is first replaced.
+static BIOS_FONT: Once<(usize, Vec<u8>)> = Once::new();
+
+/// The font the BIOS loaded and its height, which has to be read before the first mode switch.
+fn bios_font() -> &'static (usize, Vec<u8>) {
+    BIOS_FONT.call_once(|| {
+        let height = usize::from(read_crtc(CRTC_MAXIMUM_SCAN_LINE) & SCAN_LINE_MASK) + 1;
+        let mut font = Vec::with_capacity(GLYPH_COUNT * GLYPH_STRIDE);
+        with_font_plane(|plane| {
+            for offset in 0..GLYPH_COUNT * GLYPH_STRIDE {
+                font.push(unsafe { core::ptr::read_volatile(plane.add(offset)) });
+            }
+        });
+        (height, font)
+    })
+}
+
+/// Writes a font `height` scan lines high, made by merging the lines of the BIOS font.
+fn load_font(&(bios_height, ref font): &(usize, Vec<u8>), height: usize) {
+    with_font_plane(|plane| {
+        for (character, glyph) in font.chunks(GLYPH_STRIDE).enumerate() {
+            for line in 0..height {
+                // Each scan line of the new font covers one or more of the BIOS font.
+                let first = line * bios_height / height;
+                let last = ((line + 1) * bios_height / height).max(first + 1);
+                let bits = glyph[first..last].iter().fold(0, |bits, &line| bits | line);
+                let offset = character * GLYPH_STRIDE + line;
+                unsafe { core::ptr::write_volatile(plane.add(offset), bits) };
+            }
+        }
+    });
+}
+
+/// Runs `access` with plane 2, which holds the font, mapped at the text buffer's address for
+/// both reads and writes, then maps the text planes back.
+fn with_font_plane(access: impl FnOnce(*mut u8)) {
+    let map_mask = read_sequencer(SEQUENCER_MAP_MASK);
+    let memory_mode = read_sequencer(SEQUENCER_MEMORY_MODE);
+    let read_map = read_graphics(GRAPHICS_READ_MAP_SELECT);
+    let graphics_mode = read_graphics(GRAPHICS_MODE);
+    let miscellaneous = read_graphics(GRAPHICS_MISCELLANEOUS);
+
+    write_sequencer(SEQUENCER_MEMORY_MODE, memory_mode | ODD_EVEN_DISABLE);
+    write_graphics(GRAPHICS_MODE, graphics_mode & !GRAPHICS_HOST_ODD_EVEN);
+    write_graphics(
+        GRAPHICS_MISCELLANEOUS,
+        miscellaneous & !GRAPHICS_CHAIN_ODD_EVEN,
+    );
+    write_graphics(GRAPHICS_READ_MAP_SELECT, FONT_PLANE);
+    write_sequencer(SEQUENCER_MAP_MASK, 1 << FONT_PLANE);
+
+    access(VGA_BUFFER_ADDRESS as *mut u8);
+
+    write_sequencer(SEQUENCER_MAP_MASK, map_mask);
+    write_sequencer(SEQUENCER_MEMORY_MODE, memory_mode);
+    write_graphics(GRAPHICS_READ_MAP_SELECT, read_map);
+    write_graphics(GRAPHICS_MODE, graphics_mode);
+    write_graphics(GRAPHICS_MISCELLANEOUS, miscellaneous);
+}
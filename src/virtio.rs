@@ -0,0 +1,345 @@
+//! Virtio devices on PCI, through either the legacy I/O port interface or the modern interface
+//! located by vendor-specific capabilities.
+
+use x86_64::{PhysAddr, VirtAddr, instructions::port::Port};
+
+use crate::{
+    memory,
+    pci::{self, Bar, PciDevice},
+};
+
+pub mod queue;
+
+pub use queue::{Buffer, VirtQueue};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// Transitional devices use IDs from this one on, offset by the device type.
+pub const LEGACY_DEVICE_ID_BASE: u16 = 0x1000;
+/// Devices without the legacy interface use IDs from this one on, offset by the device type.
+pub const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Set in the ISR status when a queue has used buffers.
+const ISR_QUEUE: u8 = 1 << 0;
+
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// Where the device configuration starts while MSI-X is disabled.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+const CAPABILITY_COMMON_CONFIG: u8 = 1;
+const CAPABILITY_NOTIFY_CONFIG: u8 = 2;
+const CAPABILITY_ISR_CONFIG: u8 = 3;
+const CAPABILITY_DEVICE_CONFIG: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1E;
+const COMMON_QUEUE_DESCRIPTORS: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Queues larger than this are shrunk where the device allows it.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device offers neither interface, or lacks a region of the modern one.
+    UnsupportedTransport,
+    /// The device rejected the features the driver asked for.
+    FeaturesRejected,
+    QueueUnavailable,
+    BadQueueSize,
+    QueueFull,
+    OutOfMemory,
+}
+
+/// The registers of the modern interface, each mapped from the BAR a capability points into.
+#[derive(Debug)]
+pub struct ModernRegisters {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+}
+
+#[derive(Debug)]
+pub enum Transport {
+    Legacy { base: u16 },
+    Modern(ModernRegisters),
+}
+
+impl Transport {
+    /// Picks the modern interface when the device has one, and the legacy one otherwise.
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        if let Some(registers) = ModernRegisters::new(device)? {
+            device.enable(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+            return Ok(Transport::Modern(registers));
+        }
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => {
+                device.enable(pci::COMMAND_IO_SPACE | pci::COMMAND_BUS_MASTER);
+                Ok(Transport::Legacy { base: port })
+            }
+            _ => Err(VirtioError::UnsupportedTransport),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern(_))
+    }
+
+    /// Resets the device and negotiates features, accepting those of `supported` the device
+    /// offers. Returns the negotiated features.
+    pub fn initialize(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let offered = self.device_features();
+        match self {
+            Transport::Legacy { .. } => {
+                let features = offered & supported & u64::from(u32::MAX);
+                self.set_driver_features(features);
+                Ok(features)
+            }
+            Transport::Modern(_) => {
+                let features = offered & (supported | FEATURE_VERSION_1);
+                self.set_driver_features(features);
+                let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+                self.set_status(status);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    return Err(VirtioError::FeaturesRejected);
+                }
+                Ok(features)
+            }
+        }
+    }
+
+    /// Tells the device the driver is ready; queues must be set up before.
+    pub fn finish_initialization(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Resets the device, so that it stops using the queues it was given, and tells it the
+    /// driver has given up on it.
+    pub fn fail(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_FAILED);
+    }
+
+    /// Creates queue `index` and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<VirtQueue, VirtioError> {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_QUEUE_SELECT).write(index);
+                let size: u16 = Port::new(base + LEGACY_QUEUE_SIZE).read();
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                // The legacy interface cannot shrink queues.
+                let queue = VirtQueue::new(index, size)?;
+                let frame_number = queue.descriptor_address().as_u64() / 4096;
+                Port::new(base + LEGACY_QUEUE_ADDRESS).write(frame_number as u32);
+                Ok(queue)
+            },
+            Transport::Modern(registers) => unsafe {
+                let common = registers.common;
+                write(common + COMMON_QUEUE_SELECT, index);
+                let size: u16 = read(common + COMMON_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                let queue = VirtQueue::new(index, size.min(MAX_QUEUE_SIZE))?;
+                write(common + COMMON_QUEUE_SIZE, queue.size());
+                for (register, address) in [
+                    (COMMON_QUEUE_DESCRIPTORS, queue.descriptor_address()),
+                    (COMMON_QUEUE_DRIVER, queue.available_address()),
+                    (COMMON_QUEUE_DEVICE, queue.used_address()),
+                ] {
+                    // 64-bit registers are written as two halves, low half first.
+                    write(common + register, address.as_u64() as u32);
+                    write(common + register + 4, (address.as_u64() >> 32) as u32);
+                }
+                write(common + COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Tells the device that queue `index` has new buffers.
+    pub fn notify(&self, index: u16) {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_QUEUE_NOTIFY).write(index)
+            },
+            Transport::Modern(registers) => unsafe {
+                write(registers.common + COMMON_QUEUE_SELECT, index);
+                let offset: u16 = read(registers.common + COMMON_QUEUE_NOTIFY_OFFSET);
+                let address =
+                    registers.notify + u64::from(offset) * u64::from(registers.notify_multiplier);
+                write(address, index);
+            },
+        }
+    }
+
+    /// Reads and thereby clears the interrupt status, returning whether a queue interrupt was
+    /// pending.
+    pub fn acknowledge_interrupt(&self) -> bool {
+        let status: u8 = match self {
+            Transport::Legacy { base } => unsafe { Port::new(base + LEGACY_ISR_STATUS).read() },
+            Transport::Modern(registers) => unsafe { read(registers.isr) },
+        };
+        status & ISR_QUEUE != 0
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_DEVICE_CONFIG + offset).read()
+            },
+            Transport::Modern(registers) => unsafe { read(registers.device + u64::from(offset)) },
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        u64::from(self.read_config_u32(offset))
+            | (u64::from(self.read_config_u32(offset + 4)) << 32)
+    }
+
+    fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { base } => unsafe { Port::new(base + LEGACY_DEVICE_STATUS).read() },
+            Transport::Modern(registers) => unsafe {
+                read(registers.common + COMMON_DEVICE_STATUS)
+            },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_DEVICE_STATUS).write(status)
+            },
+            Transport::Modern(registers) => unsafe {
+                write(registers.common + COMMON_DEVICE_STATUS, status)
+            },
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                u64::from(Port::<u32>::new(base + LEGACY_DEVICE_FEATURES).read())
+            },
+            Transport::Modern(registers) => (0..2).fold(0, |features, half| unsafe {
+                write(registers.common + COMMON_DEVICE_FEATURE_SELECT, half as u32);
+                let bits: u32 = read(registers.common + COMMON_DEVICE_FEATURE);
+                features | (u64::from(bits) << (32 * half))
+            }),
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern(registers) => {
+                for half in 0..2 {
+                    unsafe {
+                        write(registers.common + COMMON_DRIVER_FEATURE_SELECT, half as u32);
+                        write(
+                            registers.common + COMMON_DRIVER_FEATURE,
+                            (features >> (32 * half)) as u32,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ModernRegisters {
+    /// Maps the regions named by the device's vendor-specific capabilities. Returns `None` for
+    /// legacy-only devices.
+    fn new(device: &PciDevice) -> Result<Option<Self>, VirtioError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+        let mut notify_multiplier = 0;
+        for capability in &device.capabilities {
+            if capability.id != pci::CAPABILITY_VENDOR_SPECIFIC {
+                continue;
+            }
+            let offset = u16::from(capability.offset);
+            let kind = device.address.read_u8(offset + 3);
+            let bar = device.address.read_u8(offset + 4);
+            let region_offset = device.address.read_u32(offset + 8);
+            let length = device.address.read_u32(offset + 12);
+            let slot = match kind {
+                CAPABILITY_COMMON_CONFIG => &mut common,
+                CAPABILITY_NOTIFY_CONFIG => {
+                    notify_multiplier = device.address.read_u32(offset + 16);
+                    &mut notify
+                }
+                CAPABILITY_ISR_CONFIG => &mut isr,
+                CAPABILITY_DEVICE_CONFIG => &mut config,
+                _ => continue,
+            };
+            // The first capability of a kind is the preferred one.
+            if slot.is_some() {
+                continue;
+            }
+            let Some(Some(Bar::Memory { address, .. })) = device.bars.get(usize::from(bar)) else {
+                continue;
+            };
+            let physical = PhysAddr::new(address.as_u64() + u64::from(region_offset));
+            let mapped = memory::map_device_memory(physical, u64::from(length))
+                .map_err(|_| VirtioError::OutOfMemory)?;
+            *slot = Some(mapped);
+        }
+        match (common, notify, isr, config) {
+            (None, None, None, None) => Ok(None),
+            (Some(common), Some(notify), Some(isr), Some(device)) => Ok(Some(Self {
+                common,
+                notify,
+                notify_multiplier,
+                isr,
+                device,
+            })),
+            _ => Err(VirtioError::UnsupportedTransport),
+        }
+    }
+}
+
+unsafe fn read<T>(address: VirtAddr) -> T {
+    unsafe { address.as_ptr::<T>().read_volatile() }
+}
+
+unsafe fn write<T>(address: VirtAddr, value: T) {
+    unsafe { address.as_mut_ptr::<T>().write_volatile(value) }
+}
@@ -0,0 +1,208 @@
+//! Split virtqueues: a descriptor table, a ring of buffers made available to the device and a
+//! ring of buffers the device has used.
+
+use core::sync::atomic::{Ordering, fence};
+use x86_64::{PhysAddr, structures::paging::PhysFrame};
+
+use super::VirtioError;
+use crate::memory;
+
+const PAGE_SIZE: u64 = 4096;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+/// A buffer handed to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Whether the device writes to the buffer rather than reading it.
+    pub writable: bool,
+}
+
+/// A split virtqueue in the layout legacy devices require: the available ring follows the
+/// descriptor table, and the used ring starts on the next page boundary. Modern devices are given
+/// the address of each part and accept the same layout.
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    first_frame: PhysFrame,
+    descriptors: *mut Descriptor,
+    available: *mut u16,
+    used: *mut u16,
+    /// The head of the chain of free descriptors.
+    free_head: u16,
+    free_count: u16,
+    /// The next available ring slot to fill.
+    available_index: u16,
+    /// The next used ring slot to read.
+    used_index: u16,
+}
+
+// The rings live in memory owned by the queue.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    pub fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(VirtioError::BadQueueSize);
+        }
+        let (used_offset, total) = Self::layout(size);
+        let frames = memory::allocate_contiguous_frames(total.div_ceil(PAGE_SIZE))
+            .ok_or(VirtioError::OutOfMemory)?;
+        let base = memory::physical_to_virtual(frames.start.start_address());
+        unsafe { core::ptr::write_bytes(base.as_mut_ptr::<u8>(), 0, total as usize) };
+
+        let descriptors = base.as_mut_ptr::<Descriptor>();
+        for index in 0..size {
+            unsafe { (*descriptors.add(index as usize)).next = index + 1 };
+        }
+        Ok(Self {
+            index,
+            size,
+            first_frame: frames.start,
+            descriptors,
+            available: (base + 16 * u64::from(size)).as_mut_ptr(),
+            used: (base + used_offset).as_mut_ptr(),
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            used_index: 0,
+        })
+    }
+
+    /// The offset of the used ring and the total size of a queue with `size` entries.
+    fn layout(size: u16) -> (u64, u64) {
+        let size = u64::from(size);
+        let available_end = 16 * size + 2 * (3 + size);
+        let used_offset = available_end.next_multiple_of(PAGE_SIZE);
+        (used_offset, used_offset + 2 * 3 + 8 * size)
+    }
+
+    /// Returns the frames holding the rings to the frame allocator.
+    ///
+    /// # Safety
+    /// The device must no longer use the queue, as after [`super::Transport::fail`].
+    pub unsafe fn deallocate(self) {
+        let (_, total) = Self::layout(self.size);
+        let frames = PhysFrame::range(
+            self.first_frame,
+            self.first_frame + total.div_ceil(PAGE_SIZE),
+        );
+        for frame in frames {
+            unsafe { memory::deallocate_frame(frame) };
+        }
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.first_frame.start_address()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.descriptor_address() + 16 * u64::from(self.size)
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.descriptor_address() + Self::layout(self.size).0
+    }
+
+    /// Chains `buffers` together and makes them available to the device, returning the index of
+    /// the chain's head. The device still has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let descriptor = unsafe { &mut *self.descriptors.add(usize::from(index)) };
+            let next = descriptor.next;
+            descriptor.address = buffer.address.as_u64();
+            descriptor.length = buffer.length;
+            descriptor.flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if position + 1 < buffers.len() {
+                descriptor.flags |= DESCRIPTOR_NEXT;
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = self.available_index % self.size;
+        unsafe {
+            self.available
+                .add(2 + usize::from(slot))
+                .write_volatile(head)
+        };
+        // The device must see the ring entry before the index that publishes it.
+        fence(Ordering::SeqCst);
+        self.available_index = self.available_index.wrapping_add(1);
+        unsafe { self.available.add(1).write_volatile(self.available_index) };
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether the device has used a chain that [`VirtQueue::pop_used`] has not returned yet.
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        unsafe { self.used.add(1).read_volatile() != self.used_index }
+    }
+
+    /// Takes the next chain the device has finished with, returning its head and the number of
+    /// bytes the device wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        let slot = self.used_index % self.size;
+        let element = unsafe {
+            self.used
+                .add(2)
+                .cast::<UsedElement>()
+                .add(usize::from(slot))
+                .read_volatile()
+        };
+        self.used_index = self.used_index.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            let descriptor = unsafe { &mut *self.descriptors.add(usize::from(index)) };
+            self.free_count += 1;
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+        Some((head, element.length))
+    }
+}
@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{sync::Arc, vec};
+use common::check_pattern;
+use core::panic::PanicInfo;
+use rust_os::block::{
+    self, BlockDevice, BlockError, SECTOR_SIZE,
+    ata::{self, Addressing},
+};
+
+/// Sectors of `tests/disks/pattern.img`, attached as the primary slave.
+const SECTORS: u64 = 256;
+
+fn disk() -> Arc<dyn BlockDevice> {
+    block::find("hdb").expect("the test disk was not detected")
+}
+
+#[test_case]
+fn test_drives_are_identified() {
+    let names: vec::Vec<_> = block::devices()
+        .iter()
+        .map(|device| alloc::string::String::from(device.name()))
+        .collect();
+    // The boot image is the primary master.
+    assert_eq!(names[..2], ["hda", "hdb"]);
+    let disk = disk();
+    assert_eq!(disk.block_size(), SECTOR_SIZE);
+    assert_eq!(disk.block_count(), SECTORS);
+}
+
+#[test_case]
+fn test_reads_known_pattern() {
+    let disk = disk();
+    let mut buffer = vec![0; SECTORS as usize * SECTOR_SIZE];
+    disk.read_blocks(0, &mut buffer).unwrap();
+    check_pattern(0, &buffer);
+
+    let mut buffer = vec![0; 3 * SECTOR_SIZE];
+    disk.read_blocks(SECTORS - 3, &mut buffer).unwrap();
+    check_pattern(SECTORS - 3, &buffer);
+}
+
+#[test_case]
+fn test_lba28_and_lba48_agree() {
+    let drive = ata::drives()
+        .into_iter()
+        .find(|drive| drive.name() == "hdb")
+        .unwrap();
+    assert!(drive.supports_lba48());
+    assert!(drive.model().starts_with("QEMU"));
+
+    let mut buffer = vec![0; 4 * SECTOR_SIZE];
+    drive
+        .read_sectors(40, &mut buffer, Addressing::Lba48)
+        .unwrap();
+    check_pattern(40, &buffer);
+
+    let written = vec![0xA5; 2 * SECTOR_SIZE];
+    drive
+        .write_sectors(50, &written, Addressing::Lba48)
+        .unwrap();
+    let mut read = vec![0; 2 * SECTOR_SIZE];
+    drive
+        .read_sectors(50, &mut read, Addressing::Lba28)
+        .unwrap();
+    assert_eq!(read, written);
+}
+
+#[test_case]
+fn test_write_and_read_back() {
+    let disk = disk();
+    let written: vec::Vec<u8> = (0..SECTOR_SIZE).map(|index| (index / 2) as u8).collect();
+    disk.write_blocks(100, &written).unwrap();
+    disk.flush().unwrap();
+    let mut read = vec![0; SECTOR_SIZE];
+    disk.read_blocks(100, &mut read).unwrap();
+    assert_eq!(read, written);
+
+    // The neighbouring sectors are untouched.
+    let mut buffer = vec![0; SECTOR_SIZE];
+    disk.read_blocks(101, &mut buffer).unwrap();
+    check_pattern(101, &buffer);
+}
+
+#[test_case]
+fn test_rejects_bad_requests() {
+    let disk = disk();
+    let mut buffer = vec![0; 2 * SECTOR_SIZE];
+    assert_eq!(
+        disk.read_blocks(SECTORS - 1, &mut buffer),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_blocks(0, &mut buffer[..100]),
+        Err(BlockError::BadBufferSize)
+    );
+    assert_eq!(
+        disk.write_blocks(u64::MAX, &buffer),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -13,9 +13,11 @@ fn test_println() {
 }
 
 #[cfg(test)]
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    rust_os::init();
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
     test_main();
     rust_os::hlt_loop();
 }
@@ -1,10 +1,25 @@
+use rust_os::block::SECTOR_SIZE;
+
+/// Checks that `buffer` holds the sectors of `tests/disks/pattern.img` from `first_sector` on.
+#[allow(dead_code)]
+pub fn check_pattern(first_sector: u64, buffer: &[u8]) {
+    for (index, sector) in buffer.chunks_exact(SECTOR_SIZE).enumerate() {
+        let number = first_sector + index as u64;
+        assert_eq!(sector[..4], (number as u32).to_le_bytes());
+        for (offset, &byte) in sector.iter().enumerate().skip(4) {
+            assert_eq!(byte, (number * 7 + offset as u64) as u8);
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! should_panic_test {
     ($test_fn:expr) => {
-        #[unsafe(no_mangle)]
-        pub extern "C" fn _start() -> ! {
+        bootloader::entry_point!(kernel_main);
+
+        fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
             rust_os::serial_print!("{}...\t", core::any::type_name_of_val(&$test_fn));
-            rust_os::init();
+            rust_os::init(boot_info);
 
             $test_fn();
 
@@ -25,10 +40,11 @@ macro_rules! should_panic_test {
 #[macro_export]
 macro_rules! should_run_test {
     ($test_fn:expr) => {
-        #[unsafe(no_mangle)]
-        pub extern "C" fn _start() -> ! {
+        bootloader::entry_point!(kernel_main);
+
+        fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
             rust_os::serial_print!("{}...\t", core::any::type_name_of_val(&$test_fn));
-            rust_os::init();
+            rust_os::init(boot_info);
 
             $test_fn();
 
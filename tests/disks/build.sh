@@ -0,0 +1,120 @@
+#!/bin/sh
+# Rebuilds the disk images QEMU attaches to the test kernels (see the bootimage test-args in
+# Cargo.toml). Requires python3, mkfs.fat and mtools for fat.img, and mke2fs for ext2.img.
+set -e
+cd "$(dirname "$0")"
+# pattern.img: 256 sectors; byte i of sector n holds (n * 7 + i) mod 256, and the first four bytes
+# of every sector hold n as a little-endian u32.
+python3 - <<'EOF'
+import struct
+with open("pattern.img", "wb") as image:
+    for sector in range(256):
+        data = bytearray((sector * 7 + i) % 256 for i in range(512))
+        data[0:4] = struct.pack("<I", sector)
+        image.write(data)
+EOF
+# mbr.img: 512 sectors with primary partitions 1 and 3, and an extended partition 2 holding
+# logical partitions 5 and 6. The first sector of every partition holds "partition <number>".
+python3 - <<'EOF'
+import struct
+SECTOR = 512
+image = bytearray(512 * SECTOR)
+
+def entry(system_id, first, count):
+    return struct.pack("<B3sB3sII", 0, b"\0\0\0", system_id, b"\0\0\0", first, count)
+
+def boot_record(at, entries):
+    table = b"".join(entries).ljust(64, b"\0")
+    image[at * SECTOR + 446:at * SECTOR + 510] = table
+    image[at * SECTOR + 510:at * SECTOR + 512] = b"\x55\xaa"
+
+def mark(first, number):
+    text = b"partition %d" % number
+    image[first * SECTOR:first * SECTOR + len(text)] = text
+
+boot_record(0, [entry(0x83, 4, 60), entry(0x05, 64, 192), entry(0x0C, 256, 100)])
+boot_record(64, [entry(0x83, 2, 30), entry(0x05, 32, 32)])
+boot_record(96, [entry(0x0C, 2, 30)])
+for first, number in [(4, 1), (256, 3), (66, 5), (98, 6)]:
+    mark(first, number)
+with open("mbr.img", "wb") as file:
+    file.write(image)
+EOF
+# gpt.img: 512 sectors with a protective MBR, primary and backup GPT headers and partitions
+# "alpha" (sectors 40 to 99) and "beta" (100 to 199), marked like those of mbr.img.
+python3 - <<'EOF'
+import struct, uuid, zlib
+SECTOR = 512
+SECTORS = 512
+ENTRIES = 128
+ENTRY_SIZE = 128
+image = bytearray(SECTORS * SECTOR)
+image[446:462] = struct.pack("<B3sB3sII", 0, b"\0\0\0", 0xEE, b"\0\0\0", 1, SECTORS - 1)
+image[510:512] = b"\x55\xaa"
+
+partitions = [
+    ("0fc63daf-8483-4772-8e79-3d69d8477de4", "alpha", 40, 99),
+    ("ebd0a0a2-b9e5-4433-87c0-68b6b72699c7", "beta", 100, 199),
+]
+table = bytearray(ENTRIES * ENTRY_SIZE)
+for index, (type_guid, name, first, last) in enumerate(partitions):
+    unique = uuid.UUID(int=index + 1).bytes_le
+    table[index * ENTRY_SIZE:(index + 1) * ENTRY_SIZE] = (
+        uuid.UUID(type_guid).bytes_le + unique + struct.pack("<QQQ", first, last, 0)
+        + name.encode("utf-16-le").ljust(72, b"\0")
+    )
+    text = b"partition %d" % (index + 1)
+    image[first * SECTOR:first * SECTOR + len(text)] = text
+table_sectors = ENTRIES * ENTRY_SIZE // SECTOR
+
+def header(current, backup, entries_at):
+    fields = struct.pack(
+        "<8sIIIIQQQQ16sQIII", b"EFI PART", 0x00010000, 92, 0, 0, current, backup,
+        2 + table_sectors, SECTORS - 2 - table_sectors, uuid.UUID(int=0xD15C).bytes_le,
+        entries_at, ENTRIES, ENTRY_SIZE, zlib.crc32(table))
+    fields = fields[:16] + struct.pack("<I", zlib.crc32(fields)) + fields[20:]
+    return fields.ljust(SECTOR, b"\0")
+
+image[2 * SECTOR:(2 + table_sectors) * SECTOR] = table
+backup_entries = SECTORS - 1 - table_sectors
+image[backup_entries * SECTOR:(SECTORS - 1) * SECTOR] = table
+image[SECTOR:2 * SECTOR] = header(1, SECTORS - 1, 2)
+image[(SECTORS - 1) * SECTOR:] = header(SECTORS - 1, 1, backup_entries)
+with open("gpt.img", "wb") as file:
+    file.write(image)
+EOF
+# fat.img: see fat.sh.
+sh fat.sh
+
+# ext2.img: a 4 MiB ext2 file system with 1 KiB blocks holding the files tests/ext2.rs expects.
+# sparse.bin only has data at its start and end, which is past what double indirect blocks reach.
+tree=$(mktemp -d)
+trap 'rm -rf "$tree"' EXIT
+python3 - "$tree" <<'EOF'
+import os, sys
+tree = sys.argv[1]
+os.chdir(tree)
+os.makedirs("docs/nested")
+os.makedirs("many")
+with open("hello.txt", "w") as file:
+    file.write("hello from ext2\n")
+with open("docs/nested/deep.txt", "w") as file:
+    file.write("deep\n")
+for number in range(100):
+    with open("many/file-%d" % number, "w") as file:
+        file.write("file %d\n" % number)
+with open("big.bin", "wb") as file:
+    file.write(bytes(i * 13 % 251 for i in range(300 * 1024)))
+with open("sparse.bin", "wb") as file:
+    file.write(b"start")
+    file.seek(68000000)
+    file.write(b"triple")
+os.symlink("hello.txt", "link")
+os.symlink("docs/nested/" + "./" * 25 + "deep.txt", "long-link")
+os.symlink("loop", "loop")
+EOF
+rm -f ext2.img
+truncate -s 4M ext2.img
+E2FSPROGS_FAKE_TIME=315576000 mke2fs -q -t ext2 -b 1024 -d "$tree" -L rust_os \
+    -U 01234567-89ab-cdef-0123-456789abcdef \
+    -E root_owner=0:0,hash_seed=01234567-89ab-cdef-0123-456789abcdef ext2.img
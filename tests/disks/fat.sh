@@ -0,0 +1,74 @@
+#!/bin/sh
+# Builds fat.img, which is not kept in the repository as it is large and has to come from
+# mkfs.fat. build.rs runs this when the image is missing or older than this script. Requires
+# python3, mkfs.fat and mtools.
+set -e
+cd "$(dirname "$0")"
+# fat.img: an MBR disk with a FAT12 (partition 1), a FAT16 (2) and a FAT32 (3) file system, each
+# made by mkfs.fat with one sector to a cluster and holding the files tests/fat.rs expects.
+scratch=$(mktemp -d)
+trap 'rm -rf "$scratch"' EXIT
+files="$scratch/fat"
+mkdir "$files"
+printf 'hello from fat\n' >"$files/README.TXT"
+printf 'a file with a long name\n' >"$files/Long File Name.txt"
+printf 'lower case\n' >"$files/lower.txt"
+python3 - "$files/BIG.BIN" <<'EOF'
+import sys
+with open(sys.argv[1], "wb") as file:
+    file.write(bytes(index * 13 % 251 for index in range(5000)))
+EOF
+head -c 2560 /dev/zero | tr '\0' o >"$files/OTHER.BIN"
+printf '# nested\n' >"$files/nested file.md"
+head -c 512 /dev/zero >"$scratch/pad"
+
+# Built aside and moved into place once complete, so that a failed run leaves no image behind.
+image="$scratch/fat.img"
+truncate -s $(((6712 + 68000) * 512)) "$image"
+python3 - "$image" <<'EOF'
+import struct, sys
+PARTITIONS = [(0x01, 64, 2048), (0x06, 2112, 4600), (0x0C, 6712, 68000)]
+with open(sys.argv[1], "r+b") as image:
+    for slot, (system_id, first, count) in enumerate(PARTITIONS):
+        image.seek(446 + slot * 16)
+        image.write(struct.pack("<B3sB3sII", 0, b"", system_id, b"", first, count))
+    image.seek(510)
+    image.write(b"\x55\xaa")
+EOF
+
+export MTOOLS_SKIP_CHECK=1
+# The number of free clusters on the mtools drive $1.
+free_clusters() {
+    echo $(($(mdir -i "$1" :: | sed -n 's/ *bytes free.*//p' | tr -d ' ') / 512))
+}
+
+# Makes a FAT$3 file system of $2 sectors from sector $1. big.bin, other.bin and DOCS are written
+# while the only free clusters are gaps one cluster apart, so that they are fragmented: PAD first
+# fills the file system, then every other one of its one cluster files is deleted.
+make_fat() {
+    drive="$image@@$(($1 * 512))"
+    mkfs.fat -F "$3" -s 1 -h "$1" --offset "$1" -n "FAT$3" \
+        -i "$(printf %08x $((0x12340000 + $3)))" "$image" $(($2 / 2)) >/dev/null
+    mcopy -i "$drive" "$files/README.TXT" "$files/Long File Name.txt" "$files/lower.txt" ::
+    mmd -i "$drive" ::PAD
+    # 34 clusters for the pads and a few for PAD to grow into; REST takes what is left over.
+    truncate -s $((($(free_clusters "$drive") - 40) * 512)) "$scratch/fill"
+    mcopy -i "$drive" "$scratch/fill" ::PAD/FILL
+    for pad in $(seq 10 43); do
+        mcopy -i "$drive" "$scratch/pad" "::PAD/P$pad"
+    done
+    truncate -s $(($(free_clusters "$drive") * 512)) "$scratch/rest"
+    mcopy -i "$drive" "$scratch/rest" ::PAD/REST
+    # 17 gaps: 10 clusters for big.bin, 5 for other.bin and one each for DOCS and its file.
+    mdel -i "$drive" $(seq -f '::PAD/P%g' 11 2 43)
+    mcopy -i "$drive" "$files/BIG.BIN" "$files/OTHER.BIN" ::
+    mattrib -i "$drive" +r ::OTHER.BIN
+    mmd -i "$drive" ::DOCS
+    mcopy -i "$drive" "$files/nested file.md" "::DOCS/nested file.md"
+    # Deleted last, so that no entry written after it can take its place in the root directory.
+    mdeltree -i "$drive" ::PAD
+}
+make_fat 64 2048 12
+make_fat 2112 4600 16
+make_fat 6712 68000 32
+mv "$image" fat.img
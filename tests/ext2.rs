@@ -0,0 +1,269 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{self, BlockDevice, BlockError, SECTOR_SIZE},
+    fs::{self, Ext2Fs, FileSystem, FileType, FsError, OpenFlags},
+};
+
+/// The ext2 test disk with some of its bytes replaced, leaving the disk itself untouched.
+struct PatchedDisk {
+    device: Arc<dyn BlockDevice>,
+    /// The position of each patch on the disk and the bytes read there instead.
+    patches: Vec<(usize, Vec<u8>)>,
+}
+
+impl BlockDevice for PatchedDisk {
+    fn name(&self) -> &str {
+        "patched"
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.device.read_blocks(block, buffer)?;
+        let start = block as usize * SECTOR_SIZE;
+        for (position, bytes) in &self.patches {
+            for (index, &byte) in bytes.iter().enumerate() {
+                if let Some(offset) = (position + index).checked_sub(start)
+                    && offset < buffer.len()
+                {
+                    buffer[offset] = byte;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, _: u64, _: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::OutOfRange)
+    }
+}
+
+/// Reads `length` bytes from `position` on `device`.
+fn read_bytes(device: &dyn BlockDevice, position: usize, length: usize) -> Vec<u8> {
+    let first = position / SECTOR_SIZE;
+    let count = (position + length).div_ceil(SECTOR_SIZE) - first;
+    let mut sectors = vec![0; count * SECTOR_SIZE];
+    device.read_blocks(first as u64, &mut sectors).unwrap();
+    let start = position - first * SECTOR_SIZE;
+    sectors[start..start + length].to_vec()
+}
+
+/// Mounts `tests/disks/ext2.img`, attached as the fourth virtio disk, at `/<name>`.
+fn mount(name: &str) -> (String, Arc<Ext2Fs>) {
+    let device = block::find("vdd").expect("the ext2 test disk was not detected");
+    let ext2 = Ext2Fs::new(device).unwrap();
+    let path = format!("/{name}");
+    fs::create_directory(&path).unwrap();
+    fs::mount(&path, ext2.clone()).unwrap();
+    (path, ext2)
+}
+
+fn unmount(path: &str) {
+    fs::unmount(path).unwrap();
+    fs::remove(path).unwrap();
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let file = fs::open(path, OpenFlags::READ_ONLY).unwrap();
+    let mut contents = vec![0; file.stat().unwrap().size as usize];
+    let mut done = 0;
+    while done < contents.len() {
+        let count = file.read(&mut contents[done..]).unwrap();
+        assert_ne!(count, 0);
+        done += count;
+    }
+    contents
+}
+
+#[test_case]
+fn test_reads_files_written_on_the_host() {
+    let (path, ext2) = mount("read");
+    assert_eq!(ext2.label(), "rust_os");
+    assert_eq!(ext2.block_size(), 1024);
+
+    let mut names: Vec<String> = fs::read_dir(&path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "big.bin",
+            "docs",
+            "hello.txt",
+            "link",
+            "long-link",
+            "loop",
+            "lost+found",
+            "many",
+            "sparse.bin"
+        ]
+    );
+    assert_eq!(
+        read_file(&format!("{path}/hello.txt")),
+        b"hello from ext2\n"
+    );
+    assert_eq!(
+        read_file(&format!("{path}/docs/nested/deep.txt")),
+        b"deep\n"
+    );
+    assert_eq!(
+        fs::stat(&format!("{path}/docs")).unwrap().file_type,
+        FileType::Directory
+    );
+
+    // Past the direct blocks and into the double indirect ones.
+    let big = read_file(&format!("{path}/big.bin"));
+    assert_eq!(big.len(), 300 * 1024);
+    assert!(
+        big.iter()
+            .enumerate()
+            .all(|(index, &byte)| byte == (index * 13 % 251) as u8)
+    );
+
+    // Directories spanning several blocks.
+    let many = fs::read_dir(&format!("{path}/many")).unwrap();
+    assert_eq!(many.len(), 100);
+    assert!(
+        many.iter()
+            .all(|entry| entry.file_type == FileType::Regular)
+    );
+    assert_eq!(read_file(&format!("{path}/many/file-57")), b"file 57\n");
+    unmount(&path);
+}
+
+#[test_case]
+fn test_sparse_file_reaches_the_triple_indirect_block() {
+    let (path, _) = mount("sparse");
+    let file = fs::open(&format!("{path}/sparse.bin"), OpenFlags::READ_ONLY).unwrap();
+    assert_eq!(file.stat().unwrap().size, 68_000_006);
+    let mut buffer = [0xFF; 6];
+    file.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..5], b"start");
+
+    // Holes read as zeroes.
+    file.seek(fs::SeekFrom::Start(1_000_000)).unwrap();
+    assert_eq!(file.read(&mut buffer), Ok(6));
+    assert_eq!(buffer, [0; 6]);
+
+    file.seek(fs::SeekFrom::Start(68_000_000)).unwrap();
+    assert_eq!(file.read(&mut buffer), Ok(6));
+    assert_eq!(&buffer, b"triple");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    unmount(&path);
+}
+
+#[test_case]
+fn test_symbolic_links() {
+    let (path, _) = mount("links");
+    assert_eq!(fs::read_link(&format!("{path}/link")).unwrap(), "hello.txt");
+    assert_eq!(
+        fs::read_link(&format!("{path}/hello.txt")),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(read_file(&format!("{path}/link")), b"hello from ext2\n");
+    assert_eq!(
+        fs::stat(&format!("{path}/link")).unwrap().file_type,
+        FileType::Regular
+    );
+
+    // Targets too long for the block pointers are kept in a data block.
+    let target = fs::read_link(&format!("{path}/long-link")).unwrap();
+    assert!(target.len() > 60);
+    assert_eq!(read_file(&format!("{path}/long-link")), b"deep\n");
+
+    assert_eq!(
+        fs::stat(&format!("{path}/loop")),
+        Err(FsError::TooManyLinks)
+    );
+    assert_eq!(fs::read_link(&format!("{path}/loop")).unwrap(), "loop");
+    unmount(&path);
+}
+
+#[test_case]
+fn test_writes_are_refused() {
+    let (path, ext2) = mount("readonly");
+    assert_eq!(
+        fs::open(&format!("{path}/new"), OpenFlags::CREATE).err(),
+        Some(FsError::ReadOnly)
+    );
+    assert_eq!(
+        fs::remove(&format!("{path}/hello.txt")),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(
+        fs::rename(&format!("{path}/hello.txt"), &format!("{path}/moved")),
+        Err(FsError::ReadOnly)
+    );
+    let file = ext2.root().lookup("hello.txt").unwrap();
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::ReadOnly));
+    assert_eq!(file.truncate(0), Err(FsError::ReadOnly));
+    unmount(&path);
+}
+
+#[test_case]
+fn test_damaged_file_systems_are_refused() {
+    let device = block::find("vdd").unwrap();
+    let mount_patched = |patches| {
+        Ext2Fs::new(Arc::new(PatchedDisk {
+            device: device.clone(),
+            patches,
+        }))
+        .err()
+    };
+    assert_eq!(mount_patched(Vec::new()), None);
+
+    // The first data block lies past the end of the file system.
+    let block_count =
+        u32::from_le_bytes(read_bytes(device.as_ref(), 1024 + 4, 4).try_into().unwrap());
+    let first_data_block = (block_count + 1).to_le_bytes().to_vec();
+    assert_eq!(
+        mount_patched(vec![(1024 + 20, first_data_block)]),
+        Some(FsError::InvalidArgument)
+    );
+
+    // The root directory is a regular file. With 1 KiB blocks, the group descriptors start at
+    // block 2, and the root is the second inode of the first group's table.
+    let inode_table =
+        u32::from_le_bytes(read_bytes(device.as_ref(), 2048 + 8, 4).try_into().unwrap());
+    let inode_size = u16::from_le_bytes(
+        read_bytes(device.as_ref(), 1024 + 88, 2)
+            .try_into()
+            .unwrap(),
+    );
+    let root = inode_table as usize * 1024 + inode_size as usize;
+    let regular_file = 0o100644u16.to_le_bytes().to_vec();
+    assert_eq!(
+        mount_patched(vec![(root, regular_file)]),
+        Some(FsError::InvalidArgument)
+    );
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,286 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{self, SECTOR_SIZE},
+    fs::{self, FatFs, FileSystem, FileType, FsError, OpenFlags, fat::FatType},
+};
+
+/// The partitions of `tests/disks/fat.img`, attached as the third virtio disk, and the file
+/// system each holds.
+const PARTITIONS: [(&str, FatType); 3] = [
+    ("vdc1", FatType::Fat12),
+    ("vdc2", FatType::Fat16),
+    ("vdc3", FatType::Fat32),
+];
+
+/// Mounts every partition at `/<prefix><n>` and returns the file systems.
+fn mount_all(prefix: &str) -> Vec<(String, Arc<FatFs>)> {
+    PARTITIONS
+        .iter()
+        .enumerate()
+        .map(|(index, (partition, fat_type))| {
+            let device = block::find(partition).expect("the FAT test disk was not detected");
+            let fat = FatFs::new(device).unwrap();
+            assert_eq!(fat.fat_type(), *fat_type);
+            let path = format!("/{prefix}{index}");
+            fs::create_directory(&path).unwrap();
+            fs::mount(&path, fat.clone()).unwrap();
+            (path, fat)
+        })
+        .collect()
+}
+
+fn unmount_all(mounts: Vec<(String, Arc<FatFs>)>) {
+    for (path, _) in mounts {
+        fs::unmount(&path).unwrap().sync().unwrap();
+        fs::remove(&path).unwrap();
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let file = fs::open(path, OpenFlags::READ_ONLY).unwrap();
+    let mut contents = vec![0; file.stat().unwrap().size as usize];
+    let mut done = 0;
+    while done < contents.len() {
+        let count = file.read(&mut contents[done..]).unwrap();
+        assert_ne!(count, 0);
+        done += count;
+    }
+    contents
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn test_reads_files_written_on_the_host() {
+    let mounts = mount_all("read");
+    for (path, fat) in &mounts {
+        assert_eq!(fat.label(), format!("{:?}", fat.fat_type()).to_uppercase());
+        assert_eq!(
+            names(path),
+            [
+                "README.TXT",
+                "Long File Name.txt",
+                "lower.txt",
+                "BIG.BIN",
+                "OTHER.BIN",
+                "DOCS"
+            ]
+        );
+        assert_eq!(
+            read_file(&format!("{path}/README.TXT")),
+            b"hello from fat\n"
+        );
+        // Names are matched without regard to case, and by their short form too.
+        let long_name = b"a file with a long name\n";
+        assert_eq!(read_file(&format!("{path}/long file name.TXT")), long_name);
+        assert_eq!(read_file(&format!("{path}/LONGFI~1.TXT")), long_name);
+        assert_eq!(
+            read_file(&format!("{path}/docs/nested file.md")),
+            b"# nested\n"
+        );
+
+        // Both files are spread over every other cluster.
+        let big = read_file(&format!("{path}/big.bin"));
+        assert_eq!(big.len(), 5000);
+        assert!(
+            big.iter()
+                .enumerate()
+                .all(|(index, &byte)| byte == (index * 13 % 251) as u8)
+        );
+        assert!(
+            read_file(&format!("{path}/other.bin"))
+                .iter()
+                .all(|&byte| byte == b'o')
+        );
+
+        assert_eq!(
+            fs::stat(&format!("{path}/other.bin")).unwrap().permissions,
+            0o444
+        );
+        assert_eq!(
+            fs::stat(&format!("{path}/DOCS")).unwrap().file_type,
+            FileType::Directory
+        );
+    }
+    unmount_all(mounts);
+}
+
+#[test_case]
+fn test_written_files_survive_a_remount() {
+    let mounts = mount_all("write");
+    let data: Vec<u8> = (0..20_000u32).map(|index| (index * 7 + 3) as u8).collect();
+    for (path, _) in &mounts {
+        fs::create_directory(&format!("{path}/A Directory With A Long Name")).unwrap();
+        let file = fs::open(
+            &format!("{path}/a directory with a long name/data file.bin"),
+            OpenFlags::WRITE_ONLY | OpenFlags::CREATE,
+        )
+        .unwrap();
+        assert_eq!(file.write(&data), Ok(data.len()));
+        // Enough entries that the directory needs more than one cluster.
+        for number in 0..40 {
+            fs::open(
+                &format!("{path}/A Directory With A Long Name/entry number {number}"),
+                OpenFlags::WRITE_ONLY | OpenFlags::CREATE,
+            )
+            .unwrap();
+        }
+    }
+    unmount_all(mounts);
+
+    let mounts = mount_all("write");
+    for (path, _) in &mounts {
+        let directory = format!("{path}/A Directory With A Long Name");
+        assert_eq!(names(&directory).len(), 41);
+        assert_eq!(read_file(&format!("{directory}/data file.bin")), data);
+        for number in 0..40 {
+            fs::remove(&format!("{directory}/entry number {number}")).unwrap();
+        }
+        fs::remove(&format!("{directory}/data file.bin")).unwrap();
+        fs::remove(&directory).unwrap();
+    }
+    unmount_all(mounts);
+}
+
+#[test_case]
+fn test_clusters_are_allocated_and_freed() {
+    let mounts = mount_all("space");
+    for (path, fat) in &mounts {
+        let free = fat.free_clusters();
+        let file_path = format!("{path}/grow.bin");
+        let file = fs::open(&file_path, OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+        let cluster_size = fat.cluster_size() as usize;
+        file.write(&vec![0x11; 3 * cluster_size]).unwrap();
+        assert_eq!(fat.free_clusters(), free - 3);
+
+        // Writing past the end fills the gap with zeroes.
+        file.seek(fs::SeekFrom::Start(5 * cluster_size as u64))
+            .unwrap();
+        file.write(b"end").unwrap();
+        assert_eq!(fat.free_clusters(), free - 6);
+        let contents = read_file(&file_path);
+        assert!(
+            contents[3 * cluster_size..5 * cluster_size]
+                .iter()
+                .all(|&byte| byte == 0)
+        );
+        assert_eq!(&contents[5 * cluster_size..], b"end");
+
+        fs::open(&file_path, OpenFlags::WRITE_ONLY | OpenFlags::TRUNCATE).unwrap();
+        assert_eq!(fat.free_clusters(), free);
+        fs::remove(&file_path).unwrap();
+
+        let directory = format!("{path}/scratch");
+        fs::create_directory(&directory).unwrap();
+        let file = fs::open(
+            &format!("{directory}/file"),
+            OpenFlags::WRITE_ONLY | OpenFlags::CREATE,
+        )
+        .unwrap();
+        file.write(b"x").unwrap();
+        assert_eq!(fat.free_clusters(), free - 2);
+        assert_eq!(fs::remove(&directory), Err(FsError::DirectoryNotEmpty));
+        fs::remove(&format!("{directory}/file")).unwrap();
+        // The open file keeps its cluster until it is closed.
+        assert_eq!(fat.free_clusters(), free - 2);
+        drop(file);
+        fs::remove(&directory).unwrap();
+        assert_eq!(fat.free_clusters(), free);
+    }
+    unmount_all(mounts);
+}
+
+#[test_case]
+fn test_rename() {
+    let mounts = mount_all("rename");
+    for (path, _) in &mounts {
+        fs::create_directory(&format!("{path}/moved")).unwrap();
+        fs::rename(
+            &format!("{path}/README.TXT"),
+            &format!("{path}/moved/Read Me First.txt"),
+        )
+        .unwrap();
+        assert_eq!(
+            read_file(&format!("{path}/moved/read me first.txt")),
+            b"hello from fat\n"
+        );
+        assert_eq!(
+            fs::stat(&format!("{path}/README.TXT")),
+            Err(FsError::NotFound)
+        );
+
+        // A directory cannot be moved into itself.
+        assert_eq!(
+            fs::rename(&format!("{path}/moved"), &format!("{path}/moved/inner")),
+            Err(FsError::InvalidArgument)
+        );
+        fs::rename(&format!("{path}/moved"), &format!("{path}/DOCS/moved")).unwrap();
+        assert_eq!(names(&format!("{path}/docs")), ["nested file.md", "moved"]);
+        // Finding that this would put DOCS inside itself follows the `..` entry the move updated.
+        assert_eq!(
+            fs::rename(&format!("{path}/docs"), &format!("{path}/docs/moved/docs")),
+            Err(FsError::InvalidArgument)
+        );
+
+        fs::rename(&format!("{path}/lower.txt"), &format!("{path}/big.bin")).unwrap();
+        assert_eq!(read_file(&format!("{path}/big.bin")), b"lower case\n");
+        assert_eq!(
+            fs::open(&format!("{path}/name?"), OpenFlags::CREATE).err(),
+            Some(FsError::InvalidArgument)
+        );
+    }
+    unmount_all(mounts);
+}
+
+#[test_case]
+fn test_fsinfo_tracks_free_clusters() {
+    let device = block::find("vdc3").unwrap();
+    let fat = FatFs::new(device.clone()).unwrap();
+    let file = fat.root().create("fsinfo.bin", FileType::Regular).unwrap();
+    file.write_at(0, &vec![1; 10 * fat.cluster_size() as usize])
+        .unwrap();
+    fat.sync().unwrap();
+
+    let mut fsinfo = [0; SECTOR_SIZE];
+    device.read_blocks(1, &mut fsinfo).unwrap();
+    let free = u32::from_le_bytes(fsinfo[488..492].try_into().unwrap());
+    assert_eq!(free, fat.free_clusters());
+
+    drop(file);
+    fat.root().unlink("fsinfo.bin").unwrap();
+    fat.sync().unwrap();
+    device.read_blocks(1, &mut fsinfo).unwrap();
+    let free_after = u32::from_le_bytes(fsinfo[488..492].try_into().unwrap());
+    assert_eq!(free_after, free + 10);
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -5,8 +5,6 @@
 #[path = "../common/mod.rs"]
 mod common;
 
-use rust_os;
-
 should_run_test!(page_fault);
 
 fn page_fault() {
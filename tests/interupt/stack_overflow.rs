@@ -5,11 +5,9 @@
 #[path = "../common/mod.rs"]
 mod common;
 
-use rust_os;
-
 should_panic_test!(stack_overflow);
 
-#[allow(unconditional_recursion)]
+#[allow(unconditional_recursion, clippy::unit_arg)]
 fn stack_overflow() {
     core::hint::black_box(stack_overflow());
 }
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rust_os::{
+    elf::ElfError,
+    loader::{self, LoadError},
+    vga_buffer::VGA_WRITER,
+};
+
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+static SECTIONS: &[u8] = include_bytes!("programs/sections.elf");
+
+#[test_case]
+fn test_run_with_arguments() {
+    VGA_WRITER.lock().clear();
+    let program = loader::load(HELLO, &["hello", "from-argv"], &["TERM=vga"]).unwrap();
+    assert_eq!(loader::run(&program), 2);
+
+    // The program prints its first argument followed by a newline.
+    let row = unsafe { (0xb8000 as *const u16).add(23 * 80) };
+    for (column, expected) in "from-argv".bytes().enumerate() {
+        let character = unsafe { core::ptr::read_volatile(row.add(column)) } as u8;
+        assert_eq!(expected, character);
+    }
+}
+
+#[test_case]
+fn test_run_zeroes_bss_and_loads_data() {
+    let program = loader::load(SECTIONS, &["sections"], &[]).unwrap();
+    assert_eq!(loader::run(&program), 0);
+}
+
+#[test_case]
+fn test_load_rejects_invalid_elf() {
+    let error = loader::load(&HELLO[1..], &[], &[]).unwrap_err();
+    assert_eq!(error, LoadError::Elf(ElfError::BadMagic));
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
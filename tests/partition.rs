@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, sync::Arc, vec};
+use core::panic::PanicInfo;
+use rust_os::block::{
+    self, BlockCache, BlockDevice, BlockError, SECTOR_SIZE,
+    partition::{self, PartitionError, PartitionKind},
+};
+
+/// `tests/disks/mbr.img`, attached as the secondary master.
+const MBR_DISK: &str = "hdc";
+/// `tests/disks/gpt.img`, attached as the secondary slave.
+const GPT_DISK: &str = "hdd";
+const DISK_SECTORS: u64 = 512;
+
+fn device(name: &str) -> Arc<dyn BlockDevice> {
+    block::find(name).unwrap_or_else(|| panic!("{name} was not registered"))
+}
+
+fn check_marker(device: &dyn BlockDevice, number: u32) {
+    let mut buffer = [0; SECTOR_SIZE];
+    device.read_blocks(0, &mut buffer).unwrap();
+    let marker = format!("partition {number}");
+    assert_eq!(&buffer[..marker.len()], marker.as_bytes());
+}
+
+#[test_case]
+fn test_mbr_partitions() {
+    let partitions = partition::scan(&device(MBR_DISK)).unwrap();
+    let layout: vec::Vec<_> = partitions
+        .iter()
+        .map(|partition| {
+            (
+                partition.number(),
+                partition.first_block(),
+                partition.block_count(),
+                partition.kind().clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, 4, 60, PartitionKind::Mbr(0x83)),
+            (3, 256, 100, PartitionKind::Mbr(0x0C)),
+            (5, 66, 30, PartitionKind::Mbr(0x83)),
+            (6, 98, 30, PartitionKind::Mbr(0x0C)),
+        ]
+    );
+    for number in [1, 3, 5, 6] {
+        check_marker(device(&format!("{MBR_DISK}{number}")).as_ref(), number);
+    }
+    // The extended partition itself is not a device.
+    assert!(block::find("hdc2").is_none());
+}
+
+#[test_case]
+fn test_gpt_partitions() {
+    let partitions = partition::scan(&device(GPT_DISK)).unwrap();
+    assert_eq!(partitions.len(), 2);
+    for (partition, (label, first_block, block_count)) in partitions
+        .iter()
+        .zip([("alpha", 40, 60), ("beta", 100, 100)])
+    {
+        assert_eq!(partition.first_block(), first_block);
+        assert_eq!(partition.block_count(), block_count);
+        let PartitionKind::Gpt {
+            label: found_label, ..
+        } = partition.kind()
+        else {
+            panic!("{} was not read from the GPT", partition.name());
+        };
+        assert_eq!(found_label, label);
+    }
+    check_marker(device("hdd1").as_ref(), 1);
+    check_marker(device("hdd2").as_ref(), 2);
+}
+
+#[test_case]
+fn test_writes_stay_inside_the_partition() {
+    let partition = device("hdc5");
+    let last = partition.block_count() - 1;
+    partition.write_blocks(last, &[0x5A; SECTOR_SIZE]).unwrap();
+    assert_eq!(
+        partition.write_blocks(last, &[0; 2 * SECTOR_SIZE]),
+        Err(BlockError::OutOfRange)
+    );
+
+    let disk = device(MBR_DISK);
+    let mut buffer = [0; 2 * SECTOR_SIZE];
+    disk.read_blocks(66 + last, &mut buffer).unwrap();
+    assert!(buffer[..SECTOR_SIZE].iter().all(|&byte| byte == 0x5A));
+    // The second extended boot record follows partition 5.
+    assert_eq!(buffer[2 * SECTOR_SIZE - 2..], [0x55, 0xAA]);
+}
+
+#[test_case]
+fn test_cache_over_a_partition() {
+    let cache = BlockCache::new(device("hdd2"), 4);
+    let mut buffer = [0; SECTOR_SIZE];
+    cache.read_blocks(0, &mut buffer).unwrap();
+    cache.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(cache.statistics().hits, 1);
+    assert_eq!(&buffer[..11], b"partition 2");
+
+    cache.write_blocks(1, &[0xC4; SECTOR_SIZE]).unwrap();
+    assert_eq!(cache.dirty_blocks(), 1);
+    cache.sync().unwrap();
+    assert_eq!(cache.dirty_blocks(), 0);
+    device(GPT_DISK).read_blocks(101, &mut buffer).unwrap();
+    assert!(buffer.iter().all(|&byte| byte == 0xC4));
+}
+
+#[test_case]
+fn test_backup_gpt_header_is_used() {
+    let disk = device(GPT_DISK);
+    let mut primary = [0; SECTOR_SIZE];
+    let mut backup = [0; SECTOR_SIZE];
+    disk.read_blocks(1, &mut primary).unwrap();
+    disk.read_blocks(DISK_SECTORS - 1, &mut backup).unwrap();
+
+    let mut corrupt = primary;
+    corrupt[40] ^= 0xFF;
+    disk.write_blocks(1, &corrupt).unwrap();
+    let partitions = partition::scan(&disk).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[1].first_block(), 100);
+
+    let mut corrupt = backup;
+    corrupt[0] = 0;
+    disk.write_blocks(DISK_SECTORS - 1, &corrupt).unwrap();
+    assert_eq!(
+        partition::scan(&disk).err(),
+        Some(PartitionError::BadGptHeader)
+    );
+
+    disk.write_blocks(1, &primary).unwrap();
+    disk.write_blocks(DISK_SECTORS - 1, &backup).unwrap();
+    assert!(partition::scan(&disk).is_ok());
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
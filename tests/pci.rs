@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::pci::{self, Bar, DeviceMatch, PciAddress, PciDevice, PciDriver};
+
+const INTEL: u16 = 0x8086;
+/// The PIIX3 IDE controller of QEMU's default i440FX machine.
+const PIIX3_IDE: u16 = 0x7010;
+
+fn find(vendor: u16, device: u16) -> Arc<PciDevice> {
+    pci::devices()
+        .into_iter()
+        .find(|found| found.vendor_id == vendor && found.device_id == device)
+        .expect("device not found")
+}
+
+#[test_case]
+fn test_configuration_space_access() {
+    let host = PciAddress::new(0, 0, 0);
+    assert_eq!(host.read_u16(0), INTEL);
+    assert_eq!(host.read_u32(0) & 0xFFFF, u32::from(INTEL));
+    // Functions that do not exist read as all ones.
+    assert_eq!(PciAddress::new(0, 31, 7).read_u16(0), 0xFFFF);
+}
+
+#[test_case]
+fn test_devices_are_enumerated() {
+    let devices = pci::devices();
+    assert_eq!(devices[0].address, PciAddress::new(0, 0, 0));
+    assert_eq!((devices[0].class, devices[0].subclass), (0x06, 0x00));
+    assert!(
+        devices
+            .windows(2)
+            .all(|pair| pair[0].address < pair[1].address)
+    );
+
+    let ide = find(INTEL, PIIX3_IDE);
+    assert_eq!((ide.class, ide.subclass), (0x01, 0x01));
+    // The function after the PIIX3 ISA bridge, found through its multi-function header.
+    assert_eq!(ide.address.function, 1);
+}
+
+#[test_case]
+fn test_bars_are_decoded() {
+    let ide = find(INTEL, PIIX3_IDE);
+    assert!(matches!(ide.bars[4], Some(Bar::Io { size: 16, .. })));
+
+    // The standard VGA adapter has a 16 MiB prefetchable framebuffer.
+    let vga = find(0x1234, 0x1111);
+    assert_eq!(vga.class, 0x03);
+    match vga.bars[0] {
+        Some(Bar::Memory {
+            address,
+            size,
+            prefetchable,
+            ..
+        }) => {
+            assert_eq!(size, 16 * 1024 * 1024);
+            assert!(prefetchable);
+            assert!(address.is_aligned(size));
+        }
+        bar => panic!("unexpected framebuffer BAR {:?}", bar),
+    }
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &Arc<PciDevice>) -> bool {
+    assert_eq!(device.device_id, PIIX3_IDE);
+    PROBED.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+static IDE_DRIVER: PciDriver = PciDriver {
+    name: "test-ide",
+    matches: &[DeviceMatch::Id {
+        vendor: INTEL,
+        device: PIIX3_IDE,
+    }],
+    probe,
+};
+
+static STORAGE_DRIVER: PciDriver = PciDriver {
+    name: "test-storage",
+    matches: &[DeviceMatch::Class {
+        class: 0x01,
+        subclass: 0x01,
+    }],
+    probe,
+};
+
+#[test_case]
+fn test_drivers_bind_by_id() {
+    pci::register_driver(&IDE_DRIVER);
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    assert_eq!(find(INTEL, PIIX3_IDE).driver(), Some("test-ide"));
+
+    // A bound device is not offered to other drivers.
+    pci::register_driver(&STORAGE_DRIVER);
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    assert_eq!(find(INTEL, PIIX3_IDE).driver(), Some("test-ide"));
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
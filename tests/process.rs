@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rust_os::{
+    fs::{self, OpenFlags},
+    interupt::pit,
+    memory,
+    process::{self, WaitError},
+    scheduler,
+};
+
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+static ISOLATION: &[u8] = include_bytes!("programs/isolation.elf");
+static FORK: &[u8] = include_bytes!("programs/fork.elf");
+static MMAP: &[u8] = include_bytes!("programs/mmap.elf");
+static MAPFILE: &[u8] = include_bytes!("programs/mapfile.elf");
+static ORPHAN: &[u8] = include_bytes!("programs/orphan.elf");
+
+fn allocated_frames() -> u64 {
+    memory::FRAME_ALLOCATOR
+        .get()
+        .unwrap()
+        .lock()
+        .allocated_frames()
+}
+
+#[test_case]
+fn test_wait_returns_exit_code() {
+    let pid = process::spawn(HELLO, &["hello", "world"], &[]).unwrap();
+    assert_eq!(process::wait(pid), Ok(2));
+    assert_eq!(process::wait(pid), Err(WaitError::NoSuchProcess));
+}
+
+#[test_case]
+fn test_process_cannot_read_other_process_memory() {
+    let frames_before = allocated_frames();
+    let owner = process::spawn(ISOLATION, &["isolation", "owner"], &[]).unwrap();
+    let reader = process::spawn(ISOLATION, &["isolation", "reader"], &[]).unwrap();
+    assert_eq!(process::wait(reader), Ok(process::FAULT_EXIT_CODE));
+    assert_eq!(process::wait(owner), Ok(42));
+    assert_eq!(allocated_frames(), frames_before);
+}
+
+#[test_case]
+fn test_fork_copies_on_write() {
+    let frames_before = allocated_frames();
+    let pid = process::spawn(FORK, &["fork"], &[]).unwrap();
+    assert_eq!(process::wait(pid), Ok(0));
+    assert_eq!(allocated_frames(), frames_before);
+}
+
+#[test_case]
+fn test_mmap_regions_are_lazy_and_can_be_split() {
+    let frames_before = allocated_frames();
+    let pid = process::spawn(MMAP, &["mmap"], &[]).unwrap();
+    assert_eq!(process::wait(pid), Ok(process::FAULT_EXIT_CODE));
+    assert_eq!(allocated_frames(), frames_before);
+}
+
+#[test_case]
+fn test_file_backed_mmap() {
+    let file = fs::open("/mapped", OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    file.write(&[42; 10]).unwrap();
+    let pid = process::spawn(MAPFILE, &["mapfile"], &[]).unwrap();
+    // Descriptor 3, installed before the process gets to run.
+    process::with_process(pid, |process| process.files.insert(file)).unwrap();
+    assert_eq!(process::wait(pid), Ok(42));
+    fs::remove("/mapped").unwrap();
+}
+
+#[test_case]
+fn test_children_are_reaped_when_their_parent_does_not_wait() {
+    let frames_before = allocated_frames();
+    let pids_before = process::pids();
+    let pid = process::spawn(ORPHAN, &["orphan"], &[]).unwrap();
+    assert_eq!(process::wait(pid), Ok(0));
+    // The first child was reaped along with its parent; the second is still asleep.
+    assert_eq!(process::pids().len(), pids_before.len() + 1);
+    scheduler::sleep(pit::milliseconds_to_ticks(100));
+    assert_eq!(process::pids(), pids_before);
+    assert_eq!(allocated_frames(), frames_before);
+}
+
+#[test_case]
+fn test_wait_for_unknown_process() {
+    assert_eq!(process::wait(u64::MAX), Err(WaitError::NoSuchProcess));
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Rebuilds the user programs embedded by the loader tests. Requires GNU as and ld.
+set -e
+cd "$(dirname "$0")"
+for program in hello sections isolation fork mmap mapfile orphan; do
+    as --64 -o "$program.o" "$program.s"
+    ld -static -nostdlib -z max-page-size=0x1000 -z noexecstack \
+        -Ttext-segment=0x400000000000 -o "$program.elf" "$program.o"
+    rm "$program.o"
+done
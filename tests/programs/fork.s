@@ -0,0 +1,47 @@
+# Forks; the child overwrites a value in .data and exits with it. The parent waits for the
+# child and exits with 0 if its own copy of the value is unchanged and the child exited with 7,
+# or 1 otherwise.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    mov qword ptr [rip + value], 1
+    mov eax, 57                 # fork
+    syscall
+    test rax, rax
+    js 2f
+    jz 1f
+
+    mov rdi, rax                # wait4(child, &status, 0)
+    lea rsi, [rip + status]
+    xor edx, edx
+    mov eax, 61
+    syscall
+    test rax, rax
+    js 2f
+    cmp qword ptr [rip + value], 1
+    jne 2f
+    cmp dword ptr [rip + status], 7 << 8
+    jne 2f
+    xor edi, edi
+    mov eax, 60                 # exit(0)
+    syscall
+
+1:
+    mov qword ptr [rip + value], 5
+    add qword ptr [rip + value], 2
+    mov rdi, [rip + value]
+    mov eax, 60                 # exit(value)
+    syscall
+
+2:
+    mov edi, 1
+    mov eax, 60                 # exit(1)
+    syscall
+
+.section .data
+value:
+    .quad 0
+status:
+    .long 0
@@ -0,0 +1,33 @@
+# Writes its first argument followed by a newline to stdout and exits with argc.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    mov r12, [rsp]              # argc
+    cmp r12, 2
+    jb 1f
+    mov rsi, [rsp + 16]         # argv[1]
+    xor edx, edx
+0:
+    cmp byte ptr [rsi + rdx], 0
+    je 0f
+    inc rdx
+    jmp 0b
+0:
+    mov eax, 1                  # write
+    mov edi, 1
+    syscall
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + newline]
+    mov edx, 1
+    syscall
+1:
+    mov eax, 60                 # exit
+    mov rdi, r12
+    syscall
+
+.section .rodata
+newline:
+    .ascii "\n"
@@ -0,0 +1,34 @@
+# With argv[1] == "owner": maps a page at SECRET_ADDRESS, stores 42 in it, sleeps so other
+# processes can run, then exits with the value read back. Otherwise: reads SECRET_ADDRESS, which
+# must fault because the page belongs to another process, and exits with the value read.
+.intel_syntax noprefix
+.global _start
+
+.set SECRET_ADDRESS, 0x500000000000
+
+.section .text
+_start:
+    mov rbx, [rsp + 16]         # argv[1]
+    cmp byte ptr [rbx], 'o'
+    jne 1f
+
+    mov eax, 9                  # mmap(SECRET_ADDRESS, 4096, PROT_READ | PROT_WRITE,
+    mov rdi, SECRET_ADDRESS     #      MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS)
+    mov esi, 4096
+    mov edx, 3
+    mov r10d, 0x32
+    syscall
+    mov qword ptr [rax], 42
+    mov eax, 35                 # nanosleep(50 ms)
+    lea rdi, [rip + fifty_milliseconds]
+    xor esi, esi
+    syscall
+1:
+    mov rax, SECRET_ADDRESS
+    mov rdi, [rax]
+    mov eax, 60                 # exit
+    syscall
+
+.section .rodata
+fifty_milliseconds:
+    .quad 0, 50000000
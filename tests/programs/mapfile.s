@@ -0,0 +1,24 @@
+# Maps the file open on descriptor 3 read-only and exits with its first byte plus the byte
+# 4096 bytes further in, which must be zero because the file is shorter than two pages.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    mov eax, 9                  # mmap(0, 8192, PROT_READ, MAP_PRIVATE, 3, 0)
+    xor edi, edi
+    mov esi, 8192
+    mov edx, 1
+    mov r10d, 0x02
+    mov r8d, 3
+    xor r9d, r9d
+    syscall
+    mov rdi, rax
+    test rax, rax
+    js 1f
+    movzx edi, byte ptr [rax]
+    movzx eax, byte ptr [rax + 4096]
+    add rdi, rax
+1:
+    mov eax, 60                 # exit
+    syscall
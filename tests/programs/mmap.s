@@ -0,0 +1,63 @@
+# Maps three lazily populated pages, unmaps the middle one and makes the last one read-only.
+# Exits with the number of the first check that fails; if all pass, writes to the read-only page,
+# which must kill the program.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    mov eax, 9                  # mmap(0, 3 * 4096, PROT_READ | PROT_WRITE,
+    xor edi, edi                #      MAP_PRIVATE | MAP_ANONYMOUS)
+    mov esi, 3 * 4096
+    mov edx, 3
+    mov r10d, 0x22
+    syscall
+    mov edi, 1
+    test rax, rax
+    js 9f
+    mov rbx, rax
+
+    mov edi, 2                  # fresh pages read as zero
+    cmp qword ptr [rbx + 0x2000], 0
+    jne 9f
+    mov qword ptr [rbx], 11
+    mov qword ptr [rbx + 0x1000], 22
+    mov qword ptr [rbx + 0x2000], 33
+
+    mov eax, 11                 # munmap(middle page)
+    lea rdi, [rbx + 0x1000]
+    mov esi, 4096
+    syscall
+    mov edi, 3
+    test rax, rax
+    jnz 9f
+
+    mov eax, 10                 # mprotect(middle page) fails: nothing is mapped there
+    lea rdi, [rbx + 0x1000]
+    mov esi, 4096
+    mov edx, 1
+    syscall
+    mov edi, 4
+    cmp rax, -12
+    jne 9f
+
+    mov eax, 10                 # mprotect(last page, PROT_READ)
+    lea rdi, [rbx + 0x2000]
+    mov esi, 4096
+    mov edx, 1
+    syscall
+    mov edi, 5
+    test rax, rax
+    jnz 9f
+
+    mov edi, 6                  # both remaining pages kept their contents
+    cmp qword ptr [rbx], 11
+    jne 9f
+    cmp qword ptr [rbx + 0x2000], 33
+    jne 9f
+
+    mov qword ptr [rbx + 0x2000], 0
+    xor edi, edi
+9:
+    mov eax, 60                 # exit
+    syscall
@@ -0,0 +1,47 @@
+# Forks twice and exits with 0 without waiting for either child. The first child exits at once,
+# before its parent does; the second sleeps for 50 milliseconds and outlives it.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    mov eax, 57                 # fork
+    syscall
+    test rax, rax
+    js 2f
+    jz 1f
+    lea rdi, [rip + twenty_milliseconds]
+    xor esi, esi
+    mov eax, 35                 # nanosleep(20 ms), giving the first child time to exit
+    syscall
+
+    mov eax, 57                 # fork
+    syscall
+    test rax, rax
+    js 2f
+    jnz 3f
+    lea rdi, [rip + fifty_milliseconds]
+    xor esi, esi
+    mov eax, 35                 # nanosleep(50 ms)
+    syscall
+
+1:
+    mov edi, 3
+    mov eax, 60                 # exit(3)
+    syscall
+
+2:
+    mov edi, 1
+    mov eax, 60                 # exit(1)
+    syscall
+
+3:
+    xor edi, edi
+    mov eax, 60                 # exit(0)
+    syscall
+
+.section .rodata
+twenty_milliseconds:
+    .quad 0, 20000000
+fifty_milliseconds:
+    .quad 0, 50000000
@@ -0,0 +1,36 @@
+# Checks that .bss is zeroed and .data is writable, exiting with 0 on success.
+.intel_syntax noprefix
+.global _start
+
+.section .text
+_start:
+    lea rsi, [rip + zeroed]
+    mov ecx, 8192
+0:
+    cmp byte ptr [rsi + rcx - 1], 0
+    jne 1f
+    loop 0b
+    mov byte ptr [rsi], 0xff
+    lea rdi, [rip + counter]
+    add qword ptr [rdi], 1
+    cmp qword ptr [rdi], 42
+    jne 2f
+    mov eax, 60                 # exit(0)
+    xor edi, edi
+    syscall
+1:
+    mov eax, 60                 # exit(1): .bss was not zeroed
+    mov edi, 1
+    syscall
+2:
+    mov eax, 60                 # exit(2): .data had the wrong contents
+    mov edi, 2
+    syscall
+
+.section .data
+counter:
+    .quad 41
+
+.section .bss
+zeroed:
+    .skip 8192
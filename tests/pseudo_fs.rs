@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use core::panic::PanicInfo;
+use rust_os::{
+    block,
+    fs::{self, FileType, FsError, OpenFlags},
+    interupt::pit,
+    process, scheduler,
+};
+
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+
+/// Reads a file until its end, as its size may change between `stat` and `read`.
+fn read_text(path: &str) -> String {
+    let file = fs::open(path, OpenFlags::READ_ONLY).unwrap();
+    let mut contents = Vec::new();
+    let mut buffer = [0; 64];
+    loop {
+        match file.read(&mut buffer).unwrap() {
+            0 => break,
+            count => contents.extend_from_slice(&buffer[..count]),
+        }
+    }
+    String::from_utf8(contents).unwrap()
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn test_memory_devices() {
+    let null = fs::open("/dev/null", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(null.write(b"discarded"), Ok(9));
+    assert_eq!(null.read(&mut [0; 16]), Ok(0));
+
+    let zero = fs::open("/dev/zero", OpenFlags::READ_ONLY).unwrap();
+    let mut buffer = [0xAA; 100];
+    assert_eq!(zero.read(&mut buffer), Ok(100));
+    assert!(buffer.iter().all(|&byte| byte == 0));
+
+    let random = fs::open("/dev/random", OpenFlags::READ_ONLY).unwrap();
+    let mut first = [0; 64];
+    let mut second = [0; 64];
+    assert_eq!(random.read(&mut first), Ok(64));
+    assert_eq!(random.read(&mut second), Ok(64));
+    assert_ne!(first, second);
+    assert_ne!(first, [0; 64]);
+
+    let metadata = fs::stat("/dev/console").unwrap();
+    assert_eq!(metadata.file_type, FileType::CharacterDevice);
+    assert_eq!(
+        fs::open("/dev/new", OpenFlags::CREATE).err(),
+        Some(FsError::ReadOnly)
+    );
+}
+
+#[test_case]
+fn test_block_devices_are_listed_and_readable() {
+    let listed = names("/dev");
+    assert_eq!(
+        &listed[..5],
+        ["console", "serial0", "null", "zero", "random"]
+    );
+    for device in block::devices() {
+        assert!(listed.iter().any(|name| name == device.name()));
+    }
+
+    // The second IDE disk holds pattern.img: the first bytes of each sector hold its number.
+    let metadata = fs::stat("/dev/hdb").unwrap();
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 256 * 512);
+    let disk = fs::open("/dev/hdb", OpenFlags::READ_WRITE).unwrap();
+    disk.seek(fs::SeekFrom::Start(3 * 512 + 4)).unwrap();
+    let mut buffer = [0; 4];
+    disk.read(&mut buffer).unwrap();
+    assert_eq!(buffer, [(3 * 7 + 4) as u8, 26, 27, 28]);
+
+    // Writes need not be aligned to sectors.
+    disk.seek(fs::SeekFrom::Start(510)).unwrap();
+    assert_eq!(disk.write(b"span"), Ok(4));
+    disk.seek(fs::SeekFrom::Start(508)).unwrap();
+    let mut buffer = [0; 8];
+    disk.read(&mut buffer).unwrap();
+    assert_eq!(&buffer, &[252, 253, b's', b'p', b'a', b'n', 0, 0]);
+    disk.seek(fs::SeekFrom::End(-2)).unwrap();
+    assert_eq!(disk.read(&mut [0; 16]), Ok(2));
+}
+
+#[test_case]
+fn test_kernel_state_files() {
+    assert_eq!(
+        names("/proc")[..4],
+        ["cpuinfo", "interrupts", "meminfo", "uptime"]
+    );
+    assert!(read_text("/proc/cpuinfo").starts_with("vendor_id\t: "));
+    let memory = read_text("/proc/meminfo");
+    assert!(memory.starts_with("MemTotal:"));
+    assert!(memory.contains("HeapFree:"));
+
+    let before = read_text("/proc/interrupts");
+    let timer_count = |text: &str| -> u64 {
+        let line = text.lines().find(|line| line.ends_with("timer")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    };
+    let uptime = read_text("/proc/uptime");
+    scheduler::sleep(3);
+    assert!(timer_count(&read_text("/proc/interrupts")) > timer_count(&before));
+
+    let milliseconds = |field: &str| -> u64 {
+        let (seconds, hundredths) = field.split_once('.').unwrap();
+        seconds.parse::<u64>().unwrap() * 1000 + hundredths.parse::<u64>().unwrap() * 10
+    };
+    let (up, idle) = uptime.trim_end().split_once(' ').unwrap();
+    assert!(milliseconds(up) <= pit::uptime_milliseconds());
+    assert!(milliseconds(idle) <= milliseconds(up));
+    // Nothing else was ready while this thread slept, so the CPU was idle.
+    let uptime_after = read_text("/proc/uptime");
+    let (_, idle_after) = uptime_after.trim_end().split_once(' ').unwrap();
+    assert!(milliseconds(idle_after) > milliseconds(idle));
+    assert_eq!(
+        fs::open("/proc/uptime", OpenFlags::WRITE_ONLY)
+            .unwrap()
+            .write(b"0"),
+        Err(FsError::ReadOnly)
+    );
+}
+
+#[test_case]
+fn test_process_directories() {
+    let pid = process::spawn(HELLO, &["hello"], &[]).unwrap();
+    let directory = format!("/proc/{pid}");
+    assert!(names("/proc").contains(&format!("{pid}")));
+    assert_eq!(names(&directory), ["maps", "status"]);
+
+    let status = read_text(&format!("{directory}/status"));
+    assert!(status.starts_with(&format!("Pid:\t{pid}\n")));
+    assert!(status.contains("State:\tR (running)"));
+    assert!(status.contains("FDSize:\t3"));
+    assert!(read_text(&format!("{directory}/maps")).lines().count() > 0);
+    // The kernel thread running the test belongs to no process.
+    assert_eq!(fs::stat("/proc/self"), Err(FsError::NotFound));
+
+    process::wait(pid).unwrap();
+    assert_eq!(fs::stat(&directory), Err(FsError::NotFound));
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::panic::PanicInfo;
+use rust_os::{
+    fs::{self, FileType, FsError, OpenFlags, RamFs, SeekFrom},
+    process,
+};
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn test_create_write_read_delete() {
+    let file = fs::open("/hello.txt", OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"hello, ramfs"), Ok(12));
+    assert_eq!(file.seek(SeekFrom::Start(7)), Ok(7));
+    let mut buffer = [0; 16];
+    assert_eq!(file.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"ramfs");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    assert_eq!(fs::stat("/hello.txt").unwrap().size, 12);
+
+    fs::remove("/hello.txt").unwrap();
+    assert_eq!(fs::stat("/hello.txt"), Err(FsError::NotFound));
+    // The open file keeps its data until it is dropped.
+    assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+    assert_eq!(file.read(&mut buffer), Ok(12));
+}
+
+#[test_case]
+fn test_directories() {
+    fs::create_directory("/config").unwrap();
+    fs::create_directory("/config/init").unwrap();
+    fs::open(
+        "/config/init/../motd",
+        OpenFlags::WRITE_ONLY | OpenFlags::CREATE,
+    )
+    .unwrap();
+    assert_eq!(names("/config"), ["init", "motd"]);
+    assert_eq!(
+        fs::stat("/config/./init").unwrap().file_type,
+        FileType::Directory
+    );
+    assert_eq!(
+        fs::open("/config/motd/x", OpenFlags::READ_ONLY).err(),
+        Some(FsError::NotADirectory)
+    );
+    assert_eq!(
+        fs::open("/config", OpenFlags::WRITE_ONLY).err(),
+        Some(FsError::IsADirectory)
+    );
+    assert_eq!(
+        fs::open("/config/motd", OpenFlags::CREATE | OpenFlags::EXCLUSIVE).err(),
+        Some(FsError::AlreadyExists)
+    );
+    assert_eq!(fs::remove("/config"), Err(FsError::DirectoryNotEmpty));
+    fs::remove("/config/motd").unwrap();
+    fs::remove("/config/init").unwrap();
+    fs::remove("/config").unwrap();
+}
+
+#[test_case]
+fn test_rename() {
+    fs::create_directory("/a").unwrap();
+    fs::create_directory("/a/b").unwrap();
+    let file = fs::open("/a/one", OpenFlags::WRITE_ONLY | OpenFlags::CREATE).unwrap();
+    file.write(b"1").unwrap();
+    fs::open("/a/b/two", OpenFlags::WRITE_ONLY | OpenFlags::CREATE).unwrap();
+
+    fs::rename("/a/one", "/a/b/two").unwrap();
+    assert_eq!(names("/a"), ["b"]);
+    assert_eq!(names("/a/b"), ["two"]);
+    assert_eq!(fs::stat("/a/b/two").unwrap().size, 1);
+    assert_eq!(fs::rename("/a", "/a/b/c"), Err(FsError::InvalidArgument));
+    fs::rename("/a/b", "/b").unwrap();
+    assert_eq!(names("/b"), ["two"]);
+
+    fs::remove("/b/two").unwrap();
+    fs::remove("/b").unwrap();
+    fs::remove("/a").unwrap();
+}
+
+#[test_case]
+fn test_truncate_and_size_accounting() {
+    let ramfs = Arc::new(RamFs::with_capacity(8));
+    fs::create_directory("/small").unwrap();
+    fs::mount("/small", ramfs.clone()).unwrap();
+
+    let file = fs::open("/small/data", OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"12345678"), Ok(8));
+    assert_eq!(ramfs.used_bytes(), 8);
+    assert_eq!(file.write(b"9"), Err(FsError::NoSpace));
+    drop(file);
+
+    let file = fs::open("/small/data", OpenFlags::WRITE_ONLY | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(ramfs.used_bytes(), 0);
+    file.write(b"abc").unwrap();
+    assert_eq!(fs::stat("/small/data").unwrap().size, 3);
+    assert_eq!(
+        fs::rename("/small/data", "/data"),
+        Err(FsError::CrossDevice)
+    );
+    assert_eq!(fs::remove("/small"), Err(FsError::Busy));
+
+    drop(file);
+    fs::remove("/small/data").unwrap();
+    assert_eq!(ramfs.used_bytes(), 0);
+    fs::unmount("/small").unwrap();
+    fs::remove("/small").unwrap();
+}
+
+#[test_case]
+fn test_initramfs_is_unpacked_at_boot() {
+    let file = fs::open("/etc/motd", OpenFlags::READ_ONLY).unwrap();
+    let mut buffer = [0; 64];
+    let length = file.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..length], b"Welcome to rust_os!\n");
+
+    let size = fs::stat("/bin/hello").unwrap().size as usize;
+    let mut program = vec![0; size];
+    let file = fs::open("/bin/hello", OpenFlags::READ_ONLY).unwrap();
+    assert_eq!(file.read(&mut program), Ok(size));
+    let pid = process::spawn(&program, &["hello", "world"], &[]).unwrap();
+    assert_eq!(process::wait(pid), Ok(2));
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{fmt::Write, panic::PanicInfo};
+use rust_os::{
+    fs::{self, OpenFlags},
+    serial,
+    shell::{SerialTerminal, Terminal},
+};
+
+/// Reads exactly `buffer.len()` bytes from the receive buffer.
+fn receive(buffer: &mut [u8]) {
+    let mut done = 0;
+    while done < buffer.len() {
+        done += serial::read(&mut buffer[done..]);
+    }
+}
+
+#[test_case]
+fn test_received_bytes_reach_the_device_file() {
+    serial::set_loopback(true);
+    let device = fs::open("/dev/serial0", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(device.write(b"ping\x08"), Ok(5));
+    let mut buffer = [0; 5];
+    let mut done = 0;
+    while done < buffer.len() {
+        done += device.read(&mut buffer[done..]).unwrap();
+    }
+    serial::set_loopback(false);
+    assert_eq!(&buffer, b"ping\x08");
+}
+
+#[test_case]
+fn test_terminal_translates_line_endings() {
+    serial::set_loopback(true);
+    let mut terminal = SerialTerminal;
+    write!(terminal, "a\nb").unwrap();
+    let mut buffer = [0; 4];
+    receive(&mut buffer);
+    terminal.clear_screen();
+    let mut escape = [0; 7];
+    receive(&mut escape);
+    // Input typed while the shell waits is read a byte at a time.
+    write!(terminal, "x").unwrap();
+    let typed = terminal.read_byte();
+    serial::set_loopback(false);
+    assert_eq!(&buffer, b"a\r\nb");
+    assert_eq!(&escape, b"\x1b[2J\x1b[H");
+    assert_eq!(typed, b'x');
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
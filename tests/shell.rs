@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt, panic::PanicInfo};
+use rust_os::{
+    fs::{self, OpenFlags},
+    shell::{Shell, Terminal},
+};
+
+/// Collects everything the shell prints; it never reads, as commands are passed in directly.
+#[derive(Default)]
+struct Capture {
+    output: String,
+    cleared: bool,
+}
+
+impl fmt::Write for Capture {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.output.push_str(text);
+        Ok(())
+    }
+}
+
+impl Terminal for Capture {
+    fn read_byte(&mut self) -> u8 {
+        panic!("commands do not read input");
+    }
+
+    fn clear_screen(&mut self) {
+        self.cleared = true;
+    }
+}
+
+fn run(shell: &mut Shell, line: &str) -> String {
+    let mut terminal = Capture::default();
+    shell.execute(line, &mut terminal);
+    terminal.output
+}
+
+#[test_case]
+fn test_simple_commands() {
+    let mut shell = Shell::new();
+    assert_eq!(run(&mut shell, "echo  hello   world"), "hello world\n");
+    assert_eq!(run(&mut shell, "   "), "");
+    assert_eq!(
+        run(&mut shell, "frobnicate"),
+        "frobnicate: command not found\n"
+    );
+    assert!(run(&mut shell, "help").contains("uptime"));
+    assert!(run(&mut shell, "mem").starts_with("MemTotal:"));
+    assert!(run(&mut shell, "irq").contains("timer"));
+    assert!(run(&mut shell, "uptime").starts_with("up 0:0"));
+
+    let mut terminal = Capture::default();
+    shell.execute("clear", &mut terminal);
+    assert!(terminal.cleared);
+}
+
+#[test_case]
+fn test_file_commands() {
+    let mut shell = Shell::new();
+    fs::create_directory("/shell").unwrap();
+    fs::open(
+        "/shell/notes.txt",
+        OpenFlags::CREATE | OpenFlags::WRITE_ONLY,
+    )
+    .unwrap()
+    .write(b"remember\n")
+    .unwrap();
+    fs::create_directory("/shell/sub").unwrap();
+
+    assert_eq!(run(&mut shell, "ls /shell"), "notes.txt\nsub/\n");
+    assert_eq!(run(&mut shell, "cat /etc/motd"), "Welcome to rust_os!\n");
+    assert_eq!(run(&mut shell, "cd shell/sub/.."), "");
+    assert_eq!(shell.working_directory(), "/shell");
+    assert_eq!(run(&mut shell, "pwd"), "/shell\n");
+    assert_eq!(run(&mut shell, "cat notes.txt"), "remember\n");
+    assert_eq!(run(&mut shell, "ls notes.txt"), "notes.txt\n");
+    assert_eq!(
+        run(&mut shell, "cat missing"),
+        "cat: no such file or directory\n"
+    );
+    assert_eq!(run(&mut shell, "cd notes.txt"), "cd: not a directory\n");
+    assert_eq!(run(&mut shell, "cat"), "usage: cat path...\n");
+    assert_eq!(run(&mut shell, "cd"), "");
+    assert_eq!(shell.working_directory(), "/");
+}
+
+#[test_case]
+fn test_cat_decodes_utf8() {
+    let mut shell = Shell::new();
+    // The `é` straddles the end of the first read, the last byte is not UTF-8.
+    let mut text = "a".repeat(511).into_bytes();
+    text.extend_from_slice("é─\n".as_bytes());
+    text.push(0xFF);
+    fs::open("/utf8.txt", OpenFlags::CREATE | OpenFlags::WRITE_ONLY)
+        .unwrap()
+        .write(&text)
+        .unwrap();
+    let output = run(&mut shell, "cat /utf8.txt");
+    assert_eq!(output.strip_prefix(&"a".repeat(511)), Some("é─\n\u{FFFD}"));
+}
+
+#[test_case]
+fn test_completion() {
+    let shell = Shell::new();
+    assert_eq!(shell.complete("ec"), ["echo"]);
+    assert_eq!(shell.complete("c"), ["cat", "cd", "clear"]);
+    assert_eq!(shell.complete("cat /etc/mo"), ["/etc/motd"]);
+    assert_eq!(shell.complete("ls pr"), ["proc/"]);
+    let devices: Vec<String> = shell.complete("cat /dev/z");
+    assert_eq!(devices, ["/dev/zero"]);
+    assert!(shell.complete("cat /missing/").is_empty());
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
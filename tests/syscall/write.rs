@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+#[path = "../common/mod.rs"]
+mod common;
+
+use rust_os::{memory, syscall, vga_buffer::VGA_WRITER};
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageTableFlags},
+};
+
+should_run_test!(syscall_write);
+
+const MESSAGE: &str = "Hello from ring 3!";
+const PROGRAM_ADDRESS: u64 = 0x0000_4000_0000_0000;
+const STACK_ADDRESS: u64 = 0x0000_4000_0010_0000;
+
+// Writes MESSAGE to stdout and exits with the return value of `write`.
+core::arch::global_asm!(
+    ".global user_write_program_start",
+    ".global user_write_program_end",
+    "user_write_program_start:",
+    "mov eax, 1",
+    "mov edi, 1",
+    "lea rsi, [rip + 2f]",
+    "mov edx, {message_length}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 60",
+    "syscall",
+    "2:",
+    ".ascii \"Hello from ring 3!\"",
+    "user_write_program_end:",
+    message_length = const MESSAGE.len(),
+);
+
+unsafe extern "C" {
+    static user_write_program_start: u8;
+    static user_write_program_end: u8;
+}
+
+fn syscall_write() {
+    let program = unsafe {
+        let start = &raw const user_write_program_start;
+        let end = &raw const user_write_program_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    let program_page = Page::containing_address(VirtAddr::new(PROGRAM_ADDRESS));
+    let stack_page = Page::containing_address(VirtAddr::new(STACK_ADDRESS));
+    memory::map_user_pages(program_page, 1, PageTableFlags::WRITABLE).unwrap();
+    memory::map_user_pages(
+        stack_page,
+        1,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .unwrap();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            program.as_ptr(),
+            program_page.start_address().as_mut_ptr::<u8>(),
+            program.len(),
+        );
+    }
+
+    VGA_WRITER.lock().clear();
+    let exit_code = unsafe {
+        syscall::enter_user_mode(
+            program_page.start_address(),
+            (stack_page + 1).start_address(),
+        )
+    };
+    assert_eq!(exit_code, MESSAGE.len() as i64);
+
+    let last_row = 0xb8000 as *const u16;
+    let last_row = unsafe { last_row.add(24 * 80) };
+    for (column, expected) in MESSAGE.bytes().enumerate() {
+        let character = unsafe { core::ptr::read_volatile(last_row.add(column)) } as u8;
+        assert_eq!(expected, character);
+    }
+}
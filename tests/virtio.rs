@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use common::check_pattern;
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{
+        self, BlockDevice, BlockError, SECTOR_SIZE,
+        virtio::{self, VirtioBlock},
+    },
+    pci,
+};
+
+/// Sectors of `tests/disks/pattern.img`, attached once through each transport.
+const SECTORS: u64 = 256;
+
+/// The disks holding `tests/disks/pattern.img`; the others hold file system images.
+fn disks() -> Vec<Arc<VirtioBlock>> {
+    let disks: Vec<_> = virtio::disks()
+        .into_iter()
+        .filter(|disk| disk.block_count() == SECTORS)
+        .collect();
+    assert_eq!(disks.len(), 2);
+    disks
+}
+
+#[test_case]
+fn test_both_transports_are_bound() {
+    let disks = disks();
+    assert!(disks.iter().any(|disk| disk.is_modern()));
+    assert!(disks.iter().any(|disk| !disk.is_modern()));
+    for disk in &disks {
+        assert_eq!(disk.block_count(), SECTORS);
+        assert!(!disk.is_read_only());
+        assert!(block::find(disk.name()).is_some());
+    }
+    let bound = pci::devices()
+        .iter()
+        .filter(|device| device.driver() == Some("virtio-blk"))
+        .count();
+    assert_eq!(bound, virtio::disks().len());
+}
+
+#[test_case]
+fn test_reads_known_pattern() {
+    for disk in disks() {
+        let interrupts = disk.interrupt_count();
+        // Larger than the bounce buffer, so the read is split into two requests.
+        let mut buffer = vec![0; SECTORS as usize * SECTOR_SIZE];
+        disk.read_blocks(0, &mut buffer).unwrap();
+        check_pattern(0, &buffer);
+        assert!(disk.interrupt_count() > interrupts);
+
+        let mut buffer = vec![0; SECTOR_SIZE];
+        disk.read_blocks(SECTORS - 1, &mut buffer).unwrap();
+        check_pattern(SECTORS - 1, &buffer);
+    }
+}
+
+#[test_case]
+fn test_write_and_read_back() {
+    for disk in disks() {
+        let written: Vec<u8> = (0..2 * SECTOR_SIZE)
+            .map(|index| (index / 3) as u8)
+            .collect();
+        disk.write_blocks(10, &written).unwrap();
+        disk.flush().unwrap();
+        let mut read = vec![0; 2 * SECTOR_SIZE];
+        disk.read_blocks(10, &mut read).unwrap();
+        assert_eq!(read, written);
+
+        let mut buffer = vec![0; SECTOR_SIZE];
+        disk.read_blocks(12, &mut buffer).unwrap();
+        check_pattern(12, &buffer);
+    }
+}
+
+#[test_case]
+fn test_rejects_bad_requests() {
+    for disk in disks() {
+        let mut buffer = vec![0; 2 * SECTOR_SIZE];
+        assert_eq!(
+            disk.read_blocks(SECTORS - 1, &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            disk.write_blocks(0, &buffer[..SECTOR_SIZE + 1]),
+            Err(BlockError::BadBufferSize)
+        );
+    }
+}
+
+#[cfg(test)]
+bootloader::entry_point!(kernel_main);
+
+#[cfg(test)]
+fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}